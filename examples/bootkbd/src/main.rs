@@ -116,7 +116,7 @@ fn main() -> ! {
 
     usb_host.reset_host();
 
-    let mut usb_stack = UsbStack::new(usb_host);
+    let mut usb_stack: UsbStack<atsamd::HostController> = UsbStack::new(usb_host);
     let bootkbd = BootKbdDriver::new();
     usb_stack.add_driver(BOOTKBD.init_static(bootkbd));
     USB_STACK.init_static(usb_stack);
@@ -6,7 +6,8 @@ const MAX_DEVICES: u8 = 127;
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct DevAddress(u8);
 
-pub struct AddressPool {
+/// Hands out addresses 1 to `N`, at most 127
+pub struct AddressPool<const N: usize = 127> {
     pool_bits: u128,
 }
 
@@ -25,15 +26,18 @@ impl From<DevAddress> for u8 {
     }
 }
 
-const FULL_POOL: u128 = u128::MAX >> 1;
+impl<const N: usize> AddressPool<N> {
+    /// Address `a` is bit `127 - a`, addresses above `N` are never free
+    const FULL_POOL: u128 = (u128::MAX >> 1) & !((1 << (MAX_DEVICES as usize - N)) - 1);
 
-impl AddressPool {
     pub fn new() -> Self {
-        Self { pool_bits: FULL_POOL }
+        Self {
+            pool_bits: Self::FULL_POOL,
+        }
     }
 
     pub fn reset(&mut self) {
-        self.pool_bits = FULL_POOL
+        self.pool_bits = Self::FULL_POOL
     }
 
     pub fn take_next(&mut self) -> Option<DevAddress> {
@@ -47,8 +51,9 @@ impl AddressPool {
 
     pub fn put_back(&mut self, addr: DevAddress) {
         let addr: u8 = addr.into();
-        if addr <= MAX_DEVICES {
-            self.pool_bits |= 1 << addr
+        // address 0 is the default address, never allocated
+        if addr > 0 && addr as usize <= N {
+            self.pool_bits |= 1 << (MAX_DEVICES - addr)
         }
     }
 }
@@ -56,20 +61,46 @@ impl AddressPool {
 #[cfg(test)]
 mod test {
 
-    use crate::address::AddressPool;
+    use crate::address::{AddressPool, DevAddress};
 
     #[test]
     fn take_one() {
-        let mut pool = AddressPool::new();
+        let mut pool = <AddressPool>::new();
         assert_eq!(1u8, pool.take_next().unwrap().0)
     }
 
     #[test]
     fn take_all() {
-        let mut pool = AddressPool::new();
-        for i in 1u8..127 {
+        let mut pool = <AddressPool>::new();
+        for i in 1u8..=127 {
             assert_eq!(i, pool.take_next().unwrap().0);
         }
         assert_eq!(None, pool.take_next())
     }
+
+    #[test]
+    fn put_back() {
+        let mut pool = <AddressPool>::new();
+        let first = pool.take_next().unwrap();
+        let second = pool.take_next().unwrap();
+        pool.put_back(first);
+        assert_eq!(first, pool.take_next().unwrap());
+        pool.put_back(DevAddress::from(0));
+        assert_eq!(3u8, pool.take_next().unwrap().0);
+        pool.put_back(second);
+        assert_eq!(second, pool.take_next().unwrap());
+    }
+
+    #[test]
+    fn small_pool() {
+        let mut pool = AddressPool::<3>::new();
+        for i in 1u8..=3 {
+            assert_eq!(i, pool.take_next().unwrap().0);
+        }
+        assert_eq!(None, pool.take_next());
+        pool.put_back(DevAddress::from(4));
+        assert_eq!(None, pool.take_next());
+        pool.put_back(DevAddress::from(2));
+        assert_eq!(2u8, pool.take_next().unwrap().0);
+    }
 }
@@ -0,0 +1,346 @@
+use crate::asynch::{AsyncUsbHost, BlockingHost};
+use crate::{
+    ConfigNum, DescriptorParser, DevAddress, Device, DeviceClass, DeviceState, Driver, DriverIdx, DynDrivers,
+    InterfaceNum, PortChange, PortNum, UsbError, UsbHost,
+};
+
+/// Async counterpart of `Driver`.
+/// Descriptor handling is the same as for blocking drivers, only bus operations are async.
+#[allow(async_fn_in_trait)]
+pub trait AsyncDriver<H: AsyncUsbHost> {
+    fn name(&self) -> &str;
+
+    fn accept(
+        &self, device: &mut Device, conf: &mut DescriptorParser,
+    ) -> Option<(DeviceClass, ConfigNum, InterfaceNum)>;
+
+    /// How well this driver supports a configuration, see `Driver::score`
+    fn score(&self, device: &mut Device, conf: &mut DescriptorParser) -> Option<u8> {
+        self.accept(device, conf).map(|_| 1)
+    }
+
+    fn register(&mut self, device: &mut Device, conf: &mut DescriptorParser) -> Result<(), UsbError>;
+
+    fn unregister(&mut self, device: DevAddress);
+
+    /// Milliseconds between two `run` calls once the device is running, see `Driver::poll_interval`
+    fn poll_interval(&self, _device: &Device) -> Option<u64> {
+        None
+    }
+
+    /// Called once the configuration is set. Returns the state the device should be `run` in.
+    async fn configured(&mut self, _host: &mut H, _device: &mut Device) -> DeviceState {
+        DeviceState::Running
+    }
+
+    async fn run(&mut self, host: &mut H, device: &mut Device) -> Result<(), UsbError>;
+
+    /// Downstream port changes of hub drivers, see `Driver::port_change`
+    async fn port_change(&mut self, _host: &mut H, _hub: &mut Device, _enumerating: bool) -> Option<PortChange> {
+        None
+    }
+
+    /// Downstream port reset of hub drivers, see `Driver::reset_port`
+    async fn reset_port(&mut self, _host: &mut H, _hub: &mut Device, _port: PortNum) -> bool {
+        false
+    }
+}
+
+/// Compatibility layer running a blocking `Driver` on a host that also implements `UsbHost`.
+/// Its transfers busy wait on the host and block the executor until done, other tasks do not run meanwhile.
+pub struct BlockingDriver<D> {
+    driver: D,
+}
+
+impl<D: Driver> BlockingDriver<D> {
+    pub fn new(driver: D) -> Self {
+        Self { driver }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.driver
+    }
+}
+
+impl<H: AsyncUsbHost + UsbHost, D: Driver> AsyncDriver<H> for BlockingDriver<D> {
+    fn name(&self) -> &str {
+        self.driver.name()
+    }
+
+    fn accept(
+        &self, device: &mut Device, conf: &mut DescriptorParser,
+    ) -> Option<(DeviceClass, ConfigNum, InterfaceNum)> {
+        self.driver.accept(device, conf)
+    }
+
+    fn score(&self, device: &mut Device, conf: &mut DescriptorParser) -> Option<u8> {
+        self.driver.score(device, conf)
+    }
+
+    fn register(&mut self, device: &mut Device, conf: &mut DescriptorParser) -> Result<(), UsbError> {
+        self.driver.register(device, conf)
+    }
+
+    fn unregister(&mut self, device: DevAddress) {
+        self.driver.unregister(device)
+    }
+
+    fn poll_interval(&self, device: &Device) -> Option<u64> {
+        self.driver.poll_interval(device)
+    }
+
+    async fn configured(&mut self, host: &mut H, device: &mut Device) -> DeviceState {
+        self.driver.state_after_config_set(host, device)
+    }
+
+    async fn run(&mut self, host: &mut H, device: &mut Device) -> Result<(), UsbError> {
+        self.driver.run(host, device)
+    }
+
+    async fn port_change(&mut self, host: &mut H, hub: &mut Device, enumerating: bool) -> Option<PortChange> {
+        self.driver.port_change(host, hub, enumerating)
+    }
+
+    async fn reset_port(&mut self, host: &mut H, hub: &mut Device, port: PortNum) -> bool {
+        self.driver.reset_port(host, hub, port)
+    }
+}
+
+/// Fixed set of drivers, addressed by index.
+/// Async trait methods can not be called through `dyn`, implemented for tuples of up to 4 async drivers
+/// and for the blocking drivers of a `UsbStack`.
+#[allow(async_fn_in_trait)]
+pub trait AsyncDrivers<H: AsyncUsbHost> {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn name(&self, idx: DriverIdx) -> &str;
+
+    fn accept(
+        &self, idx: DriverIdx, device: &mut Device, conf: &mut DescriptorParser,
+    ) -> Option<(DeviceClass, ConfigNum, InterfaceNum)>;
+
+    fn score(&self, idx: DriverIdx, device: &mut Device, conf: &mut DescriptorParser) -> Option<u8>;
+
+    fn register(&mut self, idx: DriverIdx, device: &mut Device, conf: &mut DescriptorParser) -> Result<(), UsbError>;
+
+    fn unregister(&mut self, idx: DriverIdx, device: DevAddress);
+
+    fn poll_interval(&self, idx: DriverIdx, device: &Device) -> Option<u64>;
+
+    async fn configured(&mut self, idx: DriverIdx, host: &mut H, device: &mut Device) -> DeviceState;
+
+    async fn run(&mut self, idx: DriverIdx, host: &mut H, device: &mut Device) -> Result<(), UsbError>;
+
+    async fn port_change(
+        &mut self, idx: DriverIdx, host: &mut H, hub: &mut Device, enumerating: bool,
+    ) -> Option<PortChange>;
+
+    async fn reset_port(&mut self, idx: DriverIdx, host: &mut H, hub: &mut Device, port: PortNum) -> bool;
+}
+
+impl<H: AsyncUsbHost> AsyncDrivers<H> for () {
+    fn len(&self) -> usize {
+        0
+    }
+
+    fn name(&self, _idx: DriverIdx) -> &str {
+        ""
+    }
+
+    fn accept(
+        &self, _idx: DriverIdx, _device: &mut Device, _conf: &mut DescriptorParser,
+    ) -> Option<(DeviceClass, ConfigNum, InterfaceNum)> {
+        None
+    }
+
+    fn score(&self, _idx: DriverIdx, _device: &mut Device, _conf: &mut DescriptorParser) -> Option<u8> {
+        None
+    }
+
+    fn register(
+        &mut self, _idx: DriverIdx, _device: &mut Device, _conf: &mut DescriptorParser,
+    ) -> Result<(), UsbError> {
+        Err(UsbError::NoDriver)
+    }
+
+    fn unregister(&mut self, _idx: DriverIdx, _device: DevAddress) {}
+
+    fn poll_interval(&self, _idx: DriverIdx, _device: &Device) -> Option<u64> {
+        None
+    }
+
+    async fn configured(&mut self, _idx: DriverIdx, _host: &mut H, _device: &mut Device) -> DeviceState {
+        DeviceState::Running
+    }
+
+    async fn run(&mut self, _idx: DriverIdx, _host: &mut H, _device: &mut Device) -> Result<(), UsbError> {
+        Err(UsbError::NoDriver)
+    }
+
+    async fn port_change(
+        &mut self, _idx: DriverIdx, _host: &mut H, _hub: &mut Device, _enumerating: bool,
+    ) -> Option<PortChange> {
+        None
+    }
+
+    async fn reset_port(&mut self, _idx: DriverIdx, _host: &mut H, _hub: &mut Device, _port: PortNum) -> bool {
+        false
+    }
+}
+
+macro_rules! driver_tuple {
+    ($len:expr; $($idx:tt $drv:ident),+) => {
+        impl<H: AsyncUsbHost, $($drv: AsyncDriver<H>),+> AsyncDrivers<H> for ($($drv,)+) {
+            fn len(&self) -> usize {
+                $len
+            }
+
+            fn name(&self, idx: DriverIdx) -> &str {
+                match idx {
+                    $($idx => self.$idx.name(),)+
+                    _ => "",
+                }
+            }
+
+            fn accept(
+                &self, idx: DriverIdx, device: &mut Device, conf: &mut DescriptorParser,
+            ) -> Option<(DeviceClass, ConfigNum, InterfaceNum)> {
+                match idx {
+                    $($idx => self.$idx.accept(device, conf),)+
+                    _ => None,
+                }
+            }
+
+            fn score(&self, idx: DriverIdx, device: &mut Device, conf: &mut DescriptorParser) -> Option<u8> {
+                match idx {
+                    $($idx => self.$idx.score(device, conf),)+
+                    _ => None,
+                }
+            }
+
+            fn register(
+                &mut self, idx: DriverIdx, device: &mut Device, conf: &mut DescriptorParser,
+            ) -> Result<(), UsbError> {
+                match idx {
+                    $($idx => self.$idx.register(device, conf),)+
+                    _ => Err(UsbError::NoDriver),
+                }
+            }
+
+            fn unregister(&mut self, idx: DriverIdx, device: DevAddress) {
+                match idx {
+                    $($idx => self.$idx.unregister(device),)+
+                    _ => {}
+                }
+            }
+
+            fn poll_interval(&self, idx: DriverIdx, device: &Device) -> Option<u64> {
+                match idx {
+                    $($idx => self.$idx.poll_interval(device),)+
+                    _ => None,
+                }
+            }
+
+            async fn configured(&mut self, idx: DriverIdx, host: &mut H, device: &mut Device) -> DeviceState {
+                match idx {
+                    $($idx => self.$idx.configured(host, device).await,)+
+                    _ => DeviceState::Running,
+                }
+            }
+
+            async fn run(&mut self, idx: DriverIdx, host: &mut H, device: &mut Device) -> Result<(), UsbError> {
+                match idx {
+                    $($idx => self.$idx.run(host, device).await,)+
+                    _ => Err(UsbError::NoDriver),
+                }
+            }
+
+            async fn port_change(
+                &mut self, idx: DriverIdx, host: &mut H, hub: &mut Device, enumerating: bool,
+            ) -> Option<PortChange> {
+                match idx {
+                    $($idx => self.$idx.port_change(host, hub, enumerating).await,)+
+                    _ => None,
+                }
+            }
+
+            async fn reset_port(&mut self, idx: DriverIdx, host: &mut H, hub: &mut Device, port: PortNum) -> bool {
+                match idx {
+                    $($idx => self.$idx.reset_port(host, hub, port).await,)+
+                    _ => false,
+                }
+            }
+        }
+    };
+}
+
+driver_tuple!(1; 0 A);
+driver_tuple!(2; 0 A, 1 B);
+driver_tuple!(3; 0 A, 1 B, 2 C);
+driver_tuple!(4; 0 A, 1 B, 2 C, 3 D);
+
+impl<const N: usize> AsyncDrivers<BlockingHost<'_>> for DynDrivers<N> {
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn name(&self, idx: DriverIdx) -> &str {
+        self.get(idx as usize).map_or("", |driver| driver.name())
+    }
+
+    fn accept(
+        &self, idx: DriverIdx, device: &mut Device, conf: &mut DescriptorParser,
+    ) -> Option<(DeviceClass, ConfigNum, InterfaceNum)> {
+        self.get(idx as usize)?.accept(device, conf)
+    }
+
+    fn score(&self, idx: DriverIdx, device: &mut Device, conf: &mut DescriptorParser) -> Option<u8> {
+        self.get(idx as usize)?.score(device, conf)
+    }
+
+    fn register(&mut self, idx: DriverIdx, device: &mut Device, conf: &mut DescriptorParser) -> Result<(), UsbError> {
+        let driver = self.get_mut(idx as usize).ok_or(UsbError::NoDriver)?;
+        driver.register(device, conf)
+    }
+
+    fn unregister(&mut self, idx: DriverIdx, device: DevAddress) {
+        if let Some(driver) = self.get_mut(idx as usize) {
+            driver.unregister(device)
+        }
+    }
+
+    fn poll_interval(&self, idx: DriverIdx, device: &Device) -> Option<u64> {
+        self.get(idx as usize)?.poll_interval(device)
+    }
+
+    async fn configured(&mut self, idx: DriverIdx, host: &mut BlockingHost<'_>, device: &mut Device) -> DeviceState {
+        match self.get(idx as usize) {
+            Some(driver) => driver.state_after_config_set(host.inner_mut(), device),
+            None => DeviceState::Running,
+        }
+    }
+
+    async fn run(&mut self, idx: DriverIdx, host: &mut BlockingHost<'_>, device: &mut Device) -> Result<(), UsbError> {
+        let driver = self.get_mut(idx as usize).ok_or(UsbError::NoDriver)?;
+        driver.run(host.inner_mut(), device)
+    }
+
+    async fn port_change(
+        &mut self, idx: DriverIdx, host: &mut BlockingHost<'_>, hub: &mut Device, enumerating: bool,
+    ) -> Option<PortChange> {
+        self.get_mut(idx as usize)?.port_change(host.inner_mut(), hub, enumerating)
+    }
+
+    async fn reset_port(
+        &mut self, idx: DriverIdx, host: &mut BlockingHost<'_>, hub: &mut Device, port: PortNum,
+    ) -> bool {
+        match self.get_mut(idx as usize) {
+            Some(driver) => driver.reset_port(host.inner_mut(), hub, port),
+            None => false,
+        }
+    }
+}
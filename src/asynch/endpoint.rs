@@ -0,0 +1,139 @@
+use crate::asynch::AsyncUsbHost;
+use crate::{
+    DescriptorType, Device, Direction, Endpoint, FeatureSelector, HostEndpoint, HostError, RequestCode,
+    RequestDirection, RequestKind, RequestRecipient, RequestType, TransferType, UsbError, WValue,
+};
+
+/// Async counterpart of `ControlEndpoint`
+#[allow(async_fn_in_trait)]
+pub trait AsyncControlEndpoint: HostEndpoint + Sized {
+    /// Generic control transfer method.
+    /// Add transfer context to host errors.
+    async fn control<H: AsyncUsbHost>(
+        &mut self, host: &mut H, request: RequestType, code: u8, w_value: WValue, w_index: u16,
+        buffer: Option<&mut [u8]>,
+    ) -> Result<usize, UsbError> {
+        host.control_transfer(self as &mut dyn HostEndpoint, request, code, w_value, w_index, buffer)
+            .await
+            .map_err(|err| UsbError::Control(self.device_address(), request, code, err))
+    }
+
+    /// Retrieve descriptor(s)
+    async fn control_get_descriptor<H: AsyncUsbHost>(
+        &mut self, host: &mut H, desc_type: DescriptorType, desc_index: u8, buffer: &mut [u8],
+    ) -> Result<usize, UsbError> {
+        let request =
+            RequestType::from((RequestDirection::DeviceToHost, RequestKind::Standard, RequestRecipient::Device));
+        self.control(
+            host,
+            request,
+            RequestCode::GetDescriptor as u8,
+            WValue::lo_hi(desc_index, desc_type as u8),
+            0,
+            Some(buffer),
+        )
+        .await
+    }
+
+    /// Generic control write
+    async fn control_set<H: AsyncUsbHost>(
+        &mut self, host: &mut H, code: u8, recip: RequestRecipient, lo_val: u8, hi_val: u8, windex: u16,
+    ) -> Result<(), UsbError> {
+        let request = RequestType::from((RequestDirection::HostToDevice, RequestKind::Standard, recip));
+        self.control(host, request, code, WValue::lo_hi(lo_val, hi_val), windex, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Generic class control write
+    async fn control_set_class<H: AsyncUsbHost>(
+        &mut self, host: &mut H, code: u8, recip: RequestRecipient, lo_val: u8, hi_val: u8, windex: u16,
+    ) -> Result<(), UsbError> {
+        let request = RequestType::from((RequestDirection::HostToDevice, RequestKind::Class, recip));
+        self.control(host, request, code, WValue::lo_hi(lo_val, hi_val), windex, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn clear_feature<H: AsyncUsbHost>(
+        &mut self, host: &mut H, recip: RequestRecipient, feature: FeatureSelector, index: u16,
+    ) -> Result<(), UsbError> {
+        self.control_set(host, RequestCode::ClearFeature as u8, recip, feature as u8, 0, index)
+            .await
+    }
+
+    /// Resume a halted endpoint, its data toggle starts over from DATA0
+    async fn clear_halt<H: AsyncUsbHost>(&mut self, host: &mut H, ep: &mut dyn HostEndpoint) -> Result<(), UsbError> {
+        let index = u8::from(ep.endpoint_address()) as u16;
+        self.clear_feature(host, RequestRecipient::Endpoint, FeatureSelector::EndpointHalt, index)
+            .await?;
+        ep.set_toggle(false);
+        Ok(())
+    }
+}
+
+impl AsyncControlEndpoint for Device {}
+
+impl AsyncControlEndpoint for Endpoint {}
+
+/// Async counterpart of the halt recovery of `BulkEndpoint` and `InterruptEndpoint`
+async fn recover_halt<H: AsyncUsbHost>(host: &mut H, ep: &mut dyn HostEndpoint, err: HostError) -> HostError {
+    if err == HostError::Stall {
+        let mut ep0 = Endpoint::from_raw(ep.device_address(), 8, 0, TransferType::Control as u8);
+        if let Err(err) = ep0.clear_halt(host, ep).await {
+            warn!("USB Ep: {:?} halt not cleared {:?}", ep.endpoint_address(), err);
+        }
+    }
+    err
+}
+
+/// Async counterpart of `BulkEndpoint`
+#[allow(async_fn_in_trait)]
+pub trait AsyncBulkEndpoint: HostEndpoint + Sized {
+    async fn bulk_in<H: AsyncUsbHost>(&mut self, host: &mut H, buffer: &mut [u8]) -> Result<usize, UsbError> {
+        if self.transfer_type() != TransferType::Bulk {
+            return Err(UsbError::TransferTypeMismatch);
+        }
+        if self.direction() != Direction::In {
+            return Err(UsbError::DirectionMismatch);
+        }
+        match host.in_transfer(self as &mut dyn HostEndpoint, buffer).await {
+            Ok(len) => Ok(len),
+            Err(err) => Err(UsbError::BulkIn(self.ep_props(), recover_halt(host, self, err).await)),
+        }
+    }
+
+    async fn bulk_out<H: AsyncUsbHost>(&mut self, host: &mut H, buffer: &[u8]) -> Result<usize, UsbError> {
+        if self.transfer_type() != TransferType::Bulk {
+            return Err(UsbError::TransferTypeMismatch);
+        }
+        if self.direction() != Direction::Out {
+            return Err(UsbError::DirectionMismatch);
+        }
+        match host.out_transfer(self as &mut dyn HostEndpoint, buffer).await {
+            Ok(len) => Ok(len),
+            Err(err) => Err(UsbError::BulkOut(self.ep_props(), recover_halt(host, self, err).await)),
+        }
+    }
+}
+
+impl AsyncBulkEndpoint for Endpoint {}
+
+/// Async counterpart of `InterruptEndpoint`
+#[allow(async_fn_in_trait)]
+pub trait AsyncInterruptEndpoint: HostEndpoint + Sized {
+    async fn interrupt_in<H: AsyncUsbHost>(&mut self, host: &mut H, buffer: &mut [u8]) -> Result<usize, UsbError> {
+        if self.transfer_type() != TransferType::Interrupt {
+            return Err(UsbError::TransferTypeMismatch);
+        }
+        if self.direction() != Direction::In {
+            return Err(UsbError::DirectionMismatch);
+        }
+        match host.in_transfer(self as &mut dyn HostEndpoint, buffer).await {
+            Ok(len) => Ok(len),
+            Err(err) => Err(UsbError::Interrupt(self.ep_props(), recover_halt(host, self, err).await)),
+        }
+    }
+}
+
+impl AsyncInterruptEndpoint for Endpoint {}
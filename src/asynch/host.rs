@@ -0,0 +1,174 @@
+use crate::{HostEndpoint, HostError, HostEvent, RequestType, Speed, UsbHost, WValue};
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+
+/// Async counterpart of `UsbHost`.
+/// Transfer futures complete when the host controller interrupt reports the transaction done,
+/// see `TransferSignal`.
+#[allow(async_fn_in_trait)]
+pub trait AsyncUsbHost {
+    /// Check for attach / detach events, without waiting
+    fn update(&mut self) -> Option<HostEvent>;
+
+    /// Wait for the next attach / detach event, woken by the host controller interrupt
+    async fn wait_event(&mut self) -> HostEvent;
+
+    /// Reset the root port again, see `UsbHost::reset_bus`
+    fn reset_bus(&mut self) -> bool {
+        false
+    }
+
+    /// Get the current connection max packet size
+    fn max_host_packet_size(&self) -> u16;
+
+    /// Link speed of the device on the root port, see `UsbHost::root_port_speed`
+    fn root_port_speed(&self) -> Speed {
+        Speed::Full
+    }
+
+    fn now(&self) -> u64;
+
+    fn after_millis(&self, millis: u64) -> u64;
+
+    fn delay_done(&self, instant: u64) -> bool {
+        self.now() >= instant
+    }
+
+    /// Wait until `instant`, as obtained from `after_millis`, woken by a timer
+    async fn delay_until(&mut self, instant: u64);
+
+    /// Issue a control transfer with an optional data stage to `ep`.
+    /// On success, the amount of data transferred into `buf` is returned.
+    async fn control_transfer(
+        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, buf: Option<&mut [u8]>,
+    ) -> Result<usize, HostError>;
+
+    /// Issue a `w_length` bytes control IN transfer, handing the data stage to `sink` piece by piece,
+    /// see `UsbHost::control_in_stream`
+    #[allow(clippy::too_many_arguments)]
+    async fn control_in_stream(
+        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, w_length: u16, buf: &mut [u8], sink: &mut dyn FnMut(&[u8]),
+    ) -> Result<usize, HostError> {
+        let buf = buf.get_mut(..w_length as usize).ok_or(HostError::InvalidRequest)?;
+        let len = self
+            .control_transfer(ep, bm_request_type, b_request, w_value, w_index, Some(buf))
+            .await?;
+        sink(&buf[..len]);
+        Ok(len)
+    }
+
+    /// Issue a transfer from `ep` to the host.
+    async fn in_transfer(&mut self, ep: &mut dyn HostEndpoint, buf: &mut [u8]) -> Result<usize, HostError>;
+
+    /// Issue a transfer from the host to `ep`.
+    async fn out_transfer(&mut self, ep: &mut dyn HostEndpoint, buf: &[u8]) -> Result<usize, HostError>;
+}
+
+/// Compatibility layer running a blocking `UsbHost` as an `AsyncUsbHost`, transfers complete on first poll.
+/// Blocking hosts have no interrupt to wake on: `wait_event` and `delay_until` poll the host again
+/// on every executor pass, keeping the executor busy. Hosts with an interrupt implement `AsyncUsbHost` instead.
+pub struct BlockingHost<'a> {
+    host: &'a mut dyn UsbHost,
+}
+
+impl<'a> BlockingHost<'a> {
+    pub fn new(host: &'a mut dyn UsbHost) -> Self {
+        Self { host }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut dyn UsbHost {
+        self.host
+    }
+}
+
+impl AsyncUsbHost for BlockingHost<'_> {
+    fn update(&mut self) -> Option<HostEvent> {
+        self.host.update()
+    }
+
+    async fn wait_event(&mut self) -> HostEvent {
+        poll_fn(|cx| match self.host.update() {
+            Some(event) => Poll::Ready(event),
+            None => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    fn reset_bus(&mut self) -> bool {
+        self.host.reset_bus()
+    }
+
+    fn max_host_packet_size(&self) -> u16 {
+        self.host.max_host_packet_size()
+    }
+
+    fn root_port_speed(&self) -> Speed {
+        self.host.root_port_speed()
+    }
+
+    fn now(&self) -> u64 {
+        self.host.now()
+    }
+
+    fn after_millis(&self, millis: u64) -> u64 {
+        self.host.after_millis(millis)
+    }
+
+    fn delay_done(&self, instant: u64) -> bool {
+        self.host.delay_done(instant)
+    }
+
+    async fn delay_until(&mut self, instant: u64) {
+        poll_fn(|cx| {
+            if self.host.delay_done(instant) {
+                Poll::Ready(())
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    async fn control_transfer(
+        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, buf: Option<&mut [u8]>,
+    ) -> Result<usize, HostError> {
+        self.host
+            .control_transfer(ep, bm_request_type, b_request, w_value, w_index, buf)
+    }
+
+    async fn control_in_stream(
+        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, w_length: u16, buf: &mut [u8], sink: &mut dyn FnMut(&[u8]),
+    ) -> Result<usize, HostError> {
+        self.host
+            .control_in_stream(ep, bm_request_type, b_request, w_value, w_index, w_length, buf, sink)
+    }
+
+    async fn in_transfer(&mut self, ep: &mut dyn HostEndpoint, buf: &mut [u8]) -> Result<usize, HostError> {
+        self.host.in_transfer(ep, buf)
+    }
+
+    async fn out_transfer(&mut self, ep: &mut dyn HostEndpoint, buf: &[u8]) -> Result<usize, HostError> {
+        self.host.out_transfer(ep, buf)
+    }
+}
+
+/// Run a future to completion without an executor.
+/// Futures of blocking hosts and drivers are done on first poll, others are polled until they are.
+pub(crate) fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+            return out;
+        }
+    }
+}
@@ -0,0 +1,17 @@
+//! Async counterparts of the `UsbHost` and `Driver` traits, run by `UsbStack::run`.
+//!
+//! Host controller drivers complete transfer futures from their interrupt handler using `TransferSignal`.
+//! Existing blocking hosts and drivers can be used through `BlockingHost` and `BlockingDriver`.
+//!
+//! The stack only yields to other tasks from drivers written against `AsyncDriver`.
+//! A `BlockingDriver`, such as the bundled hub, keyboard and MIDI drivers, holds the executor during its transfers.
+
+mod driver;
+mod endpoint;
+mod host;
+mod signal;
+
+pub use driver::*;
+pub use endpoint::*;
+pub use host::*;
+pub use signal::*;
@@ -0,0 +1,113 @@
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Poll, Waker};
+use spin::Mutex;
+
+/// Wakes a transfer future from the host controller interrupt.
+///
+/// The HCD arms the signal and starts the transaction, then awaits `wait()`.
+/// The interrupt handler calls `signal()`, after which the HCD reads back the transaction status.
+/// `signal()` never blocks: if the waiting side holds the waker lock,
+/// it checks the flag again right after releasing it.
+pub struct TransferSignal {
+    done: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Default for TransferSignal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransferSignal {
+    pub const fn new() -> Self {
+        Self {
+            done: AtomicBool::new(false),
+            waker: Mutex::new(None),
+        }
+    }
+
+    /// Clear any previous completion, call before starting a transaction
+    pub fn reset(&self) {
+        self.done.store(false, Ordering::Release);
+    }
+
+    /// Mark transaction done and wake the waiting future. Safe to call from interrupt context.
+    pub fn signal(&self) {
+        self.done.store(true, Ordering::Release);
+        if let Some(mut waker) = self.waker.try_lock() {
+            if let Some(waker) = waker.take() {
+                waker.wake()
+            }
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done.load(Ordering::Acquire)
+    }
+
+    /// Wait until `signal()` is called, consuming the completion
+    pub async fn wait(&self) {
+        poll_fn(|cx| {
+            *self.waker.lock() = Some(cx.waker().clone());
+            if self.done.load(Ordering::Acquire) {
+                self.done.store(false, Ordering::Release);
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use core::future::Future;
+    use core::pin::pin;
+    use core::sync::atomic::AtomicUsize;
+    use core::task::Context;
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    struct CountWaker(AtomicUsize);
+
+    impl Wake for CountWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn signal_wakes_waiter() {
+        let signal = TransferSignal::new();
+        let count = Arc::new(CountWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(count.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut wait = pin!(signal.wait());
+        assert!(wait.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(count.0.load(Ordering::SeqCst), 0);
+
+        signal.signal();
+        assert_eq!(count.0.load(Ordering::SeqCst), 1);
+        assert!(wait.as_mut().poll(&mut cx).is_ready());
+        assert!(!signal.is_done());
+    }
+
+    #[test]
+    fn signal_before_wait() {
+        let signal = TransferSignal::new();
+        signal.signal();
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(pin!(signal.wait()).poll(&mut cx).is_ready());
+
+        signal.signal();
+        signal.reset();
+        assert!(pin!(signal.wait()).poll(&mut cx).is_pending());
+    }
+}
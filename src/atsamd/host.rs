@@ -1,6 +1,12 @@
-use crate::{HostEndpoint, HostError, HostEvent, RequestCode, RequestType, UsbError, UsbHost, WValue};
+use crate::asynch::{block_on, TransferSignal};
+use crate::{
+    HostEndpoint, HostError, HostEvent, RequestType, Speed, TransferHandle, TransferRequest, UsbError, UsbHost,
+    WValue,
+};
+use core::task::Poll;
 
-use crate::atsamd::pipe::table::PipeTable;
+use crate::atsamd::pipe::table::{frame_number, PipeTable};
+use crate::atsamd::pipe::{frame_interrupt, pipe_interrupt, TxWait};
 
 use bsp::hal;
 use hal::prelude::*;
@@ -9,11 +15,17 @@ use atsamd_hal::{
     calibration::{usb_transn_cal, usb_transp_cal, usb_trim_cal},
     clock::{ClockGenId, ClockSource, GenericClockController},
     gpio::{self},
-    target_device::{PM, USB},
+    target_device::{usb, PM, USB},
 };
 use gpio::v2::{Floating, Input, Output};
 use embedded_hal::digital::v2::OutputPin;
 
+// Woken by the host interrupts, see `HostController::on_interrupt`
+static HOST_SIGNAL: TransferSignal = TransferSignal::new();
+
+// Host start of frame interrupt flag, the `delay_until` tick
+const HSOF_FLAG: u16 = 1 << 2;
+
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum HostIrq {
@@ -154,21 +166,58 @@ impl HostController {
             host_enable_pin.set_high().expect("USB Reset [host enable pin]");
         }
 
-        self.usb.host().intenset.write(|w| {
-            w.dconn().set_bit();
-            w.ddisc().set_bit();
-            w.wakeup().set_bit();
-            w.ramacer().set_bit();
-            w.uprsm().set_bit();
-            w.dnrsm().set_bit();
-            w.rst().set_bit();
-            w.hsof().set_bit()
-        });
+        unmask_host_irqs(self.usb.host());
 
         self.usb.host().ctrla.modify(|_, w| w.enable().set_bit());
         while self.usb.host().syncbusy.read().enable().bit_is_set() {}
         self.usb.host().ctrlb.modify(|_, w| w.vbusok().set_bit());
     }
+
+    /// USB interrupt handler, for use as an `AsyncUsbHost`.
+    /// Wakes the pending transfer or wait, the interrupts it reports stay masked until awaited again.
+    pub fn on_interrupt() {
+        // Flags and masks are only accessed through single register reads and writes
+        let host = unsafe { &*USB::ptr() }.host_mut();
+        pipe_interrupt(host);
+        let flags = host.intflag.read().bits();
+        if flags & HSOF_FLAG != 0 {
+            frame_interrupt();
+        }
+        if flags != 0 {
+            host.intenclr.write(|w| {
+                w.dconn().set_bit();
+                w.ddisc().set_bit();
+                w.wakeup().set_bit();
+                w.ramacer().set_bit();
+                w.uprsm().set_bit();
+                w.dnrsm().set_bit();
+                w.rst().set_bit();
+                w.hsof().set_bit()
+            });
+            HOST_SIGNAL.signal();
+        }
+    }
+}
+
+/// Have the next start of frame wake the waiting transfers, see `frame_interrupt`
+pub(crate) fn unmask_frame_irq() {
+    // Flags and masks are only accessed through single register reads and writes
+    let host = unsafe { &*USB::ptr() }.host();
+    host.intflag.write(|w| w.hsof().set_bit());
+    host.intenset.write(|w| w.hsof().set_bit());
+}
+
+fn unmask_host_irqs(host: &usb::HOST) {
+    host.intenset.write(|w| {
+        w.dconn().set_bit();
+        w.ddisc().set_bit();
+        w.wakeup().set_bit();
+        w.ramacer().set_bit();
+        w.uprsm().set_bit();
+        w.dnrsm().set_bit();
+        w.rst().set_bit();
+        w.hsof().set_bit()
+    });
 }
 
 impl UsbHost for HostController {
@@ -208,6 +257,15 @@ impl UsbHost for HostController {
         host_event
     }
 
+    fn reset_bus(&mut self) -> bool {
+        if self.state != HostState::Connected {
+            return false;
+        }
+        self.usb.host().ctrlb.modify(|_, w| w.busreset().set_bit());
+        self.state = HostState::BusReset;
+        true
+    }
+
     fn max_host_packet_size(&self) -> u16 {
         match self.usb.host().status.read().speed().bits() {
             0x0 => 64,
@@ -215,6 +273,13 @@ impl UsbHost for HostController {
         }
     }
 
+    fn root_port_speed(&self) -> Speed {
+        match self.usb.host().status.read().speed().bits() {
+            0x0 => Speed::Full,
+            _ => Speed::Low,
+        }
+    }
+
     fn now(&self) -> u64 {
         (self.now)()
     }
@@ -224,24 +289,163 @@ impl UsbHost for HostController {
     }
 
     fn control_transfer(
-        &mut self, endpoint: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: RequestCode,
-        w_value: WValue, w_index: u16, buf: Option<&mut [u8]>,
+        &mut self, endpoint: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, buf: Option<&mut [u8]>,
     ) -> Result<usize, HostError> {
         let mut pipe = self.pipe_table.pipe_for(self.usb.host_mut(), endpoint);
-        let len =
-            pipe.control_transfer(endpoint, bm_request_type, b_request, w_value, w_index, buf, self.after_millis)?;
+        let wait = TxWait::Spin(self.after_millis);
+        let len = block_on(pipe.control_transfer(endpoint, bm_request_type, b_request, w_value, w_index, buf, &wait))?;
         Ok(len)
     }
 
+    fn control_out(
+        &mut self, endpoint: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, data: &[u8],
+    ) -> Result<usize, HostError> {
+        let mut pipe = self.pipe_table.pipe_for(self.usb.host_mut(), endpoint);
+        let wait = TxWait::Spin(self.after_millis);
+        block_on(pipe.control_out(endpoint, bm_request_type, b_request, w_value, w_index, data, &wait))
+    }
+
+    fn control_in_stream(
+        &mut self, endpoint: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, w_length: u16, buf: &mut [u8], sink: &mut dyn FnMut(&[u8]),
+    ) -> Result<usize, HostError> {
+        let mut pipe = self.pipe_table.pipe_for(self.usb.host_mut(), endpoint);
+        let wait = TxWait::Spin(self.after_millis);
+        block_on(pipe.control_in_stream(
+            endpoint, bm_request_type, b_request, w_value, w_index, w_length, buf, sink, &wait,
+        ))
+    }
+
     fn in_transfer(&mut self, endpoint: &mut dyn HostEndpoint, buf: &mut [u8]) -> Result<usize, HostError> {
         let mut pipe = self.pipe_table.pipe_for(self.usb.host_mut(), endpoint);
-        let len = pipe.in_transfer(endpoint, buf, self.after_millis)?;
+        let len = block_on(pipe.in_transfer(endpoint, buf, &TxWait::Spin(self.after_millis)))?;
         Ok(len)
     }
 
     fn out_transfer(&mut self, endpoint: &mut dyn HostEndpoint, buf: &[u8]) -> Result<usize, HostError> {
         let mut pipe = self.pipe_table.pipe_for(self.usb.host_mut(), endpoint);
-        let len = pipe.out_transfer(endpoint, buf, self.after_millis)?;
+        let len = block_on(pipe.out_transfer(endpoint, buf, &TxWait::Spin(self.after_millis)))?;
         Ok(len)
     }
+
+    fn submit(
+        &mut self, endpoint: &mut dyn HostEndpoint, request: TransferRequest,
+    ) -> Result<TransferHandle, HostError> {
+        self.pipe_table.submit(self.usb.host_mut(), endpoint, request)
+    }
+
+    fn poll(
+        &mut self, endpoint: &mut dyn HostEndpoint, handle: TransferHandle, buf: &mut [u8],
+    ) -> Poll<Result<usize, HostError>> {
+        self.pipe_table.poll(self.usb.host_mut(), endpoint, handle, buf)
+    }
+
+    fn cancel(&mut self, handle: TransferHandle) {
+        self.pipe_table.cancel(self.usb.host_mut(), handle)
+    }
+
+    fn frame_number(&self) -> Option<u16> {
+        Some(frame_number(self.usb.host()))
+    }
+
+    fn submit_iso(
+        &mut self, endpoint: &mut dyn HostEndpoint, frame: u16, request: TransferRequest,
+    ) -> Result<TransferHandle, HostError> {
+        self.pipe_table.submit_iso(self.usb.host_mut(), endpoint, frame, request)
+    }
+}
+
+/// Transfers sleep until their pipe interrupt, `on_interrupt` must be called from the USB interrupt handler.
+/// They also wake on each start of frame to check on the same timeout as blocking transfers.
+impl crate::asynch::AsyncUsbHost for HostController {
+    fn update(&mut self) -> Option<HostEvent> {
+        UsbHost::update(self)
+    }
+
+    async fn wait_event(&mut self) -> HostEvent {
+        loop {
+            HOST_SIGNAL.reset();
+            unmask_host_irqs(self.usb.host());
+            if let Some(event) = UsbHost::update(self) {
+                return event;
+            }
+            HOST_SIGNAL.wait().await;
+        }
+    }
+
+    fn reset_bus(&mut self) -> bool {
+        UsbHost::reset_bus(self)
+    }
+
+    fn max_host_packet_size(&self) -> u16 {
+        UsbHost::max_host_packet_size(self)
+    }
+
+    fn root_port_speed(&self) -> Speed {
+        UsbHost::root_port_speed(self)
+    }
+
+    fn now(&self) -> u64 {
+        (self.now)()
+    }
+
+    fn after_millis(&self, ms: u64) -> u64 {
+        (self.after_millis)(ms)
+    }
+
+    /// Ticks on the start of frame interrupt, every millisecond while a device is connected.
+    /// Returns early on other host interrupts, for `update` to handle them.
+    async fn delay_until(&mut self, instant: u64) {
+        loop {
+            HOST_SIGNAL.reset();
+            let host = self.usb.host();
+            host.intflag.write(|w| w.hsof().set_bit());
+            unmask_host_irqs(host);
+            if self.now() >= instant || host.intflag.read().bits() & !HSOF_FLAG != 0 {
+                return;
+            }
+            HOST_SIGNAL.wait().await;
+        }
+    }
+
+    async fn control_transfer(
+        &mut self, endpoint: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, buf: Option<&mut [u8]>,
+    ) -> Result<usize, HostError> {
+        let mut pipe = self.pipe_table.pipe_for(self.usb.host_mut(), endpoint);
+        let wait = TxWait::Interrupt(self.after_millis);
+        pipe.control_transfer(endpoint, bm_request_type, b_request, w_value, w_index, buf, &wait)
+            .await
+    }
+
+    async fn control_in_stream(
+        &mut self, endpoint: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, w_length: u16, buf: &mut [u8], sink: &mut dyn FnMut(&[u8]),
+    ) -> Result<usize, HostError> {
+        let mut pipe = self.pipe_table.pipe_for(self.usb.host_mut(), endpoint);
+        pipe.control_in_stream(
+            endpoint,
+            bm_request_type,
+            b_request,
+            w_value,
+            w_index,
+            w_length,
+            buf,
+            sink,
+            &TxWait::Interrupt(self.after_millis),
+        )
+        .await
+    }
+
+    async fn in_transfer(&mut self, endpoint: &mut dyn HostEndpoint, buf: &mut [u8]) -> Result<usize, HostError> {
+        let mut pipe = self.pipe_table.pipe_for(self.usb.host_mut(), endpoint);
+        pipe.in_transfer(endpoint, buf, &TxWait::Interrupt(self.after_millis)).await
+    }
+
+    async fn out_transfer(&mut self, endpoint: &mut dyn HostEndpoint, buf: &[u8]) -> Result<usize, HostError> {
+        let mut pipe = self.pipe_table.pipe_for(self.usb.host_mut(), endpoint);
+        pipe.out_transfer(endpoint, buf, &TxWait::Interrupt(self.after_millis)).await
+    }
 }
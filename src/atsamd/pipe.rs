@@ -17,17 +17,22 @@ pub mod table;
 
 use addr::Addr;
 use core::cmp::min;
+use core::task::Poll;
 use ctrl_pipe::CtrlPipe;
 use ext_reg::ExtReg;
 use pck_size::PckSize;
 use status_bk::StatusBk;
 use status_pipe::StatusPipe;
 
+use crate::asynch::TransferSignal;
+use crate::atsamd::host::unmask_frame_irq;
 use crate::{
-    to_slice_mut, HostEndpoint, RequestCode, RequestDirection, RequestType, SetupPacket, TransferType, WValue,
+    frame_add, frame_after, to_slice_mut, HostEndpoint, RequestDirection, RequestType, SetupPacket, TransferRequest,
+    TransferType, WValue, MAX_ISO_LEN, MAX_SUBMIT_LEN,
 };
 
 use crate::HostError;
+use atsamd_hal::target_device::usb;
 use regs::PipeRegs;
 
 // Maximum time to wait for a control request with data to finish. cf §9.2.6.1 of USB 2.0.
@@ -36,20 +41,43 @@ const USB_TIMEOUT: u64 = 5000; // 5 Seconds
 // samd21 only supports 8 pipes.
 const MAX_PIPES: usize = 8;
 
+// Woken by the pipe interrupts, one per pipe
+static PIPE_SIGNALS: [TransferSignal; MAX_PIPES] = [const { TransferSignal::new() }; MAX_PIPES];
+
+// Pipes 0 and 1 serve blocking transfers, the others are allocated to submitted transfers.
+const FIRST_SUBMIT_PIPE: usize = 2;
+
 // How many times to retry a transaction that has transient errors.
 const NAK_LIMIT: usize = 15;
 
+/// How transfers wait for each packet to complete, giving up after `USB_TIMEOUT` either way
+pub(crate) enum TxWait {
+    /// Poll the pipe until done
+    Spin(fn(u64) -> u64),
+    /// Sleep until the pipe interrupt or the next start of frame, see `pipe_interrupt` and `frame_interrupt`
+    Interrupt(fn(u64) -> u64),
+}
+
+impl TxWait {
+    fn after_millis(&self, millis: u64) -> u64 {
+        match self {
+            TxWait::Spin(after_millis) | TxWait::Interrupt(after_millis) => after_millis(millis),
+        }
+    }
+}
+
 // TODO: hide regs/desc fields. Needed right now for init_pipe0.
 pub(crate) struct Pipe<'a, 'b> {
+    idx: usize,
     regs: PipeRegs<'b>,
     desc: &'a mut PipeDesc,
 }
 
 impl Pipe<'_, '_> {
     #[allow(clippy::too_many_arguments)]
-    pub(crate) fn control_transfer(
-        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: RequestCode, w_value: WValue,
-        w_index: u16, buf: Option<&mut [u8]>, after_millis: fn(u64) -> u64,
+    pub(crate) async fn control_transfer(
+        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, buf: Option<&mut [u8]>, wait: &TxWait,
     ) -> Result<usize, HostError> {
         let w_length = buf.as_ref().map_or(0, |b| b.len() as u16);
         let mut setup_packet = SetupPacket {
@@ -62,15 +90,15 @@ impl Pipe<'_, '_> {
 
         // SETUP
         self.bank0_set(to_slice_mut(&mut setup_packet), 0, ep.max_packet_size());
-        self.sync_tx(ep, PipeToken::Setup, after_millis)?;
+        self.sync_tx(ep, PipeToken::Setup, wait).await?;
 
         // DATA
         let direction = bm_request_type.direction().ok_or(HostError::InvalidRequest)?;
         let mut transfer_len = 0;
         if let Some(buf) = buf {
             transfer_len = match direction {
-                RequestDirection::DeviceToHost => self.in_transfer(ep, buf, after_millis)?,
-                RequestDirection::HostToDevice => self.out_transfer(ep, buf, after_millis)?,
+                RequestDirection::DeviceToHost => self.in_transfer(ep, buf, wait).await?,
+                RequestDirection::HostToDevice => self.out_transfer(ep, buf, wait).await?,
             }
         }
 
@@ -82,11 +110,94 @@ impl Pipe<'_, '_> {
             RequestDirection::HostToDevice => PipeToken::In,
         };
 
-        self.sync_tx(ep, token, after_millis)?;
+        self.sync_tx(ep, token, wait).await?;
+
+        Ok(transfer_len)
+    }
+
+    /// Control OUT transfer sending `data` as its data stage
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn control_out(
+        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, data: &[u8], wait: &TxWait,
+    ) -> Result<usize, HostError> {
+        if bm_request_type.direction() != Some(RequestDirection::HostToDevice) {
+            return Err(HostError::InvalidRequest);
+        }
+        let mut setup_packet = SetupPacket {
+            bm_request_type,
+            b_request,
+            w_value,
+            w_index,
+            w_length: data.len() as u16,
+        };
+
+        // SETUP
+        self.bank0_set(to_slice_mut(&mut setup_packet), 0, ep.max_packet_size());
+        self.sync_tx(ep, PipeToken::Setup, wait).await?;
+
+        // DATA
+        let transfer_len = self.out_transfer(ep, data, wait).await?;
+
+        // STATUS
+        self.bank0_size(0);
+        self.sync_tx(ep, PipeToken::In, wait).await?;
 
         Ok(transfer_len)
     }
 
+    /// Control IN transfer handing each piece of the data stage to `sink` as soon as `buf` is full
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn control_in_stream(
+        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, w_length: u16, buf: &mut [u8], sink: &mut dyn FnMut(&[u8]), wait: &TxWait,
+    ) -> Result<usize, HostError> {
+        let max_pck = ep.max_packet_size() as usize;
+        if buf.len() < max_pck {
+            return Err(HostError::InvalidRequest);
+        }
+        let mut setup_packet = SetupPacket {
+            bm_request_type,
+            b_request,
+            w_value,
+            w_index,
+            w_length,
+        };
+
+        // SETUP
+        self.bank0_set(to_slice_mut(&mut setup_packet), 0, ep.max_packet_size());
+        self.sync_tx(ep, PipeToken::Setup, wait).await?;
+
+        // DATA
+        let mut total = 0;
+        let mut piece = 0;
+        while total < w_length as usize {
+            let room = min(buf.len() - piece, w_length as usize - total);
+            self.bank0_set(&buf[..piece + room], piece, ep.max_packet_size());
+            self.sync_tx(ep, PipeToken::In, wait).await?;
+            let recvd = self.desc.bank0.pcksize.read().byte_count().bits() as usize;
+            total += recvd;
+            piece += recvd;
+            let short = recvd < max_pck;
+            if short || buf.len() - piece < max_pck {
+                sink(&buf[..piece]);
+                piece = 0;
+            }
+            if short {
+                break;
+            }
+        }
+        if piece > 0 {
+            sink(&buf[..piece]);
+        }
+
+        // STATUS
+        self.bank0_size(0);
+        self.sync_tx(ep, PipeToken::Out, wait).await?;
+
+        Ok(total)
+    }
+
     fn bank0_size(&mut self, len: u16) {
         self.desc.bank0.pcksize.modify(|_, w| {
             unsafe { w.byte_count().bits(len) };
@@ -107,13 +218,13 @@ impl Pipe<'_, '_> {
         self.regs.statusclr.write(|w| w.bk0rdy().set_bit());
     }
 
-    pub fn in_transfer(
-        &mut self, ep: &mut dyn HostEndpoint, buf: &mut [u8], after_millis: fn(u64) -> u64,
+    pub async fn in_transfer(
+        &mut self, ep: &mut dyn HostEndpoint, buf: &mut [u8], wait: &TxWait,
     ) -> Result<usize, HostError> {
         let mut total: usize = 0;
         while total < buf.len() {
             self.bank0_set(buf, total, ep.max_packet_size());
-            self.sync_tx(ep, PipeToken::In, after_millis)?;
+            self.sync_tx(ep, PipeToken::In, wait).await?;
             let recvd = self.desc.bank0.pcksize.read().byte_count().bits() as usize;
             total += recvd;
             if recvd < ep.max_packet_size() as usize {
@@ -124,14 +235,14 @@ impl Pipe<'_, '_> {
         Ok(total)
     }
 
-    pub fn out_transfer(
-        &mut self, ep: &mut dyn HostEndpoint, buf: &[u8], after_millis: fn(u64) -> u64,
+    pub async fn out_transfer(
+        &mut self, ep: &mut dyn HostEndpoint, buf: &[u8], wait: &TxWait,
     ) -> Result<usize, HostError> {
         let mut total = 0;
         while total < buf.len() {
             self.bank0_set(&buf, total, ep.max_packet_size());
             // self.desc.bank0.addr.write(|w| unsafe { w.addr().bits(buf.as_ptr() as u32 + total as u32) });
-            self.sync_tx(ep, PipeToken::Out, after_millis)?;
+            self.sync_tx(ep, PipeToken::Out, wait).await?;
             total += self.desc.bank0.pcksize.read().byte_count().bits() as usize;
         }
         Ok(total)
@@ -160,54 +271,164 @@ impl Pipe<'_, '_> {
         self.regs.statusclr.write(|w| unsafe { w.bits(1) });
     }
 
-    // This is the only function that calls `millis`. If we can make
-    // this just take the current timestamp, we can make this
-    // non-blocking.
-    fn sync_tx(
-        &mut self, ep: &mut dyn HostEndpoint, token: PipeToken, after_millis: fn(u64) -> u64,
-    ) -> Result<(), HostError> {
+    // This is the only function that calls `millis`.
+    // Submitted transfers use `poll_tx` directly and never wait.
+    async fn sync_tx(&mut self, ep: &mut dyn HostEndpoint, token: PipeToken, wait: &TxWait) -> Result<(), HostError> {
         self.dispatch_packet(ep, token);
 
-        let until = after_millis(USB_TIMEOUT);
-        // let mut last_err = TransferError::SWTimeout;
+        let until = wait.after_millis(USB_TIMEOUT);
         let mut naks = 0;
         loop {
-            if after_millis(0) > until {
+            if wait.after_millis(0) > until {
                 return Err(HostError::SoftTimeout);
             }
 
-            let res = self.dispatch_result(token);
-            match res {
-                Ok(true) => {
-                    if matches!(token, PipeToken::In | PipeToken::Out) {
-                        // Save endpoint toggle state on successful transfer.
-                        ep.set_toggle(!ep.toggle());
+            match self.wait_tx(ep, token, wait).await {
+                Poll::Ready(Ok(())) => return Ok(()),
+                Poll::Pending => continue,
+
+                // Flow error on interrupt pipes means we got a NAK = no data
+                Poll::Ready(Err(HostError::Nak)) if matches!(ep.transfer_type(), TransferType::Interrupt) => {
+                    return Err(HostError::Nak);
+                }
+
+                Poll::Ready(Err(HostError::Stall)) => return Err(HostError::Stall),
+
+                // Isochronous data is lost rather than sent again
+                Poll::Ready(Err(err)) if ep.transfer_type() == TransferType::Isochronous => return Err(err),
+
+                Poll::Ready(Err(other)) => {
+                    naks += 1;
+                    if naks > NAK_LIMIT {
+                        return Err(other);
                     }
-                    return Ok(());
                 }
-                Ok(false) => continue,
+            }
+        }
+    }
+
+    /// Check on a dispatched packet, sleeping until the next pipe interrupt or start of frame while it is pending.
+    /// Interrupts left enabled afterwards are masked again by `pipe_interrupt` and `on_interrupt`.
+    async fn wait_tx(
+        &mut self, ep: &mut dyn HostEndpoint, token: PipeToken, wait: &TxWait,
+    ) -> Poll<Result<(), HostError>> {
+        if let TxWait::Spin(_) = wait {
+            return self.poll_tx(ep, token);
+        }
+        let signal = &PIPE_SIGNALS[self.idx];
+        signal.reset();
+        self.regs.intenset.write(|w| {
+            w.trcpt0().set_bit();
+            w.trfail().set_bit();
+            w.perr().set_bit();
+            w.txstp().set_bit();
+            w.stall().set_bit()
+        });
+        unmask_frame_irq();
+        let poll = self.poll_tx(ep, token);
+        if poll.is_pending() {
+            signal.wait().await;
+        }
+        poll
+    }
 
-                Err(err) => {
-                    match err {
-                        HostError::Toggle => self.data_toggle(ep, token),
+    /// Check once on a dispatched packet. Toggle errors are corrected here.
+    fn poll_tx(&mut self, ep: &mut dyn HostEndpoint, token: PipeToken) -> Poll<Result<(), HostError>> {
+        match self.dispatch_result(token) {
+            Ok(true) => {
+                if matches!(token, PipeToken::In | PipeToken::Out) && ep.transfer_type() != TransferType::Isochronous {
+                    // Save endpoint toggle state on successful transfer.
+                    ep.set_toggle(!ep.toggle());
+                }
+                Poll::Ready(Ok(()))
+            }
+            Ok(false) => Poll::Pending,
+            Err(HostError::Toggle) => {
+                self.data_toggle(ep, token);
+                Poll::Pending
+            }
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
 
-                        // Flow error on interrupt pipes means we got a NAK = no data
-                        HostError::Nak if matches!(ep.transfer_type(), TransferType::Interrupt) => {
-                            return Err(HostError::Nak);
-                        }
+    /// Start the first packet of a submitted transfer
+    pub(crate) fn submit(&mut self, ep: &mut dyn HostEndpoint, sub: &mut Submitted) {
+        match &mut sub.setup {
+            Some(setup) => {
+                self.bank0_set(to_slice_mut(setup), 0, ep.max_packet_size());
+                self.dispatch_packet(ep, PipeToken::Setup);
+            }
+            None => self.next_packet(ep, sub),
+        }
+    }
 
-                        HostError::Stall => return Err(HostError::Stall),
+    /// Advance a submitted transfer without waiting, `frame` is the current frame number.
+    /// NAKed packets are sent again on the next poll.
+    pub(crate) fn poll_submitted(
+        &mut self, ep: &mut dyn HostEndpoint, sub: &mut Submitted, frame: u16,
+    ) -> Poll<Result<usize, HostError>> {
+        if let Some(due) = sub.due {
+            return self.dispatch_due(ep, sub, due, frame);
+        }
+        let token = sub.token();
+        match self.poll_tx(ep, token) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(HostError::Nak)) if ep.transfer_type() != TransferType::Isochronous => {
+                self.dispatch_packet(ep, token);
+                return Poll::Pending;
+            }
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Ready(Ok(())) => {}
+        }
 
-                        other => {
-                            naks += 1;
-                            if naks > NAK_LIMIT {
-                                return Err(other);
-                            }
-                        }
+        match sub.stage {
+            Stage::Setup if sub.len > 0 => sub.stage = Stage::Data,
+            Stage::Setup => sub.stage = Stage::Status,
+            Stage::Data => {
+                let sent = self.desc.bank0.pcksize.read().byte_count().bits() as usize;
+                sub.total += sent;
+                let short = token == PipeToken::In && sent < ep.max_packet_size() as usize;
+                if sub.total >= sub.len || short {
+                    if sub.setup.is_none() {
+                        return Poll::Ready(Ok(sub.total));
                     }
+                    sub.stage = Stage::Status;
                 }
             }
+            Stage::Status => return Poll::Ready(Ok(sub.total)),
+        }
+        self.next_packet(ep, sub);
+        Poll::Pending
+    }
+
+    /// Isochronous packets go out right after a start of frame:
+    /// the packet due in frame `due` is dispatched during the frame before.
+    fn dispatch_due(
+        &mut self, ep: &mut dyn HostEndpoint, sub: &mut Submitted, due: u16, frame: u16,
+    ) -> Poll<Result<usize, HostError>> {
+        let next = frame_add(frame, 1);
+        if frame_after(due, next) {
+            return Poll::Pending;
+        }
+        if due != next {
+            return Poll::Ready(Err(HostError::FrameMissed));
         }
+        sub.due = None;
+        self.next_packet(ep, sub);
+        Poll::Pending
+    }
+
+    fn next_packet(&mut self, ep: &mut dyn HostEndpoint, sub: &mut Submitted) {
+        match sub.stage {
+            Stage::Data => self.bank0_set(&sub.buf[..sub.len], sub.total, ep.max_packet_size()),
+            _ => self.bank0_size(0),
+        }
+        self.dispatch_packet(ep, sub.token());
+    }
+
+    /// Stop a submitted transfer
+    pub(crate) fn freeze(&mut self) {
+        self.regs.statusset.write(|w| w.pfreeze().set_bit());
     }
 
     fn dispatch_packet(&mut self, ep: &mut dyn HostEndpoint, token: PipeToken) {
@@ -293,6 +514,128 @@ impl Pipe<'_, '_> {
     }
 }
 
+/// Wake all waiting transfers on a start of frame, for them to check on their timeout
+pub(crate) fn frame_interrupt() {
+    for signal in PIPE_SIGNALS.iter() {
+        signal.signal();
+    }
+}
+
+/// Wake the transfers waiting on the pipes flagged in the interrupt summary.
+/// Their interrupts are masked until `wait_tx` enables them again, as their flags stay set until read.
+pub(crate) fn pipe_interrupt(host: &mut usb::HOST) {
+    let flagged = host.pintsmry.read().bits();
+    for (idx, signal) in PIPE_SIGNALS.iter().enumerate() {
+        if flagged & (1 << idx) != 0 {
+            PipeRegs::from(host, idx).intenclr.write(|w| {
+                w.trcpt0().set_bit();
+                w.trfail().set_bit();
+                w.perr().set_bit();
+                w.txstp().set_bit();
+                w.stall().set_bit()
+            });
+            signal.signal();
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Stage {
+    Setup,
+    Data,
+    Status,
+}
+
+/// A transfer submitted with `UsbHost::submit`, owning its data until completion.
+/// Lives in the pipe table, so that its buffers stay put while the pipe accesses them.
+pub(crate) struct Submitted {
+    /// Control transfers only
+    setup: Option<SetupPacket>,
+    direction: RequestDirection,
+    stage: Stage,
+    /// Room for a full isochronous packet, other transfers use at most `MAX_SUBMIT_LEN` bytes
+    buf: [u8; MAX_ISO_LEN],
+    len: usize,
+    total: usize,
+    /// Isochronous transfers only, frame the packet is due in until it is dispatched
+    due: Option<u16>,
+}
+
+impl Submitted {
+    pub(crate) fn new(request: TransferRequest) -> Result<Self, HostError> {
+        Self::with_limit(request, MAX_SUBMIT_LEN)
+    }
+
+    /// A single isochronous packet, due in frame `frame`
+    pub(crate) fn new_iso(request: TransferRequest, frame: u16, max_packet_size: u16) -> Result<Self, HostError> {
+        if let TransferRequest::Control(..) = request {
+            return Err(HostError::InvalidRequest);
+        }
+        Ok(Submitted {
+            due: Some(frame),
+            ..Self::with_limit(request, min(max_packet_size as usize, MAX_ISO_LEN))?
+        })
+    }
+
+    /// Transfer of up to `limit` bytes
+    fn with_limit(request: TransferRequest, limit: usize) -> Result<Self, HostError> {
+        let mut sub = Submitted {
+            setup: None,
+            direction: RequestDirection::DeviceToHost,
+            stage: Stage::Data,
+            buf: [0; MAX_ISO_LEN],
+            len: 0,
+            total: 0,
+            due: None,
+        };
+        let out = match request {
+            TransferRequest::Control(setup, out) => {
+                sub.setup = Some(setup);
+                sub.stage = Stage::Setup;
+                sub.len = setup.w_length as usize;
+                sub.direction = setup.bm_request_type.direction().ok_or(HostError::InvalidRequest)?;
+                match sub.direction {
+                    RequestDirection::DeviceToHost => &[],
+                    RequestDirection::HostToDevice => out,
+                }
+            }
+            TransferRequest::In(len) => {
+                sub.len = len as usize;
+                &[]
+            }
+            TransferRequest::Out(out) => {
+                sub.direction = RequestDirection::HostToDevice;
+                sub.len = out.len();
+                out
+            }
+        };
+        if sub.len > limit || out.len() > sub.len {
+            return Err(HostError::InvalidRequest);
+        }
+        sub.buf[..out.len()].copy_from_slice(out);
+        Ok(sub)
+    }
+
+    fn token(&self) -> PipeToken {
+        match (self.stage, self.direction) {
+            (Stage::Setup, _) => PipeToken::Setup,
+            (Stage::Data, RequestDirection::DeviceToHost) => PipeToken::In,
+            (Stage::Data, RequestDirection::HostToDevice) => PipeToken::Out,
+            // reciprocal translation for ACK
+            (Stage::Status, RequestDirection::DeviceToHost) => PipeToken::Out,
+            (Stage::Status, RequestDirection::HostToDevice) => PipeToken::In,
+        }
+    }
+
+    /// Received data, once done
+    pub(crate) fn received(&self) -> &[u8] {
+        match self.direction {
+            RequestDirection::DeviceToHost => &self.buf[..self.total],
+            RequestDirection::HostToDevice => &[],
+        }
+    }
+}
+
 // TODO: merge into SVD for pipe cfg register.
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
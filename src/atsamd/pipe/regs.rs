@@ -1,7 +1,7 @@
 use crate::atsamd::pipe::MAX_PIPES;
 use atsamd_hal::target_device::usb::{
     self,
-    host::{BINTERVAL, PCFG, PINTFLAG, PSTATUS, PSTATUSCLR, PSTATUSSET},
+    host::{BINTERVAL, PCFG, PINTENCLR, PINTENSET, PINTFLAG, PSTATUS, PSTATUSCLR, PSTATUSSET},
 };
 
 pub(crate) struct PipeRegs<'a> {
@@ -11,6 +11,8 @@ pub(crate) struct PipeRegs<'a> {
     pub(crate) statusset: &'a mut PSTATUSSET,
     pub(crate) status: &'a mut PSTATUS,
     pub(crate) intflag: &'a mut PINTFLAG,
+    pub(crate) intenset: &'a mut PINTENSET,
+    pub(crate) intenclr: &'a mut PINTENCLR,
 }
 
 impl<'a> PipeRegs<'a> {
@@ -24,6 +26,8 @@ impl<'a> PipeRegs<'a> {
                 statusset: &mut host.pstatusset0,
                 status: &mut host.pstatus0,
                 intflag: &mut host.pintflag0,
+                intenset: &mut host.pintenset0,
+                intenclr: &mut host.pintenclr0,
             },
             1 => Self {
                 cfg: &mut host.pcfg1,
@@ -32,6 +36,8 @@ impl<'a> PipeRegs<'a> {
                 statusset: &mut host.pstatusset1,
                 status: &mut host.pstatus1,
                 intflag: &mut host.pintflag1,
+                intenset: &mut host.pintenset1,
+                intenclr: &mut host.pintenclr1,
             },
             2 => Self {
                 cfg: &mut host.pcfg2,
@@ -40,6 +46,8 @@ impl<'a> PipeRegs<'a> {
                 statusset: &mut host.pstatusset2,
                 status: &mut host.pstatus2,
                 intflag: &mut host.pintflag2,
+                intenset: &mut host.pintenset2,
+                intenclr: &mut host.pintenclr2,
             },
             3 => Self {
                 cfg: &mut host.pcfg3,
@@ -48,6 +56,8 @@ impl<'a> PipeRegs<'a> {
                 statusset: &mut host.pstatusset3,
                 status: &mut host.pstatus3,
                 intflag: &mut host.pintflag3,
+                intenset: &mut host.pintenset3,
+                intenclr: &mut host.pintenclr3,
             },
             4 => Self {
                 cfg: &mut host.pcfg4,
@@ -56,6 +66,8 @@ impl<'a> PipeRegs<'a> {
                 statusset: &mut host.pstatusset4,
                 status: &mut host.pstatus4,
                 intflag: &mut host.pintflag4,
+                intenset: &mut host.pintenset4,
+                intenclr: &mut host.pintenclr4,
             },
             5 => Self {
                 cfg: &mut host.pcfg5,
@@ -64,6 +76,8 @@ impl<'a> PipeRegs<'a> {
                 statusset: &mut host.pstatusset5,
                 status: &mut host.pstatus5,
                 intflag: &mut host.pintflag5,
+                intenset: &mut host.pintenset5,
+                intenclr: &mut host.pintenclr5,
             },
             6 => Self {
                 cfg: &mut host.pcfg6,
@@ -72,6 +86,8 @@ impl<'a> PipeRegs<'a> {
                 statusset: &mut host.pstatusset6,
                 status: &mut host.pstatus6,
                 intflag: &mut host.pintflag6,
+                intenset: &mut host.pintenset6,
+                intenclr: &mut host.pintenclr6,
             },
             7 => Self {
                 cfg: &mut host.pcfg7,
@@ -80,6 +96,8 @@ impl<'a> PipeRegs<'a> {
                 statusset: &mut host.pstatusset7,
                 status: &mut host.pstatus7,
                 intflag: &mut host.pintflag7,
+                intenset: &mut host.pintenset7,
+                intenclr: &mut host.pintenclr7,
             },
             _ => unreachable!(),
         }
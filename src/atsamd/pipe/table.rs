@@ -1,10 +1,14 @@
 use crate::atsamd::pipe::regs::PipeRegs;
-use crate::atsamd::pipe::{Pipe, PipeDesc, PipeType, MAX_PIPES};
-use crate::{EndpointProperties, HostEndpoint, MaxPacketSize};
+use crate::atsamd::pipe::{Pipe, PipeDesc, PipeType, Submitted, FIRST_SUBMIT_PIPE, MAX_PIPES};
+use crate::{EndpointProperties, HostEndpoint, HostError, MaxPacketSize, TransferHandle, TransferRequest};
 use atsamd_hal::target_device::usb;
+use core::cmp::min;
+use core::task::Poll;
 
 pub(crate) struct PipeTable {
     tbl: [PipeDesc; MAX_PIPES],
+    /// Transfers in progress on pipes allocated by `submit`
+    submitted: [Option<Submitted>; MAX_PIPES],
 }
 
 impl PipeTable {
@@ -19,14 +23,85 @@ impl PipeTable {
 
             unsafe { core::mem::transmute(tbl) }
         };
-        Self { tbl }
+        Self {
+            tbl,
+            submitted: Default::default(),
+        }
     }
 
     pub(crate) fn pipe_for<'a, 'b>(&'a mut self, host: &'b mut usb::HOST, endpoint: &dyn HostEndpoint) -> Pipe<'a, 'b> {
         let pipe_idx = if endpoint.endpoint_address().absolute() == 0 { 0 } else { 1 };
+        Self::configure(&mut self.tbl[pipe_idx], host, endpoint, pipe_idx)
+    }
+
+    /// Start a transfer on a free pipe, the pipe number is the transfer handle
+    pub(crate) fn submit(
+        &mut self, host: &mut usb::HOST, endpoint: &mut dyn HostEndpoint, request: TransferRequest,
+    ) -> Result<TransferHandle, HostError> {
+        let pipe_idx = (FIRST_SUBMIT_PIPE..MAX_PIPES)
+            .find(|idx| self.submitted[*idx].is_none())
+            .ok_or(HostError::Fail)?;
+        let sub = self.submitted[pipe_idx].insert(Submitted::new(request)?);
+        Self::configure(&mut self.tbl[pipe_idx], host, endpoint, pipe_idx).submit(endpoint, sub);
+        Ok(TransferHandle::from(pipe_idx as u8))
+    }
+
+    /// Schedule an isochronous packet on a free pipe, dispatched right away if due in the next frame
+    pub(crate) fn submit_iso(
+        &mut self, host: &mut usb::HOST, endpoint: &mut dyn HostEndpoint, frame: u16, request: TransferRequest,
+    ) -> Result<TransferHandle, HostError> {
+        let current = frame_number(host);
+        let pipe_idx = (FIRST_SUBMIT_PIPE..MAX_PIPES)
+            .find(|idx| self.submitted[*idx].is_none())
+            .ok_or(HostError::Fail)?;
+        let sub = Submitted::new_iso(request, frame, endpoint.max_packet_size())?;
+        let sub = self.submitted[pipe_idx].insert(sub);
+        let mut pipe = Self::configure(&mut self.tbl[pipe_idx], host, endpoint, pipe_idx);
+        // a missed frame is reported by the next poll
+        let _ = pipe.poll_submitted(endpoint, sub, current);
+        Ok(TransferHandle::from(pipe_idx as u8))
+    }
+
+    pub(crate) fn poll(
+        &mut self, host: &mut usb::HOST, endpoint: &mut dyn HostEndpoint, handle: TransferHandle, buf: &mut [u8],
+    ) -> Poll<Result<usize, HostError>> {
+        let frame = frame_number(host);
+        let pipe_idx = u8::from(handle) as usize;
+        let sub = match self.submitted.get_mut(pipe_idx).and_then(Option::as_mut) {
+            Some(sub) => sub,
+            None => return Poll::Ready(Err(HostError::InvalidRequest)),
+        };
+        let mut pipe = Pipe {
+            idx: pipe_idx,
+            regs: PipeRegs::from(host, pipe_idx),
+            desc: &mut self.tbl[pipe_idx],
+        };
+        let result = pipe.poll_submitted(endpoint, sub, frame);
+        if let Poll::Ready(result) = result {
+            pipe.freeze();
+            if result.is_ok() {
+                let received = sub.received();
+                let len = min(received.len(), buf.len());
+                buf[..len].copy_from_slice(&received[..len]);
+            }
+            self.submitted[pipe_idx] = None;
+        }
+        result
+    }
 
+    pub(crate) fn cancel(&mut self, host: &mut usb::HOST, handle: TransferHandle) {
+        let pipe_idx = u8::from(handle) as usize;
+        if let Some(sub) = self.submitted.get_mut(pipe_idx) {
+            if sub.take().is_some() {
+                PipeRegs::from(host, pipe_idx).statusset.write(|w| w.pfreeze().set_bit());
+            }
+        }
+    }
+
+    fn configure<'a, 'b>(
+        pdesc: &'a mut PipeDesc, host: &'b mut usb::HOST, endpoint: &dyn HostEndpoint, pipe_idx: usize,
+    ) -> Pipe<'a, 'b> {
         let pregs = PipeRegs::from(host, pipe_idx);
-        let pdesc = &mut self.tbl[pipe_idx];
 
         pregs.cfg.write(|w| {
             let ptype = PipeType::from(endpoint.transfer_type()) as u8;
@@ -58,8 +133,14 @@ impl PipeTable {
             }
         });
         Pipe {
+            idx: pipe_idx,
             regs: pregs,
             desc: pdesc,
         }
     }
 }
+
+/// Number of the frame in progress
+pub(crate) fn frame_number(host: &usb::HOST) -> u16 {
+    host.fnum.read().fnum().bits()
+}
@@ -1,7 +1,10 @@
 #![allow(dead_code)]
 
 use crate::class::audio::AudioDescriptorRef::Unknown;
-use crate::DescriptorType;
+use crate::descriptor::format_packed;
+use crate::parser::{too_short, view, view_tail};
+use crate::{ConfigurationBuilder, DescriptorType, ParseError};
+use core::cmp::min;
 
 #[derive(Clone, Copy, Debug, PartialEq, strum_macros::FromRepr)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -27,85 +30,97 @@ pub enum AudioDescriptorRef<'a> {
 
     MSInterface(&'a MSInterfaceDescriptor),
     MSInJack(&'a MSInJackDescriptor),
-    MSOutJack(&'a MSOutJackDescriptor),
+    /// Header then the rest of the descriptor: the source of each input pin and `iJack`
+    MSOutJack(&'a MSOutJackDescriptor, &'a [u8]),
 
     ASEndpoint(&'a ASEndpointDescriptor),
-    MSEndpoint(&'a MSEndpointDescriptor),
+    /// Header then the IDs of the embedded jacks
+    MSEndpoint(&'a MSEndpointDescriptor, &'a [u8]),
 
     Unknown(&'a [u8]),
 }
 
-pub fn parse(subclass: Option<u8>, desc_type: DescriptorType, buf: &[u8]) -> AudioDescriptorRef {
+/// Class specific descriptor of an audio interface, `Unknown` unless its type and subtype are recognized.
+/// Recognized descriptors too short for their structure are errors.
+pub fn parse(
+    subclass: Option<u8>, desc_type: DescriptorType, buf: &[u8],
+) -> Result<AudioDescriptorRef<'_>, ParseError> {
     if let Some(subclass) = subclass {
-        if buf.len() < 3 {
-            return Unknown(buf);
+        if buf.len() < 3 || buf[1] != desc_type as u8 {
+            return Ok(Unknown(buf));
         }
+        // SAFETY: each descriptor is viewed once its type and subtype bytes are known to be valid
         if let Some(subclass) = AudioSubclass::from_repr(subclass) {
-            return match desc_type {
+            return Ok(match desc_type {
                 DescriptorType::ClassInterface => match subclass {
                     AudioSubclass::AudioControl => match ACInterfaceSubtype::from_repr(buf[2]) {
                         Some(ACInterfaceSubtype::InterfaceHeader) => {
-                            AudioDescriptorRef::ACInterfaceHeader(unsafe { &*(buf.as_ptr() as *const _) })
+                            AudioDescriptorRef::ACInterfaceHeader(unsafe { view(buf)? })
                         }
                         Some(ACInterfaceSubtype::InputTerminalDescriptor) => {
-                            AudioDescriptorRef::ACInputTerminal(unsafe { &*(buf.as_ptr() as *const _) })
+                            AudioDescriptorRef::ACInputTerminal(unsafe { view(buf)? })
                         }
                         Some(ACInterfaceSubtype::OutputTerminalDescriptor) => {
-                            AudioDescriptorRef::ACOutputTerminal(unsafe { &*(buf.as_ptr() as *const _) })
+                            AudioDescriptorRef::ACOutputTerminal(unsafe { view(buf)? })
                         }
                         Some(ACInterfaceSubtype::FeatureUnitDescriptor) => {
-                            AudioDescriptorRef::ACFeatureUnit(unsafe { &*(buf.as_ptr() as *const _) })
+                            AudioDescriptorRef::ACFeatureUnit(unsafe { view(buf)? })
                         }
                         Some(ACInterfaceSubtype::ClockSourceDescriptor) => {
-                            AudioDescriptorRef::ACClockSource(unsafe { &*(buf.as_ptr() as *const _) })
+                            AudioDescriptorRef::ACClockSource(unsafe { view(buf)? })
                         }
                         Some(ACInterfaceSubtype::ClockSelectorDescriptor) => {
-                            AudioDescriptorRef::ACClockSelector(unsafe { &*(buf.as_ptr() as *const _) })
+                            AudioDescriptorRef::ACClockSelector(unsafe { view(buf)? })
                         }
                         _ => Unknown(buf),
                     },
                     AudioSubclass::AudioStream => match ASInterfaceSubtype::from_repr(buf[2]) {
                         Some(ASInterfaceSubtype::AudioStreamHeader) => {
-                            AudioDescriptorRef::ASInterface(unsafe { &*(buf.as_ptr() as *const _) })
+                            AudioDescriptorRef::ASInterface(unsafe { view(buf)? })
                         }
                         Some(ASInterfaceSubtype::FormatType1) => {
-                            AudioDescriptorRef::ASFormatType1(unsafe { &*(buf.as_ptr() as *const _) })
+                            AudioDescriptorRef::ASFormatType1(unsafe { view(buf)? })
                         }
                         _ => Unknown(buf),
                     },
                     AudioSubclass::MidiStream => match MSInterfaceSubtype::from_repr(buf[2]) {
-                        Some(MSInterfaceSubtype::MsHeader) => {
-                            AudioDescriptorRef::MSInterface(unsafe { &*(buf.as_ptr() as *const _) })
-                        }
+                        Some(MSInterfaceSubtype::MsHeader) => AudioDescriptorRef::MSInterface(unsafe { view(buf)? }),
                         Some(MSInterfaceSubtype::MidiOutJack) => {
-                            AudioDescriptorRef::MSOutJack(unsafe { &*(buf.as_ptr() as *const _) })
-                        }
-                        Some(MSInterfaceSubtype::MidiInJack) => {
-                            AudioDescriptorRef::MSInJack(unsafe { &*(buf.as_ptr() as *const _) })
+                            let (jack, tail): (&MSOutJackDescriptor, _) = unsafe { view_tail(buf)? };
+                            // source ID and pin of each input pin, then iJack
+                            if tail.len() <= jack.b_nr_input_pins as usize * 2 {
+                                return Err(too_short(buf));
+                            }
+                            AudioDescriptorRef::MSOutJack(jack, tail)
                         }
+                        Some(MSInterfaceSubtype::MidiInJack) => AudioDescriptorRef::MSInJack(unsafe { view(buf)? }),
                         _ => Unknown(buf),
                     },
                 },
                 DescriptorType::ClassEndpoint => match subclass {
                     AudioSubclass::AudioStream => match ASEndpointSubtype::from_repr(buf[2]) {
                         Some(ASEndpointSubtype::IsochronousEndpoint) => {
-                            AudioDescriptorRef::ASEndpoint(unsafe { &*(buf.as_ptr() as *const _) })
+                            AudioDescriptorRef::ASEndpoint(unsafe { view(buf)? })
                         }
                         _ => Unknown(buf),
                     },
                     AudioSubclass::MidiStream => match MSEndpointSubtype::from_repr(buf[2]) {
                         Some(MSEndpointSubtype::BulkEndpoint) => {
-                            AudioDescriptorRef::MSEndpoint(unsafe { &*(buf.as_ptr() as *const _) })
+                            let (endpoint, tail): (&MSEndpointDescriptor, _) = unsafe { view_tail(buf)? };
+                            let jack_ids = tail
+                                .get(..endpoint.b_num_emb_midi_jack as usize)
+                                .ok_or_else(|| too_short(buf))?;
+                            AudioDescriptorRef::MSEndpoint(endpoint, jack_ids)
                         }
                         _ => Unknown(buf),
                     },
                     _ => Unknown(buf),
                 },
                 _ => Unknown(buf),
-            };
+            });
         }
     }
-    Unknown(buf)
+    Ok(Unknown(buf))
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, strum_macros::FromRepr)]
@@ -121,8 +136,7 @@ pub enum ACInterfaceSubtype {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
+#[repr(C, packed)]
 pub struct ACInterfaceHeaderDescriptor {
     pub b_length: u8,
     pub b_descriptor_type: DescriptorType,
@@ -133,6 +147,16 @@ pub struct ACInterfaceHeaderDescriptor {
     pub bm_controls: u8,
 }
 
+format_packed!(ACInterfaceHeaderDescriptor {
+    b_length,
+    b_descriptor_type,
+    b_descriptor_subtype,
+    bcd_adc,
+    b_category,
+    w_total_length,
+    bm_controls,
+});
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(C)]
@@ -162,8 +186,7 @@ pub struct ACClockSelectorDescriptor {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
+#[repr(C, packed)]
 pub struct ACFeatureUnitDescriptor {
     pub b_length: u8,
     pub b_descriptor_type: DescriptorType,
@@ -178,9 +201,22 @@ pub struct ACFeatureUnitDescriptor {
     pub i_feature: u8,
 }
 
+format_packed!(ACFeatureUnitDescriptor {
+    b_length,
+    b_descriptor_type,
+    b_descriptor_subtype,
+    b_unit_id,
+    b_source_id,
+    bma_controls_0,
+    bma_controls_1,
+    bma_controls_2,
+    bma_controls_3,
+    bma_controls_4,
+    i_feature,
+});
+
 #[derive(Copy, Clone, Debug, PartialEq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
+#[repr(C, packed)]
 pub struct ACInputTerminalDescriptor {
     pub b_length: u8,
     pub b_descriptor_type: DescriptorType,
@@ -196,9 +232,23 @@ pub struct ACInputTerminalDescriptor {
     pub i_terminal: u8,
 }
 
+format_packed!(ACInputTerminalDescriptor {
+    b_length,
+    b_descriptor_type,
+    b_descriptor_subtype,
+    b_terminal_id,
+    w_terminal_type,
+    b_assoc_terminal,
+    b_c_source_id,
+    b_nr_channels,
+    bm_channel_config,
+    i_channel_names,
+    bm_controls,
+    i_terminal,
+});
+
 #[derive(Copy, Clone, Debug, PartialEq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
+#[repr(C, packed)]
 pub struct ACOutputTerminalDescriptor {
     pub b_length: u8,
     pub b_descriptor_type: DescriptorType,
@@ -212,6 +262,19 @@ pub struct ACOutputTerminalDescriptor {
     pub i_terminal: u8,
 }
 
+format_packed!(ACOutputTerminalDescriptor {
+    b_length,
+    b_descriptor_type,
+    b_descriptor_subtype,
+    b_terminal_id,
+    w_terminal_type,
+    b_assoc_terminal,
+    b_source_id,
+    b_c_source_id,
+    bm_controls,
+    i_terminal,
+});
+
 // Audio Stream
 
 #[derive(Clone, Copy, Debug, PartialEq, strum_macros::FromRepr)]
@@ -223,8 +286,7 @@ pub enum ASInterfaceSubtype {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
+#[repr(C, packed)]
 pub struct ASInterfaceDescriptor {
     pub b_length: u8,
     pub b_descriptor_type: DescriptorType,
@@ -238,6 +300,19 @@ pub struct ASInterfaceDescriptor {
     pub i_channel_names: u8,
 }
 
+format_packed!(ASInterfaceDescriptor {
+    b_length,
+    b_descriptor_type,
+    b_descriptor_subtype,
+    b_terminal_link,
+    bm_controls,
+    b_format_type,
+    bm_formats,
+    b_nr_channels,
+    bm_channel_config,
+    i_channel_names,
+});
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(C)]
@@ -258,8 +333,7 @@ pub enum ASEndpointSubtype {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
+#[repr(C, packed)]
 pub struct ASEndpointDescriptor {
     pub b_length: u8,
     pub b_descriptor_type: DescriptorType,
@@ -270,6 +344,16 @@ pub struct ASEndpointDescriptor {
     pub w_lock_delay: u16,
 }
 
+format_packed!(ASEndpointDescriptor {
+    b_length,
+    b_descriptor_type,
+    b_descriptor_subtype,
+    bm_attributes,
+    bm_controls,
+    b_lock_delay_units,
+    w_lock_delay,
+});
+
 // MIDI Stream
 
 #[derive(Clone, Copy, Debug, PartialEq, strum_macros::FromRepr)]
@@ -283,8 +367,7 @@ pub enum MSInterfaceSubtype {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
+#[repr(C, packed)]
 pub struct MSInterfaceDescriptor {
     pub b_length: u8,
     pub b_descriptor_type: DescriptorType,
@@ -293,6 +376,14 @@ pub struct MSInterfaceDescriptor {
     pub w_total_length: u16,
 }
 
+format_packed!(MSInterfaceDescriptor {
+    b_length,
+    b_descriptor_type,
+    b_descriptor_subtype,
+    bcd_msc,
+    w_total_length,
+});
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(C)]
@@ -305,6 +396,7 @@ pub struct MSInJackDescriptor {
     pub i_jack: u8,
 }
 
+/// Fixed start of a MIDI out jack descriptor, the source of each input pin and `iJack` follow
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(C)]
@@ -315,9 +407,19 @@ pub struct MSOutJackDescriptor {
     pub b_jack_type: u8,
     pub b_jack_id: u8,
     pub b_nr_input_pins: u8,
-    pub ba_source_id: u8,
-    pub ba_source_pin: u8,
-    pub i_jack: u8,
+}
+
+impl MSOutJackDescriptor {
+    /// (jack ID, pin) connected to each input pin, read from the rest of the descriptor
+    pub fn sources<'t>(&self, tail: &'t [u8]) -> impl Iterator<Item = (u8, u8)> + 't {
+        let len = min(self.b_nr_input_pins as usize * 2, tail.len() & !1);
+        tail[..len].chunks_exact(2).map(|source| (source[0], source[1]))
+    }
+
+    /// String index of the jack, read from the rest of the descriptor
+    pub fn i_jack(&self, tail: &[u8]) -> u8 {
+        tail.get(self.b_nr_input_pins as usize * 2).copied().unwrap_or(0)
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, strum_macros::FromRepr)]
@@ -334,6 +436,7 @@ pub enum MSEndpointSubtype {
     BulkEndpoint = 0x01,
 }
 
+/// Fixed start of a MIDI endpoint descriptor, the IDs of its embedded jacks follow
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(C)]
@@ -342,5 +445,142 @@ pub struct MSEndpointDescriptor {
     pub b_descriptor_type: DescriptorType,
     pub b_descriptor_subtype: MSEndpointSubtype,
     pub b_num_emb_midi_jack: u8,
-    pub ba_assoc_jack_id: u8,
+}
+
+/// Audio class specific descriptors, added to the last interface or endpoint
+impl<const N: usize> ConfigurationBuilder<N> {
+    /// Audio control header, its total length covers the units and terminals that follow
+    pub fn ac_header(self, bcd_adc: u16, b_category: u8) -> Self {
+        let [adc_lo, adc_hi] = bcd_adc.to_le_bytes();
+        let body = [ACInterfaceSubtype::InterfaceHeader as u8, adc_lo, adc_hi, b_category, 0, 0, 0];
+        self.class_header(DescriptorType::ClassInterface as u8, &body, 6)
+    }
+
+    pub fn ac_input_terminal(self, b_terminal_id: u8, w_terminal_type: u16, b_nr_channels: u8) -> Self {
+        let [type_lo, type_hi] = w_terminal_type.to_le_bytes();
+        let body = [
+            ACInterfaceSubtype::InputTerminalDescriptor as u8,
+            b_terminal_id,
+            type_lo,
+            type_hi,
+            0,
+            0,
+            b_nr_channels,
+            0,
+            0,
+            0,
+            0,
+        ];
+        self.descriptor(DescriptorType::ClassInterface as u8, &body)
+    }
+
+    pub fn ac_output_terminal(self, b_terminal_id: u8, w_terminal_type: u16, b_source_id: u8) -> Self {
+        let [type_lo, type_hi] = w_terminal_type.to_le_bytes();
+        let body = [
+            ACInterfaceSubtype::OutputTerminalDescriptor as u8,
+            b_terminal_id,
+            type_lo,
+            type_hi,
+            0,
+            b_source_id,
+            0,
+            0,
+            0,
+        ];
+        self.descriptor(DescriptorType::ClassInterface as u8, &body)
+    }
+
+    /// Feature unit with the controls of the master channel and 4 logical channels
+    pub fn ac_feature_unit(self, b_unit_id: u8, b_source_id: u8, bma_controls: [u32; 5]) -> Self {
+        let body = [ACInterfaceSubtype::FeatureUnitDescriptor as u8, b_unit_id, b_source_id]
+            .into_iter()
+            .chain(bma_controls.into_iter().flat_map(u32::to_le_bytes))
+            .chain([0]);
+        self.descriptor_from(DescriptorType::ClassInterface as u8, body)
+    }
+
+    pub fn ac_clock_source(self, b_clock_id: u8, bm_attributes: u8, bm_controls: u8) -> Self {
+        let body = [
+            ACInterfaceSubtype::ClockSourceDescriptor as u8,
+            b_clock_id,
+            bm_attributes,
+            bm_controls,
+            0,
+            0,
+        ];
+        self.descriptor(DescriptorType::ClassInterface as u8, &body)
+    }
+
+    /// Clock selector with a single input pin
+    pub fn ac_clock_selector(self, b_clock_id: u8, b_source_id: u8) -> Self {
+        let body = [
+            ACInterfaceSubtype::ClockSelectorDescriptor as u8,
+            b_clock_id,
+            1,
+            b_source_id,
+            0,
+            0,
+        ];
+        self.descriptor(DescriptorType::ClassInterface as u8, &body)
+    }
+
+    /// Audio stream general descriptor, linking the interface to a terminal
+    pub fn as_general(self, b_terminal_link: u8, b_format_type: u8, bm_formats: u32, b_nr_channels: u8) -> Self {
+        let body = [ASInterfaceSubtype::AudioStreamHeader as u8, b_terminal_link, 0, b_format_type]
+            .into_iter()
+            .chain(bm_formats.to_le_bytes())
+            .chain([b_nr_channels, 0, 0, 0, 0, 0]);
+        self.descriptor_from(DescriptorType::ClassInterface as u8, body)
+    }
+
+    pub fn as_format_type1(self, b_subslot_size: u8, b_bit_resolution: u8) -> Self {
+        let body = [ASInterfaceSubtype::FormatType1 as u8, 1, b_subslot_size, b_bit_resolution];
+        self.descriptor(DescriptorType::ClassInterface as u8, &body)
+    }
+
+    /// Isochronous audio endpoint, follows the standard endpoint descriptor
+    pub fn as_endpoint(self, bm_attributes: u8, bm_controls: u8) -> Self {
+        let body = [
+            ASEndpointSubtype::IsochronousEndpoint as u8,
+            bm_attributes,
+            bm_controls,
+            0,
+            0,
+            0,
+        ];
+        self.descriptor(DescriptorType::ClassEndpoint as u8, &body)
+    }
+
+    /// MIDI streaming header, its total length covers the jacks and endpoints that follow
+    pub fn ms_header(self) -> Self {
+        let body = [MSInterfaceSubtype::MsHeader as u8, 0x00, 0x01, 0, 0];
+        self.class_header(DescriptorType::ClassInterface as u8, &body, 5)
+    }
+
+    pub fn ms_in_jack(self, jack_type: JackType, b_jack_id: u8) -> Self {
+        let body = [MSInterfaceSubtype::MidiInJack as u8, jack_type as u8, b_jack_id, 0];
+        self.descriptor(DescriptorType::ClassInterface as u8, &body)
+    }
+
+    /// MIDI out jack, `sources` are the (jack id, pin) pairs of its input pins
+    pub fn ms_out_jack(self, jack_type: JackType, b_jack_id: u8, sources: &[(u8, u8)]) -> Self {
+        let body = [
+            MSInterfaceSubtype::MidiOutJack as u8,
+            jack_type as u8,
+            b_jack_id,
+            sources.len() as u8,
+        ]
+        .into_iter()
+        .chain(sources.iter().flat_map(|(jack_id, pin)| [*jack_id, *pin]))
+        .chain([0]);
+        self.descriptor_from(DescriptorType::ClassInterface as u8, body)
+    }
+
+    /// MIDI bulk endpoint, follows the standard endpoint descriptor and lists its embedded jacks
+    pub fn ms_endpoint(self, jack_ids: &[u8]) -> Self {
+        let body = [MSEndpointSubtype::BulkEndpoint as u8, jack_ids.len() as u8]
+            .into_iter()
+            .chain(jack_ids.iter().copied());
+        self.descriptor_from(DescriptorType::ClassEndpoint as u8, body)
+    }
 }
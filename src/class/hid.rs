@@ -1,3 +1,16 @@
+//! HID class descriptors and the report descriptor parser.
+//!
+//! A report descriptor lists the fields of the reports a HID device sends and accepts.
+//! It is parsed once into fields with their place in the report, values are then read
+//! from raw reports without allocation.
+
+use core::cmp::{max, min};
+
+use heapless::Vec;
+
+use crate::descriptor::format_packed;
+use crate::{DescriptorType, UsbError};
+
 #[repr(u8)]
 pub enum HidSubclass {
     NoBoot = 0,
@@ -10,8 +23,865 @@ pub enum HidDevice {
     Mouse = 2,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, strum_macros::FromRepr)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum HidProtocol {
     Boot = 0,
     Report = 1,
 }
+
+/// HID class requests, cf §7.2 of HID 1.11
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum HidRequest {
+    GetReport = 0x01,
+    GetIdle = 0x02,
+    GetProtocol = 0x03,
+    SetReport = 0x09,
+    SetIdle = 0x0a,
+    SetProtocol = 0x0b,
+}
+
+/// HID class descriptor, follows the interface descriptor of a HID interface
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(C, packed)]
+pub struct HidDescriptor {
+    pub b_length: u8,
+    pub b_descriptor_type: DescriptorType,
+    pub bcd_hid: u16,
+    pub b_country_code: u8,
+    pub b_num_descriptors: u8,
+    /// Type of the first class descriptor, normally the report descriptor
+    pub b_class_descriptor_type: u8,
+    pub w_class_descriptor_length: u16,
+}
+
+format_packed!(HidDescriptor {
+    b_length,
+    b_descriptor_type,
+    bcd_hid,
+    b_country_code,
+    b_num_descriptors,
+    b_class_descriptor_type,
+    w_class_descriptor_length,
+});
+
+impl HidDescriptor {
+    /// Length of the report descriptor, fetched with `Device::get_report_descriptor`
+    pub fn report_descriptor_len(&self) -> Option<u16> {
+        let len = self.w_class_descriptor_length;
+        (self.b_class_descriptor_type == DescriptorType::HidReport as u8).then_some(len)
+    }
+}
+
+/// Report types, as in the high byte of the GET_REPORT and SET_REPORT `w_value`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum_macros::FromRepr)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum ReportKind {
+    Input = 1,
+    Output = 2,
+    Feature = 3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum_macros::FromRepr)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u16)]
+pub enum UsagePage {
+    GenericDesktop = 0x01,
+    Simulation = 0x02,
+    Vr = 0x03,
+    Sport = 0x04,
+    Game = 0x05,
+    GenericDevice = 0x06,
+    Keyboard = 0x07,
+    Led = 0x08,
+    Button = 0x09,
+    Ordinal = 0x0a,
+    Telephony = 0x0b,
+    Consumer = 0x0c,
+    Digitizer = 0x0d,
+}
+
+/// A usage, what a field or collection is about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Usage {
+    pub page: u16,
+    pub id: u16,
+}
+
+impl Usage {
+    pub const fn new(page: UsagePage, id: u16) -> Self {
+        Self { page: page as u16, id }
+    }
+
+    /// Usage page in the high half, id in the low half
+    fn from_extended(usage: u32) -> Self {
+        Self {
+            page: (usage >> 16) as u16,
+            id: usage as u16,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum_macros::FromRepr)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum CollectionKind {
+    Physical = 0,
+    Application = 1,
+    Logical = 2,
+    Report = 3,
+    NamedArray = 4,
+    UsageSwitch = 5,
+    UsageModifier = 6,
+}
+
+/// Group of fields and collections, such as a whole mouse or keyboard
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Collection {
+    /// `CollectionKind` or vendor defined from 0x80
+    pub kind: u8,
+    pub usage: Usage,
+    /// Index of the enclosing collection
+    pub parent: Option<u8>,
+}
+
+impl Collection {
+    pub fn kind(&self) -> Option<CollectionKind> {
+        CollectionKind::from_repr(self.kind)
+    }
+}
+
+/// Field holds constant data, such as padding
+pub const FIELD_CONSTANT: u16 = 0x01;
+/// Each value of the field is a usage of its own, otherwise values select usages (array)
+pub const FIELD_VARIABLE: u16 = 0x02;
+/// Values are changes since the last report, such as mouse movements
+pub const FIELD_RELATIVE: u16 = 0x04;
+
+/// Values of the same size and usage page, at a fixed place of a report
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ReportField {
+    pub kind: ReportKind,
+    /// 0 when the device does not use report IDs
+    pub report_id: u8,
+    /// Offset of the first value, not counting the report ID
+    pub bit_offset: u16,
+    /// Size of each value
+    pub bit_size: u8,
+    pub count: u16,
+    /// Main item bits, `FIELD_CONSTANT`, `FIELD_VARIABLE` and `FIELD_RELATIVE` among others
+    pub flags: u16,
+    /// Usages from `usage_min` to `usage_max`: one per value of a variable field, the last repeating,
+    /// or those an array field can select
+    pub usage_page: u16,
+    pub usage_min: u16,
+    pub usage_max: u16,
+    pub logical_min: i32,
+    pub logical_max: i32,
+    /// Index of the innermost collection holding the field
+    pub collection: Option<u8>,
+}
+
+impl ReportField {
+    pub fn is_variable(&self) -> bool {
+        self.flags & FIELD_VARIABLE != 0
+    }
+
+    pub fn is_array(&self) -> bool {
+        !self.is_variable()
+    }
+
+    pub fn is_relative(&self) -> bool {
+        self.flags & FIELD_RELATIVE != 0
+    }
+
+    /// Usage of value `index` of a variable field
+    pub fn usage(&self, index: u16) -> Usage {
+        let id = self.usage_min.saturating_add(index);
+        Usage {
+            page: self.usage_page,
+            id: min(id, self.usage_max),
+        }
+    }
+
+    /// Index of the value with `usage` in a variable field
+    pub fn index_of(&self, usage: Usage) -> Option<u16> {
+        if !self.is_variable() || usage.page != self.usage_page || self.count == 0 {
+            return None;
+        }
+        let index = usage.id.checked_sub(self.usage_min)?;
+        (usage.id <= self.usage_max && index < self.count).then_some(index)
+    }
+
+    /// Value `index` of the field in `report`, sign extended if the logical range is signed.
+    /// `report` starts with the report ID if the device uses them.
+    /// None if `report` has another ID or is too short.
+    pub fn value(&self, report: &[u8], index: u16) -> Option<i32> {
+        if index >= self.count {
+            return None;
+        }
+        let data = match self.report_id {
+            0 => report,
+            id if report.first() == Some(&id) => &report[1..],
+            _ => return None,
+        };
+        let offset = self.bit_offset as usize + index as usize * self.bit_size as usize;
+        let value = read_bits(data, offset, self.bit_size)?;
+        Some(if self.logical_min < 0 { sign_extend(value, self.bit_size) } else { value as i32 })
+    }
+
+    /// Write value `index` of the field to `report`, with the report ID first if the device uses them.
+    /// False if `report` is too short.
+    pub fn set_value(&self, report: &mut [u8], index: u16, value: i32) -> bool {
+        if index >= self.count {
+            return false;
+        }
+        let data = match (self.report_id, report.split_first_mut()) {
+            (0, _) => report,
+            (id, Some((first, rest))) => {
+                *first = id;
+                rest
+            }
+            (_, None) => return false,
+        };
+        let offset = self.bit_offset as usize + index as usize * self.bit_size as usize;
+        write_bits(data, offset, self.bit_size, value as u32)
+    }
+
+    /// Usage selected by value `index` of an array field in `report`, None for values out of the logical range
+    pub fn selected(&self, report: &[u8], index: u16) -> Option<Usage> {
+        let value = self.value(report, index)?;
+        if value < self.logical_min || value > self.logical_max {
+            return None;
+        }
+        let id = self.usage_min as i64 + (value as i64 - self.logical_min as i64);
+        (id <= self.usage_max as i64).then_some(Usage {
+            page: self.usage_page,
+            id: id as u16,
+        })
+    }
+}
+
+/// Why a report descriptor could not be parsed
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReportError {
+    /// Item goes past the end of the descriptor
+    Truncated,
+    TooManyFields,
+    TooManyCollections,
+    TooManyReports,
+    /// End of a collection that was not started, or collection left open
+    UnbalancedCollection,
+    /// Pop without push, or pushes nested too deep
+    GlobalStack,
+    /// Report ID 0, reserved
+    InvalidReportId,
+}
+
+impl From<ReportError> for UsbError {
+    fn from(_: ReportError) -> Self {
+        UsbError::InvalidDescriptor
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, strum_macros::FromRepr)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum ItemKind {
+    Main = 0,
+    Global = 1,
+    Local = 2,
+    Reserved = 3,
+}
+
+/// Short item of a report descriptor
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Item {
+    pub kind: ItemKind,
+    pub tag: u8,
+    /// Bytes of data, 0, 1, 2 or 4
+    pub size: u8,
+    pub data: u32,
+}
+
+impl Item {
+    /// Data as a signed number of its size
+    pub fn signed(&self) -> i32 {
+        match self.size {
+            0 => 0,
+            size => sign_extend(self.data, size * 8),
+        }
+    }
+}
+
+/// Short items of a report descriptor, long items are skipped
+pub struct Items<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Items<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+}
+
+const LONG_ITEM: u8 = 0xfe;
+
+impl Iterator for Items<'_> {
+    type Item = Result<Item, ReportError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let prefix = *self.buf.get(self.pos)?;
+            let (header, size) = match prefix {
+                LONG_ITEM => (3, *self.buf.get(self.pos + 1).unwrap_or(&0) as usize),
+                _ => (1, [0, 1, 2, 4][prefix as usize & 0x03]),
+            };
+            let start = self.pos + header;
+            if start + size > self.buf.len() {
+                warn!("Truncated report descriptor item {:x}", prefix);
+                self.pos = self.buf.len();
+                return Some(Err(ReportError::Truncated));
+            }
+            self.pos = start + size;
+            if prefix == LONG_ITEM {
+                continue;
+            }
+            let data = self.buf[start..start + size]
+                .iter()
+                .rev()
+                .fold(0u32, |data, byte| data << 8 | *byte as u32);
+            return Some(Ok(Item {
+                kind: ItemKind::from_repr((prefix >> 2) & 0x03)?,
+                tag: prefix >> 4,
+                size: size as u8,
+                data,
+            }));
+        }
+    }
+}
+
+const MAIN_INPUT: u8 = 0x8;
+const MAIN_OUTPUT: u8 = 0x9;
+const MAIN_COLLECTION: u8 = 0xa;
+const MAIN_FEATURE: u8 = 0xb;
+const MAIN_END_COLLECTION: u8 = 0xc;
+
+const GLOBAL_USAGE_PAGE: u8 = 0x0;
+const GLOBAL_LOGICAL_MIN: u8 = 0x1;
+const GLOBAL_LOGICAL_MAX: u8 = 0x2;
+const GLOBAL_REPORT_SIZE: u8 = 0x7;
+const GLOBAL_REPORT_ID: u8 = 0x8;
+const GLOBAL_REPORT_COUNT: u8 = 0x9;
+const GLOBAL_PUSH: u8 = 0xa;
+const GLOBAL_POP: u8 = 0xb;
+
+const LOCAL_USAGE: u8 = 0x0;
+const LOCAL_USAGE_MIN: u8 = 0x1;
+const LOCAL_USAGE_MAX: u8 = 0x2;
+
+/// Reports with distinct kinds or IDs a descriptor can describe
+pub const MAX_REPORTS: usize = 8;
+/// Usages listed ahead of a single main item
+const MAX_USAGES: usize = 16;
+const MAX_PUSH: usize = 4;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Globals {
+    usage_page: u16,
+    logical_min: i32,
+    /// Logical maximum read as signed and as unsigned, which one applies depends on the minimum
+    logical_max: (i32, u32),
+    report_size: u32,
+    report_count: u32,
+    report_id: u8,
+}
+
+impl Globals {
+    /// Descriptors often give an unsigned maximum, such as 255 in a single byte, when the minimum is not negative
+    fn logical_max(&self) -> i32 {
+        if self.logical_min < 0 {
+            self.logical_max.0
+        } else {
+            self.logical_max.1 as i32
+        }
+    }
+
+    /// Usage with this usage page unless it has its own
+    fn usage(&self, usage: u32) -> Usage {
+        match usage >> 16 {
+            0 => Usage {
+                page: self.usage_page,
+                id: usage as u16,
+            },
+            _ => Usage::from_extended(usage),
+        }
+    }
+}
+
+/// Usages given ahead of a main item
+#[derive(Default)]
+struct Locals {
+    usages: Vec<u32, MAX_USAGES>,
+    usage_min: Option<u32>,
+    usage_max: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ReportLen {
+    kind: ReportKind,
+    id: u8,
+    bits: u32,
+}
+
+/// Fields and collections of a report descriptor, up to `FIELDS` fields and `COLLECTIONS` collections.
+/// Constant fields without usages are padding and not kept.
+pub struct ReportDescriptor<const FIELDS: usize = 32, const COLLECTIONS: usize = 8> {
+    fields: Vec<ReportField, FIELDS>,
+    collections: Vec<Collection, COLLECTIONS>,
+    reports: Vec<ReportLen, MAX_REPORTS>,
+}
+
+impl<const FIELDS: usize, const COLLECTIONS: usize> ReportDescriptor<FIELDS, COLLECTIONS> {
+    pub fn parse(desc: &[u8]) -> Result<Self, ReportError> {
+        let mut parsed = Self {
+            fields: Vec::new(),
+            collections: Vec::new(),
+            reports: Vec::new(),
+        };
+        let mut globals = Globals::default();
+        let mut pushed: Vec<Globals, MAX_PUSH> = Vec::new();
+        let mut locals = Locals::default();
+        let mut collection: Option<u8> = None;
+
+        for item in Items::new(desc) {
+            let item = item?;
+            match item.kind {
+                ItemKind::Main => {
+                    match item.tag {
+                        MAIN_INPUT => parsed.add_fields(ReportKind::Input, &item, &globals, &locals, collection)?,
+                        MAIN_OUTPUT => parsed.add_fields(ReportKind::Output, &item, &globals, &locals, collection)?,
+                        MAIN_FEATURE => parsed.add_fields(ReportKind::Feature, &item, &globals, &locals, collection)?,
+                        MAIN_COLLECTION => {
+                            let usage = locals.usages.first().copied().or(locals.usage_min).unwrap_or(0);
+                            let index = parsed.collections.len() as u8;
+                            parsed
+                                .collections
+                                .push(Collection {
+                                    kind: item.data as u8,
+                                    usage: globals.usage(usage),
+                                    parent: collection,
+                                })
+                                .map_err(|_| ReportError::TooManyCollections)?;
+                            collection = Some(index);
+                        }
+                        MAIN_END_COLLECTION => {
+                            let index = collection.ok_or(ReportError::UnbalancedCollection)?;
+                            collection = parsed.collections[index as usize].parent;
+                        }
+                        _ => debug!("Skipped main item {:x}", item.tag),
+                    }
+                    locals = Locals::default();
+                }
+                ItemKind::Global => match item.tag {
+                    GLOBAL_USAGE_PAGE => globals.usage_page = item.data as u16,
+                    GLOBAL_LOGICAL_MIN => globals.logical_min = item.signed(),
+                    GLOBAL_LOGICAL_MAX => globals.logical_max = (item.signed(), item.data),
+                    GLOBAL_REPORT_SIZE => globals.report_size = item.data,
+                    GLOBAL_REPORT_ID => {
+                        if item.data == 0 || item.data > u8::MAX as u32 {
+                            return Err(ReportError::InvalidReportId);
+                        }
+                        globals.report_id = item.data as u8;
+                    }
+                    GLOBAL_REPORT_COUNT => globals.report_count = item.data,
+                    GLOBAL_PUSH => pushed.push(globals).map_err(|_| ReportError::GlobalStack)?,
+                    GLOBAL_POP => globals = pushed.pop().ok_or(ReportError::GlobalStack)?,
+                    // physical range and units do not change how values are read
+                    _ => {}
+                },
+                ItemKind::Local => match item.tag {
+                    // usages of 4 bytes have their own usage page
+                    LOCAL_USAGE => locals
+                        .usages
+                        .push(item.data)
+                        .unwrap_or_else(|usage| warn!("Too many usages, {:x} ignored", usage)),
+                    LOCAL_USAGE_MIN => locals.usage_min = Some(item.data),
+                    LOCAL_USAGE_MAX => locals.usage_max = Some(item.data),
+                    // designators, strings and delimiters
+                    _ => {}
+                },
+                ItemKind::Reserved => {}
+            }
+        }
+        if collection.is_some() {
+            return Err(ReportError::UnbalancedCollection);
+        }
+        Ok(parsed)
+    }
+
+    pub fn fields(&self) -> &[ReportField] {
+        &self.fields
+    }
+
+    pub fn collections(&self) -> &[Collection] {
+        &self.collections
+    }
+
+    /// Reports then start with their ID
+    pub fn uses_report_ids(&self) -> bool {
+        self.reports.iter().any(|report| report.id != 0)
+    }
+
+    /// IDs of the reports of a kind, 0 if the device does not use report IDs
+    pub fn report_ids(&self, kind: ReportKind) -> impl Iterator<Item = u8> + '_ {
+        self.reports
+            .iter()
+            .filter(move |report| report.kind == kind)
+            .map(|report| report.id)
+    }
+
+    /// Length of a report in bytes, with its ID if it has one
+    pub fn report_len(&self, kind: ReportKind, id: u8) -> Option<usize> {
+        let report = self.reports.iter().find(|report| report.kind == kind && report.id == id)?;
+        Some(report.bits.div_ceil(8) as usize + (id != 0) as usize)
+    }
+
+    /// Variable field of a kind with `usage`, and the index of its value
+    pub fn find(&self, kind: ReportKind, usage: Usage) -> Option<(&ReportField, u16)> {
+        self.fields
+            .iter()
+            .filter(|field| field.kind == kind)
+            .find_map(|field| field.index_of(usage).map(|index| (field, index)))
+    }
+
+    /// Value with `usage` in an input report
+    pub fn value(&self, report: &[u8], usage: Usage) -> Option<i32> {
+        self.fields
+            .iter()
+            .filter(|field| field.kind == ReportKind::Input)
+            .find_map(|field| field.value(report, field.index_of(usage)?))
+    }
+
+    /// Fields for a main item, a variable item with listed usages gets a field per run of consecutive usages
+    fn add_fields(
+        &mut self, kind: ReportKind, item: &Item, globals: &Globals, locals: &Locals, collection: Option<u8>,
+    ) -> Result<(), ReportError> {
+        let bits = globals.report_size.saturating_mul(globals.report_count);
+        let offset = self.reserve(kind, globals.report_id, bits)?;
+        let flags = item.data as u16;
+        let has_usages = !locals.usages.is_empty() || locals.usage_min.is_some();
+        if bits == 0 || (flags & FIELD_CONSTANT != 0 && !has_usages) {
+            return Ok(());
+        }
+        if globals.report_size > 32 || offset.saturating_add(bits) > u16::MAX as u32 {
+            warn!("Field of {} bits at {} can't be read", globals.report_size, offset);
+            return Ok(());
+        }
+        let field = ReportField {
+            kind,
+            report_id: globals.report_id,
+            bit_offset: offset as u16,
+            bit_size: globals.report_size as u8,
+            count: globals.report_count as u16,
+            flags,
+            usage_page: globals.usage_page,
+            usage_min: 0,
+            usage_max: 0,
+            logical_min: globals.logical_min,
+            logical_max: globals.logical_max(),
+            collection,
+        };
+
+        if flags & FIELD_VARIABLE != 0 && locals.usage_min.is_none() && !locals.usages.is_empty() {
+            let mut run: Option<ReportField> = None;
+            for index in 0..field.count {
+                let listed = locals.usages[min(index as usize, locals.usages.len() - 1)];
+                let usage = globals.usage(listed);
+                match &mut run {
+                    Some(run) if continues(run, usage) => {
+                        run.usage_max = max(run.usage_max, usage.id);
+                        run.count += 1;
+                    }
+                    _ => {
+                        if let Some(run) = run.take() {
+                            self.fields.push(run).map_err(|_| ReportError::TooManyFields)?;
+                        }
+                        run = Some(ReportField {
+                            bit_offset: field.bit_offset + index * field.bit_size as u16,
+                            count: 1,
+                            usage_page: usage.page,
+                            usage_min: usage.id,
+                            usage_max: usage.id,
+                            ..field
+                        });
+                    }
+                }
+            }
+            if let Some(run) = run {
+                self.fields.push(run).map_err(|_| ReportError::TooManyFields)?;
+            }
+            return Ok(());
+        }
+
+        // a range, or the usages an array selects from
+        let (first, last) = match (locals.usage_min, locals.usage_max) {
+            (Some(usage_min), usage_max) => (usage_min, usage_max.unwrap_or(usage_min)),
+            _ => {
+                let ids = locals.usages.iter().map(|usage| usage & 0xffff);
+                let page = locals.usages.first().map_or(0, |usage| usage & 0xffff_0000);
+                (page | ids.clone().min().unwrap_or(0), page | ids.max().unwrap_or(0))
+            }
+        };
+        let (first, last) = (globals.usage(first), globals.usage(last));
+        self.fields
+            .push(ReportField {
+                usage_page: first.page,
+                usage_min: first.id,
+                usage_max: max(first.id, last.id),
+                ..field
+            })
+            .map_err(|_| ReportError::TooManyFields)
+    }
+
+    /// Room for `bits` more in a report, returns where they start
+    fn reserve(&mut self, kind: ReportKind, id: u8, bits: u32) -> Result<u32, ReportError> {
+        let report = match self
+            .reports
+            .iter_mut()
+            .position(|report| report.kind == kind && report.id == id)
+        {
+            Some(index) => &mut self.reports[index],
+            None => {
+                self.reports
+                    .push(ReportLen { kind, id, bits: 0 })
+                    .map_err(|_| ReportError::TooManyReports)?;
+                self.reports.last_mut().unwrap()
+            }
+        };
+        let offset = report.bits;
+        report.bits = report.bits.saturating_add(bits);
+        Ok(offset)
+    }
+}
+
+/// Usage of the next value of a variable field is the one after the last, or the last again once repeating
+fn continues(run: &ReportField, usage: Usage) -> bool {
+    let span = (run.usage_max - run.usage_min) as u32 + 1;
+    let repeating = run.count as u32 > span;
+    usage.page == run.usage_page
+        && ((usage.id == run.usage_max && run.count as u32 >= span)
+            || (!repeating && run.usage_max < u16::MAX && usage.id == run.usage_max + 1))
+}
+
+/// `size` bits from bit `offset` of `data`, least significant first
+fn read_bits(data: &[u8], offset: usize, size: u8) -> Option<u32> {
+    let end = offset + size as usize;
+    if size == 0 || size > 32 || end > data.len() * 8 {
+        return None;
+    }
+    let bytes = &data[offset / 8..end.div_ceil(8)];
+    let value = bytes.iter().rev().fold(0u64, |value, byte| value << 8 | *byte as u64);
+    Some(((value >> (offset % 8)) & ((1u64 << size) - 1)) as u32)
+}
+
+fn write_bits(data: &mut [u8], offset: usize, size: u8, value: u32) -> bool {
+    if size == 0 || size > 32 || offset + size as usize > data.len() * 8 {
+        return false;
+    }
+    for bit in 0..size as usize {
+        let mask = 1 << ((offset + bit) % 8);
+        let byte = &mut data[(offset + bit) / 8];
+        if value >> bit & 1 != 0 {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+    }
+    true
+}
+
+fn sign_extend(value: u32, bits: u8) -> i32 {
+    let shift = 32 - bits as u32;
+    ((value << shift) as i32) >> shift
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::{SimDevice, SimHost};
+    use crate::{DescriptorParser, DescriptorRef, Device, HostEvent, Speed, UsbHost};
+
+    /// Boot keyboard, from the HID specification
+    const KBD_REPORT_DESC: [u8; 63] = [
+        0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, // generic desktop, keyboard application
+        0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25, 0x01, // modifiers
+        0x75, 0x01, 0x95, 0x08, 0x81, 0x02, // 8 variable bits
+        0x95, 0x01, 0x75, 0x08, 0x81, 0x01, // reserved byte
+        0x95, 0x05, 0x75, 0x01, 0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, // 5 LEDs
+        0x95, 0x01, 0x75, 0x03, 0x91, 0x01, // LED padding
+        0x95, 0x06, 0x75, 0x08, 0x15, 0x00, 0x25, 0x65, // 6 keys
+        0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, // array
+        0xc0,
+    ];
+
+    /// Mouse with a wheel, and consumer controls, in reports 1 and 2
+    const MOUSE_REPORT_DESC: [u8; 79] = [
+        0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x85, 0x01, // mouse application, report 1
+        0x09, 0x01, 0xa1, 0x00, // pointer
+        0x05, 0x09, 0x19, 0x01, 0x29, 0x03, 0x15, 0x00, 0x25, 0x01, // 3 buttons
+        0x95, 0x03, 0x75, 0x01, 0x81, 0x02, // variable bits
+        0x95, 0x01, 0x75, 0x05, 0x81, 0x03, // padding
+        0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x09, 0x38, // X, Y, wheel
+        0x15, 0x81, 0x25, 0x7f, 0x75, 0x08, 0x95, 0x03, 0x81, 0x06, // relative bytes
+        0xc0, 0xc0, // end of pointer and mouse
+        0x05, 0x0c, 0x09, 0x01, 0xa1, 0x01, 0x85, 0x02, // consumer control application, report 2
+        0x15, 0x00, 0x26, 0xff, 0x03, 0x19, 0x00, 0x2a, 0xff, 0x03, // 0 to 1023
+        0x75, 0x10, 0x95, 0x01, 0x81, 0x00, // 16 bits array
+        0xc0,
+    ];
+
+    #[test]
+    fn boot_keyboard() {
+        let desc: ReportDescriptor = ReportDescriptor::parse(&KBD_REPORT_DESC).unwrap();
+        assert!(!desc.uses_report_ids());
+        assert_eq!(desc.report_len(ReportKind::Input, 0), Some(8));
+        assert_eq!(desc.report_len(ReportKind::Output, 0), Some(1));
+        assert_eq!(desc.report_len(ReportKind::Feature, 0), None);
+        assert_eq!(desc.collections().len(), 1);
+        assert_eq!(desc.collections()[0].kind(), Some(CollectionKind::Application));
+        assert_eq!(desc.collections()[0].usage, Usage::new(UsagePage::GenericDesktop, 0x06));
+
+        // padding is not kept
+        let fields = desc.fields();
+        assert_eq!(fields.len(), 3);
+        let modifiers = &fields[0];
+        assert!(modifiers.is_variable());
+        assert_eq!((modifiers.bit_offset, modifiers.bit_size, modifiers.count), (0, 1, 8));
+        assert_eq!(modifiers.usage(1), Usage::new(UsagePage::Keyboard, 0xe1));
+        let leds = &fields[1];
+        assert_eq!((leds.kind, leds.usage_page), (ReportKind::Output, UsagePage::Led as u16));
+        assert_eq!((leds.usage_min, leds.usage_max, leds.count), (1, 5, 5));
+        // caps lock on, in an output report
+        let mut output = [0xffu8];
+        let caps = leds.index_of(Usage::new(UsagePage::Led, 2)).unwrap();
+        assert!(leds.set_value(&mut output, 0, 0));
+        assert!(leds.set_value(&mut output, caps, 1));
+        assert_eq!(output, [0xfe]);
+        assert!(!leds.set_value(&mut [], caps, 1));
+        let keys = &fields[2];
+        assert!(keys.is_array());
+        assert_eq!((keys.bit_offset, keys.bit_size, keys.count), (16, 8, 6));
+        assert_eq!((keys.logical_min, keys.logical_max, keys.collection), (0, 0x65, Some(0)));
+
+        // left shift and 'a'
+        let report = [0x02, 0, 0x04, 0, 0, 0, 0, 0];
+        assert_eq!(desc.value(&report, Usage::new(UsagePage::Keyboard, 0xe1)), Some(1));
+        assert_eq!(desc.value(&report, Usage::new(UsagePage::Keyboard, 0xe0)), Some(0));
+        assert_eq!(keys.selected(&report, 0), Some(Usage::new(UsagePage::Keyboard, 0x04)));
+        assert_eq!(keys.selected(&report, 1), Some(Usage::new(UsagePage::Keyboard, 0)));
+        assert_eq!(keys.value(&report[..4], 5), None);
+        assert_eq!(keys.value(&report, 6), None);
+    }
+
+    #[test]
+    fn report_ids_and_signed_values() {
+        let desc: ReportDescriptor = ReportDescriptor::parse(&MOUSE_REPORT_DESC).unwrap();
+        assert!(desc.uses_report_ids());
+        assert!(desc.report_ids(ReportKind::Input).eq([1, 2]));
+        assert_eq!(desc.report_len(ReportKind::Input, 1), Some(5));
+        assert_eq!(desc.report_len(ReportKind::Input, 2), Some(3));
+
+        let collections = desc.collections();
+        assert_eq!(collections.len(), 3);
+        assert_eq!(collections[1].kind(), Some(CollectionKind::Physical));
+        assert_eq!(collections[1].parent, Some(0));
+        assert_eq!(collections[2].parent, None);
+        assert_eq!(collections[2].usage, Usage::new(UsagePage::Consumer, 0x01));
+
+        // X and Y are consecutive, the wheel is not
+        let fields = desc.fields();
+        assert_eq!(fields.len(), 4);
+        assert_eq!((fields[1].usage_min, fields[1].usage_max, fields[1].count), (0x30, 0x31, 2));
+        assert_eq!((fields[2].bit_offset, fields[2].usage_min, fields[2].count), (24, 0x38, 1));
+        assert!(fields[2].is_relative());
+        assert_eq!(fields[0].collection, Some(1));
+
+        let x = Usage::new(UsagePage::GenericDesktop, 0x30);
+        assert_eq!(desc.find(ReportKind::Input, x), Some((&fields[1], 0)));
+        assert_eq!(desc.find(ReportKind::Output, x), None);
+        let report = [1, 0b101, 0xfb, 3, 0xff];
+        assert_eq!(desc.value(&report, Usage::new(UsagePage::Button, 1)), Some(1));
+        assert_eq!(desc.value(&report, Usage::new(UsagePage::Button, 2)), Some(0));
+        assert_eq!(desc.value(&report, x), Some(-5));
+        assert_eq!(desc.value(&report, Usage::new(UsagePage::GenericDesktop, 0x31)), Some(3));
+        assert_eq!(desc.value(&report, Usage::new(UsagePage::GenericDesktop, 0x38)), Some(-1));
+
+        // unsigned logical maximum, 16 bits array
+        let consumer = &fields[3];
+        assert_eq!((consumer.logical_min, consumer.logical_max), (0, 0x3ff));
+        let report = [2, 0x23, 0x02];
+        assert_eq!(consumer.selected(&report, 0), Some(Usage::new(UsagePage::Consumer, 0x223)));
+        assert_eq!(desc.value(&report, x), None);
+        assert_eq!(consumer.selected(&[2, 0xff, 0xff], 0), None);
+    }
+
+    #[test]
+    fn invalid_report_descriptors() {
+        let parse = |desc: &[u8]| ReportDescriptor::<32, 8>::parse(desc).err();
+        assert_eq!(parse(&[0xa1, 0x01]), Some(ReportError::UnbalancedCollection));
+        assert_eq!(parse(&[0xc0]), Some(ReportError::UnbalancedCollection));
+        assert_eq!(parse(&[0x05]), Some(ReportError::Truncated));
+        assert_eq!(parse(&[0x85, 0x00]), Some(ReportError::InvalidReportId));
+        assert_eq!(parse(&[0xb4]), Some(ReportError::GlobalStack));
+        assert_eq!(
+            ReportDescriptor::<2, 8>::parse(&KBD_REPORT_DESC).err(),
+            Some(ReportError::TooManyFields)
+        );
+        // long items are skipped
+        assert_eq!(parse(&[0xfe, 0x02, 0x10, 0xaa, 0xbb]), None);
+    }
+
+    #[test]
+    fn report_descriptor_from_device() {
+        const DEV_DESC: [u8; 18] = [
+            0x12, 0x01, 0x10, 0x01, 0x00, 0x00, 0x00, 0x08, 0x6d, 0x04, 0x1c, 0xc3, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+        ];
+        const CONF_DESC: [u8; 34] = [
+            0x09, 0x02, 0x22, 0x00, 0x01, 0x01, 0x00, 0xa0, 0x32, // configuration
+            0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, // HID interface, no boot protocol
+            0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x4f, 0x00, // HID
+            0x07, 0x05, 0x81, 0x03, 0x05, 0x00, 0x0a, // interrupt IN
+        ];
+        let mut host = SimHost::new();
+        host.attach(
+            SimDevice::new(&DEV_DESC)
+                .with_configuration(&CONF_DESC)
+                .with_report_descriptor(0, &MOUSE_REPORT_DESC),
+        );
+        while host.update() != Some(HostEvent::Ready) {}
+
+        let hid_desc = DescriptorParser::new(&CONF_DESC).find_map(|desc| match desc {
+            Ok(DescriptorRef::Hid(hid_desc)) => Some(*hid_desc),
+            _ => None,
+        });
+        let len = hid_desc.unwrap().report_descriptor_len().unwrap() as usize;
+        assert_eq!(len, MOUSE_REPORT_DESC.len());
+        let mut device = Device::new(Speed::Low);
+        let mut buf = [0u8; 128];
+        assert_eq!(device.get_report_descriptor(&mut host, 0, &mut buf[..len]), Ok(len));
+        let desc: ReportDescriptor = ReportDescriptor::parse(&buf[..len]).unwrap();
+        assert_eq!(desc.fields().len(), 4);
+        assert!(device.get_report_descriptor(&mut host, 1, &mut buf).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+//! Hub class constants and descriptor, cf §11.23 and §11.24 of USB 2.0
+
+/// Hub descriptor type, requested with a class GET_DESCRIPTOR
+pub const HUB_DESCRIPTOR_TYPE: u8 = 0x29;
+
+#[derive(Clone, Copy, Debug, PartialEq, strum_macros::FromRepr)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum PortFeature {
+    Connection = 0,
+    Enable = 1,
+    Suspend = 2,
+    OverCurrent = 3,
+    Reset = 4,
+    Power = 8,
+    LowSpeed = 9,
+    CConnection = 16,
+    CEnable = 17,
+    CSuspend = 18,
+    COverCurrent = 19,
+    CReset = 20,
+}
+
+/// wPortStatus bits, cf §11.24.2.7.1
+pub const PORT_CONNECTION: u16 = 1 << 0;
+pub const PORT_ENABLE: u16 = 1 << 1;
+pub const PORT_SUSPEND: u16 = 1 << 2;
+pub const PORT_OVER_CURRENT: u16 = 1 << 3;
+pub const PORT_RESET: u16 = 1 << 4;
+pub const PORT_POWER: u16 = 1 << 8;
+pub const PORT_LOW_SPEED: u16 = 1 << 9;
+pub const PORT_HIGH_SPEED: u16 = 1 << 10;
+
+/// wPortChange bits, cf §11.24.2.7.2
+pub const C_PORT_CONNECTION: u16 = 1 << 0;
+pub const C_PORT_ENABLE: u16 = 1 << 1;
+pub const C_PORT_SUSPEND: u16 = 1 << 2;
+pub const C_PORT_OVER_CURRENT: u16 = 1 << 3;
+pub const C_PORT_RESET: u16 = 1 << 4;
+
+/// Hub descriptor, for hubs of up to 7 ports
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(C)]
+pub struct HubDescriptor {
+    pub b_desc_length: u8,
+    pub b_descriptor_type: u8,
+    pub b_nbr_ports: u8,
+    // unaligned u16 split in lo/hi pair, see EndpointDescriptor
+    pub w_hub_characteristics_lo: u8,
+    pub w_hub_characteristics_hi: u8,
+    /// Time (in 2 ms intervals) from port power on until power is good
+    pub b_pwr_on_2_pwr_good: u8,
+    pub b_hub_contr_current: u8,
+    pub device_removable: u8,
+    pub port_pwr_ctrl_mask: u8,
+}
+
+/// Port status as returned by a port GET_STATUS request
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(C)]
+pub struct PortStatus {
+    pub w_port_status: u16,
+    pub w_port_change: u16,
+}
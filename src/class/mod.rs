@@ -2,6 +2,7 @@
 //! Used by descriptor parser and drivers
 pub mod audio;
 pub mod hid;
+pub mod hub;
 
 #[derive(Clone, Copy, Debug, PartialEq, strum_macros::FromRepr)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
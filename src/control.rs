@@ -124,11 +124,27 @@ impl Default for RequestCode {
     }
 }
 
+/// Standard feature selectors for SET_FEATURE and CLEAR_FEATURE, cf §9.4 of USB 2.0
+#[derive(Clone, Copy, Debug, PartialEq, strum_macros::FromRepr)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum FeatureSelector {
+    /// Endpoint recipient
+    EndpointHalt = 0,
+    /// Device recipient
+    DeviceRemoteWakeup = 1,
+    /// Device recipient, high speed only
+    TestMode = 2,
+}
+
+/// GET_STATUS bit of an endpoint recipient, set while the endpoint is halted
+pub const STATUS_ENDPOINT_HALT: u16 = 0x0001;
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct SetupPacket {
     pub bm_request_type: RequestType,
-    pub b_request: RequestCode,
+    pub b_request: u8,
     pub w_value: WValue,
     pub w_index: u16,
     pub w_length: u16,
@@ -153,7 +169,7 @@ mod test {
                 RequestKind::Class,
                 RequestRecipient::Endpoint,
             )),
-            b_request: RequestCode::GetInterface,
+            b_request: RequestCode::GetInterface as u8,
             w_value: WValue::lo_hi(0xf0, 0x0d),
             w_index: 0xadde,
             w_length: 0xefbe,
@@ -165,6 +181,7 @@ mod test {
         assert_offset("w_index", &sp.w_index, base, 0x04);
         assert_offset("w_length", &sp.w_length, base, 0x06);
 
+        let len = mem::size_of::<SetupPacket>();
         let result = unsafe { slice::from_raw_parts(&sp as *const _ as *const u8, len) };
         let expected = &[0x22, 0x0a, 0xf0, 0x0d, 0xde, 0xad, 0xbe, 0xef];
         assert_eq!(result, expected);
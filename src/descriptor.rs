@@ -7,9 +7,25 @@
 //! when necessary to ensure that they are able to be directly
 //! marshalled to the bus.
 
-use crate::MaxPacketSize;
+use crate::{AltSetting, ConfigNum, InterfaceNum, MaxPacketSize, UsbError};
 use core::convert::TryFrom;
 use core::mem;
+use heapless::Vec;
+
+/// `defmt::Format` for `repr(packed)` descriptors, whose fields can't be borrowed by the derive
+macro_rules! format_packed {
+    ($name:ident { $($field:ident),* $(,)? }) => {
+        #[cfg(feature = "defmt")]
+        impl defmt::Format for $name {
+            fn format(&self, f: defmt::Formatter) {
+                defmt::write!(f, "{=str} {{", stringify!($name));
+                $(defmt::write!(f, " {=str}: {}", stringify!($field), { self.$field });)*
+                defmt::write!(f, " }}");
+            }
+        }
+    };
+}
+pub(crate) use format_packed;
 
 #[derive(Clone, Copy, Debug, PartialEq, strum_macros::FromRepr)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -38,6 +54,11 @@ pub enum DescriptorType {
     BinaryObjectStore = 0xF,
     DeviceCapability = 0x10,
 
+    // HID class descriptors
+    Hid = 0x21,
+    HidReport = 0x22,
+    HidPhysical = 0x23,
+
     ClassInterface = 0x24,
     ClassEndpoint = 0x25,
 
@@ -63,8 +84,7 @@ impl TryFrom<u8> for DescriptorType {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
+#[repr(C, packed)]
 pub struct DeviceDescriptor {
     pub b_length: u8,
     pub b_descriptor_type: DescriptorType,
@@ -82,6 +102,23 @@ pub struct DeviceDescriptor {
     pub b_num_configurations: u8,
 }
 
+format_packed!(DeviceDescriptor {
+    b_length,
+    b_descriptor_type,
+    bcd_usb,
+    b_device_class,
+    b_device_sub_class,
+    b_device_protocol,
+    b_max_packet_size,
+    id_vendor,
+    id_product,
+    bcd_device,
+    i_manufacturer,
+    i_product,
+    i_serial_number,
+    b_num_configurations,
+});
+
 impl Default for DeviceDescriptor {
     fn default() -> Self {
         Self {
@@ -104,8 +141,7 @@ impl Default for DeviceDescriptor {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
+#[repr(C, packed)]
 pub struct ConfigurationDescriptor {
     pub b_length: u8,
     pub b_descriptor_type: DescriptorType,
@@ -117,6 +153,17 @@ pub struct ConfigurationDescriptor {
     pub b_max_power: u8,
 }
 
+format_packed!(ConfigurationDescriptor {
+    b_length,
+    b_descriptor_type,
+    w_total_length,
+    b_num_interfaces,
+    b_configuration_value,
+    i_configuration,
+    bm_attributes,
+    b_max_power,
+});
+
 impl Default for ConfigurationDescriptor {
     fn default() -> Self {
         Self {
@@ -199,6 +246,13 @@ pub struct Audio1EndpointDescriptor {
     pub audio2: u8,
 }
 
+impl Audio1EndpointDescriptor {
+    /// Standard part of the descriptor, audio fields come after it
+    pub fn as_endpoint(&self) -> &EndpointDescriptor {
+        unsafe { &*(self as *const Self as *const EndpointDescriptor) }
+    }
+}
+
 impl MaxPacketSize for Audio1EndpointDescriptor {
     fn max_packet_size(&self) -> u16 {
         // self.w_max_packet_size
@@ -206,6 +260,174 @@ impl MaxPacketSize for Audio1EndpointDescriptor {
     }
 }
 
+impl DeviceDescriptor {
+    /// Wire format of the descriptor, as sent by a device
+    pub fn to_bytes(&self) -> [u8; mem::size_of::<Self>()] {
+        let [usb_lo, usb_hi] = { self.bcd_usb }.to_le_bytes();
+        let [vendor_lo, vendor_hi] = { self.id_vendor }.to_le_bytes();
+        let [product_lo, product_hi] = { self.id_product }.to_le_bytes();
+        let [device_lo, device_hi] = { self.bcd_device }.to_le_bytes();
+        [
+            mem::size_of::<Self>() as u8,
+            DescriptorType::Device as u8,
+            usb_lo,
+            usb_hi,
+            self.b_device_class,
+            self.b_device_sub_class,
+            self.b_device_protocol,
+            self.b_max_packet_size,
+            vendor_lo,
+            vendor_hi,
+            product_lo,
+            product_hi,
+            device_lo,
+            device_hi,
+            self.i_manufacturer,
+            self.i_product,
+            self.i_serial_number,
+            self.b_num_configurations,
+        ]
+    }
+}
+
+/// Builds a configuration descriptor set, as a device would return it.
+/// `w_total_length`, `b_num_interfaces` and each interface's `b_num_endpoints` are filled in as descriptors are added,
+/// so are the total lengths of class specific headers. Descriptors that do not fit in `N` bytes make `build` fail.
+pub struct ConfigurationBuilder<const N: usize> {
+    buf: Vec<u8, N>,
+    /// Start of the last interface descriptor
+    interface: Option<usize>,
+    /// Start of a class specific header and offset of its total length, covering descriptors up to the next interface
+    class_header: Option<(usize, usize)>,
+    overflow: bool,
+}
+
+impl<const N: usize> ConfigurationBuilder<N> {
+    /// Configuration `b_configuration_value`, bus powered drawing 100mA until changed by `attributes`
+    pub fn new(b_configuration_value: ConfigNum) -> Self {
+        let builder = Self {
+            buf: Vec::new(),
+            interface: None,
+            class_header: None,
+            overflow: false,
+        };
+        builder.descriptor(
+            DescriptorType::Configuration as u8,
+            &[0, 0, 0, b_configuration_value, 0, 0x80, 50],
+        )
+    }
+
+    /// `bm_attributes` and `b_max_power` (in 2mA units) of the configuration
+    pub fn attributes(mut self, bm_attributes: u8, b_max_power: u8) -> Self {
+        if let Some([.., attributes, max_power]) = self.buf.get_mut(..mem::size_of::<ConfigurationDescriptor>()) {
+            *attributes = bm_attributes;
+            *max_power = b_max_power;
+        }
+        self
+    }
+
+    /// Interface association grouping `count` interfaces from `first`
+    pub fn association(self, first: InterfaceNum, count: u8, class: u8, subclass: u8, protocol: u8) -> Self {
+        self.descriptor(
+            DescriptorType::InterfaceAssociation as u8,
+            &[first, count, class, subclass, protocol, 0],
+        )
+    }
+
+    /// Interface or alternate setting, its endpoints are counted as they are added
+    pub fn interface(mut self, number: InterfaceNum, alt: AltSetting, class: u8, subclass: u8, protocol: u8) -> Self {
+        self.close_class_header();
+        let start = self.buf.len();
+        self = self.descriptor(DescriptorType::Interface as u8, &[number, alt, 0, class, subclass, protocol, 0]);
+        if !self.overflow {
+            self.interface = Some(start);
+            if alt == 0 {
+                self.buf[4] += 1;
+            }
+        }
+        self
+    }
+
+    /// Endpoint of the last interface
+    pub fn endpoint(self, address: u8, bm_attributes: u8, max_packet_size: u16, b_interval: u8) -> Self {
+        let [size_lo, size_hi] = max_packet_size.to_le_bytes();
+        self.interface_endpoint(&[address, bm_attributes, size_lo, size_hi, b_interval])
+    }
+
+    /// Audio 1.0 endpoint of the last interface, with its refresh rate and synch endpoint
+    pub fn audio_endpoint(
+        self, address: u8, bm_attributes: u8, max_packet_size: u16, b_interval: u8, b_refresh: u8, b_synch_address: u8,
+    ) -> Self {
+        let [size_lo, size_hi] = max_packet_size.to_le_bytes();
+        self.interface_endpoint(&[address, bm_attributes, size_lo, size_hi, b_interval, b_refresh, b_synch_address])
+    }
+
+    /// Any descriptor, `body` being what follows its length and type bytes
+    pub fn descriptor(self, desc_type: u8, body: &[u8]) -> Self {
+        self.descriptor_from(desc_type, body.iter().copied())
+    }
+
+    pub(crate) fn descriptor_from(mut self, desc_type: u8, body: impl IntoIterator<Item = u8>) -> Self {
+        if self.overflow {
+            return self;
+        }
+        let start = self.buf.len();
+        self.overflow = self.buf.extend_from_slice(&[0, desc_type]).is_err();
+        for byte in body {
+            if self.overflow {
+                break;
+            }
+            self.overflow = self.buf.push(byte).is_err();
+        }
+        match u8::try_from(self.buf.len() - start) {
+            Ok(len) if !self.overflow => self.buf[start] = len,
+            _ => self.overflow = true,
+        }
+        self
+    }
+
+    /// Class specific header whose total length, a `u16` at `total_offset` in the descriptor,
+    /// covers the header and the descriptors that follow it up to the next interface
+    pub fn class_header(mut self, desc_type: u8, body: &[u8], total_offset: usize) -> Self {
+        self.close_class_header();
+        let start = self.buf.len();
+        self = self.descriptor(desc_type, body);
+        if !self.overflow {
+            self.class_header = Some((start, total_offset));
+        }
+        self
+    }
+
+    /// The whole configuration descriptor set
+    pub fn build(mut self) -> Result<Vec<u8, N>, UsbError> {
+        self.close_class_header();
+        if self.overflow || self.buf.len() > u16::MAX as usize {
+            return Err(UsbError::DescriptorTooBig);
+        }
+        let total_len = (self.buf.len() as u16).to_le_bytes();
+        self.buf[2..4].copy_from_slice(&total_len);
+        Ok(self.buf)
+    }
+
+    fn interface_endpoint(mut self, body: &[u8]) -> Self {
+        self = self.descriptor(DescriptorType::Endpoint as u8, body);
+        match self.interface {
+            Some(interface) if !self.overflow => self.buf[interface + 4] += 1,
+            _ => {}
+        }
+        self
+    }
+
+    fn close_class_header(&mut self) {
+        if let Some((start, total_offset)) = self.class_header.take() {
+            if !self.overflow {
+                let total_len = ((self.buf.len() - start) as u16).to_le_bytes();
+                self.buf[start + total_offset..start + total_offset + 2].copy_from_slice(&total_len);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -237,14 +459,14 @@ mod test {
         let base = &desc as *const _ as usize;
         assert_offset("b_length", &desc.b_length, base, 0x00);
         assert_offset("b_descriptor_type", &desc.b_descriptor_type, base, 0x01);
-        assert_offset("bcd_usb", &desc.bcd_usb, base, 0x02);
+        assert_eq!(mem::offset_of!(DeviceDescriptor, bcd_usb), 0x02);
         assert_offset("b_device_class", &desc.b_device_class, base, 0x04);
         assert_offset("b_device_sub_class", &desc.b_device_sub_class, base, 0x05);
         assert_offset("b_device_protocol", &desc.b_device_protocol, base, 0x06);
         assert_offset("b_max_packet_size", &desc.b_max_packet_size, base, 0x07);
-        assert_offset("id_vendor", &desc.id_vendor, base, 0x08);
-        assert_offset("id_product", &desc.id_product, base, 0x0a);
-        assert_offset("bcd_device", &desc.bcd_device, base, 0x0c);
+        assert_eq!(mem::offset_of!(DeviceDescriptor, id_vendor), 0x08);
+        assert_eq!(mem::offset_of!(DeviceDescriptor, id_product), 0x0a);
+        assert_eq!(mem::offset_of!(DeviceDescriptor, bcd_device), 0x0c);
         assert_offset("i_manufacturer", &desc.i_manufacturer, base, 0x0e);
         assert_offset("i_product", &desc.i_product, base, 0x0f);
         assert_offset("i_serial_number", &desc.i_serial_number, base, 0x10);
@@ -274,7 +496,7 @@ mod test {
         let base = &desc as *const _ as usize;
         assert_offset("b_length", &desc.b_length, base, 0x00);
         assert_offset("b_descriptor_type", &desc.b_descriptor_type, base, 0x01);
-        assert_offset("w_total_length", &desc.w_total_length, base, 0x02);
+        assert_eq!(mem::offset_of!(ConfigurationDescriptor, w_total_length), 0x02);
         assert_offset("b_num_interfaces", &desc.b_num_interfaces, base, 0x04);
         assert_offset("b_configuration_value", &desc.b_configuration_value, base, 0x05);
         assert_offset("i_configuration", &desc.i_configuration, base, 0x06);
@@ -292,7 +514,7 @@ mod test {
         assert_eq!(len, 9);
         let desc = InterfaceDescriptor {
             b_length: len as u8,
-            b_descriptor_type: DescriptorType::Interface,
+            b_descriptor_type: DescriptorType::Interface as u8,
             b_interface_number: 0xee,
             b_alternate_setting: 0xaa,
             b_num_endpoints: 0xf7,
@@ -329,19 +551,128 @@ mod test {
 
             b_interval: 0x7a,
             // w_max_packet_size: 0xdead,
-            w_max_packet_size_lo: ad,
-            w_max_packet_size_hi: de,
+            w_max_packet_size_lo: 0xad,
+            w_max_packet_size_hi: 0xde,
         };
         let base = &desc as *const _ as usize;
         assert_offset("b_length", &desc.b_length, base, 0x00);
         assert_offset("b_descriptor_type", &desc.b_descriptor_type, base, 0x01);
         assert_offset("b_endpoint_address", &desc.b_endpoint_address, base, 0x02);
         assert_offset("bm_attributes", &desc.bm_attributes, base, 0x03);
-        assert_offset("w_max_packet_size", &desc.w_max_packet_size, base, 0x04);
+        assert_offset("w_max_packet_size_lo", &desc.w_max_packet_size_lo, base, 0x04);
+        assert_offset("w_max_packet_size_hi", &desc.w_max_packet_size_hi, base, 0x05);
         assert_offset("b_interval", &desc.b_interval, base, 0x06);
 
         let result = unsafe { slice::from_raw_parts(&desc as *const _ as *const u8, len) };
         let expected = &[0x07, 0x05, 0x02, 0xae, 0xad, 0xde, 0x7a];
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn device_descriptor_bytes() {
+        let desc = DeviceDescriptor {
+            bcd_usb: 0x0110,
+            b_max_packet_size: 8,
+            id_vendor: 0x413c,
+            id_product: 0x2003,
+            bcd_device: 0x0301,
+            i_manufacturer: 1,
+            i_product: 2,
+            b_num_configurations: 1,
+            ..Default::default()
+        };
+        let bytes = desc.to_bytes();
+        assert_eq!(
+            bytes,
+            [
+                0x12, 0x01, 0x10, 0x01, 0x00, 0x00, 0x00, 0x08, 0x3c, 0x41, 0x03, 0x20, 0x01, 0x03, 0x01, 0x02, 0x00,
+                0x01
+            ]
+        );
+        assert_eq!(crate::parse_device_descriptor(&bytes), Ok(desc));
+    }
+
+    #[test]
+    fn build_keyboard_configuration() {
+        let config = ConfigurationBuilder::<64>::new(1)
+            .attributes(0xa0, 0x32)
+            .interface(0, 0, 0x03, 0x01, 0x01)
+            .descriptor(0x21, &[0x10, 0x01, 0x00, 0x01, 0x22, 0x41, 0x00])
+            .endpoint(0x81, 0x03, 8, 10)
+            .build()
+            .unwrap();
+        let want = [
+            0x09, 0x02, 0x22, 0x00, 0x01, 0x01, 0x00, 0xa0, 0x32, // configuration
+            0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x01, 0x00, // HID boot keyboard interface
+            0x09, 0x21, 0x10, 0x01, 0x00, 0x01, 0x22, 0x41, 0x00, // HID
+            0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0a, // interrupt IN
+        ];
+        assert_eq!(config, want);
+    }
+
+    #[test]
+    fn build_midi_configuration() {
+        use crate::audio::{AudioDescriptorRef, JackType};
+        use crate::{DescriptorParser, DescriptorRef};
+
+        let config = ConfigurationBuilder::<128>::new(1)
+            .interface(0, 0, 0x01, 0x01, 0x00)
+            .ac_header(0x0100, 0)
+            .interface(1, 0, 0x01, 0x03, 0x00)
+            .ms_header()
+            .ms_in_jack(JackType::Embedded, 1)
+            .ms_out_jack(JackType::Embedded, 2, &[(1, 1), (3, 2)])
+            .endpoint(0x02, 0x02, 64, 0)
+            .ms_endpoint(&[1])
+            .endpoint(0x81, 0x02, 64, 0)
+            .ms_endpoint(&[2, 3])
+            .build()
+            .unwrap();
+        assert_eq!(config.len(), 9 + 9 + 9 + 9 + 7 + 6 + 11 + 7 + 5 + 7 + 6);
+
+        let mut jacks = 0;
+        let mut endpoints = 0;
+        for desc in DescriptorParser::new(&config) {
+            match desc.unwrap() {
+                DescriptorRef::Configuration(cdesc) => {
+                    assert_eq!({ cdesc.w_total_length } as usize, config.len());
+                    assert_eq!(cdesc.b_num_interfaces, 2);
+                }
+                DescriptorRef::Interface(idesc) => {
+                    assert_eq!(idesc.b_num_endpoints, idesc.b_interface_number * 2);
+                }
+                DescriptorRef::Audio(AudioDescriptorRef::ACInterfaceHeader(header)) => {
+                    assert_eq!({ header.w_total_length }, 9);
+                }
+                DescriptorRef::Audio(AudioDescriptorRef::MSInterface(header)) => {
+                    // header, jacks, endpoints and their class descriptors
+                    assert_eq!({ header.w_total_length }, 7 + 6 + 11 + 7 + 5 + 7 + 6);
+                }
+                DescriptorRef::Audio(AudioDescriptorRef::MSInJack(jack)) => {
+                    assert_eq!((jack.b_jack_type, jack.b_jack_id), (JackType::Embedded as u8, 1));
+                    jacks += 1;
+                }
+                DescriptorRef::Audio(AudioDescriptorRef::MSOutJack(jack, tail)) => {
+                    assert_eq!(jack.b_jack_id, 2);
+                    assert!(jack.sources(tail).eq([(1, 1), (3, 2)]));
+                    assert_eq!(jack.i_jack(tail), 0);
+                    jacks += 1;
+                }
+                DescriptorRef::Endpoint(_) => endpoints += 1,
+                DescriptorRef::Audio(AudioDescriptorRef::MSEndpoint(_, jack_ids)) => {
+                    assert!(jack_ids == [1] || jack_ids == [2, 3]);
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!((jacks, endpoints), (2, 2));
+    }
+
+    #[test]
+    fn build_overflow() {
+        let config = ConfigurationBuilder::<16>::new(1).interface(0, 0, 0xff, 0, 0).build();
+        assert_eq!(config, Err(UsbError::DescriptorTooBig));
+        let config = ConfigurationBuilder::<300>::new(1).descriptor(0x24, &[0; 254]).build();
+        assert_eq!(config, Err(UsbError::DescriptorTooBig));
+    }
 }
@@ -1,9 +1,20 @@
 use crate::address::DevAddress;
+use crate::class::hid::{HidProtocol, HidRequest, ReportKind};
 use crate::{
-    to_slice_mut, ConfigNum, ConfigurationDescriptor, DataToggle, DescriptorParser, DescriptorType, DeviceClass,
-    DeviceDescriptor, EndpointProperties, EpAddress, HostEndpoint, InterfaceNum, MaxPacketSize, RequestCode,
-    RequestDirection, RequestKind, RequestRecipient, RequestType, TransferType, UsbError, UsbHost, WValue,
+    parse_configuration_descriptor, parse_device_descriptor, to_slice_mut, AltSetting, ConfigNum,
+    ConfigurationDescriptor, DataToggle, DescriptorParser, DescriptorType, DeviceClass, DeviceDescriptor,
+    EndpointProperties, EpAddress, FeatureSelector, HostEndpoint, InterfaceNum, MaxPacketSize, PortChange, PortNum,
+    Quirks, RequestCode, RequestDirection, RequestKind, RequestRecipient, RequestType, Speed, TransferType, UsbError,
+    UsbHost, WValue, STATUS_ENDPOINT_HALT,
 };
+use core::mem;
+use utf16string::{WStr, LE};
+
+/// US English, used when a device reports no supported language
+const DEFAULT_LANG_ID: u16 = 0x0409;
+
+/// Device descriptor bytes up to and including `b_max_packet_size`
+pub(crate) const DESCRIPTOR_PROBE_LEN: usize = 8;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -14,9 +25,12 @@ pub enum DeviceState {
     /// Device needs a configuration to be selected
     SetConfig(u64),
 
+    /// Device known to need more time before being configured
+    Settle(u64),
+
     /// HID
-    /// Device needs an interface to be selected (maybe)
-    SetInterface(InterfaceNum, u64),
+    /// Device needs the boot protocol to be selected
+    SetProtocol(InterfaceNum, u64),
 
     /// HID
     SetReport(InterfaceNum),
@@ -28,6 +42,15 @@ pub enum DeviceState {
 
     /// No driver
     Orphan,
+
+    /// Enumeration failed, waiting to reset the port and try again
+    Retry(u64),
+
+    /// Port being reset, device will be enumerated again
+    PortReset,
+
+    /// Endpoint 0 packet size known, port to be reset again before the address is set
+    ProbeReset,
 }
 
 pub enum DeviceOps {
@@ -45,17 +68,117 @@ pub struct Device {
     max_packet_len: u16,
     toggle: bool,
     error: Option<UsbError>,
+    speed: Speed,
+    /// Upstream hub address and port, None for the root port device
+    hub: Option<(DevAddress, PortNum)>,
+    /// Language of string requests, read from the device on first use
+    lang_id: Option<u16>,
+    /// Last device descriptor read, zeroed until then
+    descriptor: DeviceDescriptor,
+    /// Times enumeration was retried since the device was attached
+    retries: u8,
+    /// Workarounds, known once the device descriptor is read
+    quirks: Quirks,
+    /// bMaxPacketSize0 read before the port was reset again, it is not probed twice
+    probed: Option<u8>,
 }
 
 impl Device {
-    pub fn new(max_bus_packet_size: u16) -> Self {
+    /// Device attached to the host's root port at `speed`
+    pub fn new(speed: Speed) -> Self {
         Self {
             state: DeviceState::SetAddress,
             device_address: DevAddress::from(0),
-            max_packet_len: max_bus_packet_size,
+            max_packet_len: speed.default_max_packet_size(),
             error: None,
             toggle: false,
+            speed,
+            hub: None,
+            lang_id: None,
+            descriptor: DeviceDescriptor::default(),
+            retries: 0,
+            quirks: Quirks::NONE,
+            probed: None,
+        }
+    }
+
+    /// Device attached to a hub's downstream port
+    pub fn new_downstream(hub: DevAddress, port: PortNum, speed: Speed) -> Self {
+        Self {
+            hub: Some((hub, port)),
+            ..Self::new(speed)
+        }
+    }
+
+    /// Device to be enumerated again in place of `prev`, keeping its failure count and probed packet size
+    pub(crate) fn enumerated_again(self, prev: &Device) -> Self {
+        Self {
+            retries: prev.retries,
+            probed: prev.probed,
+            ..self
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn retries(&self) -> u8 {
+        self.retries
+    }
+
+    /// bMaxPacketSize0 read before the port was reset again
+    pub(crate) fn probed(&self) -> Option<u8> {
+        self.probed
+    }
+
+    /// Keep bMaxPacketSize0 across the port reset preceding the address
+    pub(crate) fn probe_reset(&mut self, b_max_packet_size: u8) {
+        self.probed = Some(b_max_packet_size);
+        self.set_state(DeviceState::ProbeReset);
+    }
+
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    /// Apply the workarounds found for the device
+    pub(crate) fn quirks_found(&mut self, quirks: Quirks) {
+        if let Some(max_packet_size) = quirks.max_packet_size {
+            self.max_packet_len = max_packet_size;
         }
+        self.quirks = quirks;
+    }
+
+    /// Device descriptor as read during enumeration
+    pub fn descriptor(&self) -> &DeviceDescriptor {
+        &self.descriptor
+    }
+
+    pub fn id_vendor(&self) -> u16 {
+        self.descriptor.id_vendor
+    }
+
+    pub fn id_product(&self) -> u16 {
+        self.descriptor.id_product
+    }
+
+    pub fn bcd_device(&self) -> u16 {
+        self.descriptor.bcd_device
+    }
+
+    /// Device level class, 0 if each interface specifies its own
+    pub fn b_device_class(&self) -> u8 {
+        self.descriptor.b_device_class
+    }
+
+    pub fn b_num_configurations(&self) -> u8 {
+        self.descriptor.b_num_configurations
+    }
+
+    /// Address and port of the hub this device is attached to, None if on the root port
+    pub fn hub(&self) -> Option<(DevAddress, PortNum)> {
+        self.hub
     }
 
     pub fn state(&self) -> DeviceState {
@@ -75,20 +198,113 @@ impl Device {
         self.error = Some(error)
     }
 
+    /// Enumeration failed, try again once `until` is reached.
+    /// The device keeps its address until its port is reset.
+    pub(crate) fn retry(&mut self, until: u64) {
+        self.error = None;
+        self.retries += 1;
+        self.probed = None;
+        self.state = DeviceState::Retry(until);
+    }
+
     pub fn get_device_descriptor(&mut self, host: &mut dyn UsbHost) -> Result<DeviceDescriptor, UsbError> {
-        let mut dev_desc: DeviceDescriptor = DeviceDescriptor::default();
-        self.control_get_descriptor(host, DescriptorType::Device, 0, to_slice_mut(&mut dev_desc))?;
-        if dev_desc.b_max_packet_size < self.max_packet_len as u8 {
-            self.max_packet_len = dev_desc.b_max_packet_size as u16;
-        }
+        let mut buf = [0u8; mem::size_of::<DeviceDescriptor>()];
+        let len = self.control_get_descriptor(host, DescriptorType::Device, 0, &mut buf)?;
+        let dev_desc = parse_device_descriptor(&buf[..len])?;
+        self.descriptor_read(&dev_desc);
         Ok(dev_desc)
     }
 
+    /// Adopt the device's endpoint 0 packet size, low speed devices always use 8 bytes
+    pub(crate) fn max_packet_size_read(&mut self, b_max_packet_size: u8) -> Result<(), UsbError> {
+        self.max_packet_len = match (self.speed, b_max_packet_size) {
+            (Speed::Low, _) => 8,
+            (_, 8 | 16 | 32 | 64) => b_max_packet_size as u16,
+            _ => return Err(UsbError::InvalidDescriptor),
+        };
+        Ok(())
+    }
+
+    /// Keep the device descriptor
+    pub(crate) fn descriptor_read(&mut self, dev_desc: &DeviceDescriptor) {
+        self.descriptor = *dev_desc;
+    }
+
+    /// Read the device's supported LANGIDs (string descriptor zero), returns how many were copied to `lang_ids`
+    pub fn get_languages(&mut self, host: &mut dyn UsbHost, lang_ids: &mut [u16]) -> Result<usize, UsbError> {
+        if self.quirks.no_strings {
+            return Err(UsbError::NoString);
+        }
+        let mut buf = [0u8; 64];
+        let len = self.control_get_string(host, 0, 0, &mut buf)?;
+        let desc = string_body(&buf[..len])?;
+        let mut count = 0;
+        for (lang_id, raw) in lang_ids.iter_mut().zip(desc.chunks_exact(2)) {
+            *lang_id = u16::from_le_bytes([raw[0], raw[1]]);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Read string `index` in the device's first supported language
+    pub fn get_string<'b>(
+        &mut self, host: &mut dyn UsbHost, index: u8, buffer: &'b mut [u8],
+    ) -> Result<&'b WStr<LE>, UsbError> {
+        let lang_id = match self.lang_id {
+            Some(lang_id) => lang_id,
+            None => {
+                let mut lang_ids = [DEFAULT_LANG_ID];
+                if self.get_languages(host, &mut lang_ids)? == 0 {
+                    warn!("USB Device @{:?} has no string language", self.device_address);
+                }
+                self.lang_id = Some(lang_ids[0]);
+                lang_ids[0]
+            }
+        };
+        self.get_string_lang(host, index, lang_id, buffer)
+    }
+
+    /// Read string `index` in a specific language
+    pub fn get_string_lang<'b>(
+        &mut self, host: &mut dyn UsbHost, index: u8, lang_id: u16, buffer: &'b mut [u8],
+    ) -> Result<&'b WStr<LE>, UsbError> {
+        if index == 0 || self.quirks.no_strings {
+            return Err(UsbError::NoString);
+        }
+        let len = self.control_get_string(host, index, lang_id, buffer)?;
+        WStr::from_utf16le(string_body(&buffer[..len])?).map_err(|_| UsbError::InvalidDescriptor)
+    }
+
+    /// Manufacturer name, needs the device descriptor to have been read
+    pub fn manufacturer<'b>(&mut self, host: &mut dyn UsbHost, buffer: &'b mut [u8]) -> Result<&'b WStr<LE>, UsbError> {
+        self.get_string(host, self.descriptor.i_manufacturer, buffer)
+    }
+
+    /// Product name, needs the device descriptor to have been read
+    pub fn product<'b>(&mut self, host: &mut dyn UsbHost, buffer: &'b mut [u8]) -> Result<&'b WStr<LE>, UsbError> {
+        self.get_string(host, self.descriptor.i_product, buffer)
+    }
+
+    /// Serial number, needs the device descriptor to have been read
+    pub fn serial_number<'b>(
+        &mut self, host: &mut dyn UsbHost, buffer: &'b mut [u8],
+    ) -> Result<&'b WStr<LE>, UsbError> {
+        self.get_string(host, self.descriptor.i_serial_number, buffer)
+    }
+
+    /// Configuration descriptor `cfg_idx` alone, its `w_total_length` tells the size of the whole configuration
+    pub fn get_configuration_root(
+        &mut self, host: &mut dyn UsbHost, cfg_idx: u8,
+    ) -> Result<ConfigurationDescriptor, UsbError> {
+        let mut buf = [0u8; mem::size_of::<ConfigurationDescriptor>()];
+        let len = self.control_get_descriptor(host, DescriptorType::Configuration, cfg_idx, &mut buf)?;
+        Ok(parse_configuration_descriptor(&buf[..len])?)
+    }
+
     pub fn get_configuration_descriptors(
         &mut self, host: &mut dyn UsbHost, cfg_idx: u8, buffer: &mut [u8],
     ) -> Result<usize, UsbError> {
-        let mut config_root: ConfigurationDescriptor = ConfigurationDescriptor::default();
-        self.control_get_descriptor(host, DescriptorType::Configuration, cfg_idx, to_slice_mut(&mut config_root))?;
+        let config_root = self.get_configuration_root(host, cfg_idx)?;
         if config_root.w_total_length as usize > buffer.len() {
             Err(UsbError::DescriptorTooBig)
         } else {
@@ -103,34 +319,103 @@ impl Device {
 
     pub fn set_address(&mut self, host: &mut dyn UsbHost, dev_addr: DevAddress) -> Result<(), UsbError> {
         if 0u8 == self.device_address.into() {
-            self.control_set(host, RequestCode::SetAddress, RequestRecipient::Device, dev_addr.into(), 0, 0)?;
-            self.device_address = dev_addr;
-            self.state = DeviceState::SetConfig(host.after_millis(10));
+            self.control_set(
+                host,
+                RequestCode::SetAddress as u8,
+                RequestRecipient::Device,
+                dev_addr.into(),
+                0,
+                0,
+            )?;
+            self.address_set(dev_addr, host.after_millis(10));
             Ok(())
         } else {
             Err(UsbError::AddressSet)
         }
     }
 
+    /// Device now answers at `dev_addr`, configure it after `until`
+    pub(crate) fn address_set(&mut self, dev_addr: DevAddress, until: u64) {
+        self.device_address = dev_addr;
+        self.state = DeviceState::SetConfig(until);
+    }
+
     pub fn set_configuration(&mut self, host: &mut dyn UsbHost, config_num: u8) -> Result<(), UsbError> {
         if config_num == 0 {
             return Err(UsbError::InvalidConfig);
         }
-        self.control_set(host, RequestCode::SetConfiguration, RequestRecipient::Device, config_num, 0, 0)?;
+        self.control_set(
+            host,
+            RequestCode::SetConfiguration as u8,
+            RequestRecipient::Device,
+            config_num,
+            0,
+            0,
+        )?;
         Ok(())
     }
 
-    pub fn set_interface(&mut self, host: &mut dyn UsbHost, iface_num: u8, protocol: u8) -> Result<(), UsbError> {
+    /// Select an alternate setting of an interface.
+    /// Endpoints of the interface restart from DATA0, drivers must reset their toggles.
+    pub fn set_interface(
+        &mut self, host: &mut dyn UsbHost, iface_num: InterfaceNum, alt_setting: AltSetting,
+    ) -> Result<(), UsbError> {
+        self.control_set(
+            host,
+            RequestCode::SetInterface as u8,
+            RequestRecipient::Interface,
+            alt_setting,
+            0,
+            iface_num as u16,
+        )
+    }
+
+    /// Alternate setting currently selected for an interface
+    pub fn get_interface(&mut self, host: &mut dyn UsbHost, iface_num: InterfaceNum) -> Result<AltSetting, UsbError> {
+        let mut alt_setting = 0u8;
         self.control(
             host,
-            RequestType::from((RequestDirection::HostToDevice, RequestKind::Class, RequestRecipient::Interface)),
-            RequestCode::SetInterface,
-            WValue::lo_hi(protocol, 0),
-            u16::from(iface_num),
-            None,
+            RequestType::from((
+                RequestDirection::DeviceToHost,
+                RequestKind::Standard,
+                RequestRecipient::Interface,
+            )),
+            RequestCode::GetInterface as u8,
+            WValue::default(),
+            iface_num as u16,
+            Some(to_slice_mut(&mut alt_setting)),
         )?;
-        Ok(())
+        Ok(alt_setting)
+    }
+
+    /// HID report descriptor of an interface, its length is in the HID descriptor
+    pub fn get_report_descriptor(
+        &mut self, host: &mut dyn UsbHost, iface_num: InterfaceNum, buffer: &mut [u8],
+    ) -> Result<usize, UsbError> {
+        let request = RequestType::from((
+            RequestDirection::DeviceToHost,
+            RequestKind::Standard,
+            RequestRecipient::Interface,
+        ));
+        self.control(
+            host,
+            request,
+            RequestCode::GetDescriptor as u8,
+            WValue::lo_hi(0, DescriptorType::HidReport as u8),
+            iface_num as u16,
+            Some(buffer),
+        )
+    }
+}
+
+/// Body of a string descriptor, without its length and type header
+fn string_body(desc: &[u8]) -> Result<&[u8], UsbError> {
+    if desc.len() < 2 || desc[1] != DescriptorType::String as u8 {
+        return Err(UsbError::InvalidDescriptor);
     }
+    // a buffer too short for the whole string truncates it
+    let len = (desc[0] as usize).min(desc.len()) & !1;
+    Ok(&desc[2..len.max(2)])
 }
 
 impl HostEndpoint for Device {}
@@ -169,7 +454,7 @@ pub trait ControlEndpoint: HostEndpoint + Sized {
     /// Generic control transfer method.
     /// Add transfer context to host errors.
     fn control(
-        &mut self, host: &mut dyn UsbHost, request: RequestType, code: RequestCode, w_value: WValue, w_index: u16,
+        &mut self, host: &mut dyn UsbHost, request: RequestType, code: u8, w_value: WValue, w_index: u16,
         buffer: Option<&mut [u8]>,
     ) -> Result<usize, UsbError> {
         let len = host
@@ -178,6 +463,16 @@ pub trait ControlEndpoint: HostEndpoint + Sized {
         Ok(len)
     }
 
+    /// Control transfer sending `data` to the device
+    fn control_out(
+        &mut self, host: &mut dyn UsbHost, request: RequestType, code: u8, w_value: WValue, w_index: u16, data: &[u8],
+    ) -> Result<usize, UsbError> {
+        let len = host
+            .control_out(self as &mut dyn HostEndpoint, request, code, w_value, w_index, data)
+            .map_err(|err| UsbError::Control(self.device_address(), request, code, err))?;
+        Ok(len)
+    }
+
     /// Retrieve descriptor(s)
     fn control_get_descriptor(
         &mut self, host: &mut dyn UsbHost, desc_type: DescriptorType, desc_index: u8, buffer: &mut [u8],
@@ -187,17 +482,32 @@ pub trait ControlEndpoint: HostEndpoint + Sized {
         self.control(
             host,
             request,
-            RequestCode::GetDescriptor,
+            RequestCode::GetDescriptor as u8,
             WValue::lo_hi(desc_index, desc_type as u8),
             0,
             Some(buffer),
         )
     }
 
+    /// Retrieve a string descriptor in language `lang_id`
+    fn control_get_string(
+        &mut self, host: &mut dyn UsbHost, index: u8, lang_id: u16, buffer: &mut [u8],
+    ) -> Result<usize, UsbError> {
+        let request =
+            RequestType::from((RequestDirection::DeviceToHost, RequestKind::Standard, RequestRecipient::Device));
+        self.control(
+            host,
+            request,
+            RequestCode::GetDescriptor as u8,
+            WValue::lo_hi(index, DescriptorType::String as u8),
+            lang_id,
+            Some(buffer),
+        )
+    }
+
     /// Generic control write
     fn control_set(
-        &mut self, host: &mut dyn UsbHost, code: RequestCode, recip: RequestRecipient, lo_val: u8, hi_val: u8,
-        windex: u16,
+        &mut self, host: &mut dyn UsbHost, code: u8, recip: RequestRecipient, lo_val: u8, hi_val: u8, windex: u16,
     ) -> Result<(), UsbError> {
         let request = RequestType::from((RequestDirection::HostToDevice, RequestKind::Standard, recip));
         self.control(host, request, code, WValue::lo_hi(lo_val, hi_val), windex, None)?;
@@ -206,13 +516,155 @@ pub trait ControlEndpoint: HostEndpoint + Sized {
 
     /// Generic control write
     fn control_set_class(
-        &mut self, host: &mut dyn UsbHost, code: RequestCode, recip: RequestRecipient, lo_val: u8, hi_val: u8,
-        windex: u16,
+        &mut self, host: &mut dyn UsbHost, code: u8, recip: RequestRecipient, lo_val: u8, hi_val: u8, windex: u16,
     ) -> Result<(), UsbError> {
         let request = RequestType::from((RequestDirection::HostToDevice, RequestKind::Class, recip));
         self.control(host, request, code, WValue::lo_hi(lo_val, hi_val), windex, None)?;
         Ok(())
     }
+
+    /// Status of the device, an interface or an endpoint (with direction bit) depending on `recip`
+    fn get_status(&mut self, host: &mut dyn UsbHost, recip: RequestRecipient, index: u16) -> Result<u16, UsbError> {
+        let request = RequestType::from((RequestDirection::DeviceToHost, RequestKind::Standard, recip));
+        let mut status = [0u8; 2];
+        let len = self.control(
+            host,
+            request,
+            RequestCode::GetStatus as u8,
+            WValue::default(),
+            index,
+            Some(&mut status),
+        )?;
+        if len != status.len() {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        Ok(u16::from_le_bytes(status))
+    }
+
+    fn set_feature(
+        &mut self, host: &mut dyn UsbHost, recip: RequestRecipient, feature: FeatureSelector, index: u16,
+    ) -> Result<(), UsbError> {
+        self.control_set(host, RequestCode::SetFeature as u8, recip, feature as u8, 0, index)
+    }
+
+    fn clear_feature(
+        &mut self, host: &mut dyn UsbHost, recip: RequestRecipient, feature: FeatureSelector, index: u16,
+    ) -> Result<(), UsbError> {
+        self.control_set(host, RequestCode::ClearFeature as u8, recip, feature as u8, 0, index)
+    }
+
+    /// True if the device reports `ep` as halted
+    fn is_halted(&mut self, host: &mut dyn UsbHost, ep: EpAddress) -> Result<bool, UsbError> {
+        let status = self.get_status(host, RequestRecipient::Endpoint, u8::from(ep) as u16)?;
+        Ok(status & STATUS_ENDPOINT_HALT != 0)
+    }
+
+    /// Resume a halted endpoint, its data toggle starts over from DATA0
+    fn clear_halt(&mut self, host: &mut dyn UsbHost, ep: &mut dyn HostEndpoint) -> Result<(), UsbError> {
+        let index = u8::from(ep.endpoint_address()) as u16;
+        self.clear_feature(host, RequestRecipient::Endpoint, FeatureSelector::EndpointHalt, index)?;
+        ep.set_toggle(false);
+        Ok(())
+    }
+
+    /// HID GET_REPORT, report `report_id` (0 without report IDs) read into `buffer`
+    fn get_report(
+        &mut self, host: &mut dyn UsbHost, iface_num: InterfaceNum, kind: ReportKind, report_id: u8, buffer: &mut [u8],
+    ) -> Result<usize, UsbError> {
+        let request =
+            RequestType::from((RequestDirection::DeviceToHost, RequestKind::Class, RequestRecipient::Interface));
+        self.control(
+            host,
+            request,
+            HidRequest::GetReport as u8,
+            WValue::lo_hi(report_id, kind as u8),
+            iface_num as u16,
+            Some(buffer),
+        )
+    }
+
+    /// HID SET_REPORT, `data` starts with the report ID if the device uses them
+    fn set_report(
+        &mut self, host: &mut dyn UsbHost, iface_num: InterfaceNum, kind: ReportKind, report_id: u8, data: &[u8],
+    ) -> Result<(), UsbError> {
+        let request =
+            RequestType::from((RequestDirection::HostToDevice, RequestKind::Class, RequestRecipient::Interface));
+        self.control_out(
+            host,
+            request,
+            HidRequest::SetReport as u8,
+            WValue::lo_hi(report_id, kind as u8),
+            iface_num as u16,
+            data,
+        )?;
+        Ok(())
+    }
+
+    /// HID GET_IDLE, idle rate of a report in 4ms units, 0 if only sent on change
+    fn get_idle(&mut self, host: &mut dyn UsbHost, iface_num: InterfaceNum, report_id: u8) -> Result<u8, UsbError> {
+        let request =
+            RequestType::from((RequestDirection::DeviceToHost, RequestKind::Class, RequestRecipient::Interface));
+        let mut duration = 0u8;
+        let len = self.control(
+            host,
+            request,
+            HidRequest::GetIdle as u8,
+            WValue::lo_hi(report_id, 0),
+            iface_num as u16,
+            Some(to_slice_mut(&mut duration)),
+        )?;
+        if len != 1 {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        Ok(duration)
+    }
+
+    /// HID SET_IDLE, reports are only sent on change (`duration` 0) or every `duration` * 4ms
+    fn set_idle(
+        &mut self, host: &mut dyn UsbHost, iface_num: InterfaceNum, duration: u8, report_id: u8,
+    ) -> Result<(), UsbError> {
+        self.control_set_class(
+            host,
+            HidRequest::SetIdle as u8,
+            RequestRecipient::Interface,
+            report_id,
+            duration,
+            iface_num as u16,
+        )
+    }
+
+    /// HID GET_PROTOCOL, only boot interfaces support it
+    fn get_protocol(&mut self, host: &mut dyn UsbHost, iface_num: InterfaceNum) -> Result<HidProtocol, UsbError> {
+        let request =
+            RequestType::from((RequestDirection::DeviceToHost, RequestKind::Class, RequestRecipient::Interface));
+        let mut protocol = 0u8;
+        let len = self.control(
+            host,
+            request,
+            HidRequest::GetProtocol as u8,
+            WValue::default(),
+            iface_num as u16,
+            Some(to_slice_mut(&mut protocol)),
+        )?;
+        if len != 1 {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        HidProtocol::from_repr(protocol).ok_or(UsbError::InvalidDescriptor)
+    }
+
+    /// HID SET_PROTOCOL, only boot interfaces support it
+    fn set_protocol(
+        &mut self, host: &mut dyn UsbHost, iface_num: InterfaceNum, protocol: HidProtocol,
+    ) -> Result<(), UsbError> {
+        self.control_set_class(
+            host,
+            HidRequest::SetProtocol as u8,
+            RequestRecipient::Interface,
+            protocol as u8,
+            0,
+            iface_num as u16,
+        )
+    }
 }
 
 impl ControlEndpoint for Device {}
@@ -225,6 +677,12 @@ pub trait Driver {
         &self, device: &mut Device, conf: &mut DescriptorParser,
     ) -> Option<(DeviceClass, ConfigNum, InterfaceNum)>;
 
+    /// How well this driver supports a configuration, None if not at all.
+    /// The stack binds the best scoring driver and configuration, earlier ones win ties.
+    fn score(&self, device: &mut Device, conf: &mut DescriptorParser) -> Option<u8> {
+        self.accept(device, conf).map(|_| 1)
+    }
+
     fn register(&mut self, device: &mut Device, conf: &mut DescriptorParser) -> Result<(), UsbError>;
 
     fn unregister(&mut self, device: DevAddress);
@@ -234,5 +692,168 @@ pub trait Driver {
         DeviceState::Running
     }
 
+    /// Milliseconds between two `run` calls once the device is running,
+    /// usually the interval of the interrupt endpoint the driver polls.
+    /// None to run on every stack update.
+    fn poll_interval(&self, _device: &Device) -> Option<u64> {
+        None
+    }
+
     fn run(&mut self, host: &mut dyn UsbHost, device: &mut Device) -> Result<(), UsbError>;
+
+    /// Hub drivers report downstream port changes here, one at a time.
+    /// While `enumerating` is true another device is using the default address:
+    /// ports must not be reset until the stack says otherwise.
+    fn port_change(&mut self, _host: &mut dyn UsbHost, _hub: &mut Device, _enumerating: bool) -> Option<PortChange> {
+        None
+    }
+
+    /// Hub drivers reset a downstream port when asked, its device is then reported attached again.
+    /// False if the port cannot be reset.
+    fn reset_port(&mut self, _host: &mut dyn UsbHost, _hub: &mut Device, _port: PortNum) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::{SimDevice, SimHost};
+    use crate::{HostError, HostEvent};
+
+    const DEV_DESC: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x08, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x01, 0x02, 0x00, 0x01,
+    ];
+
+    const LANG_IDS: [u8; 6] = [0x06, 0x03, 0x09, 0x04, 0x07, 0x04];
+    const MANUFACTURER: [u8; 8] = [0x08, 0x03, b'A', 0, b'c', 0, b'm', 0];
+    const PRODUCT: [u8; 12] = [0x0c, 0x03, b'S', 0, b'y', 0, b'n', 0, b't', 0, b'h', 0];
+
+    fn connected(device: SimDevice) -> (SimHost, Device) {
+        let mut host = SimHost::new();
+        host.attach(device);
+        while host.update() != Some(HostEvent::Ready) {}
+        let mut dev = Device::new(Speed::Low);
+        dev.get_device_descriptor(&mut host).unwrap();
+        (host, dev)
+    }
+
+    fn sim_device() -> SimDevice {
+        SimDevice::new(&DEV_DESC)
+            .with_string(0, &LANG_IDS)
+            .with_string(1, &MANUFACTURER)
+            .with_string(2, &PRODUCT)
+    }
+
+    const STREAMING_CONF: [u8; 41] = [
+        0x09, 0x02, 0x29, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+        0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, // audio streaming, zero bandwidth
+        0x09, 0x04, 0x00, 0x01, 0x01, 0x01, 0x02, 0x00, 0x00, // operational setting
+        0x09, 0x05, 0x01, 0x05, 0xc0, 0x00, 0x01, 0x00, 0x00, // isochronous OUT
+        0x05, 0x25, 0x01, 0x00, 0x00, // class specific endpoint
+    ];
+
+    #[test]
+    fn alternate_settings() {
+        let (mut host, mut dev) = connected(SimDevice::new(&DEV_DESC).with_configuration(&STREAMING_CONF));
+        dev.set_configuration(&mut host, 1).unwrap();
+        assert_eq!(dev.get_interface(&mut host, 0), Ok(0));
+        dev.set_interface(&mut host, 0, 1).unwrap();
+        assert_eq!(host.device().unwrap().alt_setting(0), 1);
+        assert_eq!(dev.get_interface(&mut host, 0), Ok(1));
+        assert!(matches!(
+            dev.set_interface(&mut host, 0, 2),
+            Err(UsbError::Control(_, _, code, _)) if code == RequestCode::SetInterface as u8
+        ));
+        dev.set_interface(&mut host, 0, 0).unwrap();
+        assert_eq!(dev.get_interface(&mut host, 0), Ok(0));
+    }
+
+    #[test]
+    fn languages() {
+        let (mut host, mut dev) = connected(sim_device());
+        let mut lang_ids = [0u16; 4];
+        assert_eq!(dev.get_languages(&mut host, &mut lang_ids), Ok(2));
+        assert_eq!(lang_ids[..2], [0x0409, 0x0407]);
+        let mut one = [0u16; 1];
+        assert_eq!(dev.get_languages(&mut host, &mut one), Ok(1));
+    }
+
+    #[test]
+    fn device_strings() {
+        let (mut host, mut dev) = connected(sim_device());
+        let mut buf = [0u8; 64];
+        assert!(dev.manufacturer(&mut host, &mut buf).unwrap().chars().eq("Acm".chars()));
+        assert!(dev.product(&mut host, &mut buf).unwrap().chars().eq("Synth".chars()));
+        assert!(matches!(dev.serial_number(&mut host, &mut buf), Err(UsbError::NoString)));
+
+        // language table only read once
+        let lang_table = WValue::lo_hi(0, DescriptorType::String as u8);
+        let lang_reads = host
+            .device()
+            .unwrap()
+            .requests()
+            .filter(|req| req.w_value == lang_table)
+            .count();
+        dev.product(&mut host, &mut buf).unwrap();
+        let last = host.device().unwrap().requests().last().unwrap();
+        assert_eq!(last.w_index, 0x0409);
+        assert_eq!(
+            host.device()
+                .unwrap()
+                .requests()
+                .filter(|req| req.w_value == lang_table)
+                .count(),
+            lang_reads
+        );
+    }
+
+    #[test]
+    fn truncated_string() {
+        let (mut host, mut dev) = connected(sim_device());
+        let mut buf = [0u8; 7];
+        assert!(dev.get_string(&mut host, 2, &mut buf).unwrap().chars().eq("Sy".chars()));
+    }
+
+    #[test]
+    fn missing_string() {
+        let (mut host, mut dev) = connected(sim_device());
+        let mut buf = [0u8; 64];
+        assert!(matches!(
+            dev.get_string(&mut host, 5, &mut buf),
+            Err(UsbError::Control(_, _, code, _)) if code == RequestCode::GetDescriptor as u8
+        ));
+    }
+
+    #[test]
+    fn hid_class_requests() {
+        let device = sim_device()
+            .with_class_reply(HidRequest::GetIdle as u8, &[0x7d])
+            .with_class_reply(HidRequest::GetProtocol as u8, &[HidProtocol::Report as u8])
+            .with_class_reply(HidRequest::GetReport as u8, &[2, 0x10, 0x20]);
+        let (mut host, mut dev) = connected(device);
+        assert_eq!(dev.get_idle(&mut host, 0, 0), Ok(0x7d));
+        assert_eq!(dev.get_protocol(&mut host, 0), Ok(HidProtocol::Report));
+        let mut report = [0u8; 8];
+        assert_eq!(dev.get_report(&mut host, 0, ReportKind::Feature, 2, &mut report), Ok(3));
+        assert_eq!(report[..3], [2, 0x10, 0x20]);
+        let last = host.device().unwrap().requests().last().copied().unwrap();
+        assert_eq!(last.w_value, WValue::lo_hi(2, ReportKind::Feature as u8));
+
+        dev.set_report(&mut host, 1, ReportKind::Output, 0, &[0x02]).unwrap();
+        let last = host.device().unwrap().requests().last().copied().unwrap();
+        assert_eq!(last.bm_request_type.kind(), Some(RequestKind::Class));
+        assert_eq!((last.b_request, last.w_index, last.w_length), (0x09, 1, 1));
+        assert_eq!(host.device_mut().unwrap().take_control_data().unwrap(), [0x02]);
+        // errors carry the HID request code
+        host.device_mut().unwrap().fail_control(HostError::Stall);
+        assert!(matches!(
+            dev.set_report(&mut host, 1, ReportKind::Output, 0, &[0x02]),
+            Err(UsbError::Control(_, _, 0x09, HostError::Stall))
+        ));
+        // replies of the wrong length are rejected
+        let device = sim_device().with_class_reply(HidRequest::GetProtocol as u8, &[]);
+        let (mut host, mut dev) = connected(device);
+        assert_eq!(dev.get_protocol(&mut host, 0), Err(UsbError::InvalidDescriptor));
+    }
 }
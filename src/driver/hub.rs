@@ -0,0 +1,370 @@
+//! USB hub class driver.
+//! Powers downstream ports, debounces connections, resets ports and reports
+//! attached / detached devices to the stack, which enumerates them.
+//! Hubs can be chained, each tier gets its own driver entry.
+
+use core::cmp::min;
+
+use heapless::FnvIndexMap;
+
+use crate::class::hub::{
+    HubDescriptor, PortFeature, PortStatus, C_PORT_CONNECTION, C_PORT_ENABLE, C_PORT_OVER_CURRENT, C_PORT_RESET,
+    C_PORT_SUSPEND, HUB_DESCRIPTOR_TYPE, PORT_CONNECTION, PORT_ENABLE, PORT_HIGH_SPEED, PORT_LOW_SPEED,
+    PORT_OVER_CURRENT,
+};
+use crate::{
+    to_slice_mut, ConfigNum, ControlEndpoint, DescriptorParser, DescriptorRef, DevAddress, Device, DeviceClass,
+    Direction, Driver, Endpoint, EndpointProperties, HostError, InterfaceNum, InterruptEndpoint, MaxPacketSize,
+    PortChange, PortNum, RequestCode, RequestDirection, RequestKind, RequestRecipient, RequestType, Speed,
+    TransferType, UsbError, UsbHost, WValue,
+};
+
+// Ports beyond this are left unpowered
+const MAX_PORTS: usize = 7;
+
+// Connection must be stable this long before port reset. cf §7.1.7.3 of USB 2.0
+const DEBOUNCE_DELAY: u64 = 100;
+
+// Give up on port reset after this long
+const RESET_TIMEOUT: u64 = 500;
+
+// Delay after port reset before talking to the device. cf §7.1.7.5 of USB 2.0
+const RESET_RECOVERY: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum HubState {
+    /// Hub descriptor not read yet, ports not powered
+    Init,
+    /// Waiting for port power to be good
+    PowerOn(u64),
+    /// Polling status change endpoint
+    Running,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum PortState {
+    /// Nothing connected
+    Empty,
+    /// Device connected, waiting for connection to be stable
+    Debounce(u64),
+    /// Port reset requested, waiting for completion until timeout
+    Reset(u64),
+    /// Port enabled, device gets some time to recover from reset
+    Recovery(u64, Speed),
+    /// Device handed over to the stack
+    Attached,
+}
+
+struct Hub {
+    state: HubState,
+    endpoint: Endpoint,
+    num_ports: u8,
+    /// Ports with pending status changes, bit N for port N
+    changed: u16,
+    ports: [PortState; MAX_PORTS],
+}
+
+/// Hub driver for USB hosts.
+/// Supports up to `HUBS` hubs, a power of two from 2.
+pub struct HubDriver<const HUBS: usize = 4> {
+    hubs: FnvIndexMap<DevAddress, Hub, HUBS>,
+}
+
+impl<const HUBS: usize> HubDriver<HUBS> {
+    pub fn new() -> Self {
+        Self {
+            hubs: FnvIndexMap::new(),
+        }
+    }
+}
+
+impl<const HUBS: usize> Default for HubDriver<HUBS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn port_status(host: &mut dyn UsbHost, hub: &mut Device, port: PortNum) -> Result<PortStatus, UsbError> {
+    let mut status = PortStatus::default();
+    hub.control(
+        host,
+        RequestType::from((RequestDirection::DeviceToHost, RequestKind::Class, RequestRecipient::Other)),
+        RequestCode::GetStatus as u8,
+        WValue::default(),
+        port as u16,
+        Some(to_slice_mut(&mut status)),
+    )?;
+    Ok(status)
+}
+
+fn set_port_feature(
+    host: &mut dyn UsbHost, hub: &mut Device, port: PortNum, feature: PortFeature,
+) -> Result<(), UsbError> {
+    hub.control_set_class(
+        host,
+        RequestCode::SetFeature as u8,
+        RequestRecipient::Other,
+        feature as u8,
+        0,
+        port as u16,
+    )
+}
+
+fn clear_port_feature(
+    host: &mut dyn UsbHost, hub: &mut Device, port: PortNum, feature: PortFeature,
+) -> Result<(), UsbError> {
+    hub.control_set_class(
+        host,
+        RequestCode::ClearFeature as u8,
+        RequestRecipient::Other,
+        feature as u8,
+        0,
+        port as u16,
+    )
+}
+
+fn port_speed(status: &PortStatus) -> Speed {
+    if status.w_port_status & PORT_LOW_SPEED != 0 {
+        Speed::Low
+    } else if status.w_port_status & PORT_HIGH_SPEED != 0 {
+        Speed::High
+    } else {
+        Speed::Full
+    }
+}
+
+impl Hub {
+    /// Acknowledge port status changes, track connection and disconnection
+    fn status_change(
+        &mut self, host: &mut dyn UsbHost, device: &mut Device, port: PortNum,
+    ) -> Result<Option<PortChange>, UsbError> {
+        let status = port_status(host, device, port)?;
+        let change = status.w_port_change;
+        for (bit, feature) in [
+            (C_PORT_CONNECTION, PortFeature::CConnection),
+            (C_PORT_ENABLE, PortFeature::CEnable),
+            (C_PORT_SUSPEND, PortFeature::CSuspend),
+            (C_PORT_OVER_CURRENT, PortFeature::COverCurrent),
+        ] {
+            if change & bit != 0 {
+                clear_port_feature(host, device, port, feature)?;
+            }
+        }
+        if status.w_port_status & PORT_OVER_CURRENT != 0 {
+            warn!("USB Hub @{:?} port {} over-current", device.device_address(), port);
+        }
+
+        let idx = port as usize - 1;
+        let prev = self.ports[idx];
+        let connected = status.w_port_status & PORT_CONNECTION != 0;
+        let enabled = status.w_port_status & PORT_ENABLE != 0;
+
+        if change & C_PORT_CONNECTION != 0 {
+            // (re)start debounce on any connection change
+            self.ports[idx] =
+                if connected { PortState::Debounce(host.after_millis(DEBOUNCE_DELAY)) } else { PortState::Empty };
+        } else if change & C_PORT_ENABLE != 0 && !enabled && prev == PortState::Attached {
+            warn!("USB Hub @{:?} port {} disabled", device.device_address(), port);
+            self.ports[idx] = PortState::Empty;
+        } else {
+            return Ok(None);
+        }
+
+        match prev {
+            PortState::Reset(_) | PortState::Recovery(..) | PortState::Attached => Ok(Some(PortChange::Detached(port))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Advance port reset & enumeration sequence
+    fn port_progress(
+        &mut self, host: &mut dyn UsbHost, device: &mut Device, port: PortNum, enumerating: bool,
+    ) -> Result<Option<PortChange>, UsbError> {
+        let idx = port as usize - 1;
+        match self.ports[idx] {
+            PortState::Debounce(until) if !enumerating && host.delay_done(until) => {
+                set_port_feature(host, device, port, PortFeature::Reset)?;
+                self.ports[idx] = PortState::Reset(host.after_millis(RESET_TIMEOUT));
+                Ok(Some(PortChange::Reset(port)))
+            }
+            PortState::Reset(timeout) => {
+                let status = port_status(host, device, port)?;
+                if status.w_port_change & C_PORT_RESET != 0 {
+                    clear_port_feature(host, device, port, PortFeature::CReset)?;
+                    if status.w_port_status & PORT_ENABLE != 0 {
+                        let speed = port_speed(&status);
+                        self.ports[idx] = PortState::Recovery(host.after_millis(RESET_RECOVERY), speed);
+                        return Ok(None);
+                    }
+                } else if !host.delay_done(timeout) {
+                    return Ok(None);
+                }
+                warn!("USB Hub @{:?} port {} reset failed", device.device_address(), port);
+                self.ports[idx] = PortState::Empty;
+                Ok(Some(PortChange::Detached(port)))
+            }
+            PortState::Recovery(until, speed) if host.delay_done(until) => {
+                self.ports[idx] = PortState::Attached;
+                Ok(Some(PortChange::Attached(port, speed)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl<const HUBS: usize> Driver for HubDriver<HUBS> {
+    fn name(&self) -> &str {
+        "Hub"
+    }
+
+    fn accept(
+        &self, _device: &mut Device, parser: &mut DescriptorParser,
+    ) -> Option<(DeviceClass, ConfigNum, InterfaceNum)> {
+        let mut config_num = None;
+        for desc in parser {
+            match desc {
+                Ok(DescriptorRef::Configuration(cdesc)) => config_num = Some(cdesc.b_configuration_value),
+                Ok(DescriptorRef::Interface(idesc)) if idesc.b_interface_class == DeviceClass::Hub as u8 => {
+                    return Some((DeviceClass::Hub, config_num?, idesc.b_interface_number));
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn register(&mut self, device: &mut Device, parser: &mut DescriptorParser) -> Result<(), UsbError> {
+        for desc in parser {
+            if let Ok(DescriptorRef::Endpoint(edesc)) = desc {
+                let mut endpoint = Endpoint::from_raw(
+                    device.device_address(),
+                    edesc.max_packet_size(),
+                    edesc.b_endpoint_address,
+                    edesc.bm_attributes,
+                );
+                endpoint.set_interval(edesc.b_interval);
+                if endpoint.transfer_type() == TransferType::Interrupt && endpoint.direction() == Direction::In {
+                    let hub = Hub {
+                        state: HubState::Init,
+                        endpoint,
+                        num_ports: 0,
+                        changed: 0,
+                        ports: [PortState::Empty; MAX_PORTS],
+                    };
+                    return self
+                        .hubs
+                        .insert(device.device_address(), hub)
+                        .map(|_| ())
+                        .or(Err(UsbError::TooManyDevices));
+                }
+            }
+        }
+        Err(UsbError::InvalidDescriptor)
+    }
+
+    fn unregister(&mut self, address: DevAddress) {
+        let _ = self.hubs.remove(&address);
+    }
+
+    /// Setup steps run on every update, the status change endpoint is then polled at its interval
+    fn poll_interval(&self, device: &Device) -> Option<u64> {
+        let hub = self.hubs.get(&device.device_address())?;
+        (hub.state == HubState::Running).then(|| device.speed().interval_ms(hub.endpoint.b_interval()))
+    }
+
+    fn run(&mut self, host: &mut dyn UsbHost, device: &mut Device) -> Result<(), UsbError> {
+        let hub = match self.hubs.get_mut(&device.device_address()) {
+            Some(hub) => hub,
+            None => return Ok(()),
+        };
+        match hub.state {
+            HubState::Init => {
+                let mut desc = HubDescriptor::default();
+                device.control(
+                    host,
+                    RequestType::from((RequestDirection::DeviceToHost, RequestKind::Class, RequestRecipient::Device)),
+                    RequestCode::GetDescriptor as u8,
+                    WValue::lo_hi(0, HUB_DESCRIPTOR_TYPE),
+                    0,
+                    Some(to_slice_mut(&mut desc)),
+                )?;
+                if desc.b_nbr_ports as usize > MAX_PORTS {
+                    warn!(
+                        "USB Hub @{:?} has too many ports: {}",
+                        device.device_address(),
+                        desc.b_nbr_ports
+                    );
+                }
+                hub.num_ports = min(desc.b_nbr_ports, MAX_PORTS as u8);
+                for port in 1..=hub.num_ports {
+                    set_port_feature(host, device, port, PortFeature::Power)?;
+                }
+                hub.state = HubState::PowerOn(host.after_millis(desc.b_pwr_on_2_pwr_good as u64 * 2));
+            }
+            HubState::PowerOn(until) => {
+                if host.delay_done(until) {
+                    // devices may have been connected before power on
+                    hub.changed = ((1 << hub.num_ports) - 1) << 1;
+                    hub.state = HubState::Running;
+                    info!("USB Hub @{:?} running with {} ports", device.device_address(), hub.num_ports);
+                }
+            }
+            HubState::Running => {
+                // bit 0 is the hub itself, bits 1..N the ports
+                let len = (hub.num_ports as usize + 8) / 8;
+                let mut bitmap = [0u8; 2];
+                match hub.endpoint.interrupt_in(host, &mut bitmap[..len]) {
+                    Ok(_) => hub.changed |= u16::from_le_bytes(bitmap) & !1,
+                    Err(UsbError::Interrupt(_, HostError::Nak)) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn reset_port(&mut self, host: &mut dyn UsbHost, device: &mut Device, port: PortNum) -> bool {
+        let hub = match self.hubs.get_mut(&device.device_address()) {
+            Some(hub) if port >= 1 && port <= hub.num_ports => hub,
+            _ => return false,
+        };
+        if let Err(err) = set_port_feature(host, device, port, PortFeature::Reset) {
+            warn!("USB Hub @{:?} port {} reset failed: {:?}", device.device_address(), port, err);
+            return false;
+        }
+        hub.ports[port as usize - 1] = PortState::Reset(host.after_millis(RESET_TIMEOUT));
+        true
+    }
+
+    fn port_change(&mut self, host: &mut dyn UsbHost, device: &mut Device, enumerating: bool) -> Option<PortChange> {
+        let hub = self.hubs.get_mut(&device.device_address())?;
+        if hub.state != HubState::Running {
+            return None;
+        }
+        for port in 1..=hub.num_ports {
+            if hub.changed & (1 << port) != 0 {
+                hub.changed &= !(1 << port);
+                match hub.status_change(host, device, port) {
+                    Ok(Some(change)) => return Some(change),
+                    Ok(None) => {}
+                    Err(err) => warn!("USB Hub @{:?} port {} status failed: {:?}", device.device_address(), port, err),
+                }
+            }
+            match hub.port_progress(host, device, port, enumerating) {
+                Ok(Some(change)) => return Some(change),
+                Ok(None) => {}
+                Err(err) => {
+                    warn!("USB Hub @{:?} port {} failed: {:?}", device.device_address(), port, err);
+                    let reported = matches!(hub.ports[port as usize - 1], PortState::Reset(_));
+                    hub.ports[port as usize - 1] = PortState::Empty;
+                    if reported {
+                        return Some(PortChange::Detached(port));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
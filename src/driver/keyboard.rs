@@ -1,25 +1,147 @@
-//! Simple USB host-side driver for USB keyboards supporting "boot" protocol (i.e. legacy PC BIOS compatible).
+//! Simple USB host-side driver for USB keyboards.
+//! Keys are read in report protocol when the report descriptor tells where they are,
+//! which handles N-key rollover keyboards and reports with IDs.
+//! Keyboards with a report descriptor that can't be read or parsed fall back to the "boot" protocol
+//! (i.e. legacy PC BIOS compatible).
+//! Num, Caps and Scroll Lock are toggled by their keys and shown on the keyboard LEDs.
+//! Key events are turned into text by a `keymap::Keymap`.
 //! Tested working with classic Dell SK-8115
-//! Does not handle newer keyboards such as HP KU-1156
+
+use core::cmp::min;
+use core::slice;
 
 use crate::{
-    to_slice_mut, ConfigNum, DescriptorParser, DescriptorRef, DevAddress, Device, DeviceState, Driver, Endpoint,
-    EndpointProperties, InterfaceNum, InterruptEndpoint, MaxPacketSize, UsbError, UsbHost,
+    ConfigNum, ControlEndpoint, DescriptorParser, DescriptorRef, DevAddress, Device, DeviceState, Direction, Driver,
+    Endpoint, EndpointProperties, InterfaceDescriptor, InterfaceNum, InterruptEndpoint, MaxPacketSize, TransferType,
+    UsbError, UsbHost,
 };
 
 use crate::class::DeviceClass;
-use crate::hid::{HidDevice, HidProtocol, HidSubclass};
-use heapless::FnvIndexMap;
+use crate::hid::{HidDevice, HidProtocol, HidSubclass, ReportDescriptor, ReportField, ReportKind, Usage, UsagePage};
+use heapless::{Deque, FnvIndexMap, Vec};
+
+/// Most keys held down at once that are kept track of, boot protocol reports up to 6
+pub const MAX_KEYS: usize = 16;
+
+/// Largest report descriptor read, keyboards with bigger ones use the boot protocol
+const MAX_REPORT_DESC_LEN: usize = 256;
+
+/// Largest input or output report
+const MAX_REPORT_LEN: usize = 64;
+
+/// Key events waiting to be polled, older ones are dropped
+const MAX_KEY_EVENTS: usize = 16;
+
+/// Keyboard page fields of the keyboard input report: modifiers, key arrays and bitmaps
+const MAX_KEY_FIELDS: usize = 4;
+
+/// Num Lock, lit in bit 0 of the boot protocol output report
+pub const LED_NUM_LOCK: u8 = 0x01;
+/// Caps Lock, lit in bit 1 of the boot protocol output report
+pub const LED_CAPS_LOCK: u8 = 0x02;
+/// Scroll Lock, lit in bit 2 of the boot protocol output report
+pub const LED_SCROLL_LOCK: u8 = 0x04;
+
+/// Lock LEDs, LED page usages 1 to 3 in the order of the boot protocol bits
+const LOCK_LEDS: usize = 3;
+
+/// Keyboard page usage of Caps Lock
+const USAGE_CAPS_LOCK: u8 = 0x39;
+/// Keyboard page usage of Scroll Lock
+const USAGE_SCROLL_LOCK: u8 = 0x47;
+/// Keyboard page usage of Num Lock
+const USAGE_NUM_LOCK: u8 = 0x53;
 
-// How many total devices this driver can support.
-const MAX_DEVICES: usize = 2;
+/// Keyboard page usage of left control, the first of the 8 modifiers
+const USAGE_LEFT_CONTROL: u16 = 0xe0;
+/// Keyboard page usage of right GUI, the last of the 8 modifiers
+const USAGE_RIGHT_GUI: u16 = 0xe7;
+/// Keyboard page usage in every key slot when too many keys are held down
+const USAGE_ERROR_ROLL_OVER: u16 = 0x01;
+/// Keyboard page usage of the first actual key, 'a'
+const USAGE_FIRST_KEY: u16 = 0x04;
 
-/// Boot protocol keyboard driver for USB hosts.
-pub struct BootKbdDriver {
-    device_endpoints: FnvIndexMap<DevAddress, Endpoint, MAX_DEVICES>,
+/// Boot protocol keyboard driver for USB hosts, using report protocol when possible.
+/// Supports up to `DEVICES` keyboards, a power of two from 2.
+pub struct BootKbdDriver<const DEVICES: usize = 2> {
+    devices: FnvIndexMap<DevAddress, KbdDevice, DEVICES>,
+    events: KeyEvents,
 }
 
-impl Driver for BootKbdDriver {
+/// Keys going down or up on a keyboard, for the application to poll or receive through a handler
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KeyEvent {
+    /// Key with this keyboard page usage went down
+    Pressed {
+        address: DevAddress,
+        usage: u8,
+    },
+    Released {
+        address: DevAddress,
+        usage: u8,
+    },
+    /// Modifiers went up or down, left control to right GUI in bits 0 to 7
+    Modifiers {
+        address: DevAddress,
+        modifiers: u8,
+    },
+    /// A lock key toggled its lock, `LED_NUM_LOCK`, `LED_CAPS_LOCK` and `LED_SCROLL_LOCK` bits
+    Locks {
+        address: DevAddress,
+        locks: u8,
+    },
+}
+
+/// Key events go to the handler if set, or are queued
+struct KeyEvents {
+    queue: Deque<KeyEvent, MAX_KEY_EVENTS>,
+    handler: Option<fn(KeyEvent)>,
+}
+
+impl KeyEvents {
+    fn emit(&mut self, event: KeyEvent) {
+        if let Some(handler) = self.handler {
+            return handler(event);
+        }
+        if self.queue.is_full() {
+            warn!("USB key event dropped: {:?}", self.queue.pop_front());
+        }
+        let _ = self.queue.push_back(event);
+    }
+}
+
+/// A keyboard interface, how to read it and what it last reported
+struct KbdDevice {
+    iface_num: InterfaceNum,
+    /// Interface supports the boot protocol
+    boot: bool,
+    endpoint: Endpoint,
+    /// Length of the report descriptor, 0 if there is none
+    report_desc_len: u16,
+    /// Keys of the input reports, boot protocol is used without it
+    layout: Option<KbdReportLayout>,
+    keys: KbdState,
+    /// Locks on, `LED_NUM_LOCK`, `LED_CAPS_LOCK` and `LED_SCROLL_LOCK` bits
+    locks: u8,
+}
+
+impl KbdDevice {
+    /// Light the LEDs of the locks that are on
+    fn set_leds(&self, host: &mut dyn UsbHost, device: &mut Device) -> Result<(), UsbError> {
+        let mut buf = [0u8; MAX_REPORT_LEN];
+        let (report_id, report) = match &self.layout {
+            Some(layout) => match layout.led_report(self.locks, &mut buf) {
+                Some(report) => report,
+                None => return Ok(()),
+            },
+            None => (0, slice::from_ref(&self.locks)),
+        };
+        device.set_report(host, self.iface_num, ReportKind::Output, report_id, report)
+    }
+}
+
+impl<const DEVICES: usize> Driver for BootKbdDriver<DEVICES> {
     fn name(&self) -> &str {
         "BootKbd"
     }
@@ -30,15 +152,12 @@ impl Driver for BootKbdDriver {
         let mut config_num = None;
         while let Some(desc) = parser.next() {
             match desc {
-                DescriptorRef::Configuration(cdesc) => {
+                Ok(DescriptorRef::Configuration(cdesc)) => {
                     info!("USB kbd conf {:?}", cdesc);
                     config_num.replace(cdesc.b_configuration_value);
                 }
-                DescriptorRef::Interface(idesc) => {
-                    if idesc.b_interface_class == DeviceClass::Hid as u8
-                        && idesc.b_interface_sub_class == HidSubclass::Boot as u8
-                        && idesc.b_interface_protocol == HidDevice::Keyboard as u8
-                    {
+                Ok(DescriptorRef::Interface(idesc)) => {
+                    if is_keyboard(idesc) {
                         if let Some(config_num) = config_num {
                             info!("USB kbd iface {:?}", idesc);
                             return Some((DeviceClass::Hid, config_num, idesc.b_interface_number));
@@ -52,17 +171,44 @@ impl Driver for BootKbdDriver {
     }
 
     fn register(&mut self, device: &mut Device, parser: &mut DescriptorParser) -> Result<(), UsbError> {
+        // keyboard interface being read, and whether it supports the boot protocol
+        let mut keyboard = None;
+        let mut report_desc_len = 0;
         while let Some(desc) = parser.next() {
             match desc {
-                DescriptorRef::Endpoint(edesc) => {
-                    let new_ep = Endpoint::from_raw(
+                Ok(DescriptorRef::Interface(idesc)) => {
+                    report_desc_len = 0;
+                    keyboard = is_keyboard(idesc)
+                        .then_some((idesc.b_interface_number, idesc.b_interface_sub_class == HidSubclass::Boot as u8));
+                }
+                Ok(DescriptorRef::Hid(hdesc)) if keyboard.is_some() => {
+                    report_desc_len = hdesc.report_descriptor_len().unwrap_or(0);
+                }
+                Ok(DescriptorRef::Endpoint(edesc)) => {
+                    let Some((iface_num, boot)) = keyboard else {
+                        continue;
+                    };
+                    let mut endpoint = Endpoint::from_raw(
                         device.device_address(),
                         edesc.max_packet_size(),
                         edesc.b_endpoint_address,
                         edesc.bm_attributes,
                     );
-                    if let Err(err) = self.device_endpoints.insert(device.device_address(), new_ep) {
-                        warn!("Too many devices: {:?}", err)
+                    if endpoint.transfer_type() != TransferType::Interrupt || endpoint.direction() != Direction::In {
+                        continue;
+                    }
+                    endpoint.set_interval(edesc.b_interval);
+                    let kbd = KbdDevice {
+                        iface_num,
+                        boot,
+                        endpoint,
+                        report_desc_len,
+                        layout: None,
+                        keys: KbdState::default(),
+                        locks: 0,
+                    };
+                    if self.devices.insert(device.device_address(), kbd).is_err() {
+                        warn!("Too many devices")
                     }
                 }
                 _ => {}
@@ -72,34 +218,87 @@ impl Driver for BootKbdDriver {
     }
 
     fn unregister(&mut self, address: DevAddress) {
-        // nothing we can do if this return None.
-        let _ = self.device_endpoints.remove(&address);
+        // keys of a keyboard going away are released
+        if let Some(kbd) = self.devices.remove(&address) {
+            kbd.keys.changes(&KbdState::default(), address, |event| self.events.emit(event));
+        }
+    }
+
+    fn state_after_config_set(&self, host: &mut dyn UsbHost, device: &mut Device) -> DeviceState {
+        match self.devices.get(&device.device_address()) {
+            Some(kbd) => DeviceState::SetProtocol(kbd.iface_num, host.after_millis(10)),
+            None => DeviceState::Running,
+        }
     }
 
-    fn state_after_config_set(&self, host: &mut dyn UsbHost, _device: &mut Device) -> DeviceState {
-        DeviceState::SetInterface(0, host.after_millis(10))
+    fn poll_interval(&self, device: &Device) -> Option<u64> {
+        let kbd = self.devices.get(&device.device_address())?;
+        Some(device.speed().interval_ms(kbd.endpoint.b_interval()))
     }
 
     fn run(&mut self, host: &mut dyn UsbHost, device: &mut Device) -> Result<(), UsbError> {
-        if let Some(endpoint) = self.device_endpoints.get_mut(&device.device_address()) {
+        if let Some(kbd) = self.devices.get_mut(&device.device_address()) {
             match device.state() {
-                DeviceState::SetInterface(iface, until) => {
+                DeviceState::SetProtocol(iface, until) => {
                     if host.delay_done(until) {
-                        device.set_interface(host, iface, HidProtocol::Boot as u8)?;
-                        device.set_state(DeviceState::Running);
+                        kbd.layout = report_layout(host, device, iface, kbd.report_desc_len);
+                        match (&kbd.layout, kbd.boot) {
+                            (Some(_), true) => device.set_protocol(host, iface, HidProtocol::Report)?,
+                            // report protocol is all there is
+                            (Some(_), false) => {}
+                            (None, true) => device.set_protocol(host, iface, HidProtocol::Boot)?,
+                            (None, false) => return Err(UsbError::InvalidDescriptor),
+                        }
+                        if device.quirks().no_set_idle {
+                            device.set_state(DeviceState::SetReport(iface));
+                        } else {
+                            device.set_state(DeviceState::SetIdle);
+                        }
                     }
                 }
 
+                DeviceState::SetIdle => {
+                    // reports only when keys change
+                    device.set_idle(host, kbd.iface_num, 0, 0)?;
+                    device.set_state(DeviceState::SetReport(kbd.iface_num));
+                }
+
+                DeviceState::SetReport(_) => {
+                    // LEDs in a known state, the keyboard works without them
+                    if let Err(err) = kbd.set_leds(host, device) {
+                        warn!("USB kbd LEDs not set: {:?}", err);
+                    }
+                    device.set_state(DeviceState::Running);
+                }
+
                 DeviceState::Running => {
-                    let mut buf = 0u64;
-                    match endpoint.interrupt_in(host, to_slice_mut(&mut buf)) {
-                        Ok(_size) => {
-                            if buf > 0 {
-                                // FIXME don't log, decode and pass to configured callback, see MIDI
-                                info!("Got keys {:x}", buf)
+                    let mut buf = [0u8; MAX_REPORT_LEN];
+                    let len = min(kbd.endpoint.max_packet_size() as usize, MAX_REPORT_LEN);
+                    if let Ok(len) = kbd.endpoint.interrupt_in(host, &mut buf[..len]) {
+                        let report = &buf[..len];
+                        let keys = match &kbd.layout {
+                            Some(layout) => layout.decode(report),
+                            None => BootKbdPacket::from_report(report).map(KbdState::from),
+                        };
+                        if let Some(keys) = keys {
+                            let address = device.device_address();
+                            let toggled = keys
+                                .keys()
+                                .filter(|key| !kbd.keys.is_pressed(*key))
+                                .fold(0, |locks, key| locks ^ lock_led(key));
+                            kbd.keys.changes(&keys, address, |event| self.events.emit(event));
+                            kbd.keys = keys;
+                            if toggled != 0 {
+                                kbd.locks ^= toggled;
+                                self.events.emit(KeyEvent::Locks {
+                                    address,
+                                    locks: kbd.locks,
+                                });
+                                if let Err(err) = kbd.set_leds(host, device) {
+                                    warn!("USB kbd LEDs not set: {:?}", err);
+                                }
                             }
                         }
-                        Err(_) => {}
                     }
                 }
                 state => {
@@ -111,6 +310,206 @@ impl Driver for BootKbdDriver {
     }
 }
 
+/// HID interface of a keyboard, with or without boot protocol support
+fn is_keyboard(idesc: &InterfaceDescriptor) -> bool {
+    idesc.b_interface_class == DeviceClass::Hid as u8 && idesc.b_interface_protocol == HidDevice::Keyboard as u8
+}
+
+/// LED of a lock key, 0 for other keys
+fn lock_led(usage: u8) -> u8 {
+    match usage {
+        USAGE_NUM_LOCK => LED_NUM_LOCK,
+        USAGE_CAPS_LOCK => LED_CAPS_LOCK,
+        USAGE_SCROLL_LOCK => LED_SCROLL_LOCK,
+        _ => 0,
+    }
+}
+
+/// Keys in the report descriptor of an interface, None if it can't be read or has no keys
+fn report_layout(
+    host: &mut dyn UsbHost, device: &mut Device, iface_num: InterfaceNum, len: u16,
+) -> Option<KbdReportLayout> {
+    if len == 0 {
+        return None;
+    }
+    let mut buf = [0u8; MAX_REPORT_DESC_LEN];
+    let Some(buf) = buf.get_mut(..len as usize) else {
+        warn!("USB kbd report descriptor of {} bytes too big", len);
+        return None;
+    };
+    let len = device
+        .get_report_descriptor(host, iface_num, buf)
+        .inspect_err(|err| warn!("USB kbd report descriptor not read: {:?}", err))
+        .ok()?;
+    let desc: ReportDescriptor = ReportDescriptor::parse(&buf[..len])
+        .inspect_err(|err| warn!("USB kbd report descriptor not parsed: {:?}", err))
+        .ok()?;
+    KbdReportLayout::from_descriptor(&desc)
+}
+
+/// Keys held down on a keyboard, whatever its protocol
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KbdState {
+    /// Left control to right GUI, bits 0 to 7
+    pub modifiers: u8,
+    /// Keyboard page usages of the other keys held down in report order, 0 in unused slots
+    keys: [u8; MAX_KEYS],
+}
+
+impl KbdState {
+    /// Keyboard page usages of the keys held down, modifiers excluded
+    pub fn keys(&self) -> impl Iterator<Item = u8> + '_ {
+        self.keys.iter().copied().take_while(|key| *key != 0)
+    }
+
+    /// True if the key with this keyboard page usage is held down, modifiers included
+    pub fn is_pressed(&self, usage: u8) -> bool {
+        match usage as u16 {
+            USAGE_LEFT_CONTROL..=USAGE_RIGHT_GUI => self.modifiers & 1 << (usage as u16 - USAGE_LEFT_CONTROL) != 0,
+            _ => usage != 0 && self.keys.contains(&usage),
+        }
+    }
+
+    /// Events going from these keys to `next` on the keyboard at `address`:
+    /// modifiers first, then keys released, then keys pressed
+    fn changes(&self, next: &KbdState, address: DevAddress, mut emit: impl FnMut(KeyEvent)) {
+        if self.modifiers != next.modifiers {
+            emit(KeyEvent::Modifiers {
+                address,
+                modifiers: next.modifiers,
+            });
+        }
+        for usage in self.keys().filter(|key| !next.is_pressed(*key)) {
+            emit(KeyEvent::Released { address, usage });
+        }
+        for usage in next.keys().filter(|key| !self.is_pressed(*key)) {
+            emit(KeyEvent::Pressed { address, usage });
+        }
+    }
+
+    /// Add a key held down, keys beyond `MAX_KEYS` are left out
+    fn press(&mut self, usage: u16) {
+        match usage {
+            USAGE_LEFT_CONTROL..=USAGE_RIGHT_GUI => self.modifiers |= 1 << (usage - USAGE_LEFT_CONTROL),
+            USAGE_FIRST_KEY..=0xff => {
+                if let Some(slot) = self.keys.iter_mut().find(|slot| **slot == 0) {
+                    *slot = usage as u8;
+                }
+            }
+            // no event, or keyboard errors
+            _ => {}
+        }
+    }
+}
+
+impl From<BootKbdPacket> for KbdState {
+    fn from(packet: BootKbdPacket) -> Self {
+        let mut state = KbdState {
+            modifiers: packet.modifiers,
+            ..KbdState::default()
+        };
+        packet.keys.iter().for_each(|key| state.press(*key as u16));
+        state
+    }
+}
+
+/// Where the keys are in the input reports of a keyboard using report protocol
+#[derive(Clone, Debug, PartialEq)]
+pub struct KbdReportLayout {
+    /// ID of the keyboard input report, 0 without report IDs
+    report_id: u8,
+    fields: Vec<ReportField, MAX_KEY_FIELDS>,
+    /// LED page output fields with lock LEDs, all in the same report
+    led_fields: Vec<ReportField, LOCK_LEDS>,
+    /// Length of the output report with the lock LEDs, ID included
+    led_report_len: usize,
+}
+
+impl KbdReportLayout {
+    /// Keyboard page input fields of the first report that has any, None if none can report keys
+    pub fn from_descriptor<const FIELDS: usize, const COLLECTIONS: usize>(
+        desc: &ReportDescriptor<FIELDS, COLLECTIONS>,
+    ) -> Option<Self> {
+        let key_fields = desc
+            .fields()
+            .iter()
+            .filter(|field| field.kind == ReportKind::Input && field.usage_page == UsagePage::Keyboard as u16);
+        let report_id = key_fields.clone().next()?.report_id;
+        let mut layout = KbdReportLayout {
+            report_id,
+            fields: Vec::new(),
+            led_fields: Vec::new(),
+            led_report_len: 0,
+        };
+        for field in key_fields.filter(|field| field.report_id == report_id) {
+            if layout.fields.push(*field).is_err() {
+                warn!("USB kbd key field at {} ignored", field.bit_offset);
+            }
+        }
+        let led_fields = desc.fields().iter().filter(|field| {
+            field.kind == ReportKind::Output
+                && field.usage_page == UsagePage::Led as u16
+                && field.is_variable()
+                && field.usage_min as usize <= LOCK_LEDS
+        });
+        if let Some(led_report_id) = led_fields.clone().next().map(|field| field.report_id) {
+            let len = desc.report_len(ReportKind::Output, led_report_id).unwrap_or(0);
+            if len <= MAX_REPORT_LEN {
+                for field in led_fields.filter(|field| field.report_id == led_report_id) {
+                    let _ = layout.led_fields.push(*field);
+                }
+                layout.led_report_len = len;
+            }
+        }
+        let has_keys = layout.fields.iter().any(|field| field.usage_min < USAGE_LEFT_CONTROL);
+        has_keys.then_some(layout)
+    }
+
+    /// Output report lighting the lock LEDs in `locks`, with its ID.
+    /// None if the keyboard has no lock LEDs.
+    fn led_report<'a>(&self, locks: u8, buf: &'a mut [u8; MAX_REPORT_LEN]) -> Option<(u8, &'a [u8])> {
+        let report_id = self.led_fields.first()?.report_id;
+        let report = &mut buf[..self.led_report_len];
+        for led in 0..LOCK_LEDS as u16 {
+            let usage = Usage::new(UsagePage::Led, led + 1);
+            if let Some((field, index)) = self
+                .led_fields
+                .iter()
+                .find_map(|field| field.index_of(usage).map(|index| (field, index)))
+            {
+                field.set_value(report, index, (locks >> led & 1) as i32);
+            }
+        }
+        Some((report_id, report))
+    }
+
+    /// Keys held down according to an input report, None for other reports or if too many keys are down
+    pub fn decode(&self, report: &[u8]) -> Option<KbdState> {
+        if self.report_id != 0 && report.first() != Some(&self.report_id) {
+            return None;
+        }
+        let mut state = KbdState::default();
+        for field in &self.fields {
+            for index in 0..field.count {
+                if field.is_variable() {
+                    if field.value(report, index)? != 0 {
+                        state.press(field.usage(index).id);
+                    }
+                } else {
+                    match field.selected(report, index) {
+                        Some(usage) if usage.id == USAGE_ERROR_ROLL_OVER => return None,
+                        Some(usage) => state.press(usage.id),
+                        None => {}
+                    }
+                }
+            }
+        }
+        Some(state)
+    }
+}
+
+/// Input report of a keyboard in boot protocol
 #[derive(Debug, Default, Eq, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct BootKbdPacket {
@@ -119,10 +518,302 @@ pub struct BootKbdPacket {
     keys: [u8; 6],
 }
 
-impl BootKbdDriver {
+impl BootKbdPacket {
+    /// None if the report is too short or too many keys are down
+    pub fn from_report(report: &[u8]) -> Option<Self> {
+        let report: &[u8; 8] = report.get(..8)?.try_into().ok()?;
+        let keys: [u8; 6] = report[2..].try_into().ok()?;
+        if keys.contains(&(USAGE_ERROR_ROLL_OVER as u8)) {
+            return None;
+        }
+        Some(BootKbdPacket {
+            modifiers: report[0],
+            r0: report[1],
+            keys,
+        })
+    }
+}
+
+impl<const DEVICES: usize> BootKbdDriver<DEVICES> {
+    /// Key events are queued, see `poll_event`
     pub fn new() -> Self {
         Self {
-            device_endpoints: FnvIndexMap::new(),
+            devices: FnvIndexMap::new(),
+            events: KeyEvents {
+                queue: Deque::new(),
+                handler: None,
+            },
         }
     }
+
+    /// Key events are handed to `handler` as they happen
+    pub fn with_key_handler(handler: fn(KeyEvent)) -> Self {
+        let mut driver = Self::new();
+        driver.events.handler = Some(handler);
+        driver
+    }
+
+    /// Oldest key event not handled yet
+    pub fn poll_event(&mut self) -> Option<KeyEvent> {
+        self.events.queue.pop_front()
+    }
+
+    /// Protocol a keyboard is read in, once set up
+    pub fn protocol(&self, device: DevAddress) -> Option<HidProtocol> {
+        let kbd = self.devices.get(&device)?;
+        Some(match kbd.layout {
+            Some(_) => HidProtocol::Report,
+            None => HidProtocol::Boot,
+        })
+    }
+
+    /// Keys last reported held down on a keyboard
+    pub fn keys(&self, device: DevAddress) -> Option<&KbdState> {
+        self.devices.get(&device).map(|kbd| &kbd.keys)
+    }
+
+    /// Locks on for a keyboard, `LED_NUM_LOCK`, `LED_CAPS_LOCK` and `LED_SCROLL_LOCK` bits
+    pub fn locks(&self, device: DevAddress) -> Option<u8> {
+        self.devices.get(&device).map(|kbd| kbd.locks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hid::HidRequest;
+    use crate::sim::{SimDevice, SimHost, SimResponse};
+    use crate::{HostEvent, RequestCode, RequestKind, Speed, WValue};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    const KBD_DEV_DESC: [u8; 18] = [
+        0x12, 0x01, 0x10, 0x01, 0x00, 0x00, 0x00, 0x08, 0xf0, 0x03, 0x24, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+    ];
+
+    /// Modifiers, a bitmap of 104 keys and 5 LEDs in report 1, consumer controls in report 2
+    const NKRO_REPORT_DESC: [u8; 74] = [
+        0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0x85, 0x01, // keyboard application, report 1
+        0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25, 0x01, // modifiers
+        0x75, 0x01, 0x95, 0x08, 0x81, 0x02, // 8 variable bits
+        0x19, 0x00, 0x29, 0x67, 0x95, 0x68, 0x81, 0x02, // 104 variable bits
+        0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x95, 0x05, 0x91, 0x02, // 5 LEDs output
+        0x75, 0x03, 0x95, 0x01, 0x91, 0x01, // padding
+        0xc0, //
+        0x05, 0x0c, 0x09, 0x01, 0xa1, 0x01, 0x85, 0x02, // consumer control application, report 2
+        0x15, 0x00, 0x26, 0xff, 0x03, 0x19, 0x00, 0x2a, 0xff, 0x03, // 0 to 1023
+        0x75, 0x10, 0x95, 0x01, 0x81, 0x00, // 16 bits array
+        0xc0,
+    ];
+
+    fn kbd_conf_desc(report_desc_len: u8) -> [u8; 34] {
+        [
+            0x09, 0x02, 0x22, 0x00, 0x01, 0x01, 0x00, 0xa0, 0x32, // configuration
+            0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x01, 0x00, // HID boot keyboard interface
+            0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, report_desc_len, 0x00, // HID
+            0x07, 0x05, 0x81, 0x03, 0x10, 0x00, 0x01, // interrupt IN
+        ]
+    }
+
+    /// Keyboard set up and running, with its report descriptor
+    fn running(mut driver: BootKbdDriver, report_desc: &[u8]) -> (SimHost, Device, BootKbdDriver) {
+        let conf = kbd_conf_desc(report_desc.len() as u8);
+        let mut host = SimHost::new();
+        host.attach(
+            SimDevice::new(&KBD_DEV_DESC)
+                .with_configuration(&conf)
+                .with_report_descriptor(0, report_desc),
+        );
+        while host.update() != Some(HostEvent::Ready) {}
+        let mut device = Device::new(Speed::Low);
+        let mut parser = DescriptorParser::new(&conf);
+        assert!(driver.accept(&mut device, &mut parser).is_some());
+        parser.rewind();
+        driver.register(&mut device, &mut parser).unwrap();
+        let state = driver.state_after_config_set(&mut host, &mut device);
+        device.set_state(state);
+        while device.state() != DeviceState::Running {
+            host.update();
+            driver.run(&mut host, &mut device).unwrap();
+        }
+        (host, device, driver)
+    }
+
+    /// Protocol selected with SET_PROTOCOL
+    fn protocol_set(host: &mut SimHost) -> Option<u8> {
+        let sim = host.device().unwrap();
+        sim.requests()
+            .filter(|r| r.bm_request_type.kind() == Some(RequestKind::Class))
+            .find(|r| r.b_request == RequestCode::SetInterface as u8)
+            .map(|r| r.w_value.w_value_lo())
+    }
+
+    fn receive(host: &mut SimHost, device: &mut Device, driver: &mut BootKbdDriver, report: &[u8]) -> KbdState {
+        host.device_mut()
+            .unwrap()
+            .endpoint(0x81)
+            .unwrap()
+            .push(SimResponse::data(report));
+        driver.run(host, device).unwrap();
+        *driver.keys(device.device_address()).unwrap()
+    }
+
+    #[test]
+    fn report_protocol_nkro() {
+        let (mut host, mut device, mut driver) = running(BootKbdDriver::new(), &NKRO_REPORT_DESC);
+        assert_eq!(driver.protocol(device.device_address()), Some(HidProtocol::Report));
+        assert_eq!(protocol_set(&mut host), Some(HidProtocol::Report as u8));
+
+        // left shift, 'a' and 'z' out of a bitmap
+        let mut report = [0u8; 15];
+        report[0] = 1;
+        report[1] = 0x02;
+        for usage in [0x04, 0x1d] {
+            report[2 + usage / 8] |= 1 << (usage % 8);
+        }
+        let keys = receive(&mut host, &mut device, &mut driver, &report);
+        assert_eq!(keys.modifiers, 0x02);
+        assert!(keys.keys().eq([0x04, 0x1d]));
+        assert!(keys.is_pressed(0xe1));
+        assert!(keys.is_pressed(0x1d));
+        assert!(!keys.is_pressed(0x05));
+
+        // volume up, from another report
+        let keys = receive(&mut host, &mut device, &mut driver, &[2, 0xe9, 0x00]);
+        assert!(keys.keys().eq([0x04, 0x1d]));
+        let mut release = [0u8; 15];
+        release[0] = 1;
+        let keys = receive(&mut host, &mut device, &mut driver, &release);
+        assert_eq!(keys, KbdState::default());
+    }
+
+    #[test]
+    fn interrupt_out_ignored() {
+        let conf = [
+            0x09, 0x02, 0x29, 0x00, 0x01, 0x01, 0x00, 0xa0, 0x32, // configuration
+            0x09, 0x04, 0x00, 0x00, 0x02, 0x03, 0x01, 0x01, 0x00, // HID boot keyboard interface
+            0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x41, 0x00, // HID
+            0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0a, // interrupt IN
+            0x07, 0x05, 0x02, 0x03, 0x08, 0x00, 0x0a, // interrupt OUT
+        ];
+        let mut driver = <BootKbdDriver>::new();
+        let mut device = Device::new(Speed::Low);
+        driver.register(&mut device, &mut DescriptorParser::new(&conf)).unwrap();
+        let kbd = driver.devices.get(&device.device_address()).unwrap();
+        assert_eq!(u8::from(kbd.endpoint.endpoint_address()), 0x81);
+    }
+
+    #[test]
+    fn boot_protocol_fallback() {
+        // collection never ended
+        let (mut host, mut device, mut driver) = running(BootKbdDriver::new(), &[0x05, 0x01, 0x09, 0x06, 0xa1, 0x01]);
+        assert_eq!(driver.protocol(device.device_address()), Some(HidProtocol::Boot));
+        assert_eq!(protocol_set(&mut host), Some(HidProtocol::Boot as u8));
+
+        let keys = receive(&mut host, &mut device, &mut driver, &[0x01, 0, 0x04, 0x05, 0, 0, 0, 0]);
+        assert_eq!(keys.modifiers, 0x01);
+        assert!(keys.keys().eq([0x04, 0x05]));
+        // too many keys down, last known keys are kept
+        let keys = receive(&mut host, &mut device, &mut driver, &[0, 0, 1, 1, 1, 1, 1, 1]);
+        assert!(keys.keys().eq([0x04, 0x05]));
+    }
+
+    #[test]
+    fn key_events() {
+        let (mut host, mut device, mut driver) = running(BootKbdDriver::new(), &NKRO_REPORT_DESC);
+        let address = device.device_address();
+        let mut report = [0u8; 15];
+        report[0] = 1;
+
+        // left shift and 'a', then 'b' as well
+        report[1] = 0x02;
+        report[2] = 1 << 4;
+        receive(&mut host, &mut device, &mut driver, &report);
+        report[2] |= 1 << 5;
+        receive(&mut host, &mut device, &mut driver, &report);
+        // same keys again, nothing new
+        receive(&mut host, &mut device, &mut driver, &report);
+        // shift and 'a' up
+        report[1] = 0;
+        report[2] = 1 << 5;
+        receive(&mut host, &mut device, &mut driver, &report);
+        assert!(core::iter::from_fn(|| driver.poll_event()).eq([
+            KeyEvent::Modifiers {
+                address,
+                modifiers: 0x02
+            },
+            KeyEvent::Pressed { address, usage: 0x04 },
+            KeyEvent::Pressed { address, usage: 0x05 },
+            KeyEvent::Modifiers { address, modifiers: 0 },
+            KeyEvent::Released { address, usage: 0x04 },
+        ]));
+
+        // keys still down are released when the keyboard goes away
+        driver.unregister(address);
+        assert_eq!(driver.poll_event(), Some(KeyEvent::Released { address, usage: 0x05 }));
+        assert_eq!(driver.poll_event(), None);
+    }
+
+    #[test]
+    fn lock_leds() {
+        let (mut host, mut device, mut driver) = running(BootKbdDriver::new(), &NKRO_REPORT_DESC);
+        let address = device.device_address();
+        let set_report = host
+            .device()
+            .unwrap()
+            .requests()
+            .find(|r| r.b_request == HidRequest::SetReport as u8)
+            .copied()
+            .unwrap();
+        assert_eq!(set_report.w_value, WValue::lo_hi(1, ReportKind::Output as u8));
+        // LEDs off once set up
+        let mut leds = || host.device_mut().unwrap().take_control_data();
+        assert_eq!(leds().unwrap(), [1, 0]);
+
+        let mut report = [0u8; 15];
+        report[0] = 1;
+        let mut press = |host: &mut SimHost, usage: &[u8]| {
+            report[2..].fill(0);
+            usage
+                .iter()
+                .for_each(|usage| report[2 + *usage as usize / 8] |= 1 << (usage % 8));
+            receive(host, &mut device, &mut driver, &report);
+        };
+        press(&mut host, &[USAGE_CAPS_LOCK]);
+        // held down, no toggle
+        press(&mut host, &[USAGE_CAPS_LOCK, 0x04]);
+        press(&mut host, &[USAGE_NUM_LOCK]);
+        press(&mut host, &[USAGE_NUM_LOCK, USAGE_CAPS_LOCK]);
+        let sim = host.device_mut().unwrap();
+        let sent: [_; 4] = core::array::from_fn(|_| sim.take_control_data());
+        assert_eq!(
+            sent.map(|leds| leds.map(|leds| leds[1])),
+            [Some(0x02), Some(0x03), Some(0x01), None]
+        );
+        assert_eq!(driver.locks(address), Some(LED_NUM_LOCK));
+        let locks = core::iter::from_fn(|| driver.poll_event()).filter_map(|event| match event {
+            KeyEvent::Locks { locks, .. } => Some(locks),
+            _ => None,
+        });
+        assert!(locks.eq([0x02, 0x03, 0x01]));
+
+        // one byte output report in boot protocol
+        let (mut host, mut device, mut driver) = running(BootKbdDriver::new(), &[]);
+        assert_eq!(host.device_mut().unwrap().take_control_data().unwrap(), [0]);
+        receive(&mut host, &mut device, &mut driver, &[0, 0, USAGE_SCROLL_LOCK, 0, 0, 0, 0, 0]);
+        assert_eq!(host.device_mut().unwrap().take_control_data().unwrap(), [LED_SCROLL_LOCK]);
+    }
+
+    #[test]
+    fn key_handler() {
+        static PRESSED: AtomicUsize = AtomicUsize::new(0);
+        let driver = BootKbdDriver::with_key_handler(|event| {
+            if let KeyEvent::Pressed { usage, .. } = event {
+                PRESSED.store(usage as usize, Ordering::Relaxed);
+            }
+        });
+        let (mut host, mut device, mut driver) = running(driver, &[]);
+        receive(&mut host, &mut device, &mut driver, &[0, 0, 0x1d, 0, 0, 0, 0, 0]);
+        assert_eq!(PRESSED.load(Ordering::Relaxed), 0x1d);
+        assert_eq!(driver.poll_event(), None);
+    }
 }
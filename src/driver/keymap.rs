@@ -0,0 +1,751 @@
+//! Keyboard layouts, turning the key events of the keyboard driver into characters and named keys.
+//! Dead keys combine with the next character, e.g. '^' then 'e' types 'ê'.
+//! A key held down repeats after a delay (typematic repeat), timed in milliseconds of `UsbHost::now`.
+
+use heapless::Deque;
+
+use crate::keyboard::{KeyEvent, LED_CAPS_LOCK, LED_NUM_LOCK};
+
+/// Milliseconds a key is held down before it repeats
+pub const DEFAULT_REPEAT_DELAY_MS: u64 = 500;
+
+/// Milliseconds between repeats of a key held down, about 30 per second
+pub const DEFAULT_REPEAT_INTERVAL_MS: u64 = 33;
+
+/// Keys typed but not polled yet, a dead key followed by a key it doesn't combine with types two
+const MAX_TYPED: usize = 4;
+
+/// Keys of the characters of a layout: letters and digits, punctuation, then the ISO key next to left shift
+const LAYOUT_KEYS: usize = 49;
+
+/// Left or right shift modifier bits
+const MOD_SHIFT: u8 = 0x22;
+/// Right alt modifier bit, AltGr on layouts that have it
+const MOD_ALTGR: u8 = 0x40;
+
+/// Keyboard page usages of the lock keys, which don't repeat
+const USAGE_CAPS_LOCK: u8 = 0x39;
+const USAGE_SCROLL_LOCK: u8 = 0x47;
+const USAGE_NUM_LOCK: u8 = 0x53;
+
+/// A key as typed on a layout
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Key {
+    Char(char),
+    Named(NamedKey),
+}
+
+/// Keys that don't type a character
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NamedKey {
+    Enter,
+    Escape,
+    Backspace,
+    Tab,
+    CapsLock,
+    /// F1 to F24
+    Function(u8),
+    PrintScreen,
+    ScrollLock,
+    Pause,
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    Right,
+    Left,
+    Down,
+    Up,
+    NumLock,
+    /// Application key, opens a context menu
+    Menu,
+}
+
+/// A key typed, with the modifiers held down at the time
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Keystroke {
+    pub key: Key,
+    /// Left control to right GUI in bits 0 to 7, as in `KeyEvent::Modifiers`
+    pub modifiers: u8,
+    /// Typed again because the key is held down
+    pub repeat: bool,
+}
+
+/// Shift levels of a layout, AltGr is right alt
+#[derive(Clone, Copy, Debug, PartialEq, strum_macros::FromRepr)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Level {
+    Plain = 0,
+    Shift = 1,
+    AltGr = 2,
+    ShiftAltGr = 3,
+}
+
+/// A key that types nothing by itself at a level, its character combines with the next one instead
+#[derive(Debug)]
+pub struct DeadKey {
+    /// Keyboard page usage of the key
+    pub usage: u8,
+    pub level: Level,
+    /// Characters and what they make after the dead key, others are typed after its own character
+    pub combos: &'static [(char, char)],
+}
+
+/// What the character keys of a keyboard type.
+/// Each level is a string with a character per key, for keyboard page usages 0x04 to 0x27
+/// (letters and digits), 0x2d to 0x38 (punctuation) and 0x64 (ISO key next to left shift).
+/// A space stands for a key that types nothing at that level, a string can stop after its last key.
+/// Caps Lock swaps plain and shift levels of keys whose shift character is the uppercase of the plain one.
+#[derive(Debug)]
+pub struct Layout {
+    pub name: &'static str,
+    /// Plain, shift, AltGr and shift + AltGr levels
+    pub levels: [&'static str; 4],
+    pub dead_keys: &'static [DeadKey],
+}
+
+impl Layout {
+    /// Character a key types at a level, None if it types nothing
+    pub fn char(&self, usage: u8, level: Level) -> Option<char> {
+        let index = layout_index(usage)?;
+        self.levels[level as usize].chars().nth(index).filter(|c| *c != ' ')
+    }
+
+    /// Dead key of a key at a level, if it is one
+    pub fn dead_key(&self, usage: u8, level: Level) -> Option<&'static DeadKey> {
+        self.dead_keys.iter().find(|dead| dead.usage == usage && dead.level == level)
+    }
+}
+
+/// Position of a key in the levels of a layout
+fn layout_index(usage: u8) -> Option<usize> {
+    match usage {
+        0x04..=0x27 => Some(usage as usize - 0x04),
+        0x2d..=0x38 => Some(usage as usize - 0x2d + 36),
+        0x64 => Some(LAYOUT_KEYS - 1),
+        _ => None,
+    }
+}
+
+/// Keys that are the same on every layout
+fn common_key(usage: u8, num_lock: bool) -> Option<Key> {
+    let named = match usage {
+        0x28 | 0x58 => NamedKey::Enter,
+        0x29 => NamedKey::Escape,
+        0x2a => NamedKey::Backspace,
+        0x2b => NamedKey::Tab,
+        0x2c => return Some(Key::Char(' ')),
+        USAGE_CAPS_LOCK => NamedKey::CapsLock,
+        0x3a..=0x45 => NamedKey::Function(usage - 0x3a + 1),
+        0x46 => NamedKey::PrintScreen,
+        USAGE_SCROLL_LOCK => NamedKey::ScrollLock,
+        0x48 => NamedKey::Pause,
+        0x49 => NamedKey::Insert,
+        0x4a => NamedKey::Home,
+        0x4b => NamedKey::PageUp,
+        0x4c => NamedKey::Delete,
+        0x4d => NamedKey::End,
+        0x4e => NamedKey::PageDown,
+        0x4f => NamedKey::Right,
+        0x50 => NamedKey::Left,
+        0x51 => NamedKey::Down,
+        0x52 => NamedKey::Up,
+        USAGE_NUM_LOCK => NamedKey::NumLock,
+        0x54 => return Some(Key::Char('/')),
+        0x55 => return Some(Key::Char('*')),
+        0x56 => return Some(Key::Char('-')),
+        0x57 => return Some(Key::Char('+')),
+        // keypad digits, from 1 to 9 then 0
+        0x59..=0x62 if num_lock => return Some(Key::Char(b"1234567890"[usage as usize - 0x59] as char)),
+        0x63 if num_lock => return Some(Key::Char('.')),
+        0x59 => NamedKey::End,
+        0x5a => NamedKey::Down,
+        0x5b => NamedKey::PageDown,
+        0x5c => NamedKey::Left,
+        0x5e => NamedKey::Right,
+        0x5f => NamedKey::Home,
+        0x60 => NamedKey::Up,
+        0x61 => NamedKey::PageUp,
+        0x62 => NamedKey::Insert,
+        0x63 => NamedKey::Delete,
+        0x65 => NamedKey::Menu,
+        0x68..=0x73 => NamedKey::Function(usage - 0x68 + 13),
+        _ => return None,
+    };
+    Some(Key::Named(named))
+}
+
+const CIRCUMFLEX: &[(char, char)] = &[
+    ('a', 'â'),
+    ('e', 'ê'),
+    ('i', 'î'),
+    ('o', 'ô'),
+    ('u', 'û'),
+    ('A', 'Â'),
+    ('E', 'Ê'),
+    ('I', 'Î'),
+    ('O', 'Ô'),
+    ('U', 'Û'),
+];
+
+const ACUTE: &[(char, char)] = &[
+    ('a', 'á'),
+    ('e', 'é'),
+    ('i', 'í'),
+    ('o', 'ó'),
+    ('u', 'ú'),
+    ('y', 'ý'),
+    ('A', 'Á'),
+    ('E', 'É'),
+    ('I', 'Í'),
+    ('O', 'Ó'),
+    ('U', 'Ú'),
+    ('Y', 'Ý'),
+];
+
+const GRAVE: &[(char, char)] = &[
+    ('a', 'à'),
+    ('e', 'è'),
+    ('i', 'ì'),
+    ('o', 'ò'),
+    ('u', 'ù'),
+    ('A', 'À'),
+    ('E', 'È'),
+    ('I', 'Ì'),
+    ('O', 'Ò'),
+    ('U', 'Ù'),
+];
+
+const DIAERESIS: &[(char, char)] = &[
+    ('a', 'ä'),
+    ('e', 'ë'),
+    ('i', 'ï'),
+    ('o', 'ö'),
+    ('u', 'ü'),
+    ('y', 'ÿ'),
+    ('A', 'Ä'),
+    ('E', 'Ë'),
+    ('I', 'Ï'),
+    ('O', 'Ö'),
+    ('U', 'Ü'),
+];
+
+const TILDE: &[(char, char)] = &[('a', 'ã'), ('n', 'ñ'), ('o', 'õ'), ('A', 'Ã'), ('N', 'Ñ'), ('O', 'Õ')];
+
+/// US QWERTY
+pub static LAYOUT_US: Layout = Layout {
+    name: "US",
+    levels: [
+        "abcdefghijklmnopqrstuvwxyz1234567890-=[]\\\\;'`,./\\",
+        "ABCDEFGHIJKLMNOPQRSTUVWXYZ!@#$%^&*()_+{}||:\"~<>?|",
+        "",
+        "",
+    ],
+    dead_keys: &[],
+};
+
+/// UK QWERTY
+pub static LAYOUT_UK: Layout = Layout {
+    name: "UK",
+    levels: [
+        "abcdefghijklmnopqrstuvwxyz1234567890-=[]##;'`,./\\",
+        "ABCDEFGHIJKLMNOPQRSTUVWXYZ!\"£$%^&*()_+{}~~:@¬<>?|",
+        "á   é   í     ó     ú        €              ¦",
+        "Á   É   Í     Ó     Ú",
+    ],
+    dead_keys: &[],
+};
+
+/// German QWERTZ
+pub static LAYOUT_DE: Layout = Layout {
+    name: "DE",
+    levels: [
+        "abcdefghijklmnopqrstuvwxzy1234567890ß´ü+##öä^,.-<",
+        "ABCDEFGHIJKLMNOPQRSTUVWXZY!\"§$%&/()=?`Ü*''ÖÄ°;:_>",
+        "    €       µ   @          ²³   {[]}\\  ~        |",
+        "",
+    ],
+    dead_keys: &[
+        DeadKey {
+            usage: 0x35,
+            level: Level::Plain,
+            combos: CIRCUMFLEX,
+        },
+        DeadKey {
+            usage: 0x2e,
+            level: Level::Plain,
+            combos: ACUTE,
+        },
+        DeadKey {
+            usage: 0x2e,
+            level: Level::Shift,
+            combos: GRAVE,
+        },
+    ],
+};
+
+/// French AZERTY
+pub static LAYOUT_FR: Layout = Layout {
+    name: "FR",
+    levels: [
+        "qbcdefghijkl,noparstuvzxyw&é\"'(-è_çà)=^$**mù²;:!<",
+        "QBCDEFGHIJKL?NOPARSTUVZXYW1234567890°+¨£µµM% ./§>",
+        "    €                      ~#{[|`\\^@]} ¤",
+        "",
+    ],
+    dead_keys: &[
+        DeadKey {
+            usage: 0x2f,
+            level: Level::Plain,
+            combos: CIRCUMFLEX,
+        },
+        DeadKey {
+            usage: 0x2f,
+            level: Level::Shift,
+            combos: DIAERESIS,
+        },
+        DeadKey {
+            usage: 0x1f,
+            level: Level::AltGr,
+            combos: TILDE,
+        },
+        DeadKey {
+            usage: 0x24,
+            level: Level::AltGr,
+            combos: GRAVE,
+        },
+    ],
+};
+
+/// Key held down, repeated once due
+struct Held {
+    usage: u8,
+    due: u64,
+}
+
+/// Turns key events into keys typed on a layout, see `handle` and `poll`.
+/// Keys of all keyboards go to the same keymap, the last modifiers and locks reported apply.
+pub struct Keymap {
+    layout: &'static Layout,
+    modifiers: u8,
+    locks: u8,
+    /// Dead key waiting for the next character
+    dead: Option<&'static DeadKey>,
+    /// Delay and interval of the typematic repeat, None not to repeat
+    repeat: Option<(u64, u64)>,
+    held: Option<Held>,
+    typed: Deque<Keystroke, MAX_TYPED>,
+}
+
+impl Keymap {
+    /// Keys held down repeat after `DEFAULT_REPEAT_DELAY_MS`
+    pub fn new(layout: &'static Layout) -> Self {
+        Self {
+            layout,
+            modifiers: 0,
+            locks: 0,
+            dead: None,
+            repeat: Some((DEFAULT_REPEAT_DELAY_MS, DEFAULT_REPEAT_INTERVAL_MS)),
+            held: None,
+            typed: Deque::new(),
+        }
+    }
+
+    /// Keys held down repeat after `delay_ms`, then every `interval_ms`
+    pub fn with_repeat(mut self, delay_ms: u64, interval_ms: u64) -> Self {
+        self.repeat = Some((delay_ms, interval_ms.max(1)));
+        self
+    }
+
+    /// Keys held down are typed once
+    pub fn without_repeat(mut self) -> Self {
+        self.repeat = None;
+        self
+    }
+
+    pub fn layout(&self) -> &'static Layout {
+        self.layout
+    }
+
+    /// Switch layouts, a pending dead key is forgotten
+    pub fn set_layout(&mut self, layout: &'static Layout) {
+        self.layout = layout;
+        self.dead = None;
+    }
+
+    /// Take a key event from the keyboard driver, `now` is `UsbHost::now`
+    pub fn handle(&mut self, event: KeyEvent, now: u64) {
+        match event {
+            KeyEvent::Modifiers { modifiers, .. } => self.modifiers = modifiers,
+            KeyEvent::Locks { locks, .. } => self.locks = locks,
+            KeyEvent::Pressed { usage, .. } => {
+                self.held = None;
+                if self.press(usage) {
+                    if let Some((delay, _)) = self.repeat {
+                        self.held = Some(Held {
+                            usage,
+                            due: now + delay,
+                        });
+                    }
+                }
+            }
+            KeyEvent::Released { usage, .. } => {
+                if self.held.as_ref().is_some_and(|held| held.usage == usage) {
+                    self.held = None;
+                }
+            }
+        }
+    }
+
+    /// Next key typed, or a repeat of the key held down once due at `now` (`UsbHost::now`)
+    pub fn poll(&mut self, now: u64) -> Option<Keystroke> {
+        if let Some(typed) = self.typed.pop_front() {
+            return Some(typed);
+        }
+        let (_, interval) = self.repeat?;
+        let held = self.held.as_mut()?;
+        if now < held.due {
+            return None;
+        }
+        held.due += interval;
+        // no burst of repeats when polled late
+        if held.due <= now {
+            held.due = now + interval;
+        }
+        let usage = held.usage;
+        let key = self.key(usage)?;
+        Some(Keystroke {
+            key,
+            modifiers: self.modifiers,
+            repeat: true,
+        })
+    }
+
+    /// Type the key of a usage, true if holding it down repeats it
+    fn press(&mut self, usage: u8) -> bool {
+        let level = self.level(usage);
+        if let Some(dead) = self.layout.dead_key(usage, level) {
+            match self.dead.take() {
+                // the same dead key twice types its character
+                Some(pending) if pending.usage == usage && pending.level == level => {
+                    self.type_key(self.layout.char(usage, level))
+                }
+                Some(pending) => {
+                    self.type_key(self.layout.char(pending.usage, pending.level));
+                    self.dead = Some(dead);
+                }
+                None => self.dead = Some(dead),
+            }
+            return false;
+        }
+        let Some(key) = self.key(usage) else {
+            return false;
+        };
+        match (self.dead.take(), key) {
+            (Some(dead), Key::Char(c)) => {
+                let accent = self.layout.char(dead.usage, dead.level);
+                match dead.combos.iter().find(|(base, _)| *base == c) {
+                    Some((_, combined)) => self.type_key(Some(*combined)),
+                    None if c == ' ' => self.type_key(accent),
+                    None => {
+                        self.type_key(accent);
+                        self.type_key(Some(c));
+                    }
+                }
+            }
+            // other keys cancel a dead key
+            _ => self.push(key),
+        }
+        !matches!(usage, USAGE_CAPS_LOCK | USAGE_NUM_LOCK | USAGE_SCROLL_LOCK)
+    }
+
+    /// Key of a usage with the current modifiers and locks, dead keys aside
+    fn key(&self, usage: u8) -> Option<Key> {
+        let level = self.level(usage);
+        let c = self.layout.char(usage, level).or_else(|| {
+            // right alt is plain alt on layouts without AltGr characters
+            let level = Level::from_repr(level as u8 & Level::Shift as u8)?;
+            self.layout.char(usage, level)
+        });
+        c.map(Key::Char).or_else(|| common_key(usage, self.locks & LED_NUM_LOCK != 0))
+    }
+
+    /// Level of a key with the current modifiers, Caps Lock shifts letters
+    fn level(&self, usage: u8) -> Level {
+        let mut shift = self.modifiers & MOD_SHIFT != 0;
+        if self.locks & LED_CAPS_LOCK != 0 {
+            let plain = self.layout.char(usage, Level::Plain);
+            let shifted = self.layout.char(usage, Level::Shift);
+            if plain.is_some_and(|plain| plain.is_lowercase() && shifted == plain.to_uppercase().next()) {
+                shift = !shift;
+            }
+        }
+        match (shift, self.modifiers & MOD_ALTGR != 0) {
+            (false, false) => Level::Plain,
+            (true, false) => Level::Shift,
+            (false, true) => Level::AltGr,
+            (true, true) => Level::ShiftAltGr,
+        }
+    }
+
+    fn type_key(&mut self, c: Option<char>) {
+        if let Some(c) = c {
+            self.push(Key::Char(c));
+        }
+    }
+
+    fn push(&mut self, key: Key) {
+        let keystroke = Keystroke {
+            key,
+            modifiers: self.modifiers,
+            repeat: false,
+        };
+        if self.typed.push_back(keystroke).is_err() {
+            warn!("Key typed dropped: {:?}", keystroke);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::DevAddress;
+
+    fn address() -> DevAddress {
+        DevAddress::from(1)
+    }
+
+    fn press(keymap: &mut Keymap, usage: u8) {
+        keymap.handle(
+            KeyEvent::Pressed {
+                address: address(),
+                usage,
+            },
+            0,
+        );
+        keymap.handle(
+            KeyEvent::Released {
+                address: address(),
+                usage,
+            },
+            0,
+        );
+    }
+
+    fn modifiers(keymap: &mut Keymap, modifiers: u8) {
+        keymap.handle(
+            KeyEvent::Modifiers {
+                address: address(),
+                modifiers,
+            },
+            0,
+        );
+    }
+
+    /// Keys typed by pressing and releasing `usages` one after the other
+    fn typed(keymap: &mut Keymap, usages: &[u8]) -> heapless::Vec<Key, 16> {
+        let mut keys = heapless::Vec::new();
+        for usage in usages {
+            press(keymap, *usage);
+            while let Some(keystroke) = keymap.poll(0) {
+                keys.push(keystroke.key).unwrap();
+            }
+        }
+        keys
+    }
+
+    fn chars(keys: &[Key]) -> impl Iterator<Item = char> + '_ {
+        keys.iter().map(|key| match key {
+            Key::Char(c) => *c,
+            Key::Named(_) => '?',
+        })
+    }
+
+    #[test]
+    fn layout_tables() {
+        for layout in [&LAYOUT_US, &LAYOUT_UK, &LAYOUT_DE, &LAYOUT_FR] {
+            for level in layout.levels {
+                assert!(level.chars().count() <= LAYOUT_KEYS, "{}", layout.name);
+            }
+            assert_eq!(layout.levels[0].chars().count(), LAYOUT_KEYS);
+            for dead in layout.dead_keys {
+                assert!(layout.char(dead.usage, dead.level).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn us_layout() {
+        let mut keymap = Keymap::new(&LAYOUT_US);
+        // "Hi!" then enter
+        modifiers(&mut keymap, 0x02);
+        let keys = typed(&mut keymap, &[0x0b]);
+        modifiers(&mut keymap, 0);
+        let keys2 = typed(&mut keymap, &[0x0c]);
+        modifiers(&mut keymap, 0x20);
+        let keys3 = typed(&mut keymap, &[0x1e]);
+        modifiers(&mut keymap, 0);
+        assert!(chars(&keys).chain(chars(&keys2)).chain(chars(&keys3)).eq("Hi!".chars()));
+        assert_eq!(typed(&mut keymap, &[0x28])[..], [Key::Named(NamedKey::Enter)]);
+
+        // caps lock shifts letters only, shift undoes it
+        keymap.handle(
+            KeyEvent::Locks {
+                address: address(),
+                locks: LED_CAPS_LOCK,
+            },
+            0,
+        );
+        assert!(chars(&typed(&mut keymap, &[0x04, 0x1e, 0x2d])).eq("A1-".chars()));
+        modifiers(&mut keymap, 0x02);
+        assert!(chars(&typed(&mut keymap, &[0x04])).eq("a".chars()));
+        // right alt is alt
+        modifiers(&mut keymap, 0x40);
+        press(&mut keymap, 0x06);
+        assert_eq!(
+            keymap.poll(0),
+            Some(Keystroke {
+                key: Key::Char('C'),
+                modifiers: 0x40,
+                repeat: false,
+            })
+        );
+
+        // keypad follows num lock
+        modifiers(&mut keymap, 0);
+        assert_eq!(typed(&mut keymap, &[0x5a])[..], [Key::Named(NamedKey::Down)]);
+        keymap.handle(
+            KeyEvent::Locks {
+                address: address(),
+                locks: LED_NUM_LOCK,
+            },
+            0,
+        );
+        assert_eq!(
+            typed(&mut keymap, &[0x5a, 0x57, 0x3b])[..],
+            [Key::Char('2'), Key::Char('+'), Key::Named(NamedKey::Function(2))]
+        );
+    }
+
+    #[test]
+    fn national_layouts() {
+        let mut keymap = Keymap::new(&LAYOUT_DE);
+        // y and z swapped, umlauts
+        assert!(chars(&typed(&mut keymap, &[0x1d, 0x1c, 0x2f, 0x2d])).eq("yzüß".chars()));
+        modifiers(&mut keymap, 0x40);
+        assert!(chars(&typed(&mut keymap, &[0x14, 0x08, 0x24])).eq("@€{".chars()));
+
+        keymap.set_layout(&LAYOUT_FR);
+        modifiers(&mut keymap, 0);
+        assert!(chars(&typed(&mut keymap, &[0x14, 0x1a, 0x33, 0x1f])).eq("azmé".chars()));
+        modifiers(&mut keymap, 0x02);
+        assert!(chars(&typed(&mut keymap, &[0x1f, 0x10])).eq("2?".chars()));
+
+        keymap.set_layout(&LAYOUT_UK);
+        assert!(chars(&typed(&mut keymap, &[0x20, 0x34])).eq("£@".chars()));
+        modifiers(&mut keymap, 0x40);
+        assert!(chars(&typed(&mut keymap, &[0x08, 0x21])).eq("é€".chars()));
+    }
+
+    #[test]
+    fn dead_keys() {
+        let mut keymap = Keymap::new(&LAYOUT_FR);
+        // '^' then 'e', shift + '^' then 'i'
+        assert!(chars(&typed(&mut keymap, &[0x2f, 0x08])).eq("ê".chars()));
+        modifiers(&mut keymap, 0x02);
+        assert!(typed(&mut keymap, &[0x2f]).is_empty());
+        modifiers(&mut keymap, 0);
+        assert!(chars(&typed(&mut keymap, &[0x0c])).eq("ï".chars()));
+        // alone before space, before itself, or before a character it doesn't combine with
+        assert!(chars(&typed(&mut keymap, &[0x2f, 0x2c])).eq("^".chars()));
+        assert!(chars(&typed(&mut keymap, &[0x2f, 0x2f])).eq("^".chars()));
+        assert!(chars(&typed(&mut keymap, &[0x2f, 0x05])).eq("^b".chars()));
+        // named keys cancel it
+        assert_eq!(typed(&mut keymap, &[0x2f, 0x2a])[..], [Key::Named(NamedKey::Backspace)]);
+        assert!(chars(&typed(&mut keymap, &[0x08])).eq("e".chars()));
+
+        keymap.set_layout(&LAYOUT_DE);
+        modifiers(&mut keymap, 0x02);
+        assert!(typed(&mut keymap, &[0x2e]).is_empty());
+        assert!(chars(&typed(&mut keymap, &[0x08])).eq("È".chars()));
+    }
+
+    #[test]
+    fn custom_layout() {
+        static DVORAK_ISH: Layout = Layout {
+            name: "custom",
+            levels: ["axje", "AXJE", "", ""],
+            dead_keys: &[DeadKey {
+                usage: 0x07,
+                level: Level::AltGr,
+                combos: &[('a', 'å')],
+            }],
+        };
+        let mut keymap = Keymap::new(&DVORAK_ISH);
+        assert_eq!(keymap.layout().name, "custom");
+        // keys past the end of the table type nothing
+        assert!(chars(&typed(&mut keymap, &[0x05, 0x06, 0x08])).eq("xj".chars()));
+        modifiers(&mut keymap, 0x40);
+        assert!(typed(&mut keymap, &[0x07]).is_empty());
+        assert!(chars(&typed(&mut keymap, &[0x04])).eq("å".chars()));
+    }
+
+    #[test]
+    fn typematic_repeat() {
+        let mut keymap = Keymap::new(&LAYOUT_US).with_repeat(500, 30);
+        keymap.handle(
+            KeyEvent::Pressed {
+                address: address(),
+                usage: 0x04,
+            },
+            1000,
+        );
+        assert!(!keymap.poll(1000).unwrap().repeat);
+        assert_eq!(keymap.poll(1499), None);
+        let repeat = keymap.poll(1500).unwrap();
+        assert_eq!((repeat.key, repeat.repeat), (Key::Char('a'), true));
+        assert_eq!(keymap.poll(1529), None);
+        assert!(keymap.poll(1530).is_some());
+        // polled late, one repeat at a time
+        assert!(keymap.poll(2000).is_some());
+        assert_eq!(keymap.poll(2000), None);
+        // shift pressed while held applies to repeats
+        modifiers(&mut keymap, 0x02);
+        assert_eq!(keymap.poll(2030).unwrap().key, Key::Char('A'));
+        keymap.handle(
+            KeyEvent::Released {
+                address: address(),
+                usage: 0x04,
+            },
+            2040,
+        );
+        assert_eq!(keymap.poll(3000), None);
+
+        // lock keys don't repeat, nor keys of a keymap without repeat
+        keymap.handle(
+            KeyEvent::Pressed {
+                address: address(),
+                usage: 0x39,
+            },
+            0,
+        );
+        assert!(keymap.poll(0).is_some());
+        assert_eq!(keymap.poll(1000), None);
+        let mut keymap = Keymap::new(&LAYOUT_US).without_repeat();
+        keymap.handle(
+            KeyEvent::Pressed {
+                address: address(),
+                usage: 0x04,
+            },
+            0,
+        );
+        assert!(keymap.poll(0).is_some());
+        assert_eq!(keymap.poll(1000), None);
+    }
+}
@@ -1,8 +1,10 @@
+use core::task::Poll;
 use heapless::{FnvIndexMap, Vec};
 
 use crate::{
     map_entry_mut, BulkEndpoint, ConfigNum, DescriptorParser, DescriptorRef, DevAddress, Device, DeviceClass,
-    Direction, Driver, Endpoint, EndpointProperties, EpProps, InterfaceNum, MaxPacketSize, UsbError, UsbHost,
+    Direction, Driver, Endpoint, EndpointProperties, EpProps, HostError, InterfaceNum, MaxPacketSize, TransferHandle,
+    TransferType, UsbError, UsbHost, TRANSFER_TYPE_MASK,
 };
 use embedded_midi::{MidiPorts, PacketParser, PortHandle, PortId, PortInfo};
 
@@ -13,9 +15,6 @@ use crate::class::audio::AudioDescriptorRef;
 // its address. cf §9.2.6.3 of USB 2.0
 // const SETTLE_DELAY: u64 = 2;
 
-// How many total devices this driver can support.
-const MAX_MIDI_DEVICES: usize = 16;
-
 // Max number of endpoints per device.
 // 2 is the minimum for duplex devices
 const MAX_ENDPOINTS_PER_DEV: usize = 2;
@@ -23,31 +22,43 @@ const MAX_ENDPOINTS_PER_DEV: usize = 2;
 // Max number of jacks per endpoint
 const MAX_JACKS_PER_ENDPOINT: usize = 4;
 
-const MAX_ENDPOINTS: usize = MAX_MIDI_DEVICES * MAX_ENDPOINTS_PER_DEV;
-
 pub const USB_MIDI_PACKET_LEN: usize = 4;
 
+// Bytes requested per IN transfer
+const INGRESS_LEN: u16 = 64;
+
 type JackId = u8;
 
-pub struct UsbMidiDriver {
+/// MIDI driver for USB hosts.
+/// Supports up to `DEVICES` devices with `ENDPOINTS` endpoints in total, both powers of two from 2.
+/// Duplex devices use two endpoints each.
+pub struct UsbMidiDriver<const DEVICES: usize = 16, const ENDPOINTS: usize = 32> {
     /// Application MIDI ports registry
     with_midi: fn(&mut dyn FnMut(&mut (dyn MidiPorts + Send + Sync))),
 
     /// Keep track of endpoints for each device
-    device_endpoints: FnvIndexMap<DevAddress, Vec<Endpoint, MAX_ENDPOINTS_PER_DEV>, MAX_MIDI_DEVICES>,
+    device_endpoints: FnvIndexMap<DevAddress, Vec<Endpoint, MAX_ENDPOINTS_PER_DEV>, DEVICES>,
 
     /// Keep track of jacks & ports for each endpoint
-    ep_jack_port: FnvIndexMap<EpProps, FnvIndexMap<JackId, PortHandle, MAX_JACKS_PER_ENDPOINT>, MAX_ENDPOINTS>,
+    ep_jack_port: FnvIndexMap<EpProps, FnvIndexMap<JackId, PortHandle, MAX_JACKS_PER_ENDPOINT>, ENDPOINTS>,
+
+    /// IN transfers submitted but not yet completed
+    pending_in: FnvIndexMap<EpProps, TransferHandle, ENDPOINTS>,
+
+    /// Transfers of unregistered devices, cancelled on next run
+    orphans: Vec<TransferHandle, ENDPOINTS>,
 
     next_port_id: usize,
 }
 
-impl UsbMidiDriver {
+impl<const DEVICES: usize, const ENDPOINTS: usize> UsbMidiDriver<DEVICES, ENDPOINTS> {
     pub fn new(midi_ports: fn(&mut dyn FnMut(&mut (dyn MidiPorts + Send + Sync)))) -> Self {
         UsbMidiDriver {
             with_midi: midi_ports,
             device_endpoints: FnvIndexMap::new(),
             ep_jack_port: FnvIndexMap::new(),
+            pending_in: FnvIndexMap::new(),
+            orphans: Vec::new(),
             next_port_id: 0,
         }
     }
@@ -77,12 +88,44 @@ impl UsbMidiDriver {
     }
 
     /// Received packets are dispatched to ports according to their cable_num / jack_id
+    /// An IN transfer is kept pending across runs so that an idle (NAKing) device does not block the stack,
+    /// hosts that can't submit transfers receive with a blocking transfer instead
     fn midi_endpoint_ingress(
         host: &mut dyn UsbHost, midi: &mut (dyn MidiPorts + Send + Sync), endpoint: &mut Endpoint,
         jack_port: &mut FnvIndexMap<JackId, PortHandle, 4>,
+        pending_in: &mut FnvIndexMap<EpProps, TransferHandle, ENDPOINTS>,
     ) {
-        let mut buf = [0; 64];
-        match endpoint.bulk_in(host, &mut buf) {
+        let props = endpoint.ep_props();
+        let handle = match pending_in.get(&props) {
+            Some(handle) => Some(*handle),
+            None => match endpoint.bulk_in_submit(host, INGRESS_LEN) {
+                Ok(handle) => {
+                    if pending_in.insert(props, handle).is_err() {
+                        warn!("Too many pending MIDI transfers");
+                        host.cancel(handle);
+                        return;
+                    }
+                    Some(handle)
+                }
+                Err(UsbError::BulkIn(_, HostError::InvalidRequest)) => None,
+                Err(_e) => {
+                    warn!("USB MIDI IN submit failed {:?}", _e);
+                    return;
+                }
+            },
+        };
+        let mut buf = [0; INGRESS_LEN as usize];
+        let result = match handle {
+            Some(handle) => match endpoint.bulk_poll(host, handle, &mut buf) {
+                Poll::Pending => return,
+                Poll::Ready(result) => {
+                    pending_in.remove(&props);
+                    result
+                }
+            },
+            None => endpoint.bulk_in(host, &mut buf),
+        };
+        match result {
             Ok(0) => {
                 debug!("USB MIDI Zero bytes in")
             }
@@ -145,7 +188,7 @@ impl From<Direction> for embedded_midi::PortDirection {
     }
 }
 
-impl Driver for UsbMidiDriver {
+impl<const DEVICES: usize, const ENDPOINTS: usize> Driver for UsbMidiDriver<DEVICES, ENDPOINTS> {
     fn name(&self) -> &str {
         "Midi"
     }
@@ -158,8 +201,8 @@ impl Driver for UsbMidiDriver {
 
         while let Some(desc) = parser.next() {
             match desc {
-                DescriptorRef::Configuration(cdesc) => config = Some(cdesc),
-                DescriptorRef::Interface(idesc) => {
+                Ok(DescriptorRef::Configuration(cdesc)) => config = Some(cdesc),
+                Ok(DescriptorRef::Interface(idesc)) => {
                     if idesc.b_interface_class == DeviceClass::Audio as u8
                         && idesc.b_interface_sub_class == AudioSubclass::MidiStream as u8
                     {
@@ -188,8 +231,12 @@ impl Driver for UsbMidiDriver {
         let mut ep_out: Option<EpProps> = None;
 
         let dev_addr = device.device_address();
+        let interrupt_as_bulk = device.quirks().interrupt_as_bulk;
 
-        let mut register_ep = |dev_addr, max_packet_size: u16, b_endpoint_address: u8, bm_attributes: u8| {
+        let mut register_ep = |dev_addr, max_packet_size: u16, b_endpoint_address: u8, mut bm_attributes: u8| {
+            if interrupt_as_bulk && bm_attributes & TRANSFER_TYPE_MASK == TransferType::Interrupt as u8 {
+                bm_attributes = (bm_attributes & !TRANSFER_TYPE_MASK) | TransferType::Bulk as u8;
+            }
             let new_ep = Endpoint::from_raw(dev_addr, max_packet_size, b_endpoint_address, bm_attributes);
             if let Some(prev_ep) = match new_ep.direction() {
                 Direction::Out => ep_out.replace(new_ep.ep_props().clone()),
@@ -209,10 +256,10 @@ impl Driver for UsbMidiDriver {
         // phase 1 - identify interface and endpoints
         while let Some(desc) = parser.next() {
             match desc {
-                DescriptorRef::Endpoint(edesc) => {
+                Ok(DescriptorRef::Endpoint(edesc)) => {
                     register_ep(dev_addr, edesc.max_packet_size(), edesc.b_endpoint_address, edesc.bm_attributes)
                 }
-                DescriptorRef::Audio1Endpoint(edesc) => {
+                Ok(DescriptorRef::Audio1Endpoint(edesc)) => {
                     register_ep(dev_addr, edesc.max_packet_size(), edesc.b_endpoint_address, edesc.bm_attributes)
                 }
                 _ => {}
@@ -223,14 +270,14 @@ impl Driver for UsbMidiDriver {
         parser.rewind();
         while let Some(desc) = parser.next() {
             match desc {
-                DescriptorRef::Audio(AudioDescriptorRef::MSOutJack(out_jack)) => {
+                Ok(DescriptorRef::Audio(AudioDescriptorRef::MSOutJack(out_jack, _))) => {
                     if out_jack.b_jack_type == JackType::Embedded as u8 {
                         if let Some(ep_out) = ep_out {
                             self.register_port(&ep_out, out_jack.b_jack_id)
                         }
                     }
                 }
-                DescriptorRef::Audio(AudioDescriptorRef::MSInJack(in_jack)) => {
+                Ok(DescriptorRef::Audio(AudioDescriptorRef::MSInJack(in_jack))) => {
                     if in_jack.b_jack_type == JackType::Embedded as u8 {
                         if let Some(ep_in) = ep_in {
                             self.register_port(&ep_in, in_jack.b_jack_id)
@@ -246,6 +293,11 @@ impl Driver for UsbMidiDriver {
     fn unregister(&mut self, address: DevAddress) {
         if let Some(endpoints) = self.device_endpoints.remove(&address) {
             for ep in endpoints {
+                if let Some(handle) = self.pending_in.remove(&ep.ep_props()) {
+                    if self.orphans.push(handle).is_err() {
+                        warn!("Too many orphan MIDI transfers")
+                    }
+                }
                 if let Some(jack_handle) = self.ep_jack_port.remove(&ep.ep_props()) {
                     for handle in jack_handle.values() {
                         (self.with_midi)(&mut |midi: &mut (dyn MidiPorts + Send + Sync)| midi.release_port(handle))
@@ -256,6 +308,9 @@ impl Driver for UsbMidiDriver {
     }
 
     fn run(&mut self, host: &mut dyn UsbHost, device: &mut Device) -> Result<(), UsbError> {
+        while let Some(handle) = self.orphans.pop() {
+            host.cancel(handle)
+        }
         (self.with_midi)(&mut |midi: &mut (dyn MidiPorts + Send + Sync)| {
             for endpoint in self
                 .device_endpoints
@@ -266,7 +321,9 @@ impl Driver for UsbMidiDriver {
                 if let Some(jack_port) = self.ep_jack_port.get_mut(&endpoint.ep_props()) {
                     match endpoint.direction() {
                         Direction::Out => Self::midi_endpoint_egress(host, midi, endpoint, jack_port),
-                        Direction::In => Self::midi_endpoint_ingress(host, midi, endpoint, jack_port),
+                        Direction::In => {
+                            Self::midi_endpoint_ingress(host, midi, endpoint, jack_port, &mut self.pending_in)
+                        }
                     }
                 }
             }
@@ -276,25 +333,145 @@ impl Driver for UsbMidiDriver {
     }
 }
 
-// /* Setup for well known vendor/device specific configuration */
-// void USBH_MIDI::setupDeviceSpecific()
-// {
-//         // Novation
-//         if( vid == 0x1235 ) {
-//                 // LaunchPad and LaunchKey endpoint attribute is interrupt
-//                 // https://github.com/YuuichiAkagawa/USBH_MIDI/wiki/Novation-USB-Product-ID-List
-//
-//                 // LaunchPad: 0x20:S, 0x36:Mini, 0x51:Pro, 0x69:MK2
-//                 if( pid == 0x20 || pid == 0x36 || pid == 0x51 || pid == 0x69 ) {
-//                         bTransferTypeMask = 2;
-//                         return;
-//                 }
-//
-//                 // LaunchKey: 0x30-32,  0x35:Mini, 0x7B-0x7D:MK2, 0x0102,0x113-0x122:MiniMk3, 0x134-0x137:MK3
-//                 if( (0x30 <= pid && pid <= 0x32) || pid == 0x35 || (0x7B <= pid && pid <= 0x7D)
-//                   || pid == 0x102 || (0x113 <= pid && pid <= 0x122) || (0x134 <= pid && pid <= 0x137) ) {
-//                         bTransferTypeMask = 2;
-//                         return;
-//                 }
-//         }
-// }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::{SimDevice, SimHost, SimResponse};
+    use crate::{ConfigurationBuilder, HostEndpoint, HostEvent, RequestType, Speed, WValue};
+    use embedded_midi::{MidiError, Packet};
+
+    /// Host without transfer submission
+    struct BlockingSim(SimHost);
+
+    impl UsbHost for BlockingSim {
+        fn update(&mut self) -> Option<HostEvent> {
+            self.0.update()
+        }
+
+        fn max_host_packet_size(&self) -> u16 {
+            self.0.max_host_packet_size()
+        }
+
+        fn now(&self) -> u64 {
+            self.0.now()
+        }
+
+        fn after_millis(&self, millis: u64) -> u64 {
+            self.0.after_millis(millis)
+        }
+
+        fn control_transfer(
+            &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+            w_index: u16, buf: Option<&mut [u8]>,
+        ) -> Result<usize, HostError> {
+            self.0.control_transfer(ep, bm_request_type, b_request, w_value, w_index, buf)
+        }
+
+        fn in_transfer(&mut self, ep: &mut dyn HostEndpoint, buf: &mut [u8]) -> Result<usize, HostError> {
+            self.0.in_transfer(ep, buf)
+        }
+
+        fn out_transfer(&mut self, ep: &mut dyn HostEndpoint, buf: &[u8]) -> Result<usize, HostError> {
+            self.0.out_transfer(ep, buf)
+        }
+    }
+
+    /// No application ports, received packets are dropped
+    struct NoPorts;
+
+    impl MidiPorts for NoPorts {
+        fn acquire_port(&mut self, _info: PortInfo) -> Result<PortHandle, MidiError> {
+            unreachable!()
+        }
+
+        fn release_port(&mut self, _handle: &PortHandle) {}
+
+        fn write(&mut self, _handle: &PortHandle, _packet: Packet) -> Result<(), MidiError> {
+            unreachable!()
+        }
+
+        fn read(&mut self, _handle: &PortHandle) -> Result<Option<Packet>, MidiError> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn ingress_without_submit() {
+        let config = ConfigurationBuilder::<128>::new(1)
+            .interface(0, 0, DeviceClass::Audio as u8, AudioSubclass::MidiStream as u8, 0)
+            .ms_header()
+            .endpoint(0x81, 0x02, 64, 0)
+            .build()
+            .unwrap();
+        let mut host = BlockingSim(SimHost::new());
+        host.0.attach(SimDevice::new(&[0; 18]).with_configuration(&config));
+        while host.update() != Some(HostEvent::Ready) {}
+        let sim_ep = host.0.device_mut().unwrap().endpoint(0x81).unwrap();
+        sim_ep.push(SimResponse::data(&[0x09, 0x90, 0x40, 0x7f]));
+
+        let mut endpoint = Endpoint::from_raw(0.into(), 64, 0x81, TransferType::Bulk as u8);
+        let mut pending_in = FnvIndexMap::new();
+        UsbMidiDriver::<16, 32>::midi_endpoint_ingress(
+            &mut host,
+            &mut NoPorts,
+            &mut endpoint,
+            &mut FnvIndexMap::new(),
+            &mut pending_in,
+        );
+        assert!(pending_in.is_empty());
+        assert_eq!(host.0.device_mut().unwrap().endpoint(0x81).unwrap().pending(), 0);
+    }
+
+    #[test]
+    fn midi_streaming_interface() {
+        // audio control interface, then MIDI streaming with one embedded jack each way
+        let config = ConfigurationBuilder::<128>::new(2)
+            .interface(0, 0, DeviceClass::Audio as u8, AudioSubclass::AudioControl as u8, 0)
+            .ac_header(0x0100, 0)
+            .interface(1, 0, DeviceClass::Audio as u8, AudioSubclass::MidiStream as u8, 0)
+            .ms_header()
+            .ms_in_jack(JackType::Embedded, 1)
+            .ms_in_jack(JackType::External, 2)
+            .ms_out_jack(JackType::Embedded, 3, &[(2, 1)])
+            .ms_out_jack(JackType::External, 4, &[(1, 1)])
+            .endpoint(0x02, 0x02, 64, 0)
+            .ms_endpoint(&[1])
+            .endpoint(0x81, 0x02, 64, 0)
+            .ms_endpoint(&[3])
+            .build()
+            .unwrap();
+
+        let mut driver: UsbMidiDriver = UsbMidiDriver::new(|_| {});
+        let mut device = Device::new(Speed::Full);
+        let accepted = driver.accept(&mut device, &mut DescriptorParser::new(&config));
+        assert_eq!(accepted, Some((DeviceClass::Audio, 2, 1)));
+
+        driver.register(&mut device, &mut DescriptorParser::new(&config)).unwrap();
+        let endpoints = driver.device_endpoints.get(&device.device_address()).unwrap();
+        let addresses: Vec<u8, 2> = endpoints.iter().map(|ep| ep.endpoint_address().into()).collect();
+        assert_eq!(addresses, [0x02, 0x81]);
+    }
+
+    #[test]
+    fn novation_interrupt_endpoints() {
+        let config = ConfigurationBuilder::<128>::new(1)
+            .interface(0, 0, DeviceClass::Audio as u8, AudioSubclass::MidiStream as u8, 0)
+            .ms_header()
+            .ms_in_jack(JackType::Embedded, 1)
+            .ms_out_jack(JackType::Embedded, 2, &[(1, 1)])
+            .endpoint(0x02, TransferType::Interrupt as u8, 64, 1)
+            .ms_endpoint(&[1])
+            .endpoint(0x81, TransferType::Interrupt as u8, 64, 1)
+            .ms_endpoint(&[2])
+            .build()
+            .unwrap();
+
+        let mut driver: UsbMidiDriver = UsbMidiDriver::new(|_| {});
+        let mut device = Device::new(Speed::Full);
+        // LaunchPad MK2
+        device.quirks_found(crate::find_quirks(&[], 0x1235, 0x69));
+        driver.register(&mut device, &mut DescriptorParser::new(&config)).unwrap();
+        let endpoints = driver.device_endpoints.get(&device.device_address()).unwrap();
+        assert!(endpoints.iter().all(|ep| ep.transfer_type() == TransferType::Bulk));
+    }
+}
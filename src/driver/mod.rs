@@ -1,4 +1,7 @@
+mod hub;
 pub mod keyboard;
+pub mod keymap;
 pub mod midi;
 
+pub use hub::*;
 pub use midi::*;
@@ -1,4 +1,8 @@
-use crate::{DevAddress, Direction, MaxPacketSize, TransferType, UsbError, UsbHost};
+use crate::{
+    ControlEndpoint, DevAddress, Direction, HostError, MaxPacketSize, TransferHandle, TransferRequest, TransferType,
+    UsbError, UsbHost,
+};
+use core::task::Poll;
 
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -6,6 +10,8 @@ pub struct Endpoint {
     props: EpProps,
     max_packet_len: u16,
     toggle: bool,
+    /// Polling interval from the endpoint descriptor, see `Speed::interval_ms`
+    b_interval: u8,
 }
 
 impl Endpoint {
@@ -20,9 +26,18 @@ impl Endpoint {
             },
             max_packet_len: max_packet_size,
             toggle: false,
+            b_interval: 0,
         }
     }
 
+    pub fn b_interval(&self) -> u8 {
+        self.b_interval
+    }
+
+    pub fn set_interval(&mut self, b_interval: u8) {
+        self.b_interval = b_interval
+    }
+
     pub fn set_max_packet_size(&mut self, size: u16) {
         self.max_packet_len = size
     }
@@ -181,8 +196,8 @@ pub trait BulkEndpoint: HostEndpoint + Sized {
         if self.direction() != Direction::In {
             return Err(UsbError::DirectionMismatch);
         }
-        host.in_transfer(self as &mut dyn HostEndpoint, buffer)
-            .map_err(|err| UsbError::BulkIn(self.ep_props(), err))
+        let result = host.in_transfer(self as &mut dyn HostEndpoint, buffer);
+        result.map_err(|err| UsbError::BulkIn(self.ep_props(), recover_halt(host, self, err)))
     }
 
     fn bulk_out(&mut self, host: &mut dyn UsbHost, buffer: &[u8]) -> Result<usize, UsbError> {
@@ -192,9 +207,45 @@ pub trait BulkEndpoint: HostEndpoint + Sized {
         if self.direction() != Direction::Out {
             return Err(UsbError::DirectionMismatch);
         }
-        host.out_transfer(self, buffer)
+        let result = host.out_transfer(self, buffer);
+        result.map_err(|err| UsbError::BulkOut(self.ep_props(), recover_halt(host, self, err)))
+    }
+
+    /// Start receiving up to `len` bytes without waiting for the device, see `bulk_poll`
+    fn bulk_in_submit(&mut self, host: &mut dyn UsbHost, len: u16) -> Result<TransferHandle, UsbError> {
+        if self.transfer_type() != TransferType::Bulk {
+            return Err(UsbError::TransferTypeMismatch);
+        }
+        if self.direction() != Direction::In {
+            return Err(UsbError::DirectionMismatch);
+        }
+        host.submit(self, TransferRequest::In(len))
+            .map_err(|err| UsbError::BulkIn(self.ep_props(), err))
+    }
+
+    /// Start sending `buffer` without waiting for the device, see `bulk_poll`
+    fn bulk_out_submit(&mut self, host: &mut dyn UsbHost, buffer: &[u8]) -> Result<TransferHandle, UsbError> {
+        if self.transfer_type() != TransferType::Bulk {
+            return Err(UsbError::TransferTypeMismatch);
+        }
+        if self.direction() != Direction::Out {
+            return Err(UsbError::DirectionMismatch);
+        }
+        host.submit(self, TransferRequest::Out(buffer))
             .map_err(|err| UsbError::BulkOut(self.ep_props(), err))
     }
+
+    /// Check on a submitted bulk transfer, received data goes to `buffer`
+    fn bulk_poll(
+        &mut self, host: &mut dyn UsbHost, handle: TransferHandle, buffer: &mut [u8],
+    ) -> Poll<Result<usize, UsbError>> {
+        let props = self.ep_props();
+        let result = host.poll(self, handle, buffer);
+        result.map_err(|err| match (props.direction(), recover_halt(host, self, err)) {
+            (Direction::In, err) => UsbError::BulkIn(props, err),
+            (Direction::Out, err) => UsbError::BulkOut(props, err),
+        })
+    }
 }
 
 impl BulkEndpoint for Endpoint {}
@@ -207,11 +258,90 @@ pub trait InterruptEndpoint: HostEndpoint + Sized {
         if self.direction() != Direction::In {
             return Err(UsbError::DirectionMismatch);
         }
-        host.in_transfer(self as &mut dyn HostEndpoint, buffer)
+        let result = host.in_transfer(self as &mut dyn HostEndpoint, buffer);
+        result.map_err(|err| UsbError::Interrupt(self.ep_props(), recover_halt(host, self, err)))
+    }
+
+    /// Start receiving up to `len` bytes without waiting for the device, see `interrupt_poll`
+    fn interrupt_in_submit(&mut self, host: &mut dyn UsbHost, len: u16) -> Result<TransferHandle, UsbError> {
+        if self.transfer_type() != TransferType::Interrupt {
+            return Err(UsbError::TransferTypeMismatch);
+        }
+        if self.direction() != Direction::In {
+            return Err(UsbError::DirectionMismatch);
+        }
+        host.submit(self, TransferRequest::In(len))
             .map_err(|err| UsbError::Interrupt(self.ep_props(), err))
     }
+
+    /// Check on a submitted interrupt transfer, received data goes to `buffer`
+    fn interrupt_poll(
+        &mut self, host: &mut dyn UsbHost, handle: TransferHandle, buffer: &mut [u8],
+    ) -> Poll<Result<usize, UsbError>> {
+        let props = self.ep_props();
+        let result = host.poll(self, handle, buffer);
+        result.map_err(|err| UsbError::Interrupt(props, recover_halt(host, self, err)))
+    }
 }
 
 impl InterruptEndpoint for Endpoint {}
 
+/// Isochronous transactions are scheduled for a frame, no handshake and no retry.
+/// Data that did not make it is simply lost, each packet reports its own status.
+pub trait IsochronousEndpoint: HostEndpoint + Sized {
+    /// Receive up to `len` bytes during frame `frame`, see `iso_poll`
+    fn iso_in_submit(&mut self, host: &mut dyn UsbHost, frame: u16, len: u16) -> Result<TransferHandle, UsbError> {
+        if self.transfer_type() != TransferType::Isochronous {
+            return Err(UsbError::TransferTypeMismatch);
+        }
+        if self.direction() != Direction::In {
+            return Err(UsbError::DirectionMismatch);
+        }
+        host.submit_iso(self, frame, TransferRequest::In(len))
+            .map_err(|err| UsbError::Isochronous(self.ep_props(), err))
+    }
+
+    /// Send `buffer` during frame `frame`, see `iso_poll`
+    fn iso_out_submit(
+        &mut self, host: &mut dyn UsbHost, frame: u16, buffer: &[u8],
+    ) -> Result<TransferHandle, UsbError> {
+        if self.transfer_type() != TransferType::Isochronous {
+            return Err(UsbError::TransferTypeMismatch);
+        }
+        if self.direction() != Direction::Out {
+            return Err(UsbError::DirectionMismatch);
+        }
+        host.submit_iso(self, frame, TransferRequest::Out(buffer))
+            .map_err(|err| UsbError::Isochronous(self.ep_props(), err))
+    }
+
+    /// Check on a scheduled packet, received data goes to `buffer`
+    fn iso_poll(
+        &mut self, host: &mut dyn UsbHost, handle: TransferHandle, buffer: &mut [u8],
+    ) -> Poll<Result<usize, UsbError>> {
+        let props = self.ep_props();
+        host.poll(self, handle, buffer).map_err(|err| UsbError::Isochronous(props, err))
+    }
+}
+
+impl IsochronousEndpoint for Endpoint {}
+
+/// A stalled bulk or interrupt endpoint stays halted until the host clears it.
+/// Clear the halt and reset the data toggle so the next transfer can go through,
+/// the stall is still reported for the failed transfer.
+fn recover_halt(host: &mut dyn UsbHost, ep: &mut dyn HostEndpoint, err: HostError) -> HostError {
+    if err == HostError::Stall {
+        // CLEAR_FEATURE has no data stage, the smallest packet size fits any device
+        let mut ep0 = Endpoint::from_raw(ep.device_address(), 8, 0, TransferType::Control as u8);
+        match ep0.clear_halt(host, ep) {
+            Ok(()) => debug!("USB Ep: {:?} halt cleared", ep.endpoint_address()),
+            Err(err) => warn!("USB Ep: {:?} halt not cleared {:?}", ep.endpoint_address(), err),
+        }
+    }
+    err
+}
+
+/// Control transfers to the default endpoint, for requests that do not need the `Device`
+impl ControlEndpoint for Endpoint {}
+
 pub trait HostEndpoint: DataToggle + MaxPacketSize + EndpointProperties {}
@@ -1,22 +1,131 @@
-use crate::{HostEndpoint, HostError, RequestCode, RequestType, WValue};
+use crate::{HostEndpoint, HostError, RequestType, SetupPacket, WValue};
+use core::task::Poll;
 
-#[derive(Debug)]
+pub type PortNum = u8;
+
+#[derive(Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum HostEvent {
     Reset,
     Ready,
 }
 
+/// Link speed of a device, as detected on its (root or hub) port.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Speed {
+    Low,
+    Full,
+    High,
+}
+
+impl Speed {
+    /// Packet size to use on endpoint 0 until the device descriptor tells otherwise
+    pub fn default_max_packet_size(&self) -> u16 {
+        match self {
+            Speed::Low => 8,
+            Speed::Full | Speed::High => 64,
+        }
+    }
+
+    /// Milliseconds between two polls of an interrupt endpoint with this `b_interval`, at least 1.
+    /// cf §9.6.6 of USB 2.0
+    pub fn interval_ms(&self, b_interval: u8) -> u64 {
+        match self {
+            Speed::Low | Speed::Full => b_interval.max(1) as u64,
+            // 2^(b_interval-1) microframes of 125µs
+            Speed::High => ((1u64 << (b_interval.clamp(1, 16) - 1)) / 8).max(1),
+        }
+    }
+}
+
+/// Downstream port changes reported by hub drivers to the stack.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PortChange {
+    /// Port is being reset, its device will soon answer on the default address
+    Reset(PortNum),
+    /// Port reset done, device is waiting at the default address
+    Attached(PortNum, Speed),
+    /// Device was unplugged (or port disabled)
+    Detached(PortNum),
+}
+
+/// Frame numbers count SOF packets modulo 2048. cf §8.4.3 of USB 2.0
+pub const FRAME_NUMBER_MASK: u16 = 0x7ff;
+
+/// Frame number `count` frames after `frame`
+pub fn frame_add(frame: u16, count: u16) -> u16 {
+    frame.wrapping_add(count) & FRAME_NUMBER_MASK
+}
+
+/// True if `frame` comes after `reference`, looking at most half the frame counter ahead
+pub fn frame_after(frame: u16, reference: u16) -> bool {
+    let ahead = frame.wrapping_sub(reference) & FRAME_NUMBER_MASK;
+    ahead != 0 && ahead <= FRAME_NUMBER_MASK / 2
+}
+
+/// Largest data stage sent by the default `UsbHost::control_out`
+pub const MAX_CONTROL_OUT_LEN: usize = 64;
+
+/// Largest payload of a transfer submitted with `UsbHost::submit`
+pub const MAX_SUBMIT_LEN: usize = 64;
+
+/// Largest isochronous packet submitted with `UsbHost::submit_iso`, the full speed wMaxPacketSize limit
+pub const MAX_ISO_LEN: usize = 1023;
+
+/// Handle to a transfer submitted with `UsbHost::submit`, valid until the transfer completes or is cancelled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TransferHandle(u8);
+
+impl From<u8> for TransferHandle {
+    fn from(handle: u8) -> Self {
+        TransferHandle(handle)
+    }
+}
+
+impl From<TransferHandle> for u8 {
+    fn from(handle: TransferHandle) -> Self {
+        handle.0
+    }
+}
+
+/// A transfer to be submitted without waiting for its completion.
+/// OUT data is copied by the host on submission, up to `MAX_SUBMIT_LEN` bytes.
+#[derive(Clone, Copy, Debug)]
+pub enum TransferRequest<'a> {
+    /// Control transfer. Data stage direction and length come from the setup packet,
+    /// OUT data stage is taken from the slice.
+    Control(SetupPacket, &'a [u8]),
+    /// Receive up to this many bytes from the endpoint
+    In(u16),
+    /// Send bytes to the endpoint
+    Out(&'a [u8]),
+}
+
 /// Trait for host controller interface.
 pub trait UsbHost {
     /// Perform endpoint upkeep, read / write operations
     fn update(&mut self) -> Option<HostEvent>;
 
+    /// Reset the root port again, `update` then reports `HostEvent::Ready` when the bus has settled.
+    /// False if the host cannot do it.
+    fn reset_bus(&mut self) -> bool {
+        false
+    }
+
     /// Get the current connection max packet size
     /// This depends on negotiated USB link speed
     /// Endpoints may specify smaller packet sizes
     fn max_host_packet_size(&self) -> u16;
 
+    /// Link speed of the device on the root port, known once `update` reported `HostEvent::Ready`.
+    /// Hosts supporting low or high speed devices must tell, others use this default.
+    fn root_port_speed(&self) -> Speed {
+        Speed::Full
+    }
+
     fn now(&self) -> u64;
 
     /// Get current time in milliseconds
@@ -33,10 +142,36 @@ pub trait UsbHost {
     ///
     /// On success, the amount of data transferred into `buf` is returned.
     fn control_transfer(
-        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: RequestCode, w_value: WValue,
+        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
         w_index: u16, buf: Option<&mut [u8]>,
     ) -> Result<usize, HostError>;
 
+    /// Issue a host to device control transfer with `data` as its data stage.
+    /// Hosts that only send from a mutable buffer use this default, which copies up to `MAX_CONTROL_OUT_LEN` bytes.
+    fn control_out(
+        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, data: &[u8],
+    ) -> Result<usize, HostError> {
+        let mut buf = [0u8; MAX_CONTROL_OUT_LEN];
+        let buf = buf.get_mut(..data.len()).ok_or(HostError::InvalidRequest)?;
+        buf.copy_from_slice(data);
+        self.control_transfer(ep, bm_request_type, b_request, w_value, w_index, Some(buf))
+    }
+
+    /// Issue a `w_length` bytes control IN transfer, handing the data stage to `sink` piece by piece.
+    /// Each piece is received into `buf`, which must hold at least one packet.
+    /// Hosts that can't split the data stage use this default, which needs `buf` to hold everything.
+    #[allow(clippy::too_many_arguments)]
+    fn control_in_stream(
+        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, w_length: u16, buf: &mut [u8], sink: &mut dyn FnMut(&[u8]),
+    ) -> Result<usize, HostError> {
+        let buf = buf.get_mut(..w_length as usize).ok_or(HostError::InvalidRequest)?;
+        let len = self.control_transfer(ep, bm_request_type, b_request, w_value, w_index, Some(buf))?;
+        sink(&buf[..len]);
+        Ok(len)
+    }
+
     /// Issue a transfer from `ep` to the host.
     /// On success, the amount of data transferred into `buf` is returned.
     fn in_transfer(&mut self, ep: &mut dyn HostEndpoint, buf: &mut [u8]) -> Result<usize, HostError>;
@@ -45,4 +180,37 @@ pub trait UsbHost {
     /// On success, the amount of data transferred from `buf` is returned.
     /// This should always be equal to `buf.len()`.
     fn out_transfer(&mut self, ep: &mut dyn HostEndpoint, buf: &[u8]) -> Result<usize, HostError>;
+
+    /// Start a transfer on `ep` and return immediately.
+    /// The transfer makes progress every time it is polled. A NAKing endpoint keeps it pending.
+    /// Hosts that can't keep transfers in flight refuse them.
+    fn submit(&mut self, _ep: &mut dyn HostEndpoint, _request: TransferRequest) -> Result<TransferHandle, HostError> {
+        Err(HostError::InvalidRequest)
+    }
+
+    /// Check on a submitted transfer.
+    /// Once done, IN data is copied into `buf`, the amount of data transferred is returned and the handle is released.
+    fn poll(
+        &mut self, _ep: &mut dyn HostEndpoint, _handle: TransferHandle, _buf: &mut [u8],
+    ) -> Poll<Result<usize, HostError>> {
+        Poll::Ready(Err(HostError::InvalidRequest))
+    }
+
+    /// Abandon a submitted transfer, releasing its handle
+    fn cancel(&mut self, _handle: TransferHandle) {}
+
+    /// Number of the current frame, None if the host does not count frames
+    fn frame_number(&self) -> Option<u16> {
+        None
+    }
+
+    /// Schedule a single isochronous transaction on `ep` during frame `frame`, checked on with `poll`.
+    /// Isochronous transactions are not retried: the transfer fails with `HostError::FrameMissed`
+    /// if `frame` had already begun on submission.
+    /// Only `In` and `Out` requests are allowed, of up to the endpoint's max packet size and `MAX_ISO_LEN` bytes.
+    fn submit_iso(
+        &mut self, _ep: &mut dyn HostEndpoint, _frame: u16, _request: TransferRequest,
+    ) -> Result<TransferHandle, HostError> {
+        Err(HostError::InvalidRequest)
+    }
 }
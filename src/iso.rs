@@ -0,0 +1,270 @@
+//! Isochronous streams, packets scheduled ahead one frame slot at a time.
+//!
+//! Isochronous endpoints carry time sensitive data such as audio: every packet is due
+//! in a given frame and is lost rather than retried if anything goes wrong.
+
+use crate::{
+    frame_add, frame_after, EndpointProperties, HostError, IsochronousEndpoint, TransferHandle, UsbError, UsbHost,
+};
+use core::task::Poll;
+use heapless::Deque;
+
+/// Frames between now and the first packet of a stream, so that the host has time to set it up
+pub const ISO_LEAD_FRAMES: u16 = 2;
+
+/// Outcome of one isochronous packet
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct IsoPacket {
+    /// Frame the packet was scheduled in
+    pub frame: u16,
+    /// Bytes transferred, or why the packet was lost
+    pub result: Result<usize, UsbError>,
+}
+
+struct IsoSlot {
+    frame: u16,
+    handle: TransferHandle,
+}
+
+/// Packets in flight on an isochronous endpoint, one slot per scheduled frame.
+/// Packets are `interval` frames apart and complete in order.
+/// A stream that fell behind starts over `ISO_LEAD_FRAMES` ahead of the current frame.
+pub struct IsoSlots<const SLOTS: usize> {
+    slots: Deque<IsoSlot, SLOTS>,
+    interval: u16,
+    /// Frame following the last scheduled packet
+    next_frame: Option<u16>,
+}
+
+impl<const SLOTS: usize> IsoSlots<SLOTS> {
+    pub fn new(interval: u16) -> Self {
+        Self {
+            slots: Deque::new(),
+            interval: interval.max(1),
+            next_frame: None,
+        }
+    }
+
+    /// Packets scheduled and not yet polled to completion
+    pub fn in_flight(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.slots.is_full()
+    }
+
+    /// Fill the free slots with IN packets of up to `len` bytes, returns how many were scheduled
+    pub fn submit_in<E: IsochronousEndpoint>(
+        &mut self, host: &mut dyn UsbHost, ep: &mut E, len: u16,
+    ) -> Result<usize, UsbError> {
+        let current = current_frame(host, ep)?;
+        let mut count = 0;
+        while !self.slots.is_full() {
+            let frame = self.schedule(current);
+            let handle = ep.iso_in_submit(host, frame, len)?;
+            let _ = self.slots.push_back(IsoSlot { frame, handle });
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Schedule `data` in the next free slot, false if all slots are taken
+    pub fn submit_out<E: IsochronousEndpoint>(
+        &mut self, host: &mut dyn UsbHost, ep: &mut E, data: &[u8],
+    ) -> Result<bool, UsbError> {
+        if self.slots.is_full() {
+            return Ok(false);
+        }
+        let frame = self.schedule(current_frame(host, ep)?);
+        let handle = ep.iso_out_submit(host, frame, data)?;
+        let _ = self.slots.push_back(IsoSlot { frame, handle });
+        Ok(true)
+    }
+
+    /// Outcome of the oldest packet once its frame is over, IN data goes to `buffer`.
+    /// None while that packet is still due or if nothing is in flight.
+    pub fn poll<E: IsochronousEndpoint>(
+        &mut self, host: &mut dyn UsbHost, ep: &mut E, buffer: &mut [u8],
+    ) -> Option<IsoPacket> {
+        let slot = self.slots.front()?;
+        let frame = slot.frame;
+        let result = match ep.iso_poll(host, slot.handle, buffer) {
+            Poll::Pending => return None,
+            Poll::Ready(result) => result,
+        };
+        self.slots.pop_front();
+        Some(IsoPacket { frame, result })
+    }
+
+    /// Abandon the packets in flight, the stream starts over on the next submission
+    pub fn cancel(&mut self, host: &mut dyn UsbHost) {
+        while let Some(slot) = self.slots.pop_front() {
+            host.cancel(slot.handle);
+        }
+        self.next_frame = None;
+    }
+
+    /// Frame of the next packet, right after the last scheduled one unless that is too late
+    fn schedule(&mut self, current: u16) -> u16 {
+        let frame = match self.next_frame {
+            Some(frame) if frame_after(frame, current) => frame,
+            _ => frame_add(current, ISO_LEAD_FRAMES),
+        };
+        self.next_frame = Some(frame_add(frame, self.interval));
+        frame
+    }
+}
+
+fn current_frame(host: &dyn UsbHost, ep: &dyn EndpointProperties) -> Result<u16, UsbError> {
+    host.frame_number()
+        .ok_or(UsbError::Isochronous(ep.ep_props(), HostError::InvalidRequest))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::{SimDevice, SimHost, SimResponse};
+    use crate::{DataToggle, Endpoint, HostEvent, FRAME_NUMBER_MASK};
+
+    const DEV_DESC: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x08, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+    ];
+
+    const CONF_DESC: [u8; 36] = [
+        0x09, 0x02, 0x24, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+        0x09, 0x04, 0x01, 0x01, 0x02, 0x01, 0x02, 0x00, 0x00, // audio streaming interface
+        0x09, 0x05, 0x81, 0x05, 0x40, 0x00, 0x01, 0x00, 0x00, // asynchronous isochronous IN
+        0x09, 0x05, 0x02, 0x09, 0x40, 0x00, 0x01, 0x00, 0x00, // adaptive isochronous OUT
+    ];
+
+    fn connected() -> SimHost {
+        let mut host = SimHost::new();
+        host.attach(SimDevice::new(&DEV_DESC).with_configuration(&CONF_DESC));
+        while host.update() != Some(HostEvent::Ready) {}
+        host
+    }
+
+    #[test]
+    fn frame_arithmetic() {
+        assert_eq!(frame_add(FRAME_NUMBER_MASK, 2), 1);
+        assert!(frame_after(1, FRAME_NUMBER_MASK));
+        assert!(!frame_after(FRAME_NUMBER_MASK, 1));
+        assert!(!frame_after(5, 5));
+    }
+
+    #[test]
+    fn iso_in_per_frame() {
+        let mut host = connected();
+        let sim_ep = host.device_mut().unwrap().endpoint(0x81).unwrap();
+        sim_ep.push(SimResponse::data(&[1, 2]));
+        sim_ep.push(SimResponse::Timeout);
+        sim_ep.push(SimResponse::data(&[3]));
+
+        let mut ep = Endpoint::from_raw(0.into(), 64, 0x81, 0x05);
+        let mut slots: IsoSlots<4> = IsoSlots::new(1);
+        let first = host.frame_number().unwrap() + ISO_LEAD_FRAMES;
+        assert_eq!(slots.submit_in(&mut host, &mut ep, 64), Ok(4));
+        assert!(slots.is_full());
+
+        let mut buf = [0u8; 64];
+        // nothing happens before the first scheduled frame
+        assert_eq!(slots.poll(&mut host, &mut ep, &mut buf), None);
+        host.advance_millis(ISO_LEAD_FRAMES as u64);
+        let packet = slots.poll(&mut host, &mut ep, &mut buf).unwrap();
+        assert_eq!(
+            packet,
+            IsoPacket {
+                frame: first,
+                result: Ok(2)
+            }
+        );
+        assert_eq!(buf[..2], [1, 2]);
+        assert_eq!(slots.poll(&mut host, &mut ep, &mut buf), None);
+
+        host.advance_millis(3);
+        let packet = slots.poll(&mut host, &mut ep, &mut buf).unwrap();
+        assert_eq!(packet.frame, first + 1);
+        assert!(matches!(packet.result, Err(UsbError::Isochronous(_, HostError::HardTimeout))));
+        assert_eq!(slots.poll(&mut host, &mut ep, &mut buf).unwrap().result, Ok(1));
+        // an idle endpoint sends empty packets
+        assert_eq!(slots.poll(&mut host, &mut ep, &mut buf).unwrap().result, Ok(0));
+        assert_eq!(slots.in_flight(), 0);
+        // no data toggle on isochronous endpoints
+        assert!(!ep.toggle());
+    }
+
+    #[test]
+    fn iso_out_catches_up() {
+        let mut host = connected();
+        let mut ep = Endpoint::from_raw(0.into(), 64, 0x02, 0x09);
+        let mut slots: IsoSlots<2> = IsoSlots::new(1);
+        assert_eq!(slots.submit_out(&mut host, &mut ep, &[1]), Ok(true));
+        assert_eq!(slots.submit_out(&mut host, &mut ep, &[2]), Ok(true));
+        assert_eq!(slots.submit_out(&mut host, &mut ep, &[3]), Ok(false));
+
+        host.advance_millis(10);
+        assert_eq!(slots.poll(&mut host, &mut ep, &mut []).unwrap().result, Ok(1));
+        assert_eq!(slots.poll(&mut host, &mut ep, &mut []).unwrap().result, Ok(1));
+        let sim_ep = host.device_mut().unwrap().endpoint(0x02).unwrap();
+        assert_eq!(sim_ep.take_received().unwrap(), [1]);
+        assert_eq!(sim_ep.take_received().unwrap(), [2]);
+
+        // the stream fell behind, it starts over ahead of the current frame
+        let now = host.frame_number().unwrap();
+        assert_eq!(slots.submit_out(&mut host, &mut ep, &[3]), Ok(true));
+        host.advance_millis(ISO_LEAD_FRAMES as u64);
+        let packet = slots.poll(&mut host, &mut ep, &mut []).unwrap();
+        assert_eq!(
+            packet,
+            IsoPacket {
+                frame: now + ISO_LEAD_FRAMES,
+                result: Ok(1)
+            }
+        );
+    }
+
+    #[test]
+    fn iso_large_packets() {
+        let mut host = connected();
+        let data = [0x5a; 96];
+        let sim_ep = host.device_mut().unwrap().endpoint(0x81).unwrap();
+        sim_ep.push(SimResponse::data(&data));
+
+        // 48 kHz mono, 16 bits per sample
+        let mut ep_in = Endpoint::from_raw(0.into(), 96, 0x81, 0x05);
+        let mut ep_out = Endpoint::from_raw(0.into(), 96, 0x02, 0x09);
+        let frame = host.frame_number().unwrap() + 1;
+        let in_handle = ep_in.iso_in_submit(&mut host, frame, 96).unwrap();
+        let out_handle = ep_out.iso_out_submit(&mut host, frame, &data).unwrap();
+        assert!(matches!(
+            ep_in.iso_in_submit(&mut host, frame, 97),
+            Err(UsbError::Isochronous(_, HostError::InvalidRequest))
+        ));
+
+        host.advance_millis(1);
+        let mut buf = [0u8; 96];
+        assert_eq!(ep_in.iso_poll(&mut host, in_handle, &mut buf), Poll::Ready(Ok(96)));
+        assert_eq!(buf, data);
+        assert_eq!(ep_out.iso_poll(&mut host, out_handle, &mut []), Poll::Ready(Ok(96)));
+        let sim_ep = host.device_mut().unwrap().endpoint(0x02).unwrap();
+        assert_eq!(sim_ep.take_received().unwrap(), data);
+    }
+
+    #[test]
+    fn iso_late_submission() {
+        let mut host = connected();
+        let mut ep = Endpoint::from_raw(0.into(), 64, 0x81, 0x05);
+        let now = host.frame_number().unwrap();
+        let handle = ep.iso_in_submit(&mut host, now, 64).unwrap();
+        host.advance_millis(1);
+        assert!(matches!(
+            ep.iso_poll(&mut host, handle, &mut []),
+            Poll::Ready(Err(UsbError::Isochronous(_, HostError::FrameMissed)))
+        ));
+
+        let mut bulk = Endpoint::from_raw(0.into(), 64, 0x81, 0x02);
+        assert_eq!(bulk.iso_in_submit(&mut host, now, 64), Err(UsbError::TransferTypeMismatch));
+    }
+}
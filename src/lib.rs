@@ -21,6 +21,7 @@ extern crate log;
 extern crate static_assertions;
 
 pub mod address;
+pub mod asynch;
 pub mod class;
 pub mod control;
 pub mod descriptor;
@@ -28,7 +29,9 @@ pub mod device;
 pub mod driver;
 pub mod endpoint;
 pub mod host;
+pub mod iso;
 pub mod parser;
+pub mod quirks;
 pub mod stack;
 
 #[cfg(feature = "atsamd")]
@@ -37,6 +40,9 @@ pub mod atsamd;
 #[cfg(feature = "stm32")]
 pub mod stm32;
 
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+
 use core::hash::Hash;
 pub use address::*;
 pub use class::*;
@@ -48,7 +54,9 @@ pub use driver::*;
 pub use endpoint::*;
 use heapless::FnvIndexMap;
 pub use host::*;
+pub use iso::*;
 pub use parser::*;
+pub use quirks::*;
 pub use stack::*;
 
 /// Errors that can be generated when attempting to do a USB transfer.
@@ -61,17 +69,24 @@ pub enum UsbError {
     InvalidConfig,
     TransferTypeMismatch,
     DirectionMismatch,
-    Control(DevAddress, RequestType, RequestCode, HostError),
+    /// Control request failed, with its raw `b_request` as class requests have their own codes
+    Control(DevAddress, RequestType, u8, HostError),
     BulkIn(EpProps, HostError),
     BulkOut(EpProps, HostError),
     Interrupt(EpProps, HostError),
+    Isochronous(EpProps, HostError),
     InvalidDescriptor,
+    /// Device has no string for this index
+    NoString,
     Driver,
     NoDriver,
     OutOfRange,
     TooManyDrivers,
     TooManyDevices,
     TooManyEndpoints,
+    TooManyQuirks,
+    /// Low speed device on a hub port, the host can't send the preamble its transfers need
+    LowSpeedBehindHub,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -91,6 +106,8 @@ pub enum HostError {
     DataPid,
     SoftTimeout,
     HardTimeout,
+    // isochronous transaction submitted too late for its frame
+    FrameMissed,
 }
 
 /// The type of transfer to use when talking to USB devices.
@@ -123,6 +140,7 @@ pub enum Direction {
 
 pub type ConfigNum = u8;
 pub type InterfaceNum = u8;
+pub type AltSetting = u8;
 
 pub trait MaxPacketSize {
     fn max_packet_size(&self) -> u16;
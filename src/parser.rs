@@ -1,9 +1,15 @@
+use core::cmp::min;
+use core::mem;
+use core::ops::Range;
 use utf16string::{WStr, LE};
 
 use crate::class::audio::AudioDescriptorRef;
+use crate::class::hid::HidDescriptor;
 use crate::class::{audio, DeviceClass, DeviceSubclass};
 use crate::descriptor::{ConfigurationDescriptor, DescriptorType, EndpointDescriptor, InterfaceDescriptor};
-use crate::{Audio1EndpointDescriptor, DeviceDescriptor, InterfaceAssociationDescriptor};
+use crate::{
+    AltSetting, Audio1EndpointDescriptor, DeviceDescriptor, InterfaceAssociationDescriptor, InterfaceNum, UsbError,
+};
 
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -18,6 +24,7 @@ pub enum DescriptorRef<'a> {
     InterfaceAssociation(&'a InterfaceAssociationDescriptor),
 
     Audio(AudioDescriptorRef<'a>),
+    Hid(&'a HidDescriptor),
 
     UnknownClassInterface(&'a [u8]),
     UnknownClassEndpoint(&'a [u8]),
@@ -30,108 +37,760 @@ pub struct DescriptorParser<'a> {
     pos: usize,
     class: Option<DeviceClass>,
     subclass: Option<DeviceSubclass>,
+    interface: Option<(InterfaceNum, AltSetting)>,
+    /// Class of the last interface descriptor read, whatever its subclass
+    interface_class: u8,
+    /// Parsing jumps from `header` to `start`, to see a configuration descriptor and one of its interface groups
+    header: usize,
+    start: usize,
+    end: usize,
+}
+
+/// Why a descriptor could not be parsed
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParseError {
+    /// Length byte below 2, descriptors after it can't be found
+    InvalidLength(u8),
+    /// Descriptor of this length goes past the end of the buffer
+    Truncated(u8),
+    /// Descriptor too short for its type, skipped
+    TooShort { desc_type: u8, len: u8 },
+    /// String descriptor is not UTF-16, skipped
+    InvalidString,
+    /// Another type of descriptor, or none, where this one was expected
+    Expected(DescriptorType),
+}
+
+impl From<ParseError> for UsbError {
+    fn from(_: ParseError) -> Self {
+        UsbError::InvalidDescriptor
+    }
+}
+
+/// View the descriptor structure `T` at the start of `desc`, if long enough.
+///
+/// # Safety
+/// `T` must be `repr(C)` with an alignment of 1, and every enum field of `T` must have been checked
+/// to hold a valid value in `desc`.
+pub(crate) unsafe fn view<T>(desc: &[u8]) -> Result<&T, ParseError> {
+    const { core::assert!(mem::align_of::<T>() == 1, "descriptor structures must be packed") };
+    if desc.len() < mem::size_of::<T>() {
+        return Err(too_short(desc));
+    }
+    Ok(&*(desc.as_ptr() as *const T))
+}
+
+/// View the fixed header `T` of a variable length descriptor, along with the rest of the descriptor up to its length.
+///
+/// # Safety
+/// Same as `view`.
+pub(crate) unsafe fn view_tail<T>(desc: &[u8]) -> Result<(&T, &[u8]), ParseError> {
+    let desc = &desc[..min(desc[0] as usize, desc.len())];
+    let header = view(desc)?;
+    Ok((header, &desc[mem::size_of::<T>()..]))
+}
+
+/// Descriptor too short for what its header announces
+pub(crate) fn too_short(desc: &[u8]) -> ParseError {
+    ParseError::TooShort {
+        desc_type: desc[1],
+        len: desc[0],
+    }
 }
 
 impl<'a> Iterator for DescriptorParser<'a> {
-    type Item = DescriptorRef<'a>;
+    type Item = Result<DescriptorRef<'a>, ParseError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.pos >= self.buf.len() {
+        if self.pos == self.header {
+            self.pos = self.start;
+        }
+        if self.pos >= self.end {
             // we're done here
             return None;
         }
 
         let desc_len = self.buf[self.pos] as usize;
-        if desc_len == 0 {
-            warn!("Invalid descriptor of len 0");
-            return None;
+        if desc_len < 2 {
+            warn!("Invalid descriptor of len {}", desc_len);
+            self.pos = self.end;
+            return Some(Err(ParseError::InvalidLength(desc_len as u8)));
         }
 
-        if self.pos + desc_len > self.buf.len() {
+        if self.pos + desc_len > self.end {
             warn!("truncated descriptor of len {}", desc_len);
-            return None;
+            self.pos = self.end;
+            return Some(Err(ParseError::Truncated(desc_len as u8)));
+        }
+
+        let desc = &self.buf[self.pos..self.pos + desc_len];
+        // advance to next descriptor, malformed ones are skipped
+        self.pos += desc_len;
+
+        let desc_ref = self.parse(desc);
+        if let Err(err) = desc_ref {
+            warn!("Skipped invalid descriptor: {:?}", err);
         }
+        Some(desc_ref)
+    }
+}
 
-        let desc_next = self.pos + desc_len;
+impl<'a> DescriptorParser<'a> {
+    // TODO earlier DeviceDesc might provide class and subclass instead of interfaces
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            pos: 0,
+            class: None,
+            subclass: None,
+            interface: None,
+            interface_class: 0,
+            header: 0,
+            start: 0,
+            end: buf.len(),
+        }
+    }
 
-        let desc_type: u8 = self.buf[self.pos + 1];
-        let desc_offset = unsafe { self.buf.as_ptr().add(self.pos as usize) };
-        let body_offset = unsafe { self.buf.as_ptr().add((self.pos + 2) as usize) };
+    /// Configuration descriptor at the start of `buf` followed by the group at `group`
+    fn with_group(buf: &'a [u8], header: usize, group: Range<usize>) -> Self {
+        Self {
+            header,
+            start: group.start,
+            end: group.end,
+            ..Self::new(buf)
+        }
+    }
 
-        let desc_ref = match DescriptorType::from_repr(desc_type) {
-            Some(DescriptorType::Device) => Some(DescriptorRef::Device(unsafe { &*(desc_offset as *const _) })),
-            Some(DescriptorType::Configuration) => {
-                Some(DescriptorRef::Configuration(unsafe { &*(desc_offset as *const _) }))
+    /// Descriptor `desc`, of valid length
+    fn parse(&mut self, desc: &'a [u8]) -> Result<DescriptorRef<'a>, ParseError> {
+        let desc_type = DescriptorType::from_repr(desc[1]);
+        // SAFETY: descriptor structures are packed, their type is checked before viewing them
+        let desc_ref = match desc_type {
+            Some(DescriptorType::Device) => DescriptorRef::Device(unsafe { view(desc)? }),
+            Some(DescriptorType::Configuration) => DescriptorRef::Configuration(unsafe { view(desc)? }),
+            Some(DescriptorType::String) => {
+                DescriptorRef::String(WStr::from_utf16le(&desc[2..]).map_err(|_| ParseError::InvalidString)?)
             }
-            Some(DescriptorType::String) => Some(DescriptorRef::String(unsafe {
-                WStr::from_utf16le_unchecked(core::slice::from_raw_parts(
-                    body_offset as *const _,
-                    (desc_len - 2) as usize,
-                ))
-            })),
             Some(DescriptorType::Interface) => {
-                let ifdesc: &InterfaceDescriptor = unsafe { &*(desc_offset as *const _) };
+                let ifdesc: &InterfaceDescriptor = unsafe { view(desc)? };
+                self.interface = Some((ifdesc.b_interface_number, ifdesc.b_alternate_setting));
+                self.interface_class = ifdesc.b_interface_class;
                 if ifdesc.b_interface_class != 0 && ifdesc.b_interface_sub_class != 0 {
                     self.class = DeviceClass::from_repr(ifdesc.b_interface_class);
                     self.subclass = Some(ifdesc.b_interface_sub_class);
                 }
-                Some(DescriptorRef::Interface(ifdesc))
-            }
-            Some(DescriptorType::Endpoint) => match desc_len {
-                7 => Some(DescriptorRef::Endpoint(unsafe { &*(desc_offset as *const _) })),
-                9 => Some(DescriptorRef::Audio1Endpoint(unsafe { &*(desc_offset as *const _) })),
-                _ => {
-                    warn!("Invalid endpoint descriptor of size {}", desc_len);
-                    None
-                }
-            },
-            Some(DescriptorType::InterfaceAssociation) => {
-                Some(DescriptorRef::InterfaceAssociation(unsafe { &*(desc_offset as *const _) }))
+                DescriptorRef::Interface(ifdesc)
             }
-
-            Some(DescriptorType::ClassInterface) if self.class == Some(DeviceClass::Audio) => {
-                Some(DescriptorRef::Audio(audio::parse(
-                    self.subclass,
-                    DescriptorType::ClassInterface,
-                    &self.buf[self.pos..desc_next],
-                )))
-            }
-            Some(DescriptorType::ClassEndpoint) if self.class == Some(DeviceClass::Audio) => {
-                Some(DescriptorRef::Audio(audio::parse(
-                    self.subclass,
-                    DescriptorType::ClassEndpoint,
-                    &self.buf[self.pos..desc_next],
-                )))
+            Some(DescriptorType::Endpoint) if desc.len() == mem::size_of::<Audio1EndpointDescriptor>() => {
+                DescriptorRef::Audio1Endpoint(unsafe { view(desc)? })
             }
+            Some(DescriptorType::Endpoint) => DescriptorRef::Endpoint(unsafe { view(desc)? }),
+            Some(DescriptorType::InterfaceAssociation) => DescriptorRef::InterfaceAssociation(unsafe { view(desc)? }),
 
-            Some(DescriptorType::ClassInterface) => {
-                Some(DescriptorRef::UnknownClassInterface(&self.buf[self.pos..desc_next]))
+            Some(desc_type @ (DescriptorType::ClassInterface | DescriptorType::ClassEndpoint))
+                if self.class == Some(DeviceClass::Audio) =>
+            {
+                DescriptorRef::Audio(audio::parse(self.subclass, desc_type, desc)?)
             }
-            Some(DescriptorType::ClassEndpoint) => {
-                Some(DescriptorRef::UnknownClassEndpoint(&self.buf[self.pos..desc_next]))
+
+            Some(DescriptorType::Hid) if self.interface_class == DeviceClass::Hid as u8 => {
+                DescriptorRef::Hid(unsafe { view(desc)? })
             }
 
-            _ => Some(DescriptorRef::Unknown(&self.buf[self.pos..desc_next])),
+            Some(DescriptorType::ClassInterface) => DescriptorRef::UnknownClassInterface(desc),
+            Some(DescriptorType::ClassEndpoint) => DescriptorRef::UnknownClassEndpoint(desc),
+
+            _ => DescriptorRef::Unknown(desc),
         };
+        Ok(desc_ref)
+    }
+
+    pub fn rewind(&mut self) {
+        self.pos = 0;
+        self.interface = None;
+        self.interface_class = 0;
+    }
 
-        // advance to next descriptor
-        self.pos = desc_next;
-        return desc_ref;
+    /// Interface number and alternate setting of the last interface descriptor read
+    pub fn interface(&self) -> Option<(InterfaceNum, AltSetting)> {
+        self.interface
+    }
+
+    /// Endpoints of an interface's alternate setting, wherever the parser is
+    pub fn endpoints(&self, iface: InterfaceNum, alt: AltSetting) -> InterfaceEndpoints<'a> {
+        InterfaceEndpoints {
+            parser: DescriptorParser::with_group(self.buf, self.header, self.start..self.end),
+            interface: (iface, alt),
+        }
     }
 }
 
-impl<'a> DescriptorParser<'a> {
-    // TODO earlier DeviceDesc might provide class and subclass instead of interfaces
+/// Copy of the device descriptor at the start of `buf`
+pub fn parse_device_descriptor(buf: &[u8]) -> Result<DeviceDescriptor, ParseError> {
+    match DescriptorParser::new(buf).next() {
+        Some(Ok(DescriptorRef::Device(desc))) => Ok(*desc),
+        Some(Err(err)) => Err(err),
+        _ => Err(ParseError::Expected(DescriptorType::Device)),
+    }
+}
+
+/// Copy of the configuration descriptor at the start of `buf`, interfaces are not looked at
+pub fn parse_configuration_descriptor(buf: &[u8]) -> Result<ConfigurationDescriptor, ParseError> {
+    match DescriptorParser::new(buf).next() {
+        Some(Ok(DescriptorRef::Configuration(desc))) => Ok(*desc),
+        Some(Err(err)) => Err(err),
+        _ => Err(ParseError::Expected(DescriptorType::Configuration)),
+    }
+}
+
+pub struct InterfaceEndpoints<'a> {
+    parser: DescriptorParser<'a>,
+    interface: (InterfaceNum, AltSetting),
+}
+
+impl<'a> Iterator for InterfaceEndpoints<'a> {
+    type Item = &'a EndpointDescriptor;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(desc) = self.parser.next() {
+            if self.parser.interface() != Some(self.interface) {
+                continue;
+            }
+            match desc {
+                Ok(DescriptorRef::Endpoint(edesc)) => return Some(edesc),
+                Ok(DescriptorRef::Audio1Endpoint(edesc)) => return Some(edesc.as_endpoint()),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+/// Interface association and interface descriptors (but not alternate settings) start a new group.
+/// Interfaces part of an association are kept with it, `iad_left` counts them down.
+fn starts_group(desc: &[u8], iad_left: &mut u8) -> bool {
+    if desc.len() < 4 {
+        return false;
+    }
+    match DescriptorType::from_repr(desc[1]) {
+        Some(DescriptorType::InterfaceAssociation) => {
+            *iad_left = desc[3];
+            true
+        }
+        Some(DescriptorType::Interface) if desc[3] == 0 => {
+            if *iad_left > 0 {
+                *iad_left -= 1;
+                false
+            } else {
+                true
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Interface groups of a whole configuration descriptor, each parsed behind the configuration descriptor.
+/// A group is an interface with its alternate settings, class and endpoint descriptors,
+/// or all the interfaces of an association.
+pub struct InterfaceGroups<'a> {
+    buf: &'a [u8],
+    header: usize,
+    pos: usize,
+    iad_left: u8,
+}
+
+impl<'a> InterfaceGroups<'a> {
     pub fn new(buf: &'a [u8]) -> Self {
+        let header = match buf {
+            [len, ty, ..] if *ty == DescriptorType::Configuration as u8 && *len as usize <= buf.len() => *len as usize,
+            _ => {
+                warn!("Configuration descriptor expected");
+                buf.len()
+            }
+        };
         Self {
             buf,
-            pos: 0,
-            class: None,
-            subclass: None,
+            header,
+            pos: header,
+            iad_left: 0,
         }
     }
+}
 
-    pub fn rewind(&mut self) {
-        self.pos = 0;
+impl<'a> Iterator for InterfaceGroups<'a> {
+    type Item = DescriptorParser<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.pos;
+        while self.pos + 1 < self.buf.len() {
+            let desc_len = self.buf[self.pos] as usize;
+            if desc_len < 2 || self.pos + desc_len > self.buf.len() {
+                warn!("Invalid descriptor of len {}", desc_len);
+                break;
+            }
+            let desc = &self.buf[self.pos..self.pos + desc_len];
+            if self.pos > start && starts_group(desc, &mut self.iad_left) {
+                return Some(DescriptorParser::with_group(self.buf, self.header, start..self.pos));
+            }
+            if self.pos == start {
+                // group start, counted when first seen
+                starts_group(desc, &mut self.iad_left);
+            }
+            self.pos += desc_len;
+        }
+        self.pos = self.buf.len();
+        (start < self.pos).then(|| DescriptorParser::with_group(self.buf, self.header, start..self.pos))
+    }
+}
+
+/// Splits a configuration descriptor received in pieces into interface groups.
+/// Each group is handed over behind the configuration descriptor, so it can be parsed on its own.
+/// A group is an interface with its alternate settings, class and endpoint descriptors,
+/// or all the interfaces of an association.
+pub struct ConfigStream<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    /// Length of the configuration descriptor at the start of `buf`, 0 until received
+    header: usize,
+    /// Start of the descriptor being received
+    desc: usize,
+    /// Bytes left to drop of a descriptor too big for `buf`
+    skip: usize,
+    /// Interfaces left in the current association
+    iad_left: u8,
+    /// Current group did not fit in `buf`
+    overflow: bool,
+    /// A group was kept, the rest is ignored
+    kept: bool,
+    invalid: bool,
+}
+
+impl<const N: usize> Default for ConfigStream<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ConfigStream<N> {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            header: 0,
+            desc: 0,
+            skip: 0,
+            iad_left: 0,
+            overflow: false,
+            kept: false,
+            invalid: false,
+        }
+    }
+
+    /// Feed the next piece of the configuration descriptor.
+    /// `on_group` is called with each complete group and returns true to keep it, ending the stream.
+    pub fn push(&mut self, data: &[u8], on_group: &mut dyn FnMut(&[u8]) -> bool) {
+        for byte in data {
+            if self.kept || self.invalid {
+                return;
+            }
+            self.push_byte(*byte, on_group);
+        }
+    }
+
+    /// End of the configuration descriptor, hand over the last group
+    pub fn finish(&mut self, on_group: &mut dyn FnMut(&[u8]) -> bool) {
+        if !self.kept && !self.invalid && !self.overflow && self.desc > self.header && self.header > 0 {
+            self.len = self.desc;
+            self.kept = on_group(&self.buf[..self.len]);
+        }
+    }
+
+    /// The group `on_group` chose to keep
+    pub fn kept(&self) -> Option<&[u8]> {
+        self.kept.then(|| &self.buf[..self.len])
+    }
+
+    fn push_byte(&mut self, byte: u8, on_group: &mut dyn FnMut(&[u8]) -> bool) {
+        if self.skip == 0 && self.len == N {
+            self.make_room();
+        }
+        if self.skip > 0 {
+            self.skip -= 1;
+            return;
+        }
+        if self.invalid {
+            return;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        let desc_len = self.buf[self.desc] as usize;
+        if desc_len < 2 {
+            warn!("Invalid descriptor of len {}", desc_len);
+            self.invalid = true;
+            return;
+        }
+        let received = self.len - self.desc;
+        // interface number and alternate setting are enough to find group boundaries
+        if received == min(desc_len, 4) && self.header > 0 {
+            self.desc_start(on_group);
+        }
+        if !self.kept && self.len - self.desc == desc_len {
+            self.desc_done();
+        }
+    }
+
+    /// Out of buffer: drop the current group, keeping the descriptor being received
+    fn make_room(&mut self) {
+        if self.header == 0 {
+            warn!("Configuration descriptor does not fit in {} bytes", N);
+            self.invalid = true;
+            return;
+        }
+        if !self.overflow {
+            warn!("Interface descriptors do not fit in {} bytes", N);
+            self.overflow = true;
+        }
+        if self.desc == self.header {
+            // the descriptor alone fills the buffer
+            self.skip = self.buf[self.desc] as usize - (self.len - self.desc);
+            self.len = self.desc;
+        } else {
+            self.buf.copy_within(self.desc..self.len, self.header);
+            self.len = self.header + self.len - self.desc;
+            self.desc = self.header;
+        }
+    }
+
+    /// Hand over the current group if this descriptor starts a new one
+    fn desc_start(&mut self, on_group: &mut dyn FnMut(&[u8]) -> bool) {
+        let start = self.desc;
+        let desc = &self.buf[start..self.len];
+        let new_group = starts_group(desc, &mut self.iad_left);
+        if new_group {
+            if start > self.header && !self.overflow && on_group(&self.buf[..start]) {
+                self.kept = true;
+                self.len = start;
+                return;
+            }
+            self.buf.copy_within(start..self.len, self.header);
+            self.len = self.header + self.len - start;
+            self.desc = self.header;
+            self.overflow = false;
+        }
+    }
+
+    fn desc_done(&mut self) {
+        if self.header == 0 {
+            if self.buf[1] != DescriptorType::Configuration as u8 {
+                warn!("Configuration descriptor expected");
+                self.invalid = true;
+            }
+            self.header = self.len;
+        }
+        self.desc = self.len;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    const CONFIG: [u8; 9] = [0x09, 0x02, 0x00, 0x00, 0x04, 0x01, 0x00, 0x80, 0x32];
+    const HID_IFACE: [u8; 25] = [
+        0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x01, 0x00, // interface 0
+        0x09, 0x21, 0x10, 0x01, 0x00, 0x01, 0x22, 0x41, 0x00, // HID
+        0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0a, // interrupt IN
+    ];
+    const AUDIO_IAD: [u8; 35] = [
+        0x08, 0x0b, 0x01, 0x02, 0x01, 0x00, 0x00, 0x00, // association of interfaces 1 and 2
+        0x09, 0x04, 0x01, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00, // audio control
+        0x09, 0x04, 0x02, 0x00, 0x00, 0x01, 0x03, 0x00, 0x00, // midi streaming
+        0x09, 0x04, 0x02, 0x01, 0x00, 0x01, 0x03, 0x00, 0x00, // alternate setting
+    ];
+    const VENDOR_IFACE: [u8; 23] = [
+        0x09, 0x04, 0x03, 0x00, 0x02, 0xff, 0x00, 0x00, 0x00, // vendor interface
+        0x07, 0x05, 0x82, 0x02, 0x40, 0x00, 0x00, // bulk IN
+        0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, // bulk OUT
+    ];
+
+    fn config() -> Vec<u8> {
+        [&CONFIG[..], &HID_IFACE, &AUDIO_IAD, &VENDOR_IFACE].concat()
+    }
+
+    fn groups<const N: usize>(config: &[u8], piece: usize) -> Vec<Vec<u8>> {
+        let mut groups = Vec::new();
+        let mut stream: ConfigStream<N> = ConfigStream::new();
+        let mut collect = |group: &[u8]| {
+            groups.push(group.to_vec());
+            false
+        };
+        for data in config.chunks(piece) {
+            stream.push(data, &mut collect);
+        }
+        stream.finish(&mut collect);
+        groups
+    }
+
+    #[test]
+    fn endpoints_by_alternate_setting() {
+        let config = [&CONFIG[..], &HID_IFACE, &VENDOR_IFACE].concat();
+        let parser = DescriptorParser::new(&config);
+        let addrs = |iface, alt| parser.endpoints(iface, alt).map(|ep| ep.b_endpoint_address).collect::<Vec<_>>();
+        assert_eq!(addrs(0, 0), [0x81]);
+        assert_eq!(addrs(3, 0), [0x82, 0x02]);
+        assert!(addrs(3, 1).is_empty());
+        assert!(addrs(1, 0).is_empty());
+
+        let mut parser = DescriptorParser::new(&config);
+        let mut interfaces = Vec::new();
+        while let Some(desc) = parser.next() {
+            if let Ok(DescriptorRef::Endpoint(_)) = desc {
+                interfaces.push(parser.interface().unwrap());
+            }
+        }
+        assert_eq!(interfaces, [(0, 0), (3, 0), (3, 0)]);
+    }
+
+    #[test]
+    fn stream_interface_groups() {
+        let config = config();
+        for piece in 1..config.len() {
+            let groups = groups::<64>(&config, piece);
+            assert_eq!(groups.len(), 3, "piece {}", piece);
+            assert_eq!(groups[0], [&CONFIG[..], &HID_IFACE].concat());
+            assert_eq!(groups[1], [&CONFIG[..], &AUDIO_IAD].concat());
+            assert_eq!(groups[2], [&CONFIG[..], &VENDOR_IFACE].concat());
+        }
+    }
+
+    #[test]
+    fn whole_config_groups() {
+        let config = config();
+        let mut interfaces = Vec::new();
+        for (group_idx, mut group) in InterfaceGroups::new(&config).enumerate() {
+            assert!(matches!(group.next(), Some(Ok(DescriptorRef::Configuration(_)))));
+            let mut count = 1;
+            for desc in group.by_ref() {
+                count += 1;
+                if let Ok(DescriptorRef::Interface(idesc)) = desc {
+                    interfaces.push((group_idx, idesc.b_interface_number, idesc.b_alternate_setting));
+                }
+            }
+            group.rewind();
+            assert_eq!(group.count(), count);
+        }
+        assert_eq!(interfaces, [(0, 0, 0), (1, 1, 0), (1, 2, 0), (1, 2, 1), (2, 3, 0)]);
+    }
+
+    #[test]
+    fn stream_keeps_accepted_group() {
+        let mut stream: ConfigStream<64> = ConfigStream::new();
+        let mut offered = 0;
+        let mut accept = |group: &[u8]| {
+            offered += 1;
+            DescriptorParser::new(group).any(|desc| matches!(desc, Ok(DescriptorRef::InterfaceAssociation(_))))
+        };
+        for data in config().chunks(7) {
+            stream.push(data, &mut accept);
+        }
+        stream.finish(&mut accept);
+        assert_eq!(offered, 2);
+        assert_eq!(stream.kept().unwrap(), [&CONFIG[..], &AUDIO_IAD].concat());
+    }
+
+    #[test]
+    fn stream_drops_oversized_group() {
+        // audio group does not fit in 40 bytes
+        let groups = groups::<40>(&config(), 5);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0], [&CONFIG[..], &HID_IFACE].concat());
+        assert_eq!(groups[1], [&CONFIG[..], &VENDOR_IFACE].concat());
+    }
+
+    #[test]
+    fn stream_rejects_garbage() {
+        assert!(groups::<64>(&[0x09, 0x04, 0, 0, 0, 0, 0, 0, 0], 4).is_empty());
+        let mut config = config();
+        config[9] = 0;
+        assert!(groups::<64>(&config, 4).is_empty());
+    }
+
+    #[test]
+    fn skips_malformed_descriptors() {
+        let config = [
+            &CONFIG[..],
+            &[0x04, 0x05, 0x81, 0x03],       // endpoint too short
+            &[0x05, 0x03, 0x41, 0x00, 0x00], // string of odd length
+            &HID_IFACE,
+        ]
+        .concat();
+        let descs: Vec<_> = DescriptorParser::new(&config).collect();
+        assert_eq!(descs.len(), 6);
+        assert!(matches!(descs[0], Ok(DescriptorRef::Configuration(_))));
+        assert_eq!(
+            descs[1].as_ref().err(),
+            Some(&ParseError::TooShort {
+                desc_type: 0x05,
+                len: 4
+            })
+        );
+        assert_eq!(descs[2].as_ref().err(), Some(&ParseError::InvalidString));
+        assert!(matches!(descs[3], Ok(DescriptorRef::Interface(_))));
+        assert!(matches!(descs[5], Ok(DescriptorRef::Endpoint(edesc)) if edesc.b_endpoint_address == 0x81));
+    }
+
+    #[test]
+    fn stops_at_invalid_length() {
+        let mut config = config();
+        config[9] = 0;
+        let mut parser = DescriptorParser::new(&config);
+        assert!(matches!(parser.next(), Some(Ok(DescriptorRef::Configuration(_)))));
+        assert_eq!(parser.next().unwrap().err(), Some(ParseError::InvalidLength(0)));
+        assert!(parser.next().is_none());
+
+        let descs: Vec<_> = DescriptorParser::new(&HID_IFACE[..20]).collect();
+        assert_eq!(descs.len(), 3);
+        assert_eq!(descs[2].as_ref().err(), Some(&ParseError::Truncated(7)));
+    }
+
+    #[test]
+    fn single_descriptors() {
+        assert_eq!(parse_configuration_descriptor(&CONFIG).unwrap().b_num_interfaces, 4);
+        assert_eq!(
+            parse_device_descriptor(&CONFIG),
+            Err(ParseError::Expected(DescriptorType::Device))
+        );
+        assert_eq!(parse_device_descriptor(&[]), Err(ParseError::Expected(DescriptorType::Device)));
+        // device descriptors are 18 bytes long
+        let short = parse_device_descriptor(&[0x09, 0x01, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            short,
+            Err(ParseError::TooShort {
+                desc_type: 0x01,
+                len: 9
+            })
+        );
+    }
+
+    #[test]
+    fn variable_length_midi_descriptors() {
+        const MIDI_STREAM: [u8; 9] = [0x09, 0x04, 0x01, 0x00, 0x02, 0x01, 0x03, 0x00, 0x00];
+        let config = [
+            &MIDI_STREAM[..],
+            &[0x07, 0x24, 0x03, 0x01, 0x02, 0x00, 0x05], // out jack without input pins
+            &[0x0b, 0x24, 0x03, 0x01, 0x03, 0x02, 0x01, 0x01, 0x02, 0x01, 0x00],
+            &[0x09, 0x24, 0x03, 0x01, 0x04, 0x02, 0x01, 0x01, 0x00], // iJack missing
+            &[0x04, 0x25, 0x01, 0x00],                               // endpoint without embedded jacks
+            &[0x07, 0x25, 0x01, 0x03, 0x02, 0x03, 0x04],
+            &[0x05, 0x25, 0x01, 0x02, 0x02], // second jack ID missing
+        ]
+        .concat();
+        let descs: Vec<_> = DescriptorParser::new(&config).collect();
+        assert_eq!(descs.len(), 7);
+        match descs[1] {
+            Ok(DescriptorRef::Audio(AudioDescriptorRef::MSOutJack(jack, tail))) => {
+                assert_eq!(jack.sources(tail).count(), 0);
+                assert_eq!(jack.i_jack(tail), 5);
+            }
+            ref other => panic!("unexpected {:?}", other),
+        }
+        match descs[2] {
+            Ok(DescriptorRef::Audio(AudioDescriptorRef::MSOutJack(jack, tail))) => {
+                assert_eq!(jack.b_jack_id, 3);
+                assert!(jack.sources(tail).eq([(1, 1), (2, 1)]));
+            }
+            ref other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            descs[3].as_ref().err(),
+            Some(&ParseError::TooShort {
+                desc_type: 0x24,
+                len: 9
+            })
+        );
+        assert!(matches!(
+            descs[4],
+            Ok(DescriptorRef::Audio(AudioDescriptorRef::MSEndpoint(_, &[])))
+        ));
+        assert!(matches!(
+            descs[5],
+            Ok(DescriptorRef::Audio(AudioDescriptorRef::MSEndpoint(_, &[2, 3, 4])))
+        ));
+        assert_eq!(
+            descs[6].as_ref().err(),
+            Some(&ParseError::TooShort {
+                desc_type: 0x25,
+                len: 5
+            })
+        );
+    }
+
+    /// Deterministic xorshift, good enough to make up descriptors
+    struct Random(u32);
+
+    impl Random {
+        fn next(&mut self) -> u8 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 as u8
+        }
+
+        /// Descriptors of known types, with random lengths and contents
+        fn descriptors(&mut self, len: usize) -> Vec<u8> {
+            const TYPES: [u8; 10] = [0x01, 0x02, 0x03, 0x04, 0x05, 0x0b, 0x21, 0x24, 0x25, 0xff];
+            let mut buf = Vec::new();
+            while buf.len() < len {
+                let desc_len = self.next() % 24;
+                buf.push(desc_len);
+                buf.push(TYPES[self.next() as usize % TYPES.len()]);
+                // audio subtypes are small
+                buf.push(self.next() % 12);
+                for _ in 3..desc_len {
+                    buf.push(self.next());
+                }
+            }
+            buf.truncate(len);
+            buf
+        }
+    }
+
+    #[test]
+    fn random_bytes() {
+        // audio and HID interfaces, for class specific descriptors to be parsed too
+        const AUDIO_CONTROL: [u8; 9] = [0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00];
+        const MIDI_STREAM: [u8; 9] = [0x09, 0x04, 0x01, 0x00, 0x02, 0x01, 0x03, 0x00, 0x00];
+        const HID: [u8; 9] = [0x09, 0x04, 0x02, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00];
+        let mut random = Random(0x2545_f491);
+        for round in 0..5000 {
+            let len = random.next() as usize % 96;
+            let body = match round % 2 {
+                0 => (0..len).map(|_| random.next()).collect(),
+                _ => random.descriptors(len),
+            };
+            let iface: &[u8] = match round % 4 {
+                0 => &[],
+                1 => &AUDIO_CONTROL,
+                2 => &MIDI_STREAM,
+                _ => &HID,
+            };
+            let config = [&CONFIG[..], iface, &body].concat();
+
+            // every descriptor takes at least two bytes, parsing always ends
+            assert!(DescriptorParser::new(&config).count() <= config.len() / 2, "round {}", round);
+            assert!(DescriptorParser::new(&body).count() <= body.len() / 2 + 1, "round {}", round);
+            DescriptorParser::new(&config).endpoints(0, 0).for_each(drop);
+            let _ = parse_device_descriptor(&body);
+            let _ = parse_configuration_descriptor(&body);
+
+            for group in InterfaceGroups::new(&config) {
+                group.for_each(drop);
+            }
+            groups::<64>(&config, 1 + round % 7);
+        }
     }
 }
@@ -0,0 +1,115 @@
+//! Devices known to deviate from the USB specification, and how to work around them.
+//!
+//! Quirks are looked up by vendor and product id once the device descriptor is read,
+//! the stack and drivers then read them from the `Device`.
+
+/// Workarounds needed by a device, none by default
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Quirks {
+    /// Endpoints described as interrupt endpoints are to be used as bulk endpoints
+    pub interrupt_as_bulk: bool,
+    /// Extra wait after reading the device descriptor, before the device is configured
+    pub settle_delay_ms: u64,
+    /// String descriptors are missing or garbage, they are not requested
+    pub no_strings: bool,
+    /// HID SET_IDLE is stalled or upsets the device, it is not sent
+    pub no_set_idle: bool,
+    /// Endpoint 0 packet size to use instead of the one in the device descriptor
+    pub max_packet_size: Option<u16>,
+}
+
+impl Quirks {
+    pub const NONE: Quirks = Quirks {
+        interrupt_as_bulk: false,
+        settle_delay_ms: 0,
+        no_strings: false,
+        no_set_idle: false,
+        max_packet_size: None,
+    };
+}
+
+/// Quirks of the products of a vendor, from `first_product` to `last_product` included
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct QuirkEntry {
+    pub id_vendor: u16,
+    pub first_product: u16,
+    pub last_product: u16,
+    pub quirks: Quirks,
+}
+
+impl QuirkEntry {
+    /// Quirks of a single product
+    pub const fn product(id_vendor: u16, id_product: u16, quirks: Quirks) -> Self {
+        Self::products(id_vendor, id_product, id_product, quirks)
+    }
+
+    pub const fn products(id_vendor: u16, first_product: u16, last_product: u16, quirks: Quirks) -> Self {
+        Self {
+            id_vendor,
+            first_product,
+            last_product,
+            quirks,
+        }
+    }
+
+    pub fn matches(&self, id_vendor: u16, id_product: u16) -> bool {
+        self.id_vendor == id_vendor && (self.first_product..=self.last_product).contains(&id_product)
+    }
+}
+
+const NOVATION: u16 = 0x1235;
+
+/// Novation MIDI controllers describe their bulk endpoints as interrupt endpoints
+const NOVATION_QUIRKS: Quirks = Quirks {
+    interrupt_as_bulk: true,
+    ..Quirks::NONE
+};
+
+/// Quirks of known devices.
+/// Novation product ids from https://github.com/YuuichiAkagawa/USBH_MIDI/wiki/Novation-USB-Product-ID-List
+pub const BUILTIN_QUIRKS: &[QuirkEntry] = &[
+    // LaunchPad S, Mini, Pro and MK2
+    QuirkEntry::product(NOVATION, 0x20, NOVATION_QUIRKS),
+    QuirkEntry::product(NOVATION, 0x36, NOVATION_QUIRKS),
+    QuirkEntry::product(NOVATION, 0x51, NOVATION_QUIRKS),
+    QuirkEntry::product(NOVATION, 0x69, NOVATION_QUIRKS),
+    // LaunchKey, Mini, MK2, Mini MK3 and MK3
+    QuirkEntry::products(NOVATION, 0x30, 0x32, NOVATION_QUIRKS),
+    QuirkEntry::product(NOVATION, 0x35, NOVATION_QUIRKS),
+    QuirkEntry::products(NOVATION, 0x7b, 0x7d, NOVATION_QUIRKS),
+    QuirkEntry::product(NOVATION, 0x102, NOVATION_QUIRKS),
+    QuirkEntry::products(NOVATION, 0x113, 0x122, NOVATION_QUIRKS),
+    QuirkEntry::products(NOVATION, 0x134, 0x137, NOVATION_QUIRKS),
+];
+
+/// Quirks of a device, the first matching entry of `entries` wins over the built-in ones
+pub fn find_quirks(entries: &[QuirkEntry], id_vendor: u16, id_product: u16) -> Quirks {
+    entries
+        .iter()
+        .chain(BUILTIN_QUIRKS)
+        .find(|entry| entry.matches(id_vendor, id_product))
+        .map_or(Quirks::NONE, |entry| entry.quirks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn added_entries_first() {
+        // LaunchKey MK2
+        assert!(find_quirks(&[], NOVATION, 0x7c).interrupt_as_bulk);
+        assert_eq!(find_quirks(&[], NOVATION, 0x7e), Quirks::NONE);
+        assert_eq!(find_quirks(&[], 0x413c, 0x7c), Quirks::NONE);
+
+        let no_strings = Quirks {
+            no_strings: true,
+            ..Quirks::NONE
+        };
+        let entries = [QuirkEntry::products(NOVATION, 0x00, 0xff, no_strings)];
+        assert_eq!(find_quirks(&entries, NOVATION, 0x7c), no_strings);
+        assert!(find_quirks(&entries, NOVATION, 0x134).interrupt_as_bulk);
+    }
+}
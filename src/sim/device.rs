@@ -0,0 +1,713 @@
+use core::cmp::min;
+
+use heapless::{Deque, Vec};
+
+use crate::class::hub::{
+    PortFeature, C_PORT_CONNECTION, C_PORT_RESET, HUB_DESCRIPTOR_TYPE, PORT_CONNECTION, PORT_ENABLE, PORT_HIGH_SPEED,
+    PORT_LOW_SPEED, PORT_POWER, PORT_RESET,
+};
+use crate::{
+    DescriptorType, FeatureSelector, HostError, PortNum, RequestCode, RequestDirection, RequestKind, RequestRecipient,
+    RequestType, SetupPacket, Speed, WValue,
+};
+
+/// Largest configuration descriptor a simulated device can hold
+pub const SIM_DESC_LEN: usize = 512;
+
+/// Largest single transfer payload a scripted response can hold, isochronous packets can go past 64 bytes
+pub const SIM_PACKET_LEN: usize = 128;
+
+const SIM_MAX_CONFIGS: usize = 2;
+const SIM_MAX_STRINGS: usize = 8;
+const SIM_MAX_ENDPOINTS: usize = 8;
+const SIM_MAX_RESPONSES: usize = 8;
+const SIM_MAX_REQUESTS: usize = 64;
+const SIM_MAX_PORTS: usize = 7;
+const SIM_MAX_INTERFACES: usize = 8;
+const SIM_MAX_REPORT_DESCS: usize = 2;
+
+const HUB_DEV_DESC: [u8; 18] = [
+    0x12, 0x01, 0x00, 0x02, 0x09, 0x00, 0x00, 0x40, 0x09, 0x04, 0x4b, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+];
+
+const HUB_CONF_DESC: [u8; 25] = [
+    0x09, 0x02, 0x19, 0x00, 0x01, 0x01, 0x00, 0xe0, 0x00, // configuration
+    0x09, 0x04, 0x00, 0x00, 0x01, 0x09, 0x00, 0x00, 0x00, // hub interface
+    0x07, 0x05, 0x81, 0x03, 0x01, 0x00, 0xff, // status change endpoint
+];
+
+const HUB_STATUS_EP: u8 = 0x81;
+
+/// Scripted behavior of a simulated endpoint for a single transaction
+#[derive(Clone, Debug, PartialEq)]
+pub enum SimResponse {
+    /// IN: send this data. OUT: accept the data (content ignored)
+    Data(Vec<u8, SIM_PACKET_LEN>),
+    /// No data available (yet)
+    Nak,
+    /// Endpoint halts until the host clears the feature
+    Stall,
+    /// Device does not answer at all
+    Timeout,
+}
+
+impl SimResponse {
+    pub fn data(bytes: &[u8]) -> Self {
+        SimResponse::Data(Vec::from_slice(bytes).expect("Simulated packet too long"))
+    }
+
+    pub fn ack() -> Self {
+        SimResponse::Data(Vec::new())
+    }
+}
+
+/// Device side of a non-control endpoint
+pub struct SimEndpoint {
+    address: u8,
+    responses: Deque<SimResponse, SIM_MAX_RESPONSES>,
+    received: Deque<Vec<u8, SIM_PACKET_LEN>, SIM_MAX_RESPONSES>,
+    halted: bool,
+    toggle: bool,
+}
+
+impl SimEndpoint {
+    fn new(address: u8) -> Self {
+        Self {
+            address,
+            responses: Deque::new(),
+            received: Deque::new(),
+            halted: false,
+            toggle: false,
+        }
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Queue the response to the next transaction on this endpoint
+    pub fn push(&mut self, response: SimResponse) {
+        self.responses.push_back(response).expect("Too many simulated responses")
+    }
+
+    /// Number of queued responses not yet consumed by the host
+    pub fn pending(&self) -> usize {
+        self.responses.len()
+    }
+
+    /// Take the oldest payload sent by the host to this OUT endpoint
+    pub fn take_received(&mut self) -> Option<Vec<u8, SIM_PACKET_LEN>> {
+        self.received.pop_front()
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Data toggle the device expects for the next transaction
+    pub fn toggle(&self) -> bool {
+        self.toggle
+    }
+
+    fn reset(&mut self) {
+        self.halted = false;
+        self.toggle = false;
+    }
+
+    /// Keep a payload sent by the host, dropping the oldest one when full
+    fn receive(&mut self, buf: &[u8]) {
+        if self.received.is_full() {
+            self.received.pop_front();
+        }
+        let _ = self
+            .received
+            .push_back(Vec::from_slice(&buf[..min(buf.len(), SIM_PACKET_LEN)]).unwrap());
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct SimPort {
+    /// Something is plugged in, with this speed
+    plugged: Option<Speed>,
+    status: u16,
+    change: u16,
+}
+
+/// Downstream ports of a simulated hub
+struct SimHub {
+    ports: Vec<SimPort, SIM_MAX_PORTS>,
+    /// Ports reset since the host last checked
+    resets: u16,
+}
+
+impl SimHub {
+    fn port(&mut self, port: u16) -> Result<&mut SimPort, HostError> {
+        (port as usize)
+            .checked_sub(1)
+            .and_then(|idx| self.ports.get_mut(idx))
+            .ok_or(HostError::Stall)
+    }
+
+    fn class_in(
+        &mut self, request_type: RequestType, request: RequestCode, value: WValue, index: u16,
+    ) -> Result<Vec<u8, SIM_PACKET_LEN>, HostError> {
+        let mut reply = Vec::new();
+        match (request, request_type.recipient()) {
+            (RequestCode::GetDescriptor, Some(RequestRecipient::Device))
+                if value.w_value_hi() == HUB_DESCRIPTOR_TYPE =>
+            {
+                let nbr = self.ports.len() as u8;
+                let _ = reply.extend_from_slice(&[9, HUB_DESCRIPTOR_TYPE, nbr, 0, 0, 50, 100, 0, 0xff]);
+            }
+            (RequestCode::GetStatus, Some(RequestRecipient::Device)) => {
+                let _ = reply.extend_from_slice(&[0, 0, 0, 0]);
+            }
+            (RequestCode::GetStatus, Some(RequestRecipient::Other)) => {
+                let port = self.port(index)?;
+                let _ = reply.extend_from_slice(&port.status.to_le_bytes());
+                let _ = reply.extend_from_slice(&port.change.to_le_bytes());
+            }
+            _ => return Err(HostError::Stall),
+        }
+        Ok(reply)
+    }
+
+    fn class_out(
+        &mut self, request_type: RequestType, request: RequestCode, value: WValue, index: u16,
+    ) -> Result<(), HostError> {
+        if request_type.recipient() != Some(RequestRecipient::Other) {
+            return Ok(());
+        }
+        let feature = PortFeature::from_repr(value.w_value_lo()).ok_or(HostError::Stall)?;
+        let port = self.port(index)?;
+        let mut reset = false;
+        match (request, feature) {
+            (RequestCode::SetFeature, PortFeature::Power) => {
+                port.status |= PORT_POWER;
+                if let Some(speed) = port.plugged {
+                    port.connect(speed);
+                }
+            }
+            (RequestCode::SetFeature, PortFeature::Reset) => {
+                if port.status & PORT_CONNECTION != 0 {
+                    port.status |= PORT_ENABLE;
+                    reset = true;
+                }
+                port.status &= !PORT_RESET;
+                port.change |= C_PORT_RESET;
+            }
+            (RequestCode::ClearFeature, PortFeature::Power) => port.status = 0,
+            (RequestCode::ClearFeature, PortFeature::Enable) => port.status &= !PORT_ENABLE,
+            (RequestCode::ClearFeature, feature) if feature as u8 >= PortFeature::CConnection as u8 => {
+                port.change &= !(1 << (feature as u8 - PortFeature::CConnection as u8))
+            }
+            (RequestCode::SetFeature, _) | (RequestCode::ClearFeature, _) => {}
+            _ => return Err(HostError::Stall),
+        }
+        if reset {
+            self.resets |= 1 << index;
+        }
+        Ok(())
+    }
+
+    /// Status change bitmap, bit N for port N
+    fn changes(&self) -> u16 {
+        self.ports
+            .iter()
+            .enumerate()
+            .filter(|(_, port)| port.change != 0)
+            .fold(0, |bits, (idx, _)| bits | 1 << (idx + 1))
+    }
+}
+
+impl SimPort {
+    fn connect(&mut self, speed: Speed) {
+        self.status |= PORT_CONNECTION;
+        self.status &= !(PORT_LOW_SPEED | PORT_HIGH_SPEED);
+        self.status |= match speed {
+            Speed::Low => PORT_LOW_SPEED,
+            Speed::Full => 0,
+            Speed::High => PORT_HIGH_SPEED,
+        };
+        self.change |= C_PORT_CONNECTION;
+    }
+}
+
+/// A virtual USB device with its own descriptors and scripted endpoint behavior.
+/// Standard control requests are answered from the descriptors.
+/// Class and vendor requests are acknowledged, or answered from registered replies.
+pub struct SimDevice {
+    address: u8,
+    configuration: u8,
+    /// Interfaces switched to a non-zero alternate setting
+    alt_settings: Vec<(u8, u8), SIM_MAX_INTERFACES>,
+    speed: Speed,
+    hub: Option<SimHub>,
+    device_desc: Vec<u8, 18>,
+    configs: Vec<Vec<u8, SIM_DESC_LEN>, SIM_MAX_CONFIGS>,
+    strings: Vec<(u8, Vec<u8, SIM_PACKET_LEN>), SIM_MAX_STRINGS>,
+    endpoints: Vec<SimEndpoint, SIM_MAX_ENDPOINTS>,
+    class_replies: Vec<(u8, Vec<u8, SIM_PACKET_LEN>), SIM_MAX_STRINGS>,
+    /// HID report descriptors by interface
+    report_descs: Vec<(u8, Vec<u8, SIM_DESC_LEN>), SIM_MAX_REPORT_DESCS>,
+    control_faults: Deque<HostError, SIM_MAX_RESPONSES>,
+    requests: Deque<SetupPacket, SIM_MAX_REQUESTS>,
+    /// Data stages of class and vendor OUT requests
+    control_data: Deque<Vec<u8, SIM_PACKET_LEN>, SIM_MAX_RESPONSES>,
+    /// Bus or port resets seen, attachment included
+    resets: u8,
+}
+
+impl SimDevice {
+    /// Create a device from its raw device descriptor.
+    /// Endpoints are created from the configuration descriptors as they are added.
+    pub fn new(device_desc: &[u8]) -> Self {
+        Self {
+            address: 0,
+            configuration: 0,
+            alt_settings: Vec::new(),
+            speed: Speed::Full,
+            hub: None,
+            device_desc: Vec::from_slice(device_desc).expect("Invalid device descriptor"),
+            configs: Vec::new(),
+            strings: Vec::new(),
+            endpoints: Vec::new(),
+            class_replies: Vec::new(),
+            report_descs: Vec::new(),
+            control_faults: Deque::new(),
+            requests: Deque::new(),
+            control_data: Deque::new(),
+            resets: 0,
+        }
+    }
+
+    /// A full speed hub with `ports` downstream ports
+    pub fn new_hub(ports: u8) -> Self {
+        let mut hub = Self::new(&HUB_DEV_DESC).with_configuration(&HUB_CONF_DESC);
+        let mut sim_hub = SimHub {
+            ports: Vec::new(),
+            resets: 0,
+        };
+        for _ in 0..ports {
+            sim_hub.ports.push(SimPort::default()).expect("Too many simulated hub ports");
+        }
+        hub.hub = Some(sim_hub);
+        hub
+    }
+
+    pub fn with_speed(mut self, speed: Speed) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// Add a full configuration descriptor set (configuration, interfaces, endpoints, class descriptors)
+    pub fn with_configuration(mut self, config: &[u8]) -> Self {
+        let mut pos = 0;
+        while pos + 1 < config.len() && config[pos] != 0 {
+            let len = config[pos] as usize;
+            if config[pos + 1] == DescriptorType::Endpoint as u8 && pos + 2 < config.len() {
+                let address = config[pos + 2];
+                if self.endpoint(address).is_none() {
+                    self.endpoints
+                        .push(SimEndpoint::new(address))
+                        .ok()
+                        .expect("Too many simulated endpoints");
+                }
+            }
+            pos += len;
+        }
+        self.configs
+            .push(Vec::from_slice(config).expect("Simulated configuration too big"))
+            .expect("Too many simulated configurations");
+        self
+    }
+
+    /// Add a raw string descriptor (including its length and type bytes)
+    pub fn with_string(mut self, index: u8, desc: &[u8]) -> Self {
+        self.strings
+            .push((index, Vec::from_slice(desc).expect("Simulated string too long")))
+            .expect("Too many simulated strings");
+        self
+    }
+
+    /// HID report descriptor of interface `iface`
+    pub fn with_report_descriptor(mut self, iface: u8, desc: &[u8]) -> Self {
+        self.report_descs
+            .push((iface, Vec::from_slice(desc).expect("Simulated report descriptor too big")))
+            .expect("Too many simulated report descriptors");
+        self
+    }
+
+    /// Answer IN class or vendor requests with code `b_request` with `data`
+    pub fn with_class_reply(mut self, b_request: u8, data: &[u8]) -> Self {
+        self.class_replies
+            .push((b_request, Vec::from_slice(data).expect("Simulated reply too long")))
+            .expect("Too many simulated replies");
+        self
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Currently selected configuration value, 0 if unconfigured
+    pub fn configuration(&self) -> u8 {
+        self.configuration
+    }
+
+    /// Alternate setting selected for an interface of the current configuration
+    pub fn alt_setting(&self, iface: u8) -> u8 {
+        self.alt_settings
+            .iter()
+            .find(|(num, _)| *num == iface)
+            .map_or(0, |(_, alt)| *alt)
+    }
+
+    /// Current configuration has this interface alternate setting
+    fn has_interface(&self, iface: u8, alt: u8) -> bool {
+        let config = match self.configs.iter().find(|c| c.get(5) == Some(&self.configuration)) {
+            Some(config) => config,
+            None => return false,
+        };
+        let mut pos = 0;
+        while pos + 3 < config.len() && config[pos] != 0 {
+            let desc = &config[pos..];
+            if desc[1] == DescriptorType::Interface as u8 && desc[2] == iface && desc[3] == alt {
+                return true;
+            }
+            pos += desc[0] as usize;
+        }
+        false
+    }
+
+    pub fn endpoint(&mut self, address: u8) -> Option<&mut SimEndpoint> {
+        self.endpoints.iter_mut().find(|ep| ep.address == address)
+    }
+
+    /// Fail the next control transfer with `error`
+    pub fn fail_control(&mut self, error: HostError) {
+        self.control_faults.push_back(error).expect("Too many simulated faults")
+    }
+
+    /// Control requests received by the device, oldest first
+    pub fn requests(&self) -> impl Iterator<Item = &SetupPacket> {
+        self.requests.iter()
+    }
+
+    /// Take the oldest data stage of a class or vendor OUT request
+    pub fn take_control_data(&mut self) -> Option<Vec<u8, SIM_PACKET_LEN>> {
+        self.control_data.pop_front()
+    }
+
+    /// Forget about past control requests
+    pub fn clear_requests(&mut self) {
+        self.requests.clear()
+    }
+
+    /// Times the device was reset, attachment included
+    pub fn resets(&self) -> u8 {
+        self.resets
+    }
+
+    fn max_packet_size0(&self) -> usize {
+        self.device_desc.get(7).map_or(8, |mps| *mps as usize)
+    }
+
+    /// Back to default state, as after a bus reset
+    pub(crate) fn reset(&mut self) {
+        self.resets = self.resets.saturating_add(1);
+        self.address = 0;
+        self.configuration = 0;
+        self.endpoints.iter_mut().for_each(SimEndpoint::reset);
+        if let Some(hub) = &mut self.hub {
+            for port in &mut hub.ports {
+                port.status = 0;
+                port.change = 0;
+            }
+        }
+    }
+
+    /// Device plugged in hub port
+    pub(crate) fn port_plug(&mut self, port: PortNum, speed: Option<Speed>) {
+        if let Some(port) = self.hub.as_mut().and_then(|hub| hub.port(port as u16).ok()) {
+            port.plugged = speed;
+            match speed {
+                Some(speed) if port.status & PORT_POWER != 0 => port.connect(speed),
+                Some(_) => {}
+                None => {
+                    if port.status & PORT_CONNECTION != 0 {
+                        port.change |= C_PORT_CONNECTION;
+                    }
+                    port.status &= PORT_POWER;
+                }
+            }
+        }
+    }
+
+    /// Hub port enabled, device downstream can be reached
+    pub(crate) fn port_enabled(&self, port: PortNum) -> bool {
+        self.hub
+            .as_ref()
+            .and_then(|hub| hub.ports.get(port as usize - 1))
+            .is_some_and(|port| port.status & (PORT_ENABLE | PORT_CONNECTION) == PORT_ENABLE | PORT_CONNECTION)
+    }
+
+    /// Hub ports reset since last call, bit N for port N
+    pub(crate) fn take_port_resets(&mut self) -> u16 {
+        self.hub.as_mut().map_or(0, |hub| core::mem::take(&mut hub.resets))
+    }
+
+    pub(crate) fn control(
+        &mut self, request_type: RequestType, request: u8, value: WValue, index: u16, host_mps: u16,
+        buf: Option<&mut [u8]>,
+    ) -> Result<usize, HostError> {
+        let setup = SetupPacket {
+            bm_request_type: request_type,
+            b_request: request,
+            w_value: value,
+            w_index: index,
+            w_length: buf.as_ref().map_or(0, |b| b.len() as u16),
+        };
+        if self.requests.is_full() {
+            self.requests.pop_front();
+        }
+        let _ = self.requests.push_back(setup);
+
+        if let Some(err) = self.control_faults.pop_front() {
+            return Err(err);
+        }
+
+        let direction = request_type.direction().ok_or(HostError::InvalidRequest)?;
+        // hubs use the standard request codes for their class requests
+        let standard = RequestCode::from_repr(request).ok_or(HostError::Stall);
+        match (request_type.kind(), direction) {
+            (Some(RequestKind::Standard), RequestDirection::DeviceToHost) => {
+                let mut reply: Vec<u8, SIM_DESC_LEN> = Vec::new();
+                self.standard_in(request_type, standard?, value, index, &mut reply)?;
+                Ok(buf.map_or(0, |buf| self.data_stage(&reply, buf, host_mps)))
+            }
+            (Some(RequestKind::Standard), RequestDirection::HostToDevice) => {
+                self.standard_out(request_type, standard?, value, index)?;
+                Ok(buf.map_or(0, |b| b.len()))
+            }
+            (Some(RequestKind::Class), RequestDirection::DeviceToHost) if self.hub.is_some() => {
+                let reply = self.hub.as_mut().unwrap().class_in(request_type, standard?, value, index)?;
+                Ok(buf.map_or(0, |buf| self.data_stage(&reply, buf, host_mps)))
+            }
+            (Some(RequestKind::Class), RequestDirection::HostToDevice) if self.hub.is_some() => {
+                self.hub.as_mut().unwrap().class_out(request_type, standard?, value, index)?;
+                Ok(buf.map_or(0, |b| b.len()))
+            }
+            (_, RequestDirection::DeviceToHost) => {
+                let reply = self
+                    .class_replies
+                    .iter()
+                    .find(|(code, _)| *code == request)
+                    .map(|(_, data)| data.clone())
+                    .ok_or(HostError::Stall)?;
+                Ok(buf.map_or(0, |buf| self.data_stage(&reply, buf, host_mps)))
+            }
+            (_, RequestDirection::HostToDevice) => {
+                let data: &[u8] = buf.map_or(&[], |b| &*b);
+                if !data.is_empty() {
+                    if self.control_data.is_full() {
+                        self.control_data.pop_front();
+                    }
+                    let kept = &data[..min(data.len(), SIM_PACKET_LEN)];
+                    let _ = self.control_data.push_back(Vec::from_slice(kept).unwrap());
+                }
+                Ok(data.len())
+            }
+        }
+    }
+
+    /// Split reply in packets of the device's control packet size.
+    /// A packet shorter than what the host expects ends the data stage.
+    fn data_stage(&self, reply: &[u8], buf: &mut [u8], host_mps: u16) -> usize {
+        let dev_mps = self.max_packet_size0();
+        let mut total = 0;
+        loop {
+            let packet = min(dev_mps, reply.len() - total);
+            let take = min(packet, buf.len() - total);
+            buf[total..total + take].copy_from_slice(&reply[total..total + take]);
+            total += take;
+            if total == buf.len() || packet < host_mps as usize {
+                return total;
+            }
+        }
+    }
+
+    fn standard_in(
+        &mut self, request_type: RequestType, request: RequestCode, value: WValue, index: u16,
+        reply: &mut Vec<u8, SIM_DESC_LEN>,
+    ) -> Result<(), HostError> {
+        let data: &[u8] = match request {
+            RequestCode::GetDescriptor => match DescriptorType::from_repr(value.w_value_hi()) {
+                Some(DescriptorType::Device) => &self.device_desc,
+                Some(DescriptorType::Configuration) => {
+                    self.configs.get(value.w_value_lo() as usize).ok_or(HostError::Stall)?
+                }
+                Some(DescriptorType::String) => self
+                    .strings
+                    .iter()
+                    .find(|(idx, _)| *idx == value.w_value_lo())
+                    .map(|(_, desc)| desc.as_slice())
+                    .ok_or(HostError::Stall)?,
+                Some(DescriptorType::HidReport) if request_type.recipient() == Some(RequestRecipient::Interface) => {
+                    self.report_descs
+                        .iter()
+                        .find(|(iface, _)| *iface as u16 == index)
+                        .map(|(_, desc)| desc.as_slice())
+                        .ok_or(HostError::Stall)?
+                }
+                _ => return Err(HostError::Stall),
+            },
+            RequestCode::GetConfiguration => &[self.configuration],
+            RequestCode::GetInterface if self.has_interface(index as u8, 0) => &[self.alt_setting(index as u8)],
+            RequestCode::GetStatus => match request_type.recipient() {
+                Some(RequestRecipient::Endpoint) => {
+                    let halted = self.endpoint(index as u8).ok_or(HostError::Stall)?.halted;
+                    if halted {
+                        &[1, 0]
+                    } else {
+                        &[0, 0]
+                    }
+                }
+                _ => &[0, 0],
+            },
+            _ => return Err(HostError::Stall),
+        };
+        reply.extend_from_slice(data).map_err(|_| HostError::Stall)
+    }
+
+    fn standard_out(
+        &mut self, request_type: RequestType, request: RequestCode, value: WValue, index: u16,
+    ) -> Result<(), HostError> {
+        match request {
+            RequestCode::SetAddress => self.address = value.w_value_lo(),
+            RequestCode::SetConfiguration => {
+                let config = value.w_value_lo();
+                if config != 0 && !self.configs.iter().any(|c| c.get(5) == Some(&config)) {
+                    return Err(HostError::Stall);
+                }
+                self.configuration = config;
+                self.alt_settings.clear();
+                self.endpoints.iter_mut().for_each(SimEndpoint::reset);
+            }
+            RequestCode::SetInterface => {
+                let (iface, alt) = (index as u8, value.w_value_lo());
+                if !self.has_interface(iface, alt) {
+                    return Err(HostError::Stall);
+                }
+                self.alt_settings.retain(|(num, _)| *num != iface);
+                if alt != 0 {
+                    self.alt_settings.push((iface, alt)).map_err(|_| HostError::Stall)?;
+                }
+                self.endpoints.iter_mut().for_each(SimEndpoint::reset);
+            }
+            RequestCode::ClearFeature | RequestCode::SetFeature
+                if request_type.recipient() == Some(RequestRecipient::Endpoint)
+                    && value.w_value_lo() == FeatureSelector::EndpointHalt as u8 =>
+            {
+                let ep = self.endpoint(index as u8).ok_or(HostError::Stall)?;
+                if request == RequestCode::ClearFeature {
+                    ep.reset();
+                } else {
+                    ep.halted = true;
+                }
+            }
+            RequestCode::ClearFeature | RequestCode::SetFeature => {}
+            _ => return Err(HostError::Stall),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn in_transfer(&mut self, ep_addr: u8, toggle: bool, buf: &mut [u8]) -> Result<usize, HostError> {
+        let changes = self.hub.as_ref().map(SimHub::changes);
+        let ep = self.endpoint(ep_addr).ok_or(HostError::HardTimeout)?;
+        if ep.halted {
+            return Err(HostError::Stall);
+        }
+        if ep.toggle != toggle {
+            return Err(HostError::Toggle);
+        }
+        if let (Some(changes), HUB_STATUS_EP) = (changes, ep_addr) {
+            if changes == 0 {
+                return Err(HostError::Nak);
+            }
+            let bitmap = changes.to_le_bytes();
+            let len = min(buf.len(), bitmap.len());
+            buf[..len].copy_from_slice(&bitmap[..len]);
+            ep.toggle = !ep.toggle;
+            return Ok(len);
+        }
+        match ep.responses.pop_front() {
+            None | Some(SimResponse::Nak) => Err(HostError::Nak),
+            Some(SimResponse::Stall) => {
+                ep.halted = true;
+                Err(HostError::Stall)
+            }
+            Some(SimResponse::Timeout) => Err(HostError::HardTimeout),
+            Some(SimResponse::Data(data)) => {
+                let len = min(data.len(), buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                ep.toggle = !ep.toggle;
+                Ok(len)
+            }
+        }
+    }
+
+    pub(crate) fn out_transfer(&mut self, ep_addr: u8, toggle: bool, buf: &[u8]) -> Result<usize, HostError> {
+        let ep = self.endpoint(ep_addr).ok_or(HostError::HardTimeout)?;
+        if ep.halted {
+            return Err(HostError::Stall);
+        }
+        if ep.toggle != toggle {
+            return Err(HostError::Toggle);
+        }
+        match ep.responses.pop_front() {
+            Some(SimResponse::Nak) => Err(HostError::Nak),
+            Some(SimResponse::Stall) => {
+                ep.halted = true;
+                Err(HostError::Stall)
+            }
+            Some(SimResponse::Timeout) => Err(HostError::HardTimeout),
+            None | Some(SimResponse::Data(_)) => {
+                ep.receive(buf);
+                ep.toggle = !ep.toggle;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    /// Isochronous IN transaction: no handshake, no data toggle.
+    /// An endpoint with nothing to send (`Nak`, `Stall` or nothing queued) sends a zero length packet.
+    pub(crate) fn iso_in_transfer(&mut self, ep_addr: u8, buf: &mut [u8]) -> Result<usize, HostError> {
+        let ep = self.endpoint(ep_addr).ok_or(HostError::HardTimeout)?;
+        match ep.responses.pop_front() {
+            None | Some(SimResponse::Nak) | Some(SimResponse::Stall) => Ok(0),
+            Some(SimResponse::Timeout) => Err(HostError::HardTimeout),
+            Some(SimResponse::Data(data)) => {
+                let len = min(data.len(), buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Ok(len)
+            }
+        }
+    }
+
+    /// Isochronous OUT transaction, the data is lost only if the device does not answer
+    pub(crate) fn iso_out_transfer(&mut self, ep_addr: u8, buf: &[u8]) -> Result<usize, HostError> {
+        let ep = self.endpoint(ep_addr).ok_or(HostError::HardTimeout)?;
+        match ep.responses.pop_front() {
+            Some(SimResponse::Timeout) => Err(HostError::HardTimeout),
+            _ => {
+                ep.receive(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+}
@@ -0,0 +1,838 @@
+use core::cmp::min;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+
+use heapless::Vec;
+
+use crate::sim::{SimDevice, SIM_DESC_LEN};
+use crate::{
+    frame_after, HostEndpoint, HostError, HostEvent, PortNum, RequestDirection, RequestType, SetupPacket, Speed,
+    TransferHandle, TransferRequest, UsbHost, WValue, FRAME_NUMBER_MASK, MAX_ISO_LEN, MAX_SUBMIT_LEN,
+};
+
+/// Bus settle delay after reset. cf §7.1.7.3 of USB 2.0
+const SETTLE_DELAY: u64 = 20;
+
+/// Max number of devices on the simulated bus, hubs included
+const SIM_MAX_DEVICES: usize = 8;
+
+/// Max number of transfers submitted at once
+const SIM_MAX_TRANSFERS: usize = 8;
+
+/// Handle to a device plugged in the simulated bus
+pub type SimId = u8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SimState {
+    Init,
+    Disconnected,
+    BusSettleUntil(u64),
+    Connected,
+}
+
+struct SimNode {
+    id: SimId,
+    /// Hub id and port, None for the root port device
+    upstream: Option<(SimId, PortNum)>,
+    device: SimDevice,
+}
+
+#[derive(Clone, Copy)]
+enum SimRequest {
+    Control(SetupPacket),
+    In,
+    Out,
+    /// Isochronous transaction in `frame`, `late` if submitted once the frame had begun
+    IsoIn {
+        frame: u16,
+        late: bool,
+    },
+    IsoOut {
+        frame: u16,
+        late: bool,
+    },
+}
+
+/// A submitted transfer, attempted once per poll
+struct SimTransfer {
+    handle: TransferHandle,
+    request: SimRequest,
+    data: Vec<u8, MAX_ISO_LEN>,
+}
+
+/// Software host controller.
+/// Follows the same attach / reset / settle sequence as hardware controllers.
+/// Time only moves forward on `update()` (1 ms per call) or through `advance_millis()`.
+pub struct SimHost {
+    state: SimState,
+    now: u64,
+    /// Speed of the root port device
+    speed: Speed,
+    nodes: Vec<SimNode, SIM_MAX_DEVICES>,
+    next_id: SimId,
+    transfers: Vec<SimTransfer, SIM_MAX_TRANSFERS>,
+    next_handle: u8,
+    /// Async `wait_event` with nothing to wait for, woken by `attach`
+    waker: Option<Waker>,
+}
+
+impl SimHost {
+    /// A full speed host with nothing attached
+    pub fn new() -> Self {
+        Self {
+            state: SimState::Init,
+            now: 0,
+            speed: Speed::Full,
+            nodes: Vec::new(),
+            next_id: 0,
+            transfers: Vec::new(),
+            next_handle: 0,
+            waker: None,
+        }
+    }
+
+    pub fn state(&self) -> SimState {
+        self.state
+    }
+
+    /// Plug `device` in the root port, replacing any previous device
+    pub fn attach(&mut self, mut device: SimDevice) -> SimId {
+        self.nodes.clear();
+        device.reset();
+        self.speed = device.speed();
+        let id = self.insert(None, device);
+        if self.state == SimState::Disconnected {
+            self.state = SimState::BusSettleUntil(self.now + SETTLE_DELAY);
+        }
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        id
+    }
+
+    /// Unplug the root port device (and everything downstream)
+    pub fn detach(&mut self) -> Option<SimDevice> {
+        self.state = SimState::Init;
+        let root = self.nodes.iter().position(|node| node.upstream.is_none())?;
+        let root = self.nodes.swap_remove(root);
+        self.nodes.clear();
+        Some(root.device)
+    }
+
+    /// Plug `device` in a port of simulated hub `hub`
+    pub fn attach_to_hub(&mut self, hub: SimId, port: PortNum, mut device: SimDevice) -> SimId {
+        device.reset();
+        let speed = device.speed();
+        self.node_mut(hub).expect("No such simulated hub").port_plug(port, Some(speed));
+        self.insert(Some((hub, port)), device)
+    }
+
+    /// Unplug whatever is in port `port` of simulated hub `hub` (and everything downstream)
+    pub fn detach_from_hub(&mut self, hub: SimId, port: PortNum) -> Option<SimDevice> {
+        self.node_mut(hub)?.port_plug(port, None);
+        let idx = self.nodes.iter().position(|node| node.upstream == Some((hub, port)))?;
+        Some(self.remove(idx))
+    }
+
+    /// Root port device
+    pub fn device(&self) -> Option<&SimDevice> {
+        self.nodes.iter().find(|node| node.upstream.is_none()).map(|node| &node.device)
+    }
+
+    /// Root port device
+    pub fn device_mut(&mut self) -> Option<&mut SimDevice> {
+        self.nodes
+            .iter_mut()
+            .find(|node| node.upstream.is_none())
+            .map(|node| &mut node.device)
+    }
+
+    pub fn node(&self, id: SimId) -> Option<&SimDevice> {
+        self.nodes.iter().find(|node| node.id == id).map(|node| &node.device)
+    }
+
+    pub fn node_mut(&mut self, id: SimId) -> Option<&mut SimDevice> {
+        self.nodes.iter_mut().find(|node| node.id == id).map(|node| &mut node.device)
+    }
+
+    pub fn advance_millis(&mut self, millis: u64) {
+        self.now += millis
+    }
+
+    /// Number of submitted transfers not yet completed or cancelled
+    pub fn pending_transfers(&self) -> usize {
+        self.transfers.len()
+    }
+
+    fn insert(&mut self, upstream: Option<(SimId, PortNum)>, device: SimDevice) -> SimId {
+        let id = self.next_id;
+        self.next_id += 1;
+        if self.nodes.push(SimNode { id, upstream, device }).is_err() {
+            panic!("Too many simulated devices")
+        }
+        id
+    }
+
+    fn remove(&mut self, idx: usize) -> SimDevice {
+        let node = self.nodes.remove(idx);
+        while let Some(child) = self.nodes.iter().position(|n| n.upstream.map(|(hub, _)| hub) == Some(node.id)) {
+            self.remove(child);
+        }
+        node.device
+    }
+
+    /// Device can hear the host: root port connected and all upstream hub ports enabled.
+    /// Hubs only repeat full speed traffic to low speed devices after a preamble, which is never sent.
+    fn reachable(&self, idx: usize) -> bool {
+        match self.nodes[idx].upstream {
+            None => self.state == SimState::Connected,
+            Some(_) if self.nodes[idx].device.speed() == Speed::Low => false,
+            Some((hub, port)) => self
+                .nodes
+                .iter()
+                .position(|node| node.id == hub)
+                .is_some_and(|hub_idx| self.reachable(hub_idx) && self.nodes[hub_idx].device.port_enabled(port)),
+        }
+    }
+
+    /// Find the device answering to `addr`. Two devices answering at once garble the bus.
+    fn route(&mut self, addr: u8) -> Result<&mut SimDevice, HostError> {
+        let mut found = None;
+        for idx in 0..self.nodes.len() {
+            if self.nodes[idx].device.address() == addr && self.reachable(idx) {
+                if found.is_some() {
+                    return Err(HostError::Crc);
+                }
+                found = Some(idx);
+            }
+        }
+        found.map(|idx| &mut self.nodes[idx].device).ok_or(HostError::HardTimeout)
+    }
+
+    /// Reset devices behind hub ports that were just reset
+    fn port_resets(&mut self, addr: u8) {
+        let hub = match self.nodes.iter_mut().find(|node| node.device.address() == addr) {
+            Some(node) => node,
+            None => return,
+        };
+        let resets = hub.device.take_port_resets();
+        let hub_id = hub.id;
+        for node in self.nodes.iter_mut() {
+            if let Some((id, port)) = node.upstream {
+                if id == hub_id && resets & (1 << port) != 0 {
+                    node.device.reset();
+                }
+            }
+        }
+    }
+
+    /// Copy a submitted request and its OUT data, IN requests get a buffer of their length.
+    /// Payloads are limited to `limit` bytes.
+    fn transfer_data(request: TransferRequest, limit: usize) -> Result<(SimRequest, Vec<u8, MAX_ISO_LEN>), HostError> {
+        let (request, data) = match request {
+            TransferRequest::Control(setup, out) => {
+                let data = match setup.bm_request_type.direction() {
+                    Some(RequestDirection::DeviceToHost) => {
+                        let mut data = Vec::new();
+                        data.resize(setup.w_length as usize, 0).map_err(|_| HostError::InvalidRequest)?;
+                        data
+                    }
+                    _ => Vec::from_slice(out).map_err(|_| HostError::InvalidRequest)?,
+                };
+                (SimRequest::Control(setup), data)
+            }
+            TransferRequest::In(len) => {
+                let mut data = Vec::new();
+                data.resize(len as usize, 0).map_err(|_| HostError::InvalidRequest)?;
+                (SimRequest::In, data)
+            }
+            TransferRequest::Out(out) => {
+                (SimRequest::Out, Vec::from_slice(out).map_err(|_| HostError::InvalidRequest)?)
+            }
+        };
+        if data.len() > limit {
+            return Err(HostError::InvalidRequest);
+        }
+        Ok((request, data))
+    }
+
+    fn queue(&mut self, request: SimRequest, data: Vec<u8, MAX_ISO_LEN>) -> Result<TransferHandle, HostError> {
+        if self.transfers.is_full() {
+            return Err(HostError::Fail);
+        }
+        let mut handle = TransferHandle::from(self.next_handle);
+        while self.transfers.iter().any(|t| t.handle == handle) {
+            self.next_handle = self.next_handle.wrapping_add(1);
+            handle = TransferHandle::from(self.next_handle);
+        }
+        self.next_handle = self.next_handle.wrapping_add(1);
+        let _ = self.transfers.push(SimTransfer { handle, request, data });
+        Ok(handle)
+    }
+
+    fn current_frame(&self) -> u16 {
+        self.now as u16 & FRAME_NUMBER_MASK
+    }
+
+    /// Single attempt at a submitted transfer. A NAK leaves it pending.
+    /// Isochronous transfers stay pending until their frame, they are then done in one go.
+    fn attempt(&mut self, ep: &mut dyn HostEndpoint, transfer: &mut SimTransfer) -> Poll<Result<usize, HostError>> {
+        if let SimRequest::IsoIn { frame, late } | SimRequest::IsoOut { frame, late } = transfer.request {
+            if late {
+                return Poll::Ready(Err(HostError::FrameMissed));
+            }
+            if frame_after(frame, self.current_frame()) {
+                return Poll::Pending;
+            }
+        }
+        let addr = ep.device_address().into();
+        let dev = match self.route(addr) {
+            Ok(dev) => dev,
+            Err(err) => return Poll::Ready(Err(err)),
+        };
+        let result = match transfer.request {
+            SimRequest::Control(setup) => {
+                let data = (setup.w_length > 0).then_some(transfer.data.as_mut_slice());
+                let result = dev.control(
+                    setup.bm_request_type,
+                    setup.b_request,
+                    setup.w_value,
+                    setup.w_index,
+                    ep.max_packet_size(),
+                    data,
+                );
+                if result.is_ok() {
+                    self.port_resets(addr);
+                }
+                result
+            }
+            SimRequest::In => dev.in_transfer(ep.endpoint_address().into(), ep.toggle(), &mut transfer.data),
+            SimRequest::Out => dev.out_transfer(ep.endpoint_address().into(), ep.toggle(), &transfer.data),
+            SimRequest::IsoIn { .. } => dev.iso_in_transfer(ep.endpoint_address().into(), &mut transfer.data),
+            SimRequest::IsoOut { .. } => dev.iso_out_transfer(ep.endpoint_address().into(), &transfer.data),
+        };
+        match result {
+            Err(HostError::Nak) => Poll::Pending,
+            Err(err) => Poll::Ready(Err(err)),
+            Ok(len) => {
+                if matches!(transfer.request, SimRequest::In | SimRequest::Out) {
+                    ep.flip_toggle();
+                }
+                transfer.data.truncate(len);
+                Poll::Ready(Ok(len))
+            }
+        }
+    }
+}
+
+impl Default for SimHost {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UsbHost for SimHost {
+    fn update(&mut self) -> Option<HostEvent> {
+        self.now += 1;
+        match self.state {
+            SimState::Init => {
+                self.state = if self.nodes.is_empty() {
+                    SimState::Disconnected
+                } else {
+                    SimState::BusSettleUntil(self.now + SETTLE_DELAY)
+                };
+                Some(HostEvent::Reset)
+            }
+            SimState::BusSettleUntil(until) if self.now >= until => {
+                self.state = SimState::Connected;
+                Some(HostEvent::Ready)
+            }
+            _ => None,
+        }
+    }
+
+    fn reset_bus(&mut self) -> bool {
+        if self.state != SimState::Connected {
+            return false;
+        }
+        // downstream devices lose power along with their hubs' ports
+        self.nodes.iter_mut().for_each(|node| node.device.reset());
+        self.state = SimState::BusSettleUntil(self.now + SETTLE_DELAY);
+        true
+    }
+
+    fn max_host_packet_size(&self) -> u16 {
+        self.speed.default_max_packet_size()
+    }
+
+    fn root_port_speed(&self) -> Speed {
+        self.speed
+    }
+
+    fn now(&self) -> u64 {
+        self.now
+    }
+
+    fn after_millis(&self, millis: u64) -> u64 {
+        self.now + millis
+    }
+
+    fn control_transfer(
+        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, buf: Option<&mut [u8]>,
+    ) -> Result<usize, HostError> {
+        let host_mps = ep.max_packet_size();
+        let addr = ep.device_address().into();
+        let dev = self.route(addr)?;
+        let len = dev.control(bm_request_type, b_request, w_value, w_index, host_mps, buf)?;
+        self.port_resets(addr);
+        Ok(len)
+    }
+
+    fn control_in_stream(
+        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, w_length: u16, buf: &mut [u8], sink: &mut dyn FnMut(&[u8]),
+    ) -> Result<usize, HostError> {
+        if buf.len() < ep.max_packet_size() as usize {
+            return Err(HostError::InvalidRequest);
+        }
+        let mut data: Vec<u8, SIM_DESC_LEN> = Vec::new();
+        data.resize(w_length as usize, 0).map_err(|_| HostError::InvalidRequest)?;
+        let len = self.control_transfer(ep, bm_request_type, b_request, w_value, w_index, Some(&mut data))?;
+        for piece in data[..len].chunks(buf.len()) {
+            buf[..piece.len()].copy_from_slice(piece);
+            sink(&buf[..piece.len()]);
+        }
+        Ok(len)
+    }
+
+    fn in_transfer(&mut self, ep: &mut dyn HostEndpoint, buf: &mut [u8]) -> Result<usize, HostError> {
+        let dev = self.route(ep.device_address().into())?;
+        let len = dev.in_transfer(ep.endpoint_address().into(), ep.toggle(), buf)?;
+        ep.flip_toggle();
+        Ok(len)
+    }
+
+    fn out_transfer(&mut self, ep: &mut dyn HostEndpoint, buf: &[u8]) -> Result<usize, HostError> {
+        let dev = self.route(ep.device_address().into())?;
+        let len = dev.out_transfer(ep.endpoint_address().into(), ep.toggle(), buf)?;
+        ep.flip_toggle();
+        Ok(len)
+    }
+
+    fn submit(&mut self, _ep: &mut dyn HostEndpoint, request: TransferRequest) -> Result<TransferHandle, HostError> {
+        let (request, data) = Self::transfer_data(request, MAX_SUBMIT_LEN)?;
+        self.queue(request, data)
+    }
+
+    fn poll(
+        &mut self, ep: &mut dyn HostEndpoint, handle: TransferHandle, buf: &mut [u8],
+    ) -> Poll<Result<usize, HostError>> {
+        let idx = match self.transfers.iter().position(|t| t.handle == handle) {
+            Some(idx) => idx,
+            None => return Poll::Ready(Err(HostError::InvalidRequest)),
+        };
+        let mut transfer = self.transfers.swap_remove(idx);
+        match self.attempt(ep, &mut transfer) {
+            Poll::Pending => {
+                let _ = self.transfers.push(transfer);
+                Poll::Pending
+            }
+            Poll::Ready(Ok(len)) => {
+                if let SimRequest::In | SimRequest::IsoIn { .. } | SimRequest::Control(_) = transfer.request {
+                    let len = min(len, buf.len());
+                    buf[..len].copy_from_slice(&transfer.data[..len]);
+                }
+                Poll::Ready(Ok(len))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+        }
+    }
+
+    fn cancel(&mut self, handle: TransferHandle) {
+        self.transfers.retain(|t| t.handle != handle);
+    }
+
+    /// One frame per millisecond, as on a full speed bus
+    fn frame_number(&self) -> Option<u16> {
+        Some(self.current_frame())
+    }
+
+    fn submit_iso(
+        &mut self, ep: &mut dyn HostEndpoint, frame: u16, request: TransferRequest,
+    ) -> Result<TransferHandle, HostError> {
+        let late = !frame_after(frame, self.current_frame());
+        let limit = min(ep.max_packet_size() as usize, MAX_ISO_LEN);
+        let (request, data) = match Self::transfer_data(request, limit)? {
+            (SimRequest::In, data) => (SimRequest::IsoIn { frame, late }, data),
+            (SimRequest::Out, data) => (SimRequest::IsoOut { frame, late }, data),
+            _ => return Err(HostError::InvalidRequest),
+        };
+        self.queue(request, data)
+    }
+}
+
+/// Simulated time only moves when asked: waiting for an event advances it like `update`,
+/// delays jump straight to their end. Waiting with nothing in the settle sequence stays pending until `attach`.
+impl crate::asynch::AsyncUsbHost for SimHost {
+    fn update(&mut self) -> Option<HostEvent> {
+        UsbHost::update(self)
+    }
+
+    async fn wait_event(&mut self) -> HostEvent {
+        poll_fn(|cx| {
+            if let Some(event) = UsbHost::update(self) {
+                return Poll::Ready(event);
+            }
+            match self.state {
+                // time moves on with every poll
+                SimState::BusSettleUntil(_) => cx.waker().wake_by_ref(),
+                _ => self.waker = Some(cx.waker().clone()),
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    fn reset_bus(&mut self) -> bool {
+        UsbHost::reset_bus(self)
+    }
+
+    fn max_host_packet_size(&self) -> u16 {
+        UsbHost::max_host_packet_size(self)
+    }
+
+    fn root_port_speed(&self) -> Speed {
+        UsbHost::root_port_speed(self)
+    }
+
+    fn now(&self) -> u64 {
+        self.now
+    }
+
+    fn after_millis(&self, millis: u64) -> u64 {
+        self.now + millis
+    }
+
+    async fn delay_until(&mut self, instant: u64) {
+        self.now = self.now.max(instant);
+    }
+
+    async fn control_transfer(
+        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, buf: Option<&mut [u8]>,
+    ) -> Result<usize, HostError> {
+        UsbHost::control_transfer(self, ep, bm_request_type, b_request, w_value, w_index, buf)
+    }
+
+    async fn control_in_stream(
+        &mut self, ep: &mut dyn HostEndpoint, bm_request_type: RequestType, b_request: u8, w_value: WValue,
+        w_index: u16, w_length: u16, buf: &mut [u8], sink: &mut dyn FnMut(&[u8]),
+    ) -> Result<usize, HostError> {
+        UsbHost::control_in_stream(self, ep, bm_request_type, b_request, w_value, w_index, w_length, buf, sink)
+    }
+
+    async fn in_transfer(&mut self, ep: &mut dyn HostEndpoint, buf: &mut [u8]) -> Result<usize, HostError> {
+        UsbHost::in_transfer(self, ep, buf)
+    }
+
+    async fn out_transfer(&mut self, ep: &mut dyn HostEndpoint, buf: &[u8]) -> Result<usize, HostError> {
+        UsbHost::out_transfer(self, ep, buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::asynch::block_on;
+    use crate::sim::SimResponse;
+    use crate::{
+        BulkEndpoint, ControlEndpoint, DataToggle, DescriptorType, Device, Endpoint, EndpointProperties,
+        FeatureSelector, RequestCode, RequestDirection, RequestKind, RequestRecipient, UsbError,
+    };
+    use core::future::Future;
+    use core::pin::pin;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use core::task::Context;
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    const DEV_DESC: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x08, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+    ];
+
+    const CONF_DESC: [u8; 32] = [
+        0x09, 0x02, 0x20, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+        0x09, 0x04, 0x00, 0x00, 0x02, 0xff, 0x00, 0x00, 0x00, // vendor interface
+        0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, // bulk IN
+        0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, // bulk OUT
+    ];
+
+    fn connected() -> SimHost {
+        let mut host = SimHost::new();
+        assert_eq!(host.update(), Some(HostEvent::Reset));
+        host.attach(SimDevice::new(&DEV_DESC).with_configuration(&CONF_DESC));
+        while host.update() != Some(HostEvent::Ready) {}
+        host
+    }
+
+    #[test]
+    fn async_wait_until_attach() {
+        struct Woken(AtomicBool);
+
+        impl Wake for Woken {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::Relaxed);
+            }
+        }
+
+        let mut host = SimHost::new();
+        assert_eq!(host.update(), Some(HostEvent::Reset));
+        let woken = Arc::new(Woken(AtomicBool::new(false)));
+        let waker = Waker::from(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        {
+            let mut wait = pin!(crate::asynch::AsyncUsbHost::wait_event(&mut host));
+            assert_eq!(wait.as_mut().poll(&mut cx), Poll::Pending);
+            assert_eq!(wait.as_mut().poll(&mut cx), Poll::Pending);
+        }
+        assert!(!woken.0.load(Ordering::Relaxed));
+        host.attach(SimDevice::new(&DEV_DESC).with_configuration(&CONF_DESC));
+        assert!(woken.0.load(Ordering::Relaxed));
+        assert_eq!(block_on(crate::asynch::AsyncUsbHost::wait_event(&mut host)), HostEvent::Ready);
+    }
+
+    #[test]
+    fn attach_detach_events() {
+        let mut host = SimHost::new();
+        assert_eq!(host.update(), Some(HostEvent::Reset));
+        assert_eq!(host.update(), None);
+        host.attach(SimDevice::new(&DEV_DESC));
+        let attach_time = host.now();
+        while host.update() != Some(HostEvent::Ready) {}
+        assert!(host.now() >= attach_time + SETTLE_DELAY);
+        assert!(host.detach().is_some());
+        assert_eq!(host.update(), Some(HostEvent::Reset));
+        assert_eq!(host.state(), SimState::Disconnected);
+    }
+
+    #[test]
+    fn device_descriptor_packetized() {
+        let mut host = connected();
+        let mut dev = Device::new(Speed::Full);
+        let mut buf = [0u8; 18];
+        // device control packet size is 8, host expects 64: short packet ends transfer
+        let len = dev
+            .control_get_descriptor(&mut host, DescriptorType::Device, 0, &mut buf)
+            .unwrap();
+        assert_eq!(len, 8);
+        assert_eq!(buf[..8], DEV_DESC[..8]);
+    }
+
+    #[test]
+    fn set_address_and_configuration() {
+        let mut host = connected();
+        let mut dev = Device::new(Speed::Low);
+        dev.set_address(&mut host, 3.into()).unwrap();
+        assert_eq!(host.device().unwrap().address(), 3);
+        dev.set_configuration(&mut host, 1).unwrap();
+        assert_eq!(host.device().unwrap().configuration(), 1);
+        assert!(matches!(
+            dev.set_configuration(&mut host, 2),
+            Err(UsbError::Control(_, _, code, HostError::Stall)) if code == RequestCode::SetConfiguration as u8
+        ));
+    }
+
+    #[test]
+    fn bulk_scripted_responses() {
+        let mut host = connected();
+        let sim = host.device_mut().unwrap();
+        let ep_in = sim.endpoint(0x81).unwrap();
+        ep_in.push(SimResponse::Nak);
+        ep_in.push(SimResponse::data(&[1, 2, 3]));
+        ep_in.push(SimResponse::Stall);
+
+        let mut ep = Endpoint::from_raw(0.into(), 64, 0x81, 0x02);
+        let mut buf = [0u8; 64];
+        assert!(matches!(
+            ep.bulk_in(&mut host, &mut buf),
+            Err(UsbError::BulkIn(_, HostError::Nak))
+        ));
+        assert_eq!(ep.bulk_in(&mut host, &mut buf), Ok(3));
+        assert_eq!(buf[..3], [1, 2, 3]);
+        assert!(matches!(
+            ep.bulk_in(&mut host, &mut buf),
+            Err(UsbError::BulkIn(_, HostError::Stall))
+        ));
+        // the halt is cleared right away, transfers resume from DATA0
+        assert!(!host.device_mut().unwrap().endpoint(0x81).unwrap().is_halted());
+        assert!(!ep.toggle());
+        host.device_mut().unwrap().endpoint(0x81).unwrap().push(SimResponse::data(&[4]));
+        assert_eq!(ep.bulk_in(&mut host, &mut buf), Ok(1));
+
+        let mut dev = Device::new(Speed::Low);
+        let request = RequestType::from((
+            RequestDirection::HostToDevice,
+            RequestKind::Standard,
+            RequestRecipient::Endpoint,
+        ));
+        dev.control(
+            &mut host,
+            request,
+            RequestCode::SetFeature as u8,
+            WValue::lo_hi(0, 0),
+            0x81,
+            None,
+        )
+        .unwrap();
+        assert!(host.device_mut().unwrap().endpoint(0x81).unwrap().is_halted());
+    }
+
+    #[test]
+    fn endpoint_halt_status() {
+        let mut host = connected();
+        let mut dev = Device::new(Speed::Low);
+        let mut ep = Endpoint::from_raw(0.into(), 64, 0x02, 0x02);
+        assert_eq!(ep.bulk_out(&mut host, &[1]), Ok(1));
+        assert!(ep.toggle());
+
+        let ep_addr = ep.endpoint_address();
+        assert_eq!(dev.is_halted(&mut host, ep_addr), Ok(false));
+        dev.set_feature(&mut host, RequestRecipient::Endpoint, FeatureSelector::EndpointHalt, 0x02)
+            .unwrap();
+        assert_eq!(dev.is_halted(&mut host, ep_addr), Ok(true));
+        assert_eq!(dev.get_status(&mut host, RequestRecipient::Device, 0), Ok(0));
+
+        dev.clear_halt(&mut host, &mut ep).unwrap();
+        assert_eq!(dev.is_halted(&mut host, ep_addr), Ok(false));
+        assert!(!ep.toggle());
+        assert_eq!(ep.bulk_out(&mut host, &[2]), Ok(1));
+        // unknown endpoints stall the request
+        assert!(matches!(
+            dev.is_halted(&mut host, 0x85.into()),
+            Err(UsbError::Control(_, _, code, HostError::Stall)) if code == RequestCode::GetStatus as u8
+        ));
+    }
+
+    #[test]
+    fn bulk_out_received() {
+        let mut host = connected();
+        let mut ep = Endpoint::from_raw(0.into(), 64, 0x02, 0x02);
+        assert_eq!(ep.bulk_out(&mut host, &[9, 8, 7]), Ok(3));
+        let sim_ep = host.device_mut().unwrap().endpoint(0x02).unwrap();
+        assert_eq!(sim_ep.take_received().unwrap(), [9, 8, 7]);
+        assert!(ep.toggle());
+    }
+
+    #[test]
+    fn toggle_mismatch() {
+        let mut host = connected();
+        host.device_mut().unwrap().endpoint(0x81).unwrap().push(SimResponse::data(&[1]));
+        let mut ep = Endpoint::from_raw(0.into(), 64, 0x81, 0x02);
+        ep.set_toggle(true);
+        let mut buf = [0u8; 8];
+        assert!(matches!(
+            ep.bulk_in(&mut host, &mut buf),
+            Err(UsbError::BulkIn(_, HostError::Toggle))
+        ));
+        assert_eq!(ep.device_address(), 0.into());
+    }
+
+    #[test]
+    fn submitted_in_pending_until_data() {
+        let mut host = connected();
+        let mut ep = Endpoint::from_raw(0.into(), 64, 0x81, 0x02);
+        let handle = ep.bulk_in_submit(&mut host, 64).unwrap();
+        let mut buf = [0u8; 64];
+        // device NAKs when nothing is queued
+        assert!(ep.bulk_poll(&mut host, handle, &mut buf).is_pending());
+        assert!(ep.bulk_poll(&mut host, handle, &mut buf).is_pending());
+        host.device_mut()
+            .unwrap()
+            .endpoint(0x81)
+            .unwrap()
+            .push(SimResponse::data(&[4, 5]));
+        assert_eq!(ep.bulk_poll(&mut host, handle, &mut buf), Poll::Ready(Ok(2)));
+        assert_eq!(buf[..2], [4, 5]);
+        assert!(ep.toggle());
+        assert_eq!(host.pending_transfers(), 0);
+        // completed handles are released
+        assert!(matches!(
+            ep.bulk_poll(&mut host, handle, &mut buf),
+            Poll::Ready(Err(UsbError::BulkIn(_, HostError::InvalidRequest)))
+        ));
+    }
+
+    #[test]
+    fn submitted_transfers_are_independent() {
+        let mut host = connected();
+        let mut ep_in = Endpoint::from_raw(0.into(), 64, 0x81, 0x02);
+        let mut ep_out = Endpoint::from_raw(0.into(), 64, 0x02, 0x02);
+        let in_handle = ep_in.bulk_in_submit(&mut host, 64).unwrap();
+        let out_handle = ep_out.bulk_out_submit(&mut host, &[1, 2, 3]).unwrap();
+        assert_ne!(in_handle, out_handle);
+        assert_eq!(host.pending_transfers(), 2);
+
+        let mut buf = [0u8; 64];
+        assert!(ep_in.bulk_poll(&mut host, in_handle, &mut buf).is_pending());
+        assert_eq!(ep_out.bulk_poll(&mut host, out_handle, &mut []), Poll::Ready(Ok(3)));
+        let sim_ep = host.device_mut().unwrap().endpoint(0x02).unwrap();
+        assert_eq!(sim_ep.take_received().unwrap(), [1, 2, 3]);
+
+        host.cancel(in_handle);
+        assert_eq!(host.pending_transfers(), 0);
+        assert!(!ep_in.toggle());
+    }
+
+    #[test]
+    fn submitted_control() {
+        let mut host = connected();
+        let mut dev = Device::new(Speed::Full);
+        let request =
+            RequestType::from((RequestDirection::DeviceToHost, RequestKind::Standard, RequestRecipient::Device));
+        let setup = SetupPacket {
+            bm_request_type: request,
+            b_request: RequestCode::GetDescriptor as u8,
+            w_value: WValue::lo_hi(0, DescriptorType::Device as u8),
+            w_index: 0,
+            w_length: 18,
+        };
+        let handle = host.submit(&mut dev, TransferRequest::Control(setup, &[])).unwrap();
+        let mut buf = [0u8; 18];
+        assert_eq!(host.poll(&mut dev, handle, &mut buf), Poll::Ready(Ok(8)));
+        assert_eq!(buf[..8], DEV_DESC[..8]);
+    }
+
+    #[test]
+    fn submit_limits() {
+        let mut host = connected();
+        let mut ep = Endpoint::from_raw(0.into(), 64, 0x02, 0x02);
+        assert!(matches!(
+            ep.bulk_out_submit(&mut host, &[0; MAX_SUBMIT_LEN + 1]),
+            Err(UsbError::BulkOut(_, HostError::InvalidRequest))
+        ));
+        for _ in 0..SIM_MAX_TRANSFERS {
+            ep.bulk_out_submit(&mut host, &[0]).unwrap();
+        }
+        assert!(matches!(
+            ep.bulk_out_submit(&mut host, &[0]),
+            Err(UsbError::BulkOut(_, HostError::Fail))
+        ));
+    }
+
+    #[test]
+    fn control_fault_injection() {
+        let mut host = connected();
+        host.device_mut().unwrap().fail_control(HostError::Crc);
+        let mut dev = Device::new(Speed::Low);
+        assert!(matches!(
+            dev.set_address(&mut host, 1.into()),
+            Err(UsbError::Control(_, _, code, HostError::Crc)) if code == RequestCode::SetAddress as u8
+        ));
+        dev.set_address(&mut host, 1.into()).unwrap();
+        assert_eq!(host.device().unwrap().requests().count(), 2);
+    }
+}
@@ -0,0 +1,10 @@
+//! Simulated USB host controller and devices.
+//!
+//! `SimHost` implements `UsbHost` without any hardware, so that the stack
+//! and class drivers can be exercised with `cargo test` on the build machine.
+
+mod device;
+mod host;
+
+pub use device::*;
+pub use host::*;
@@ -1,73 +1,591 @@
+use crate::asynch::{block_on, AsyncControlEndpoint, AsyncDrivers, AsyncUsbHost, BlockingHost};
+use crate::device::DESCRIPTOR_PROBE_LEN;
 use crate::{
-    AddressPool, DescriptorParser, Device, DeviceDescriptor, DeviceState, Driver, EndpointProperties, HostEvent,
-    InterfaceNum, UsbError, UsbHost,
+    find_quirks, parse_configuration_descriptor, parse_device_descriptor, AddressPool, ConfigNum, ConfigStream,
+    ConfigurationDescriptor, DescriptorParser, DescriptorType, DevAddress, Device, DeviceClass, DeviceDescriptor,
+    DeviceState, Driver, Endpoint, EndpointProperties, HostEvent, InterfaceGroups, InterfaceNum, MaxPacketSize,
+    PortChange, PortNum, QuirkEntry, RequestCode, RequestDirection, RequestKind, RequestRecipient, RequestType, Speed,
+    TransferType, UsbError, UsbHost, WValue,
 };
 use core::cell::RefCell;
-use heapless::Vec;
+use core::cmp::min;
+use core::mem;
+use heapless::{Deque, Vec};
 
-pub struct UsbStack<H> {
-    host: RefCell<H>,
-    drivers: Vec<RefCell<&'static mut (dyn Driver + Sync + Send)>, 4>,
-    addr_pool: RefCell<AddressPool>,
-    devices: Vec<RefCell<(Device, Option<DriverIdx>)>, 16>,
+// Max number of hub port changes handled per update
+const MAX_PORT_CHANGES: usize = 4;
+
+/// Max number of interfaces bound to drivers, per device
+const MAX_BINDINGS: usize = 4;
+
+/// Max number of device events waiting to be polled, older ones are dropped
+const MAX_EVENTS: usize = 8;
+
+/// Size of each piece of a streamed configuration descriptor
+const STREAM_PIECE_LEN: usize = 64;
+
+/// Max number of quirk entries added by the application
+const MAX_QUIRKS: usize = 8;
+
+/// Blocking drivers of a `UsbStack`, added with `add_driver`
+pub type DynDrivers<const N: usize> = Vec<&'static mut (dyn Driver + Sync + Send), N>;
+
+/// `DRIVERS` and `DEVICES` are the max number of drivers and devices (hubs included, at most 127).
+/// `CONF_BUF` is the size of the buffer holding a device's configuration descriptor,
+/// or a single interface group of it when streaming.
+/// A blocking host and its drivers are driven by `update`, an `AsyncUsbHost` and its `AsyncDrivers` by `run`.
+pub struct UsbStack<
+    H,
+    const DRIVERS: usize = 4,
+    const DEVICES: usize = 16,
+    const CONF_BUF: usize = 256,
+    D = DynDrivers<DRIVERS>,
+> {
+    host: H,
+    core: StackCore<D, DEVICES, CONF_BUF>,
+}
+
+/// State machine shared by blocking and async stacks, lent the host on each update
+struct StackCore<D, const DEVICES: usize, const CONF_BUF: usize> {
+    drivers: RefCell<D>,
+    addr_pool: RefCell<AddressPool<DEVICES>>,
+    devices: Vec<RefCell<(Device, Bindings)>, DEVICES>,
+    /// Hub port being reset, its device will take the default address
+    port_reset: Option<(DevAddress, PortNum)>,
+    /// Offer configuration descriptors to drivers one interface group at a time
+    stream_config: bool,
+    /// Reset the port between the endpoint 0 packet size probe and SET_ADDRESS
+    probe_reset: bool,
+    /// Device events waiting for the application
+    events: RefCell<Deque<DeviceEvent, MAX_EVENTS>>,
+    /// Receives device events instead of the queue, if set
+    on_event: Option<fn(DeviceEvent)>,
+    /// How failed devices are enumerated again
+    retry_policy: RetryPolicy,
+    /// Application quirk entries, looked up before the built-in ones
+    quirks: Vec<QuirkEntry, MAX_QUIRKS>,
+    /// Next run of drivers with a polling interval
+    schedule: RefCell<Vec<PollDue, DEVICES>>,
+}
+
+/// A driver with a polling interval runs again once `due` is reached
+#[derive(Clone, Copy, Debug)]
+struct PollDue {
+    address: DevAddress,
+    driver: DriverIdx,
+    due: u64,
+}
+
+/// Failed devices get their port reset and are enumerated again, up to `max_retries` times.
+/// Retry N (from 0) waits `initial_delay_ms << N`, at most `max_delay_ms`.
+/// No retries by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RetryPolicy {
+    pub max_retries: u8,
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl RetryPolicy {
+    fn delay_ms(&self, retry: u8) -> u64 {
+        let factor = 1u64 << min(retry, 63);
+        min(self.initial_delay_ms.saturating_mul(factor), self.max_delay_ms)
+    }
+}
+
+/// Device lifecycle events, for the application to poll or receive through a handler
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeviceEvent {
+    /// Connected to the root port or to the `hub` port, about to be enumerated
+    DeviceAttached {
+        hub: Option<(DevAddress, PortNum)>,
+        speed: Speed,
+    },
+    /// A driver claimed one of the device's interfaces
+    DriverBound {
+        address: DevAddress,
+        interface: InterfaceNum,
+        class: DeviceClass,
+        driver: DriverIdx,
+    },
+    /// Enumeration done, with `drivers` interfaces bound (none for orphan devices)
+    DeviceConfigured {
+        address: DevAddress,
+        id_vendor: u16,
+        id_product: u16,
+        class: u8,
+        speed: Speed,
+        drivers: u8,
+    },
+    /// Enumeration or a driver failed, the device is ignored until detached
+    DeviceFailed {
+        address: DevAddress,
+        error: UsbError,
+    },
+    DeviceDetached {
+        address: DevAddress,
+    },
 }
 
 pub type DriverIdx = u8;
 
-impl<H: UsbHost> UsbStack<H> {
+/// Drivers bound to a device's interfaces.
+/// The driver handling the device's setup states comes first.
+pub type Bindings = Vec<(InterfaceNum, DriverIdx), MAX_BINDINGS>;
+
+/// Each driver bound to some interface, once
+fn bound_drivers(bindings: &Bindings) -> impl Iterator<Item = DriverIdx> + '_ {
+    bindings
+        .iter()
+        .enumerate()
+        .filter(|(i, (_, idx))| !bindings[..*i].iter().any(|(_, prev)| prev == idx))
+        .map(|(_, (_, idx))| *idx)
+}
+
+impl<H: UsbHost, const DRIVERS: usize, const DEVICES: usize, const CONF_BUF: usize>
+    UsbStack<H, DRIVERS, DEVICES, CONF_BUF>
+{
     pub fn new(host: H) -> Self {
-        Self {
-            host: RefCell::new(host),
-            drivers: Vec::new(),
-            addr_pool: RefCell::new(AddressPool::new()),
-            devices: Vec::new(),
-        }
+        Self::with_drivers(host, Vec::new())
+    }
+
+    /// Name of the driver in `DriverBound` events
+    pub fn driver_name(&mut self, driver: DriverIdx) -> Option<&str> {
+        self.core.drivers.get_mut().get(driver as usize).map(|driver| driver.name())
+    }
+
+    /// Access a device by address, e.g. to read its strings
+    pub fn with_device<R>(
+        &mut self, address: DevAddress, f: impl FnOnce(&mut dyn UsbHost, &mut Device) -> R,
+    ) -> Option<R> {
+        let cell = self
+            .core
+            .devices
+            .iter()
+            .find(|cell| cell.borrow().0.device_address() == address)?;
+        let dev = &mut cell.borrow_mut().0;
+        Some(f(&mut self.host, dev))
     }
 
     /// Drivers are added on startup, never removed
     pub fn add_driver(&mut self, driver: &'static mut (dyn Driver + Sync + Send)) {
-        self.drivers
-            .push(RefCell::new(driver))
+        self.core
+            .drivers
+            .get_mut()
+            .push(driver)
             .or(Err(UsbError::TooManyDrivers))
             .unwrap()
     }
 
     pub fn update(&mut self) {
-        let mut host = self.host.borrow_mut();
-        if let Some(host_event) = host.update() {
+        let mut host = BlockingHost::new(&mut self.host);
+        let host_event = host.update();
+        block_on(self.core.update(&mut host, host_event))
+    }
+
+    pub fn update_dev(&self, host: &mut dyn UsbHost, cell: &RefCell<(Device, Bindings)>) -> Result<(), UsbError> {
+        block_on(self.core.update_dev(&mut BlockingHost::new(host), cell))
+    }
+
+    /// Offer every configuration to the drivers, select the best scoring one
+    /// and bind each of its interface groups to the best driver for it
+    pub fn configure_dev(&self, host: &mut dyn UsbHost, device: &mut Device) -> Result<Bindings, UsbError> {
+        block_on(self.core.configure_dev(&mut BlockingHost::new(host), device))
+    }
+}
+
+impl<H: AsyncUsbHost, D: AsyncDrivers<H>, const DRIVERS: usize, const DEVICES: usize, const CONF_BUF: usize>
+    UsbStack<H, DRIVERS, DEVICES, CONF_BUF, D>
+{
+    /// Run the stack forever, to be spawned on an executor.
+    /// Sleeps until a device attaches, then wakes every millisecond for device upkeep.
+    /// Only cooperative with drivers written against `AsyncDriver`,
+    /// a `BlockingDriver` blocks the executor during its transfers.
+    pub async fn run(&mut self) -> ! {
+        loop {
+            self.run_once().await;
+            let next = self.host.after_millis(1);
+            self.host.delay_until(next).await;
+        }
+    }
+
+    async fn run_once(&mut self) {
+        let host_event =
+            if self.core.devices.is_empty() { Some(self.host.wait_event().await) } else { self.host.update() };
+        self.core.update(&mut self.host, host_event).await
+    }
+}
+
+impl<H, const DRIVERS: usize, const DEVICES: usize, const CONF_BUF: usize, D>
+    UsbStack<H, DRIVERS, DEVICES, CONF_BUF, D>
+{
+    /// Stack with a fixed set of drivers, e.g. a tuple of `AsyncDriver`
+    pub fn with_drivers(host: H, drivers: D) -> Self {
+        Self {
+            host,
+            core: StackCore {
+                drivers: RefCell::new(drivers),
+                addr_pool: RefCell::new(AddressPool::new()),
+                devices: Vec::new(),
+                port_reset: None,
+                stream_config: false,
+                probe_reset: false,
+                events: RefCell::new(Deque::new()),
+                on_event: None,
+                retry_policy: RetryPolicy::default(),
+                quirks: Vec::new(),
+                schedule: RefCell::new(Vec::new()),
+            },
+        }
+    }
+
+    /// Work around a device's deviations from the spec, this entry wins over the built-in ones
+    pub fn add_quirk(&mut self, entry: QuirkEntry) -> Result<(), UsbError> {
+        self.core.quirks.push(entry).or(Err(UsbError::TooManyQuirks))
+    }
+
+    /// Enumerate failed devices again, according to `policy`
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.core.retry_policy = policy;
+    }
+
+    /// Next device event, oldest first
+    pub fn poll_event(&mut self) -> Option<DeviceEvent> {
+        self.core.events.get_mut().pop_front()
+    }
+
+    /// Hand device events to `handler` as they happen instead of queueing them
+    pub fn set_event_handler(&mut self, handler: Option<fn(DeviceEvent)>) {
+        self.core.on_event = handler;
+    }
+
+    /// Stream configuration descriptors instead of reading them whole.
+    /// Drivers then see the configuration descriptor followed by a single interface group,
+    /// letting devices with large descriptors enumerate with a small `CONF_BUF`.
+    pub fn set_config_streaming(&mut self, stream: bool) {
+        self.core.stream_config = stream;
+    }
+
+    /// Reset the port again once the endpoint 0 packet size is known, before setting the address.
+    /// Some OSes do so, and some devices only take their address after this second reset.
+    pub fn set_probe_reset(&mut self, reset: bool) {
+        self.core.probe_reset = reset;
+    }
+
+    /// Direct access to the host controller
+    pub fn host_mut(&mut self) -> &mut H {
+        &mut self.host
+    }
+
+    /// Direct access to the drivers, e.g. to collect received data
+    pub fn drivers_mut(&mut self) -> &mut D {
+        self.core.drivers.get_mut()
+    }
+}
+
+// Cells are only borrowed by the stack's own update, which runs as a single task
+#[allow(clippy::await_holding_refcell_ref)]
+impl<D, const DEVICES: usize, const CONF_BUF: usize> StackCore<D, DEVICES, CONF_BUF> {
+    fn emit(&self, event: DeviceEvent) {
+        if let Some(handler) = self.on_event {
+            return handler(event);
+        }
+        let mut events = self.events.borrow_mut();
+        if events.is_full() {
+            warn!("USB device event dropped: {:?}", events.pop_front());
+        }
+        let _ = events.push_back(event);
+    }
+
+    async fn update<B: AsyncUsbHost>(&mut self, host: &mut B, host_event: Option<HostEvent>)
+    where
+        D: AsyncDrivers<B>,
+    {
+        if let Some(host_event) = host_event {
             match host_event {
                 HostEvent::Ready => {
-                    let root_dev = Device::new(host.max_host_packet_size());
-                    self.devices
-                        .push(RefCell::new((root_dev, None)))
-                        .expect("USB stack could not register root device");
+                    let root_dev = Device::new(host.root_port_speed());
+                    let retried = self.devices.iter().find(|cell| {
+                        let dev = &cell.borrow().0;
+                        dev.hub().is_none() && dev.state() == DeviceState::PortReset
+                    });
+                    if let Some(cell) = retried {
+                        let dev = &mut cell.borrow_mut().0;
+                        *dev = root_dev.enumerated_again(dev);
+                    } else {
+                        self.emit(DeviceEvent::DeviceAttached {
+                            hub: None,
+                            speed: root_dev.speed(),
+                        });
+                        self.devices
+                            .push(RefCell::new((root_dev, Bindings::new())))
+                            .expect("USB stack could not register root device");
+                    }
                 }
                 HostEvent::Reset => {
                     for dev_drv in self.devices.iter().map(|d| d.borrow_mut()) {
-                        if let Some(driver_idx) = dev_drv.1 {
-                            let driver = &self.drivers[driver_idx as usize];
-                            driver.borrow_mut().unregister(dev_drv.0.device_address());
+                        let address = dev_drv.0.device_address();
+                        for driver_idx in bound_drivers(&dev_drv.1) {
+                            self.drivers.borrow_mut().unregister(driver_idx, address);
                         }
+                        self.emit(DeviceEvent::DeviceDetached { address });
                     }
                     self.devices.clear();
-                    self.addr_pool.borrow_mut().reset();
+                    self.schedule.get_mut().clear();
+                    self.addr_pool.get_mut().reset();
+                    self.port_reset = None;
                 }
             }
         }
 
         // Perform device upkeep
         for cell in &self.devices {
-            if let Err(err) = self.update_dev(&mut *host, cell) {
+            if let Err(err) = self.update_dev(host, cell).await {
                 let dev = &mut cell.borrow_mut().0;
                 warn!("USB Device Failed: {:?}, Error: {:?}", dev.state(), err);
                 dev.set_error(err);
+                self.emit(DeviceEvent::DeviceFailed {
+                    address: dev.device_address(),
+                    error: err,
+                });
             }
         }
+
+        // probed devices get their port reset before their address
+        while let Some(idx) = self
+            .devices
+            .iter()
+            .position(|cell| cell.borrow().0.state() == DeviceState::ProbeReset)
+        {
+            self.reset_port(host, idx).await;
+        }
+
+        self.retry_devices(host).await;
+
+        let changes = self.port_changes(host).await;
+        for (hub, change) in changes {
+            self.apply_port_change::<B>(hub, change);
+        }
     }
 
-    pub fn update_dev(
-        &self, host: &mut dyn UsbHost, cell: &RefCell<(Device, Option<DriverIdx>)>,
-    ) -> Result<(), UsbError> {
+    /// Only one device can be enumerated on the default address at a time.
+    /// Devices waiting for retry hold their address until their port is reset.
+    fn enumerating(&self) -> bool {
+        self.port_reset.is_some()
+            || self.devices.iter().any(|cell| {
+                let dev = &cell.borrow().0;
+                u8::from(dev.device_address()) == 0 && dev.error().is_none() && dev.state() != DeviceState::PortReset
+            })
+    }
+
+    /// Schedule failed devices for another enumeration, reset their port once the delay is over
+    async fn retry_devices<B: AsyncUsbHost>(&mut self, host: &mut B)
+    where
+        D: AsyncDrivers<B>,
+    {
+        let policy = self.retry_policy;
+        while let Some(idx) = self.devices.iter().position(|cell| {
+            let dev = &cell.borrow().0;
+            dev.error().is_some() && dev.retries() < policy.max_retries
+        }) {
+            self.forget_device(host, idx);
+        }
+
+        let ready = self.devices.iter().position(|cell| match cell.borrow().0.state() {
+            DeviceState::Retry(until) => host.delay_done(until),
+            _ => false,
+        });
+        let idx = match ready {
+            Some(idx) => idx,
+            None => return,
+        };
+        let others_enumerating = self.port_reset.is_some()
+            || self.devices.iter().any(|cell| {
+                let dev = &cell.borrow().0;
+                u8::from(dev.device_address()) == 0
+                    && dev.error().is_none()
+                    && !matches!(dev.state(), DeviceState::Retry(_) | DeviceState::PortReset)
+            });
+        if !others_enumerating {
+            self.reset_port(host, idx).await;
+        }
+    }
+
+    /// Unbind a failed device, it waits in `DeviceState::Retry` to be enumerated again.
+    /// Devices downstream of it are removed.
+    fn forget_device<B: AsyncUsbHost>(&mut self, host: &mut B, idx: usize)
+    where
+        D: AsyncDrivers<B>,
+    {
+        let mut dev_drv = self.devices[idx].borrow_mut();
+        let (device, bindings) = &mut *dev_drv;
+        let addr = device.device_address();
+        for driver_idx in bound_drivers(bindings) {
+            self.drivers.get_mut().unregister(driver_idx, addr);
+        }
+        bindings.clear();
+        self.schedule.get_mut().retain(|poll| poll.address != addr);
+
+        let delay = self.retry_policy.delay_ms(device.retries());
+        info!("USB Device @{:?} retry {} in {}ms", addr, device.retries() + 1, delay);
+        device.retry(host.after_millis(delay));
+        drop(dev_drv);
+
+        if self.port_reset.map(|(hub, _)| hub) == Some(addr) {
+            self.port_reset = None;
+        }
+        if u8::from(addr) != 0 {
+            while let Some(child) = self
+                .devices
+                .iter()
+                .position(|cell| cell.borrow().0.hub().map(|(hub, _)| hub) == Some(addr))
+            {
+                self.remove_device::<B>(child);
+            }
+        }
+    }
+
+    /// Reset the port of a device done waiting for retry, through its hub driver for downstream devices.
+    /// Without a port reset, the device is enumerated again as is.
+    async fn reset_port<B: AsyncUsbHost>(&mut self, host: &mut B, idx: usize)
+    where
+        D: AsyncDrivers<B>,
+    {
+        let dev = &mut self.devices[idx].borrow_mut().0;
+        let addr = dev.device_address();
+        if u8::from(addr) != 0 {
+            self.addr_pool.get_mut().put_back(addr);
+        }
+        let (fresh, reset) = match dev.hub() {
+            None => (Device::new(host.root_port_speed()), host.reset_bus()),
+            Some((hub, port)) => {
+                let hub_cell = self.devices.iter().find(|cell| cell.borrow().0.device_address() == hub);
+                let mut reset = false;
+                if let Some(cell) = hub_cell {
+                    let mut hub_drv = cell.borrow_mut();
+                    let (hub_dev, bindings) = &mut *hub_drv;
+                    for driver_idx in bound_drivers(bindings) {
+                        if self.drivers.get_mut().reset_port(driver_idx, host, hub_dev, port).await {
+                            reset = true;
+                            break;
+                        }
+                    }
+                }
+                if reset {
+                    self.port_reset = Some((hub, port));
+                }
+                (Device::new_downstream(hub, port, dev.speed()), reset)
+            }
+        };
+        *dev = fresh.enumerated_again(dev);
+        if reset {
+            dev.set_state(DeviceState::PortReset);
+        }
+    }
+
+    /// Collect downstream port changes from hub drivers
+    async fn port_changes<B: AsyncUsbHost>(&self, host: &mut B) -> Vec<(DevAddress, PortChange), MAX_PORT_CHANGES>
+    where
+        D: AsyncDrivers<B>,
+    {
+        let mut changes = Vec::new();
+        // only one device can be enumerated on the default address at a time
+        let mut enumerating = self.enumerating();
+        for cell in &self.devices {
+            let mut dev_drv = cell.borrow_mut();
+            if dev_drv.0.state() != DeviceState::Running || dev_drv.0.error().is_some() {
+                continue;
+            }
+            let (device, bindings) = &mut *dev_drv;
+            for driver_idx in bound_drivers(bindings) {
+                let mut drivers = self.drivers.borrow_mut();
+                if let Some(change) = drivers.port_change(driver_idx, host, device, enumerating).await {
+                    enumerating |= matches!(change, PortChange::Reset(_));
+                    if changes.push((device.device_address(), change)).is_err() {
+                        return changes;
+                    }
+                }
+            }
+        }
+        changes
+    }
+
+    fn apply_port_change<B: AsyncUsbHost>(&mut self, hub: DevAddress, change: PortChange)
+    where
+        D: AsyncDrivers<B>,
+    {
+        debug!("USB Hub @{:?} {:?}", hub, change);
+        match change {
+            PortChange::Reset(port) => self.port_reset = Some((hub, port)),
+            PortChange::Attached(port, speed) => {
+                if self.port_reset == Some((hub, port)) {
+                    self.port_reset = None;
+                }
+                let dev = Device::new_downstream(hub, port, speed);
+                let retried = self.devices.iter().find(|cell| {
+                    let dev = &cell.borrow().0;
+                    dev.hub() == Some((hub, port)) && dev.state() == DeviceState::PortReset
+                });
+                if let Some(cell) = retried {
+                    let retried = &mut cell.borrow_mut().0;
+                    *retried = dev.enumerated_again(retried);
+                } else if self.devices.push(RefCell::new((dev, Bindings::new()))).is_err() {
+                    warn!("USB Hub @{:?} port {}: {:?}", hub, port, UsbError::TooManyDevices);
+                } else {
+                    self.emit(DeviceEvent::DeviceAttached {
+                        hub: Some((hub, port)),
+                        speed,
+                    });
+                }
+            }
+            PortChange::Detached(port) => {
+                if self.port_reset == Some((hub, port)) {
+                    self.port_reset = None;
+                }
+                let child = self.devices.iter().position(|cell| cell.borrow().0.hub() == Some((hub, port)));
+                if let Some(idx) = child {
+                    self.remove_device::<B>(idx);
+                }
+            }
+        }
+    }
+
+    /// Remove device and everything downstream of it
+    fn remove_device<B: AsyncUsbHost>(&mut self, idx: usize)
+    where
+        D: AsyncDrivers<B>,
+    {
+        let (dev, bindings) = self.devices.remove(idx).into_inner();
+        let addr = dev.device_address();
+        for driver_idx in bound_drivers(&bindings) {
+            self.drivers.get_mut().unregister(driver_idx, addr);
+        }
+        self.schedule.get_mut().retain(|poll| poll.address != addr);
+        if self.port_reset.map(|(hub, _)| hub) == Some(addr) {
+            self.port_reset = None;
+        }
+        if u8::from(addr) != 0 {
+            self.addr_pool.get_mut().put_back(addr);
+            while let Some(child) = self
+                .devices
+                .iter()
+                .position(|cell| cell.borrow().0.hub().map(|(hub, _)| hub) == Some(addr))
+            {
+                self.remove_device::<B>(child);
+            }
+        }
+        info!("USB Device @{:?} removed", addr);
+        self.emit(DeviceEvent::DeviceDetached { address: addr });
+    }
+
+    async fn update_dev<B: AsyncUsbHost>(
+        &self, host: &mut B, cell: &RefCell<(Device, Bindings)>,
+    ) -> Result<(), UsbError>
+    where
+        D: AsyncDrivers<B>,
+    {
         let mut dev_drv = cell.borrow_mut();
 
         if dev_drv.0.error().is_some() {
@@ -76,83 +594,1296 @@ impl<H: UsbHost> UsbStack<H> {
 
         match dev_drv.0.state() {
             DeviceState::SetAddress => {
-                let _dev_desc = self.address_dev(host, &mut dev_drv.0)?;
                 // what happens if address set fails?
-                dev_drv.0.set_state(DeviceState::SetConfig(host.after_millis(10)))
+                if self.address_dev(host, &mut dev_drv.0).await? {
+                    dev_drv.0.set_state(DeviceState::SetConfig(host.after_millis(10)))
+                }
             }
 
             DeviceState::SetConfig(until) => {
                 if host.delay_done(until) {
-                    let idx_iface = self.configure_dev(host, &mut dev_drv.0)?;
-                    if let Some((driver_idx, _iface_num)) = idx_iface {
-                        dev_drv.1 = Some(driver_idx);
-                        let driver = dev_drv.1.map(|idx| self.drivers[idx as usize].borrow_mut());
-                        if let Some(driver) = driver {
-                            let next_state = driver.state_after_config_set(host, &mut dev_drv.0);
-                            dev_drv.0.set_state(next_state);
-                        } else {
-                            return Err(UsbError::NoDriver);
-                        }
+                    // full descriptor, now that endpoint 0 packet size is known
+                    let (device, bindings) = &mut *dev_drv;
+                    get_device_descriptor(host, device).await?;
+                    let quirks = find_quirks(&self.quirks, device.id_vendor(), device.id_product());
+                    device.quirks_found(quirks);
+                    if quirks.settle_delay_ms > 0 {
+                        device.set_state(DeviceState::Settle(host.after_millis(quirks.settle_delay_ms)));
                     } else {
-                        dev_drv.0.set_state(DeviceState::Orphan);
+                        self.configure(host, device, bindings).await?;
                     }
                 }
             }
 
-            DeviceState::Orphan => {}
+            DeviceState::Settle(until) => {
+                if host.delay_done(until) {
+                    let (device, bindings) = &mut *dev_drv;
+                    self.configure(host, device, bindings).await?;
+                }
+            }
+
+            DeviceState::Orphan | DeviceState::Retry(_) | DeviceState::PortReset | DeviceState::ProbeReset => {}
 
             // Other states handled by driver
             _ => {
-                let driver = dev_drv.1.map(|idx| self.drivers[idx as usize].borrow_mut());
-                if let Some(mut driver) = driver {
-                    driver.run(host, &mut dev_drv.0)?;
+                let (device, bindings) = &mut *dev_drv;
+                let setup_driver = bindings.first().ok_or(UsbError::NoDriver)?.1;
+                if device.state() == DeviceState::Running {
+                    for driver_idx in bound_drivers(bindings) {
+                        if self.poll_due(host, device, driver_idx) {
+                            self.drivers.borrow_mut().run(driver_idx, host, device).await?;
+                        }
+                    }
                 } else {
-                    return Err(UsbError::NoDriver);
+                    self.drivers.borrow_mut().run(setup_driver, host, device).await?;
                 }
             }
         }
         Ok(())
     }
 
-    fn address_dev(&self, host: &mut dyn UsbHost, dev: &mut Device) -> Result<DeviceDescriptor, UsbError> {
-        let mut addr_pool = self.addr_pool.borrow_mut();
-        let addr = addr_pool.take_next().ok_or(UsbError::OutOfAddresses)?;
+    /// Drivers with a polling interval run once it has elapsed since their last run, others every time.
+    /// Without room left in the schedule a driver runs every time.
+    fn poll_due<B: AsyncUsbHost>(&self, host: &B, device: &Device, driver: DriverIdx) -> bool
+    where
+        D: AsyncDrivers<B>,
+    {
+        let interval = match self.drivers.borrow().poll_interval(driver, device) {
+            Some(interval) => interval,
+            None => return true,
+        };
+        let address = device.device_address();
+        let mut schedule = self.schedule.borrow_mut();
+        let next = PollDue {
+            address,
+            driver,
+            due: host.after_millis(interval),
+        };
+        match schedule
+            .iter_mut()
+            .find(|poll| poll.address == address && poll.driver == driver)
+        {
+            Some(poll) if !host.delay_done(poll.due) => false,
+            Some(poll) => {
+                *poll = next;
+                true
+            }
+            None => {
+                if schedule.push(next).is_err() {
+                    debug!("USB Device @{:?} driver {} not scheduled", address, driver);
+                }
+                true
+            }
+        }
+    }
 
-        // TODO determine correct packet size to use from descriptor
-        let short_desc = dev.get_device_descriptor(host)?;
+    /// Bind drivers and select a configuration, drivers may then ask for more setup
+    async fn configure<B: AsyncUsbHost>(
+        &self, host: &mut B, device: &mut Device, bindings: &mut Bindings,
+    ) -> Result<(), UsbError>
+    where
+        D: AsyncDrivers<B>,
+    {
+        *bindings = self.configure_dev(host, device).await?;
+        if bindings.is_empty() {
+            device.set_state(DeviceState::Orphan);
+        } else {
+            // first driver asking for more setup gets to do it, before the others run
+            let mut next_state = DeviceState::Running;
+            for i in 0..bindings.len() {
+                let state = self.drivers.borrow_mut().configured(bindings[i].1, host, device).await;
+                if state != DeviceState::Running {
+                    next_state = state;
+                    bindings.swap(0, i);
+                    break;
+                }
+            }
+            device.set_state(next_state);
+        }
+        self.emit(DeviceEvent::DeviceConfigured {
+            address: device.device_address(),
+            id_vendor: device.id_vendor(),
+            id_product: device.id_product(),
+            class: device.b_device_class(),
+            speed: device.speed(),
+            drivers: bindings.len() as u8,
+        });
+        Ok(())
+    }
+
+    /// Learn the endpoint 0 packet size then assign an address.
+    /// Some OSes reset the port again before setting the address, the spec does not require it: see `set_probe_reset`.
+    /// Returns false if the port is to be reset first.
+    async fn address_dev<B: AsyncUsbHost>(&self, host: &mut B, dev: &mut Device) -> Result<bool, UsbError> {
+        // hosts are not told the speed of each transfer
+        if dev.hub().is_some() && dev.speed() == Speed::Low {
+            return Err(UsbError::LowSpeedBehindHub);
+        }
+        let b_max_packet_size = match dev.probed() {
+            Some(b_max_packet_size) => b_max_packet_size,
+            None => probe_max_packet_size(host, dev).await?,
+        };
+        dev.max_packet_size_read(b_max_packet_size)?;
+        if self.probe_reset && dev.probed().is_none() {
+            dev.probe_reset(b_max_packet_size);
+            return Ok(false);
+        }
 
-        if let Err(err) = dev.set_address(host, addr) {
-            addr_pool.put_back(addr);
+        if u8::from(dev.device_address()) != 0 {
+            return Err(UsbError::AddressSet);
+        }
+        let addr = self.addr_pool.borrow_mut().take_next().ok_or(UsbError::OutOfAddresses)?;
+        let set_address = RequestCode::SetAddress as u8;
+        if let Err(err) = dev
+            .control_set(host, set_address, RequestRecipient::Device, addr.into(), 0, 0)
+            .await
+        {
+            self.addr_pool.borrow_mut().put_back(addr);
             return Err(err);
         }
-        Ok(short_desc)
+        dev.address_set(addr, host.after_millis(10));
+        Ok(true)
+    }
+
+    /// Offer every configuration to the drivers, select the best scoring one
+    /// and bind each of its interface groups to the best driver for it
+    async fn configure_dev<B: AsyncUsbHost>(&self, host: &mut B, device: &mut Device) -> Result<Bindings, UsbError>
+    where
+        D: AsyncDrivers<B>,
+    {
+        let num_configs = device.b_num_configurations().max(1);
+        if self.stream_config {
+            return self.configure_dev_streamed(host, device, num_configs).await;
+        }
+        let mut buf = [0u8; CONF_BUF];
+        let mut best: Option<(u16, u8)> = None;
+        let mut size = 0;
+        // configuration held in `buf`, the last one that fit
+        let mut loaded = None;
+        let mut too_big = false;
+        for cfg_idx in 0..num_configs {
+            size = match get_configuration_descriptors(host, device, cfg_idx, &mut buf).await {
+                Ok(size) => size,
+                Err(UsbError::DescriptorTooBig) => {
+                    warn!("USB Device @{:?} config {} too big, skipped", device.device_address(), cfg_idx);
+                    too_big = true;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            loaded = Some(cfg_idx);
+            let mut score = None;
+            for mut group in InterfaceGroups::new(&buf[..size]) {
+                if let Some((group_score, _)) = self.best_driver::<B>(device, &mut group) {
+                    score = Some(score.unwrap_or(0) + group_score as u16);
+                }
+            }
+            if let Some(score) = score {
+                if best.is_none_or(|(best_score, _)| score > best_score) {
+                    best = Some((score, cfg_idx));
+                }
+            }
+        }
+        let (_, cfg_idx) = match best {
+            Some(best) => best,
+            None if too_big => return Err(UsbError::DescriptorTooBig),
+            None => return Ok(Bindings::new()),
+        };
+        if loaded != Some(cfg_idx) {
+            size = get_configuration_descriptors(host, device, cfg_idx, &mut buf).await?;
+        }
+        set_configuration(host, device, configuration_value(&buf[..size])?).await?;
+        let mut bindings = Bindings::new();
+        for mut group in InterfaceGroups::new(&buf[..size]) {
+            self.bind_group::<B>(device, &mut group, &mut bindings);
+        }
+        Ok(bindings)
+    }
+
+    /// Read configurations in pieces, scoring each interface group.
+    /// The best configuration is then streamed again, binding its groups as they come.
+    async fn configure_dev_streamed<B: AsyncUsbHost>(
+        &self, host: &mut B, device: &mut Device, num_configs: u8,
+    ) -> Result<Bindings, UsbError>
+    where
+        D: AsyncDrivers<B>,
+    {
+        let mut best: Option<(u16, u8, ConfigNum)> = None;
+        for cfg_idx in 0..num_configs {
+            let mut stream: ConfigStream<CONF_BUF> = ConfigStream::new();
+            let mut score = None;
+            let mut config_value = None;
+            self.stream_config(host, device, cfg_idx, &mut stream, &mut |device, group| {
+                config_value = configuration_value(group).ok();
+                if let Some((group_score, _)) = self.best_driver::<B>(device, &mut DescriptorParser::new(group)) {
+                    score = Some(score.unwrap_or(0) + group_score as u16);
+                }
+                false
+            })
+            .await?;
+            if let (Some(score), Some(config_value)) = (score, config_value) {
+                if best.is_none_or(|(best_score, ..)| score > best_score) {
+                    best = Some((score, cfg_idx, config_value));
+                }
+            }
+        }
+        let (_, cfg_idx, config_value) = match best {
+            Some(best) => best,
+            None => return Ok(Bindings::new()),
+        };
+
+        set_configuration(host, device, config_value).await?;
+        let mut bindings = Bindings::new();
+        let mut stream: ConfigStream<CONF_BUF> = ConfigStream::new();
+        self.stream_config(host, device, cfg_idx, &mut stream, &mut |device, group| {
+            self.bind_group::<B>(device, &mut DescriptorParser::new(group), &mut bindings);
+            false
+        })
+        .await?;
+        Ok(bindings)
+    }
+
+    /// Stream configuration `cfg_idx` through `stream`, `device` is lent to `on_group` meanwhile
+    async fn stream_config<B: AsyncUsbHost>(
+        &self, host: &mut B, device: &mut Device, cfg_idx: u8, stream: &mut ConfigStream<CONF_BUF>,
+        on_group: &mut dyn FnMut(&mut Device, &[u8]) -> bool,
+    ) -> Result<(), UsbError> {
+        let config_root = get_configuration_root(host, device, cfg_idx).await?;
+
+        let request =
+            RequestType::from((RequestDirection::DeviceToHost, RequestKind::Standard, RequestRecipient::Device));
+        let w_value = WValue::lo_hi(cfg_idx, DescriptorType::Configuration as u8);
+        let dev_addr = device.device_address();
+        let mut ep0 = Endpoint::from_raw(dev_addr, device.max_packet_size(), 0, TransferType::Control as u8);
+
+        let mut on_group = |group: &[u8]| on_group(device, group);
+        let mut piece = [0u8; STREAM_PIECE_LEN];
+        host.control_in_stream(
+            &mut ep0,
+            request,
+            RequestCode::GetDescriptor as u8,
+            w_value,
+            0,
+            config_root.w_total_length,
+            &mut piece,
+            &mut |data| stream.push(data, &mut on_group),
+        )
+        .await
+        .map_err(|err| UsbError::Control(dev_addr, request, RequestCode::GetDescriptor as u8, err))?;
+        stream.finish(&mut on_group);
+        Ok(())
+    }
+
+    /// Highest scoring driver for these descriptors, the first one wins ties
+    fn best_driver<B: AsyncUsbHost>(&self, device: &mut Device, desc: &mut DescriptorParser) -> Option<(u8, DriverIdx)>
+    where
+        D: AsyncDrivers<B>,
+    {
+        let drivers = self.drivers.borrow();
+        let mut best: Option<(u8, DriverIdx)> = None;
+        for idx in 0..drivers.len() as DriverIdx {
+            desc.rewind();
+            let score = drivers.score(idx, device, desc);
+            if let Some(score) = score {
+                if best.is_none_or(|(best_score, _)| score > best_score) {
+                    best = Some((score, idx));
+                }
+            }
+        }
+        best
+    }
+
+    /// Register an interface group with the best driver for it, once the configuration is set
+    fn bind_group<B: AsyncUsbHost>(&self, device: &mut Device, group: &mut DescriptorParser, bindings: &mut Bindings)
+    where
+        D: AsyncDrivers<B>,
+    {
+        let driver_idx = match self.best_driver::<B>(device, group) {
+            Some((_, driver_idx)) => driver_idx,
+            None => return,
+        };
+        if bindings.is_full() {
+            warn!("USB Device @{:?} has too many interfaces bound", device.device_address());
+            return;
+        }
+        let mut drivers = self.drivers.borrow_mut();
+        group.rewind();
+        let (class, _, iface_num) = match drivers.accept(driver_idx, device, group) {
+            Some(accepted) => accepted,
+            None => return,
+        };
+        group.rewind();
+        if let Err(err) = drivers.register(driver_idx, device, group) {
+            warn!("USB Device @{:?} not registered:  {:?}", device.device_address(), err);
+        }
+        info!(
+            "USB Device @{:?} interface {} registered by driver '{}' for class '{:?}'",
+            device.device_address(),
+            iface_num,
+            drivers.name(driver_idx),
+            class
+        );
+        let _ = bindings.push((iface_num, driver_idx));
+        self.emit(DeviceEvent::DriverBound {
+            address: device.device_address(),
+            interface: iface_num,
+            class,
+            driver: driver_idx,
+        });
+    }
+}
+
+/// Full device descriptor, once endpoint 0 packet size is known
+async fn get_device_descriptor<B: AsyncUsbHost>(host: &mut B, device: &mut Device) -> Result<(), UsbError> {
+    let mut buf = [0u8; mem::size_of::<DeviceDescriptor>()];
+    let len = device.control_get_descriptor(host, DescriptorType::Device, 0, &mut buf).await?;
+    device.descriptor_read(&parse_device_descriptor(&buf[..len])?);
+    Ok(())
+}
+
+/// Configuration descriptor `cfg_idx` alone, its `w_total_length` tells the size of the whole configuration
+async fn get_configuration_root<B: AsyncUsbHost>(
+    host: &mut B, device: &mut Device, cfg_idx: u8,
+) -> Result<ConfigurationDescriptor, UsbError> {
+    let mut buf = [0u8; mem::size_of::<ConfigurationDescriptor>()];
+    let len = device
+        .control_get_descriptor(host, DescriptorType::Configuration, cfg_idx, &mut buf)
+        .await?;
+    Ok(parse_configuration_descriptor(&buf[..len])?)
+}
+
+/// Read the start of the device descriptor for bMaxPacketSize0.
+/// Only the first packet is requested, it is at least 8 bytes long whatever the device.
+async fn probe_max_packet_size<B: AsyncUsbHost>(host: &mut B, device: &mut Device) -> Result<u8, UsbError> {
+    let mut probe = [0u8; DESCRIPTOR_PROBE_LEN];
+    if device
+        .control_get_descriptor(host, DescriptorType::Device, 0, &mut probe)
+        .await?
+        < DESCRIPTOR_PROBE_LEN
+    {
+        return Err(UsbError::InvalidDescriptor);
+    }
+    Ok(probe[DESCRIPTOR_PROBE_LEN - 1])
+}
+
+async fn get_configuration_descriptors<B: AsyncUsbHost>(
+    host: &mut B, device: &mut Device, cfg_idx: u8, buffer: &mut [u8],
+) -> Result<usize, UsbError> {
+    let config_root = get_configuration_root(host, device, cfg_idx).await?;
+    let buffer = buffer
+        .get_mut(..config_root.w_total_length as usize)
+        .ok_or(UsbError::DescriptorTooBig)?;
+    device
+        .control_get_descriptor(host, DescriptorType::Configuration, cfg_idx, buffer)
+        .await
+}
+
+async fn set_configuration<B: AsyncUsbHost>(host: &mut B, device: &mut Device, config_num: u8) -> Result<(), UsbError> {
+    if config_num == 0 {
+        return Err(UsbError::InvalidConfig);
+    }
+    let set_configuration = RequestCode::SetConfiguration as u8;
+    device
+        .control_set(host, set_configuration, RequestRecipient::Device, config_num, 0, 0)
+        .await
+}
+
+/// Value to select the configuration described at the start of `desc`
+fn configuration_value(desc: &[u8]) -> Result<ConfigNum, UsbError> {
+    Ok(parse_configuration_descriptor(desc)?.b_configuration_value)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::asynch::{AsyncDriver, AsyncInterruptEndpoint, BlockingDriver};
+    use crate::hid::HidRequest;
+    use crate::keyboard::BootKbdDriver;
+    use crate::sim::{SimDevice, SimHost, SimResponse};
+    use crate::{
+        DescriptorRef, DeviceClass, HostError, HubDriver, MaxPacketSize, Quirks, RequestCode, RequestKind, Speed,
+    };
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::boxed::Box;
+
+    const KBD_DEV_DESC: [u8; 18] = [
+        0x12, 0x01, 0x10, 0x01, 0x00, 0x00, 0x00, 0x08, 0x3c, 0x41, 0x03, 0x20, 0x01, 0x03, 0x01, 0x02, 0x00, 0x01,
+    ];
+
+    const KBD_CONF_DESC: [u8; 34] = [
+        0x09, 0x02, 0x22, 0x00, 0x01, 0x01, 0x00, 0xa0, 0x32, // configuration
+        0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x01, 0x00, // HID boot keyboard interface
+        0x09, 0x21, 0x10, 0x01, 0x00, 0x01, 0x22, 0x41, 0x00, // HID
+        0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0a, // interrupt IN
+    ];
+
+    fn kbd_stack() -> UsbStack<SimHost> {
+        let mut stack = UsbStack::new(SimHost::new());
+        stack.add_driver(Box::leak(Box::new(<BootKbdDriver>::new())));
+        stack
+    }
+
+    fn run<const DRIVERS: usize, const DEVICES: usize, const CONF_BUF: usize>(
+        stack: &mut UsbStack<SimHost, DRIVERS, DEVICES, CONF_BUF>, ticks: usize,
+    ) {
+        for _ in 0..ticks {
+            stack.update();
+        }
+    }
+
+    #[test]
+    fn enumerate_and_bind() {
+        let mut stack = kbd_stack();
+        run(&mut stack, 5);
+        stack
+            .host_mut()
+            .attach(SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC));
+        run(&mut stack, 100);
+
+        assert_eq!(stack.core.devices.len(), 1);
+        let dev_drv = stack.core.devices[0].borrow();
+        assert_eq!(dev_drv.0.error(), None);
+        assert_eq!(dev_drv.0.state(), DeviceState::Running);
+        assert_eq!(dev_drv.1[..], [(0, 0)]);
+        drop(dev_drv);
+
+        let sim = stack.host_mut().device().unwrap();
+        assert_eq!(sim.address(), 1);
+        assert_eq!(sim.configuration(), 1);
+        assert!(sim.requests().any(|r| r.bm_request_type.kind() == Some(RequestKind::Class)));
+    }
+
+    #[test]
+    fn probe_reset() {
+        let mut stack = kbd_stack();
+        stack.set_probe_reset(true);
+        stack
+            .host_mut()
+            .attach(SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC));
+        run(&mut stack, 100);
+
+        assert_eq!(stack.core.devices.len(), 1);
+        assert_eq!(stack.core.devices[0].borrow().0.state(), DeviceState::Running);
+        let sim = stack.host_mut().device().unwrap();
+        assert_eq!(sim.address(), 1);
+        // reset on attachment, then between the probe and SET_ADDRESS
+        assert_eq!(sim.resets(), 2);
+        let probes = sim
+            .requests()
+            .filter(|r| r.b_request == RequestCode::GetDescriptor as u8 && r.w_length == DESCRIPTOR_PROBE_LEN as u16)
+            .count();
+        assert_eq!(probes, 1);
+    }
+
+    #[test]
+    fn device_descriptor_kept() {
+        let mut stack = kbd_stack();
+        let kbd = SimDevice::new(&KBD_DEV_DESC)
+            .with_speed(Speed::Low)
+            .with_configuration(&KBD_CONF_DESC);
+        stack.host_mut().attach(kbd);
+        run(&mut stack, 100);
+
+        let dev_drv = stack.core.devices[0].borrow();
+        let dev = &dev_drv.0;
+        assert_eq!(dev.id_vendor(), 0x413c);
+        assert_eq!(dev.id_product(), 0x2003);
+        assert_eq!(dev.bcd_device(), 0x0301);
+        assert_eq!(dev.b_device_class(), 0);
+        assert_eq!(dev.b_num_configurations(), 1);
+        assert_eq!(dev.speed(), Speed::Low);
+    }
+
+    #[test]
+    fn ep0_packet_size_probe() {
+        let mut stack = kbd_stack();
+        // full speed device with an 8 byte control endpoint, host starts at 64
+        stack
+            .host_mut()
+            .attach(SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC));
+        run(&mut stack, 100);
+
+        let dev_drv = stack.core.devices[0].borrow();
+        assert_eq!(dev_drv.0.state(), DeviceState::Running);
+        assert_eq!(dev_drv.0.max_packet_size(), 8);
+        assert_eq!(dev_drv.0.speed(), Speed::Full);
+        assert_eq!(dev_drv.0.id_vendor(), 0x413c);
+        drop(dev_drv);
+
+        let mut requests = stack.host_mut().device().unwrap().requests();
+        let probe = requests.next().unwrap();
+        assert_eq!(probe.b_request, RequestCode::GetDescriptor as u8);
+        assert_eq!(probe.w_length, 8);
+        assert_eq!(requests.next().unwrap().b_request, RequestCode::SetAddress as u8);
+        let full = requests.next().unwrap();
+        assert_eq!(full.b_request, RequestCode::GetDescriptor as u8);
+        assert_eq!(full.w_length, 18);
+    }
+
+    #[test]
+    fn low_speed_ep0_forced() {
+        let mut dev_desc = KBD_DEV_DESC;
+        dev_desc[7] = 64;
+        let mut stack = kbd_stack();
+        let kbd = SimDevice::new(&dev_desc)
+            .with_speed(Speed::Low)
+            .with_configuration(&KBD_CONF_DESC);
+        stack.host_mut().attach(kbd);
+        run(&mut stack, 100);
+
+        let dev_drv = stack.core.devices[0].borrow();
+        assert_eq!(dev_drv.0.state(), DeviceState::Running);
+        assert_eq!(dev_drv.0.max_packet_size(), 8);
+    }
+
+    #[test]
+    fn invalid_ep0_packet_size() {
+        let mut dev_desc = KBD_DEV_DESC;
+        dev_desc[7] = 12;
+        let mut stack = kbd_stack();
+        stack
+            .host_mut()
+            .attach(SimDevice::new(&dev_desc).with_configuration(&KBD_CONF_DESC));
+        run(&mut stack, 100);
+
+        assert_eq!(stack.core.devices[0].borrow().0.error(), Some(UsbError::InvalidDescriptor));
+        assert_eq!(stack.host_mut().device().unwrap().address(), 0);
+    }
+
+    /// Keyboard behind a vendor interface with lots of class descriptors, 331 bytes in all
+    fn big_config() -> std::vec::Vec<u8> {
+        let mut config = KBD_CONF_DESC[..9].to_vec();
+        config.extend_from_slice(&[0x09, 0x04, 0x01, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00]);
+        for _ in 0..6 {
+            let mut class_desc = [0u8; 48];
+            class_desc[0] = 48;
+            class_desc[1] = 0x24;
+            config.extend_from_slice(&class_desc);
+        }
+        config.extend_from_slice(&KBD_CONF_DESC[9..]);
+        let total = config.len() as u16;
+        config[2..4].copy_from_slice(&total.to_le_bytes());
+        config[4] = 2;
+        config
+    }
+
+    #[test]
+    fn config_too_big() {
+        let mut stack = kbd_stack();
+        stack
+            .host_mut()
+            .attach(SimDevice::new(&KBD_DEV_DESC).with_configuration(&big_config()));
+        run(&mut stack, 100);
+        assert_eq!(stack.core.devices[0].borrow().0.error(), Some(UsbError::DescriptorTooBig));
     }
 
-    pub fn configure_dev(
-        &self, host: &mut dyn UsbHost, device: &mut Device,
-    ) -> Result<Option<(DriverIdx, InterfaceNum)>, UsbError> {
-        let mut buf = [0u8; 256];
-        let size = device.get_configuration_descriptors(host, 0, &mut buf)?;
+    #[test]
+    fn config_too_big_skipped() {
+        let mut stack = kbd_stack();
+        let mut dev_desc = KBD_DEV_DESC;
+        dev_desc[17] = 2;
+        let mut kbd_conf = KBD_CONF_DESC;
+        kbd_conf[5] = 2;
+        let sim = SimDevice::new(&dev_desc)
+            .with_configuration(&big_config())
+            .with_configuration(&kbd_conf);
+        stack.host_mut().attach(sim);
+        run(&mut stack, 100);
+        assert_eq!(stack.core.devices[0].borrow().0.error(), None);
+        assert_eq!(stack.host_mut().device().unwrap().configuration(), 2);
+    }
+
+    #[test]
+    fn config_streaming() {
+        let mut stack: UsbStack<SimHost, 4, 16, 64> = UsbStack::new(SimHost::new());
+        stack.add_driver(Box::leak(Box::new(<BootKbdDriver>::new())));
+        stack.set_config_streaming(true);
+        stack
+            .host_mut()
+            .attach(SimDevice::new(&KBD_DEV_DESC).with_configuration(&big_config()));
+        run(&mut stack, 100);
+
+        let dev_drv = stack.core.devices[0].borrow();
+        assert_eq!(dev_drv.0.error(), None);
+        assert_eq!(dev_drv.0.state(), DeviceState::Running);
+        assert_eq!(dev_drv.1[..], [(0, 0)]);
+        drop(dev_drv);
+        assert_eq!(stack.host_mut().device().unwrap().configuration(), 1);
+    }
+
+    const VENDOR_CONF_DESC: [u8; 18] = [
+        0x09, 0x02, 0x12, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration 1
+        0x09, 0x04, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, // vendor interface
+    ];
+
+    /// Keyboard in its second configuration only
+    fn two_config_kbd() -> SimDevice {
+        let mut dev_desc = KBD_DEV_DESC;
+        dev_desc[17] = 2;
+        let mut kbd_conf = KBD_CONF_DESC;
+        kbd_conf[5] = 2;
+        SimDevice::new(&dev_desc)
+            .with_configuration(&VENDOR_CONF_DESC)
+            .with_configuration(&kbd_conf)
+    }
+
+    /// Claims vendor interfaces, preferring them over anything else
+    struct VendorDriver;
+
+    impl Driver for VendorDriver {
+        fn name(&self) -> &str {
+            "Vendor"
+        }
 
-        let mut desc_parser = DescriptorParser::new(&buf[0..size]);
-        for (idx, driver) in self.drivers.iter().enumerate() {
-            let mut driver = driver.borrow_mut();
-            if let Some((class, conf_num, iface_num)) = driver.accept(device, &mut desc_parser) {
-                device.set_configuration(host, conf_num)?;
-                desc_parser.rewind();
-                if let Err(err) = driver.register(device, &mut desc_parser) {
-                    warn!("USB Device @{:?} not registered:  {:?}", device.device_address(), err);
+        fn accept(
+            &self, _device: &mut Device, parser: &mut DescriptorParser,
+        ) -> Option<(DeviceClass, ConfigNum, InterfaceNum)> {
+            let mut config = None;
+            for desc in parser {
+                match desc {
+                    Ok(DescriptorRef::Configuration(cdesc)) => config = Some(cdesc.b_configuration_value),
+                    Ok(DescriptorRef::Interface(idesc)) if idesc.b_interface_class == 0xff => {
+                        return Some((DeviceClass::VendorSpecific, config?, idesc.b_interface_number));
+                    }
+                    _ => {}
                 }
-                info!(
-                    "USB Device @{:?} registered by driver '{}' for class '{:?}'",
-                    device.device_address(),
-                    driver.name(),
-                    class
-                );
-                return Ok(Some((idx as DriverIdx, iface_num)));
             }
-            desc_parser.rewind();
+            None
         }
-        Ok(None)
+
+        fn score(&self, device: &mut Device, conf: &mut DescriptorParser) -> Option<u8> {
+            self.accept(device, conf).map(|_| 10)
+        }
+
+        fn register(&mut self, _device: &mut Device, _conf: &mut DescriptorParser) -> Result<(), UsbError> {
+            Ok(())
+        }
+
+        fn unregister(&mut self, _device: DevAddress) {}
+
+        fn run(&mut self, _host: &mut dyn UsbHost, _device: &mut Device) -> Result<(), UsbError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn second_configuration() {
+        for streaming in [false, true] {
+            let mut stack = kbd_stack();
+            stack.set_config_streaming(streaming);
+            stack.host_mut().attach(two_config_kbd());
+            run(&mut stack, 100);
+
+            let dev_drv = stack.core.devices[0].borrow();
+            assert_eq!(dev_drv.0.state(), DeviceState::Running);
+            assert_eq!(dev_drv.1[..], [(0, 0)]);
+            drop(dev_drv);
+            assert_eq!(stack.host_mut().device().unwrap().configuration(), 2);
+        }
+    }
+
+    #[test]
+    fn best_score_wins() {
+        for streaming in [false, true] {
+            let mut stack = kbd_stack();
+            stack.add_driver(Box::leak(Box::new(VendorDriver)));
+            stack.set_config_streaming(streaming);
+            stack.host_mut().attach(two_config_kbd());
+            run(&mut stack, 100);
+
+            assert_eq!(stack.core.devices[0].borrow().1[..], [(0, 1)]);
+            assert_eq!(stack.host_mut().device().unwrap().configuration(), 1);
+        }
+    }
+
+    const COMPOSITE_CONF_DESC: [u8; 43] = [
+        0x09, 0x02, 0x2b, 0x00, 0x02, 0x01, 0x00, 0xa0, 0x32, // configuration
+        0x09, 0x04, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, // vendor interface
+        0x09, 0x04, 0x01, 0x00, 0x01, 0x03, 0x01, 0x01, 0x00, // HID boot keyboard interface
+        0x09, 0x21, 0x10, 0x01, 0x00, 0x01, 0x22, 0x41, 0x00, // HID
+        0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0a, // interrupt IN
+    ];
+
+    #[test]
+    fn composite_bindings() {
+        for streaming in [false, true] {
+            let mut stack = kbd_stack();
+            stack.add_driver(Box::leak(Box::new(VendorDriver)));
+            stack.set_config_streaming(streaming);
+            stack
+                .host_mut()
+                .attach(SimDevice::new(&KBD_DEV_DESC).with_configuration(&COMPOSITE_CONF_DESC));
+            run(&mut stack, 100);
+
+            let dev_drv = stack.core.devices[0].borrow();
+            assert_eq!(dev_drv.0.error(), None);
+            assert_eq!(dev_drv.0.state(), DeviceState::Running);
+            // keyboard first, it sets the protocol of its own interface
+            assert_eq!(dev_drv.1[..], [(1, 0), (0, 1)]);
+            drop(dev_drv);
+            let sim = stack.host_mut().device().unwrap();
+            let set_protocol = sim.requests().find(|r| r.bm_request_type.kind() == Some(RequestKind::Class));
+            assert_eq!(set_protocol.unwrap().w_index, 1);
+
+            let ep = stack.host_mut().device_mut().unwrap().endpoint(0x81).unwrap();
+            ep.push(SimResponse::data(&[0, 0, 4, 0, 0, 0, 0, 0]));
+            run(&mut stack, 20);
+            assert_eq!(stack.host_mut().device_mut().unwrap().endpoint(0x81).unwrap().pending(), 0);
+        }
+    }
+
+    #[test]
+    fn interrupt_polling() {
+        let mut stack = kbd_stack();
+        stack
+            .host_mut()
+            .attach(SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC));
+        run(&mut stack, 100);
+
+        let ep = stack.host_mut().device_mut().unwrap().endpoint(0x81).unwrap();
+        ep.push(SimResponse::data(&[0, 0, 4, 0, 0, 0, 0, 0]));
+        ep.push(SimResponse::data(&[0; 8]));
+        // polled every 10ms as per bInterval, not on every update
+        run(&mut stack, 10);
+        assert_eq!(stack.host_mut().device_mut().unwrap().endpoint(0x81).unwrap().pending(), 1);
+        run(&mut stack, 10);
+        assert_eq!(stack.host_mut().device_mut().unwrap().endpoint(0x81).unwrap().pending(), 0);
+        assert_eq!(stack.core.devices[0].borrow().0.error(), None);
+    }
+
+    #[test]
+    fn orphan_device() {
+        let mut stack: UsbStack<SimHost> = UsbStack::new(SimHost::new());
+        stack
+            .host_mut()
+            .attach(SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC));
+        run(&mut stack, 100);
+        assert_eq!(stack.core.devices[0].borrow().0.state(), DeviceState::Orphan);
+    }
+
+    #[test]
+    fn enumeration_failure() {
+        let mut stack = kbd_stack();
+        let mut sim = SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC);
+        sim.fail_control(HostError::Stall);
+        stack.host_mut().attach(sim);
+        run(&mut stack, 100);
+        assert!(matches!(
+            stack.core.devices[0].borrow().0.error(),
+            Some(UsbError::Control(_, _, code, HostError::Stall)) if code == RequestCode::GetDescriptor as u8
+        ));
+        assert!(matches!(stack.poll_event(), Some(DeviceEvent::DeviceAttached { .. })));
+        assert!(matches!(
+            stack.poll_event(),
+            Some(DeviceEvent::DeviceFailed {
+                error: UsbError::Control(..),
+                ..
+            })
+        ));
+        assert_eq!(stack.poll_event(), None);
+    }
+
+    const RETRY_POLICY: RetryPolicy = RetryPolicy {
+        max_retries: 2,
+        initial_delay_ms: 10,
+        max_delay_ms: 100,
+    };
+
+    #[test]
+    fn retry_delays() {
+        let delays: std::vec::Vec<_> = (0..6).map(|retry| RETRY_POLICY.delay_ms(retry)).collect();
+        assert_eq!(delays, [10, 20, 40, 80, 100, 100]);
+        assert_eq!(RETRY_POLICY.delay_ms(200), 100);
+    }
+
+    #[test]
+    fn enumeration_retry() {
+        let mut stack = kbd_stack();
+        stack.set_retry_policy(RETRY_POLICY);
+        let mut sim = SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC);
+        sim.fail_control(HostError::Crc);
+        stack.host_mut().attach(sim);
+        run(&mut stack, 200);
+
+        let dev_drv = stack.core.devices[0].borrow();
+        assert_eq!(dev_drv.0.error(), None);
+        assert_eq!(dev_drv.0.state(), DeviceState::Running);
+        assert_eq!(dev_drv.0.retries(), 1);
+        drop(dev_drv);
+        assert_eq!(stack.host_mut().device().unwrap().address(), 1);
+        assert!(matches!(stack.poll_event(), Some(DeviceEvent::DeviceAttached { .. })));
+        assert!(matches!(stack.poll_event(), Some(DeviceEvent::DeviceFailed { .. })));
+        assert!(matches!(stack.poll_event(), Some(DeviceEvent::DriverBound { .. })));
+        assert!(matches!(stack.poll_event(), Some(DeviceEvent::DeviceConfigured { .. })));
+    }
+
+    #[test]
+    fn retries_exhausted() {
+        let mut stack = kbd_stack();
+        stack.set_retry_policy(RETRY_POLICY);
+        let mut sim = SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC);
+        for _ in 0..3 {
+            sim.fail_control(HostError::Crc);
+        }
+        stack.host_mut().attach(sim);
+        run(&mut stack, 500);
+
+        let dev_drv = stack.core.devices[0].borrow();
+        assert!(dev_drv.0.error().is_some());
+        assert_eq!(dev_drv.0.retries(), 2);
+    }
+
+    #[test]
+    fn device_events() {
+        let mut stack = kbd_stack();
+        stack
+            .host_mut()
+            .attach(SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC));
+        run(&mut stack, 100);
+        stack.host_mut().detach();
+        run(&mut stack, 1);
+
+        let address = 1.into();
+        let events: std::vec::Vec<_> = core::iter::from_fn(|| stack.poll_event()).collect();
+        assert_eq!(
+            events,
+            [
+                DeviceEvent::DeviceAttached {
+                    hub: None,
+                    speed: Speed::Full
+                },
+                DeviceEvent::DriverBound {
+                    address,
+                    interface: 0,
+                    class: DeviceClass::Hid,
+                    driver: 0
+                },
+                DeviceEvent::DeviceConfigured {
+                    address,
+                    id_vendor: 0x413c,
+                    id_product: 0x2003,
+                    class: 0,
+                    speed: Speed::Full,
+                    drivers: 1
+                },
+                DeviceEvent::DeviceDetached { address },
+            ]
+        );
+        assert_eq!(stack.driver_name(0), Some("BootKbd"));
+        assert_eq!(stack.driver_name(1), None);
+    }
+
+    #[test]
+    fn device_event_handler() {
+        static DETACHED: AtomicUsize = AtomicUsize::new(0);
+        let mut stack = hub_stack();
+        stack.set_event_handler(Some(|event| {
+            if let DeviceEvent::DeviceDetached { .. } = event {
+                DETACHED.fetch_add(1, Ordering::Relaxed);
+            }
+        }));
+        let hub = stack.host_mut().attach(SimDevice::new_hub(4));
+        stack.host_mut().attach_to_hub(hub, 1, kbd());
+        stack.host_mut().attach_to_hub(hub, 2, kbd());
+        run(&mut stack, 1000);
+        assert_running(&stack, 3);
+
+        stack.host_mut().detach_from_hub(hub, 1);
+        // noticed on the next poll of the hub status endpoint, every 255ms
+        run(&mut stack, 300);
+        assert_eq!(DETACHED.load(Ordering::Relaxed), 1);
+        // hub and its remaining keyboard
+        stack.host_mut().detach();
+        run(&mut stack, 1);
+        assert_eq!(DETACHED.load(Ordering::Relaxed), 3);
+        assert_eq!(stack.poll_event(), None);
+    }
+
+    #[test]
+    fn detach_clears_devices() {
+        let mut stack = kbd_stack();
+        stack
+            .host_mut()
+            .attach(SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC));
+        run(&mut stack, 100);
+        assert_eq!(stack.core.devices.len(), 1);
+
+        stack.host_mut().detach();
+        run(&mut stack, 1);
+        assert!(stack.core.devices.is_empty());
+
+        // same device comes back, gets the same address again
+        stack
+            .host_mut()
+            .attach(SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC));
+        run(&mut stack, 100);
+        assert_eq!(stack.core.devices[0].borrow().0.state(), DeviceState::Running);
+        assert_eq!(stack.host_mut().device().unwrap().address(), 1);
+    }
+
+    fn kbd() -> SimDevice {
+        SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC)
+    }
+
+    fn hub_stack() -> UsbStack<SimHost> {
+        let mut stack = UsbStack::new(SimHost::new());
+        stack.add_driver(Box::leak(Box::new(<HubDriver>::new())));
+        stack.add_driver(Box::leak(Box::new(<BootKbdDriver>::new())));
+        stack
+    }
+
+    fn assert_running(stack: &UsbStack<SimHost>, count: usize) {
+        assert_eq!(stack.core.devices.len(), count);
+        for cell in &stack.core.devices {
+            let dev_drv = cell.borrow();
+            assert_eq!(dev_drv.0.error(), None);
+            assert_eq!(dev_drv.0.state(), DeviceState::Running);
+            assert!(!dev_drv.1.is_empty());
+        }
+    }
+
+    fn device_on(stack: &UsbStack<SimHost>, hub: u8, port: PortNum) -> Option<(DevAddress, Speed)> {
+        stack
+            .core
+            .devices
+            .iter()
+            .map(|cell| cell.borrow())
+            .find(|dev_drv| dev_drv.0.hub() == Some((hub.into(), port)))
+            .map(|dev_drv| (dev_drv.0.device_address(), dev_drv.0.speed()))
+    }
+
+    #[test]
+    fn hub_enumerates_downstream() {
+        let mut stack = hub_stack();
+        let hub = stack.host_mut().attach(SimDevice::new_hub(4));
+        stack.host_mut().attach_to_hub(hub, 1, kbd());
+        stack.host_mut().attach_to_hub(hub, 3, kbd());
+        run(&mut stack, 1000);
+
+        assert_running(&stack, 3);
+        assert_eq!(device_on(&stack, 1, 1), Some((2.into(), Speed::Full)));
+        assert_eq!(device_on(&stack, 1, 3), Some((3.into(), Speed::Full)));
+        assert_eq!(stack.core.port_reset, None);
+    }
+
+    #[test]
+    fn hub_port_low_speed_rejected() {
+        let mut stack = hub_stack();
+        let hub = stack.host_mut().attach(SimDevice::new_hub(4));
+        stack.host_mut().attach_to_hub(hub, 1, kbd().with_speed(Speed::Low));
+        stack.host_mut().attach_to_hub(hub, 3, kbd());
+        run(&mut stack, 1000);
+
+        assert_eq!(device_on(&stack, 1, 1), Some((0.into(), Speed::Low)));
+        let low_speed = stack
+            .core
+            .devices
+            .iter()
+            .find(|cell| cell.borrow().0.hub() == Some((1.into(), 1)))
+            .unwrap();
+        assert_eq!(low_speed.borrow().0.error(), Some(UsbError::LowSpeedBehindHub));
+        // the other port still enumerates
+        assert_eq!(device_on(&stack, 1, 3), Some((2.into(), Speed::Full)));
+    }
+
+    #[test]
+    fn hub_port_probe_reset() {
+        let mut stack = hub_stack();
+        stack.set_probe_reset(true);
+        let hub = stack.host_mut().attach(SimDevice::new_hub(4));
+        let kbd = stack.host_mut().attach_to_hub(hub, 1, kbd());
+        run(&mut stack, 1000);
+
+        assert_running(&stack, 2);
+        assert_eq!(device_on(&stack, 1, 1), Some((2.into(), Speed::Full)));
+        // once on attachment, once between the probe and SET_ADDRESS
+        let port_resets = stack
+            .host_mut()
+            .node(hub)
+            .unwrap()
+            .requests()
+            .filter(|r| {
+                r.b_request == RequestCode::SetFeature as u8 && r.w_value == WValue::lo_hi(4, 0) && r.w_index == 1
+            })
+            .count();
+        assert_eq!(port_resets, 2);
+        let probes = stack
+            .host_mut()
+            .node(kbd)
+            .unwrap()
+            .requests()
+            .filter(|r| r.b_request == RequestCode::GetDescriptor as u8 && r.w_length == DESCRIPTOR_PROBE_LEN as u16)
+            .count();
+        assert_eq!(probes, 1);
+    }
+
+    #[test]
+    fn small_stack() {
+        let mut stack: UsbStack<SimHost, 2, 2> = UsbStack::new(SimHost::new());
+        stack.add_driver(Box::leak(Box::new(HubDriver::<2>::new())));
+        stack.add_driver(Box::leak(Box::new(BootKbdDriver::<2>::new())));
+        let hub = stack.host_mut().attach(SimDevice::new_hub(4));
+        stack.host_mut().attach_to_hub(hub, 1, kbd());
+        stack.host_mut().attach_to_hub(hub, 3, kbd());
+        run(&mut stack, 1000);
+
+        // no room for the second keyboard
+        assert_eq!(stack.core.devices.len(), 2);
+        for cell in &stack.core.devices {
+            assert_eq!(cell.borrow().0.state(), DeviceState::Running);
+        }
+        assert_eq!(stack.core.devices[1].borrow().0.hub(), Some((1.into(), 1)));
+        assert_eq!(stack.core.addr_pool.borrow_mut().take_next(), None);
+    }
+
+    #[test]
+    fn hub_port_retry() {
+        let mut stack = hub_stack();
+        stack.set_retry_policy(RETRY_POLICY);
+        let hub = stack.host_mut().attach(SimDevice::new_hub(4));
+        let mut flaky = kbd();
+        flaky.fail_control(HostError::Toggle);
+        stack.host_mut().attach_to_hub(hub, 1, flaky);
+        stack.host_mut().attach_to_hub(hub, 2, kbd());
+        run(&mut stack, 1000);
+
+        assert_running(&stack, 3);
+        let retried = stack
+            .core
+            .devices
+            .iter()
+            .find(|cell| cell.borrow().0.hub() == Some((1.into(), 1)))
+            .unwrap();
+        assert_eq!(retried.borrow().0.retries(), 1);
+        assert_eq!(stack.core.port_reset, None);
+        // port reset twice
+        let port_resets = stack
+            .host_mut()
+            .node(hub)
+            .unwrap()
+            .requests()
+            .filter(|r| {
+                r.b_request == RequestCode::SetFeature as u8 && r.w_value == WValue::lo_hi(4, 0) && r.w_index == 1
+            })
+            .count();
+        assert_eq!(port_resets, 2);
+    }
+
+    #[test]
+    fn hub_port_detach_attach() {
+        let mut stack = hub_stack();
+        let hub = stack.host_mut().attach(SimDevice::new_hub(4));
+        stack.host_mut().attach_to_hub(hub, 1, kbd());
+        stack.host_mut().attach_to_hub(hub, 2, kbd());
+        run(&mut stack, 1000);
+        assert_running(&stack, 3);
+        let (first, _) = device_on(&stack, 1, 1).unwrap();
+
+        stack.host_mut().detach_from_hub(hub, 1);
+        // noticed on the next poll of the hub status endpoint, every 255ms
+        run(&mut stack, 300);
+        assert_running(&stack, 2);
+        assert_eq!(device_on(&stack, 1, 1), None);
+
+        // freed address gets reused
+        stack.host_mut().attach_to_hub(hub, 4, kbd());
+        run(&mut stack, 1000);
+        assert_running(&stack, 3);
+        assert_eq!(device_on(&stack, 1, 4), Some((first, Speed::Full)));
+    }
+
+    #[test]
+    fn tiered_hubs() {
+        let mut stack = hub_stack();
+        let root_hub = stack.host_mut().attach(SimDevice::new_hub(4));
+        let hub = stack.host_mut().attach_to_hub(root_hub, 2, SimDevice::new_hub(2));
+        stack.host_mut().attach_to_hub(hub, 1, kbd());
+        stack.host_mut().attach_to_hub(root_hub, 4, kbd());
+        run(&mut stack, 2000);
+        assert_running(&stack, 4);
+        let (hub_addr, _) = device_on(&stack, 1, 2).unwrap();
+        assert!(device_on(&stack, hub_addr.into(), 1).is_some());
+
+        // whole tier goes away with its hub
+        stack.host_mut().detach_from_hub(root_hub, 2);
+        // noticed on the next poll of the hub status endpoint, every 255ms
+        run(&mut stack, 300);
+        assert_running(&stack, 2);
+        assert_eq!(device_on(&stack, hub_addr.into(), 1), None);
+    }
+
+    #[test]
+    fn device_quirks() {
+        let class_requests = |stack: &mut UsbStack<SimHost>| {
+            let sim = stack.host_mut().device().unwrap();
+            sim.requests()
+                .filter(|r| r.bm_request_type.kind() == Some(RequestKind::Class))
+                .count()
+        };
+
+        // SET_PROTOCOL, SET_IDLE then SET_REPORT for the LEDs
+        let mut stack = kbd_stack();
+        stack
+            .host_mut()
+            .attach(SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC));
+        run(&mut stack, 100);
+        assert_eq!(stack.core.devices[0].borrow().0.quirks(), &Quirks::NONE);
+        assert_eq!(class_requests(&mut stack), 3);
+        let sim = stack.host_mut().device().unwrap();
+        assert!(sim.requests().any(|r| r.b_request == HidRequest::SetIdle as u8));
+
+        let quirks = Quirks {
+            settle_delay_ms: 200,
+            no_strings: true,
+            no_set_idle: true,
+            ..Quirks::NONE
+        };
+        let mut stack = kbd_stack();
+        stack.add_quirk(QuirkEntry::product(0x413c, 0x2003, quirks)).unwrap();
+        stack
+            .host_mut()
+            .attach(SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC));
+        run(&mut stack, 100);
+        assert!(matches!(stack.core.devices[0].borrow().0.state(), DeviceState::Settle(_)));
+        assert_eq!(stack.host_mut().device().unwrap().configuration(), 0);
+
+        run(&mut stack, 200);
+        let dev_drv = stack.core.devices[0].borrow();
+        assert_eq!(dev_drv.0.state(), DeviceState::Running);
+        assert_eq!(dev_drv.0.quirks(), &quirks);
+        let addr = dev_drv.0.device_address();
+        drop(dev_drv);
+        let sim = stack.host_mut().device().unwrap();
+        assert!(!sim.requests().any(|r| r.b_request == HidRequest::SetIdle as u8));
+        assert_eq!(class_requests(&mut stack), 2);
+        let mut buf = [0u8; 32];
+        let product = stack.with_device(addr, |host, dev| dev.product(host, &mut buf).err());
+        assert_eq!(product, Some(Some(UsbError::NoString)));
+    }
+
+    /// Native async driver counting interrupt reports
+    #[derive(Default)]
+    struct ReportCounter {
+        endpoint: Option<Endpoint>,
+        reports: usize,
+    }
+
+    impl<H: AsyncUsbHost> AsyncDriver<H> for ReportCounter {
+        fn name(&self) -> &str {
+            "ReportCounter"
+        }
+
+        fn accept(
+            &self, _device: &mut Device, conf: &mut DescriptorParser,
+        ) -> Option<(DeviceClass, ConfigNum, InterfaceNum)> {
+            let mut config_num = None;
+            for desc in conf {
+                match desc {
+                    Ok(DescriptorRef::Configuration(cdesc)) => config_num = Some(cdesc.b_configuration_value),
+                    Ok(DescriptorRef::Interface(idesc)) if idesc.b_interface_class == DeviceClass::Hid as u8 => {
+                        return Some((DeviceClass::Hid, config_num?, idesc.b_interface_number));
+                    }
+                    _ => {}
+                }
+            }
+            None
+        }
+
+        fn register(&mut self, device: &mut Device, conf: &mut DescriptorParser) -> Result<(), UsbError> {
+            for desc in conf {
+                if let Ok(DescriptorRef::Endpoint(edesc)) = desc {
+                    self.endpoint = Some(Endpoint::from_raw(
+                        device.device_address(),
+                        edesc.max_packet_size(),
+                        edesc.b_endpoint_address,
+                        edesc.bm_attributes,
+                    ));
+                }
+            }
+            Ok(())
+        }
+
+        fn unregister(&mut self, device: DevAddress) {
+            if self.endpoint.as_ref().map(|ep| ep.device_address()) == Some(device) {
+                self.endpoint = None;
+            }
+        }
+
+        async fn run(&mut self, host: &mut H, _device: &mut Device) -> Result<(), UsbError> {
+            let endpoint = self.endpoint.as_mut().ok_or(UsbError::Driver)?;
+            let mut buf = [0u8; 8];
+            match endpoint.interrupt_in(host, &mut buf).await {
+                Ok(_) => self.reports += 1,
+                Err(UsbError::Interrupt(_, HostError::Nak)) => {}
+                Err(err) => return Err(err),
+            }
+            Ok(())
+        }
+    }
+
+    fn async_stack<D: AsyncDrivers<SimHost>>(drivers: D) -> UsbStack<SimHost, 0, 16, 256, D> {
+        let mut stack = UsbStack::with_drivers(SimHost::new(), drivers);
+        stack
+            .host_mut()
+            .attach(SimDevice::new(&KBD_DEV_DESC).with_configuration(&KBD_CONF_DESC));
+        stack
+    }
+
+    fn run_async<D: AsyncDrivers<SimHost>>(stack: &mut UsbStack<SimHost, 0, 16, 256, D>, ticks: usize) {
+        block_on(async {
+            for _ in 0..ticks {
+                stack.run_once().await
+            }
+        })
+    }
+
+    #[test]
+    fn async_stack_blocking_driver() {
+        let mut stack = async_stack((BlockingDriver::new(<BootKbdDriver>::new()),));
+        run_async(&mut stack, 100);
+
+        let dev_drv = stack.core.devices[0].borrow();
+        assert_eq!(dev_drv.0.error(), None);
+        assert_eq!(dev_drv.0.state(), DeviceState::Running);
+        assert_eq!(dev_drv.1.as_slice(), &[(0, 0)]);
+        drop(dev_drv);
+        let sim = stack.host_mut().device().unwrap();
+        assert_eq!(sim.address(), 1);
+        assert_eq!(sim.configuration(), 1);
+    }
+
+    #[test]
+    fn async_stack_async_driver() {
+        let mut stack = async_stack((BlockingDriver::new(<HubDriver>::new()), ReportCounter::default()));
+        run_async(&mut stack, 100);
+        assert_eq!(stack.core.devices[0].borrow().0.state(), DeviceState::Running);
+        assert_eq!(stack.core.devices[0].borrow().1.as_slice(), &[(0, 1)]);
+        assert_eq!(stack.drivers_mut().1.reports, 0);
+
+        let ep = stack.host_mut().device_mut().unwrap().endpoint(0x81).unwrap();
+        ep.push(SimResponse::data(&[0, 0, 4, 0, 0, 0, 0, 0]));
+        ep.push(SimResponse::data(&[0; 8]));
+        run_async(&mut stack, 3);
+        assert_eq!(stack.drivers_mut().1.reports, 2);
+        assert_eq!(stack.core.devices[0].borrow().0.error(), None);
+    }
+
+    #[test]
+    fn async_stack_orphan_and_detach() {
+        let mut stack = async_stack(());
+        run_async(&mut stack, 100);
+        assert_eq!(stack.core.devices[0].borrow().0.state(), DeviceState::Orphan);
+        assert!(matches!(stack.poll_event(), Some(DeviceEvent::DeviceAttached { .. })));
+        assert!(matches!(
+            stack.poll_event(),
+            Some(DeviceEvent::DeviceConfigured { drivers: 0, .. })
+        ));
+
+        stack.host_mut().detach();
+        run_async(&mut stack, 1);
+        assert!(stack.core.devices.is_empty());
+        assert!(matches!(stack.poll_event(), Some(DeviceEvent::DeviceDetached { .. })));
     }
 }